@@ -0,0 +1,287 @@
+//! Activity feed of boards and what's on them.
+//!
+//! Each operation records who made it and when. When it is applied, entries
+//! describing it are added to the feed, which is kept separately from the
+//! operation log so it survives pruning. Only operations applied on this
+//! replica are in its feed: a replica that started from a snapshot doesn't
+//! know what happened before it.
+
+use serde::{Serialize, Deserialize};
+use std::fmt;
+use uuid::Uuid;
+
+use super::{Card, Change, List, Operation};
+
+/// Who made a change.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize,
+         Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Actor {
+    User { name: String },
+    /// An automation rule, acting on its own
+    Rule { name: String },
+}
+
+impl fmt::Display for Actor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Actor::User { ref name } => write!(f, "{}", name),
+            Actor::Rule { ref name } => write!(f, "rule '{}'", name),
+        }
+    }
+}
+
+/// The kind of object an activity is about.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Board,
+    List,
+    Card,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Kind::Board => write!(f, "board"),
+            Kind::List => write!(f, "list"),
+            Kind::Card => write!(f, "card"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Created { name: String },
+    Removed { name: String },
+    Renamed { from: String, to: String },
+    /// A card moved between lists, named by the lists
+    Moved { name: String, from: String, to: String },
+    Labeled { name: String, added: Vec<String>, removed: Vec<String> },
+    /// A change to something else, such as a card's description
+    Edited { name: String },
+}
+
+/// An entry in the activity feed.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Activity {
+    /// The operation this comes from
+    pub operation: Uuid,
+    pub board_id: Uuid,
+    /// The object this is about, the board itself or something on it
+    pub subject: Uuid,
+    pub kind: Kind,
+    pub actor: Option<Actor>,
+    /// When the change was made, in seconds since the epoch
+    pub time: Option<u64>,
+    pub event: Event,
+}
+
+impl Activity {
+    /// Describe an operation, with an entry for each thing it changed.
+    ///
+    /// `lists` are the lists on the board, to name the ones a card moved
+    /// between. Encrypted operations can't be described, they have to be
+    /// decrypted first.
+    pub fn from_operation(op: &Operation, lists: &[List]) -> Vec<Activity> {
+        let entry = |subject, kind, event| Activity {
+            operation: op.id,
+            board_id: op.board_id,
+            subject,
+            kind,
+            actor: op.actor.clone(),
+            time: op.time,
+            event,
+        };
+        let (subject, kind, event) = match op.change {
+            Change::AddBoard { ref name } => {
+                (op.board_id, Kind::Board,
+                 Event::Created { name: name.clone() })
+            }
+            Change::RemoveBoard { ref name } => {
+                (op.board_id, Kind::Board,
+                 Event::Removed { name: name.clone() })
+            }
+            Change::AddList { ref list } => {
+                (list.id, Kind::List, Event::Created { name: list.name.clone() })
+            }
+            Change::RemoveList { ref list } => {
+                (list.id, Kind::List, Event::Removed { name: list.name.clone() })
+            }
+            Change::AddCard { ref card, previous: Some(ref previous) } => {
+                return card_changes(card, previous, lists).into_iter()
+                    .map(|event| entry(card.id, Kind::Card, event))
+                    .collect();
+            }
+            Change::AddCard { ref card, previous: None } => {
+                (card.id, Kind::Card, Event::Created { name: card.title.clone() })
+            }
+            Change::RemoveCard { ref card } => {
+                (card.id, Kind::Card, Event::Removed { name: card.title.clone() })
+            }
+            Change::Encrypted { .. } => return Vec::new(),
+        };
+        vec![entry(subject, kind, event)]
+    }
+}
+
+/// Describe how a card was changed, by comparing it with the one it
+/// replaced.
+fn card_changes(card: &Card, previous: &Card, lists: &[List]) -> Vec<Event> {
+    let mut events = Vec::new();
+    if card.title != previous.title {
+        events.push(Event::Renamed {
+            from: previous.title.clone(),
+            to: card.title.clone(),
+        });
+    }
+    if card.list != previous.list {
+        let list_name = |id| match lists.iter().find(|l| l.id == id) {
+            Some(list) => list.name.clone(),
+            None => id.to_string(),
+        };
+        events.push(Event::Moved {
+            name: card.title.clone(),
+            from: list_name(previous.list),
+            to: list_name(card.list),
+        });
+    }
+    let added: Vec<String> = card.labels.iter()
+        .filter(|l| !previous.labels.contains(l))
+        .cloned()
+        .collect();
+    let removed: Vec<String> = previous.labels.iter()
+        .filter(|l| !card.labels.contains(l))
+        .cloned()
+        .collect();
+    if !added.is_empty() || !removed.is_empty() {
+        events.push(Event::Labeled {
+            name: card.title.clone(),
+            added,
+            removed,
+        });
+    }
+    if events.is_empty() && card != previous {
+        events.push(Event::Edited { name: card.title.clone() });
+    }
+    events
+}
+
+impl fmt::Display for Activity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.actor {
+            Some(ref actor) => write!(f, "{} ", actor)?,
+            None => write!(f, "someone ")?,
+        }
+        match self.event {
+            Event::Created { ref name } => {
+                write!(f, "created {} '{}'", self.kind, name)
+            }
+            Event::Removed { ref name } => {
+                write!(f, "removed {} '{}'", self.kind, name)
+            }
+            Event::Renamed { ref from, ref to } => {
+                write!(f, "renamed {} '{}' to '{}'", self.kind, from, to)
+            }
+            Event::Moved { ref name, ref from, ref to } => {
+                write!(f, "moved {} '{}' from '{}' to '{}'", self.kind, name,
+                       from, to)
+            }
+            Event::Labeled { ref name, ref added, ref removed } => {
+                write!(f, "labeled {} '{}'", self.kind, name)?;
+                if !added.is_empty() {
+                    write!(f, " with {}", added.join(", "))?;
+                }
+                if !removed.is_empty() {
+                    let sep = if added.is_empty() { "" } else { "," };
+                    write!(f, "{} removing {}", sep, removed.join(", "))?;
+                }
+                Ok(())
+            }
+            Event::Edited { ref name } => {
+                write!(f, "edited {} '{}'", self.kind, name)
+            }
+        }
+    }
+}
+
+/// The current time, in seconds since the epoch.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn now() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// The current time, in seconds since the epoch.
+#[cfg(target_arch = "wasm32")]
+pub fn now() -> u64 {
+    (js_sys::Date::now() / 1000.0) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use crate::{Card, Change, List, Operation};
+    use super::{Activity, Actor, Event, Kind};
+
+    #[test]
+    fn test_from_operation() {
+        let list = List { id: Uuid::new_v4(), name: "todo".into() };
+        let mut op = Operation::new(Uuid::new_v4(), Change::AddList {
+            list: list.clone(),
+        });
+        op.actor = Some(Actor::User { name: "remram".into() });
+        let activity = Activity::from_operation(&op, &[]).remove(0);
+        assert_eq!(activity.subject, list.id);
+        assert_eq!(activity.kind, Kind::List);
+        assert_eq!(activity.event, Event::Created { name: "todo".into() });
+        assert_eq!(activity.time, op.time);
+        assert_eq!(activity.to_string(), "remram created list 'todo'");
+
+        let op = Operation::new(op.board_id, Change::Encrypted {
+            key_id: "0011223344556677".into(),
+            data: "".into(),
+        });
+        assert!(Activity::from_operation(&op, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_card_changes() {
+        let todo = List { id: Uuid::new_v4(), name: "todo".into() };
+        let done = List { id: Uuid::new_v4(), name: "done".into() };
+        let mut previous = Card::new(todo.id, "card");
+        previous.labels = vec!["bug".into(), "ui".into()];
+        let describe = |card: Card| {
+            let op = Operation::new(Uuid::new_v4(), Change::AddCard {
+                card,
+                previous: Some(previous.clone()),
+            });
+            Activity::from_operation(&op, &[todo.clone(), done.clone()])
+                .into_iter()
+                .map(|a| a.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            describe(Card {
+                title: "renamed".into(),
+                list: done.id,
+                labels: vec!["ui".into(), "urgent".into()],
+                ..previous.clone()
+            }),
+            vec![
+                "someone renamed card 'card' to 'renamed'",
+                "someone moved card 'renamed' from 'todo' to 'done'",
+                "someone labeled card 'renamed' with urgent, removing bug",
+            ],
+        );
+        assert_eq!(
+            describe(Card { description: "more".into(), ..previous.clone() }),
+            vec!["someone edited card 'card'"],
+        );
+        assert!(describe(previous.clone()).is_empty());
+    }
+}
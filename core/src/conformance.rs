@@ -0,0 +1,372 @@
+//! Tests that every `Storage` backend must pass.
+//!
+//! Backends call `run()` from their own tests, with a function creating an
+//! empty storage. Each check gets a new one. `run()` waits on the futures,
+//! backends that need an event loop to make progress, like IndexedDB in the
+//! browser, use `run_async()` from an async test instead.
+//!
+//! This is only built with the `conformance` feature, which backends enable
+//! in their dev-dependencies.
+
+use futures::executor::block_on;
+use futures::future::ready;
+use futures::poll;
+use std::fmt::Debug;
+use std::future::Future;
+use uuid::Uuid;
+
+use super::{Board, Card, Change, Comment, List, Operation, Storage, Store,
+            Transaction};
+use super::activity::{Activity, Actor};
+
+/// Run all the checks on storages created by `new_storage`.
+pub fn run<S, F>(mut new_storage: F)
+    where S: Storage, S::Error: Debug, F: FnMut() -> S
+{
+    block_on(run_async(|| ready(new_storage())))
+}
+
+/// Run all the checks on storages created asynchronously by `new_storage`.
+pub async fn run_async<S, F, R>(mut new_storage: F)
+    where S: Storage, S::Error: Debug, F: FnMut() -> R,
+          R: Future<Output=S>
+{
+    check_boards(&new_storage().await).await;
+    check_lists(&new_storage().await).await;
+    check_cards(&new_storage().await).await;
+    check_delete_board(&new_storage().await).await;
+    check_operations(&new_storage().await).await;
+    check_cancel_operation(&new_storage().await).await;
+    check_prune_operations(&new_storage().await).await;
+    check_sync_cursor(&new_storage().await).await;
+    check_replica_id(&new_storage().await).await;
+    check_activity(&new_storage().await).await;
+    check_transactions(&new_storage().await).await;
+}
+
+async fn new_board<S: Store>(storage: &S, name: &str) -> Board
+    where S::Error: Debug
+{
+    let board = Board { id: Uuid::new_v4(), name: name.into() };
+    storage.add_board(&board).await.unwrap();
+    board
+}
+
+async fn new_list<S: Store>(storage: &S, board_id: &Uuid, name: &str)
+    -> List
+    where S::Error: Debug
+{
+    let list = List { id: Uuid::new_v4(), name: name.into() };
+    storage.add_list(board_id, &list).await.unwrap();
+    list
+}
+
+async fn new_card<S: Store>(storage: &S, board_id: &Uuid, list: &List,
+                              title: &str)
+    -> Card
+    where S::Error: Debug
+{
+    let card = Card::new(list.id, title);
+    storage.add_card(board_id, &card).await.unwrap();
+    card
+}
+
+/// Get cards in a known order, backends don't have to keep them sorted.
+async fn sorted_cards<S: Store>(storage: &S, board_id: &Uuid)
+    -> Vec<Card>
+    where S::Error: Debug
+{
+    let mut cards = storage.get_cards(board_id).await.unwrap();
+    cards.sort_by_key(|c| c.id);
+    cards
+}
+
+fn add_list_op(board_id: &Uuid, name: &str) -> Operation {
+    let mut op = Operation::new(*board_id, Change::AddList {
+        list: List { id: Uuid::new_v4(), name: name.into() },
+    });
+    op.actor = Some(Actor::User { name: "remram".into() });
+    op
+}
+
+pub async fn check_boards<S: Storage>(storage: &S) where S::Error: Debug {
+    let work = new_board(storage, "Work").await;
+    let home = new_board(storage, "Home").await;
+    assert_eq!(storage.get_board(&work.id).await.unwrap(), Some(work));
+    assert_eq!(storage.get_board(&home.id).await.unwrap(), Some(home));
+    assert_eq!(storage.get_board(&Uuid::new_v4()).await.unwrap(), None);
+}
+
+pub async fn check_lists<S: Storage>(storage: &S) where S::Error: Debug {
+    let work = new_board(storage, "Work").await;
+    let home = new_board(storage, "Home").await;
+    assert_eq!(storage.get_lists(&work.id).await.unwrap(), vec![]);
+
+    let todo = new_list(storage, &work.id, "todo").await;
+    let doing = new_list(storage, &work.id, "doing").await;
+    let done = new_list(storage, &work.id, "done").await;
+    let chores = new_list(storage, &home.id, "chores").await;
+    // Lists come in the order they were added
+    assert_eq!(storage.get_lists(&work.id).await.unwrap(),
+               vec![todo.clone(), doing.clone(), done.clone()]);
+    assert_eq!(storage.get_lists(&home.id).await.unwrap(),
+               vec![chores.clone()]);
+
+    storage.delete_list(&work.id, &todo.id).await.unwrap();
+    let archive = new_list(storage, &work.id, "archive").await;
+    assert_eq!(storage.get_lists(&work.id).await.unwrap(),
+               vec![doing, done, archive]);
+    assert_eq!(storage.get_lists(&home.id).await.unwrap(), vec![chores]);
+    // Deleting something that's not there is not an error
+    storage.delete_list(&work.id, &todo.id).await.unwrap();
+}
+
+pub async fn check_cards<S: Storage>(storage: &S) where S::Error: Debug {
+    let work = new_board(storage, "Work").await;
+    let home = new_board(storage, "Home").await;
+    let todo = new_list(storage, &work.id, "todo").await;
+    let chores = new_list(storage, &home.id, "chores").await;
+    assert_eq!(storage.get_cards(&work.id).await.unwrap(), vec![]);
+
+    let write = new_card(storage, &work.id, &todo, "Write report").await;
+    let mut send = new_card(storage, &work.id, &todo, "Send report").await;
+    let dishes = new_card(storage, &home.id, &chores, "Dishes").await;
+    let mut expected = vec![write.clone(), send.clone()];
+    expected.sort_by_key(|c| c.id);
+    assert_eq!(sorted_cards(storage, &work.id).await, expected);
+    assert_eq!(sorted_cards(storage, &home.id).await, vec![dishes.clone()]);
+
+    // Adding a card again replaces it, with all its fields
+    send.title = "Send the report".into();
+    send.description = "To the *whole* team".into();
+    send.labels = vec!["urgent".into(), "report".into()];
+    send.due = Some(1_550_000_000);
+    send.assignees = vec!["remram".into()];
+    send.comments = vec![
+        Comment {
+            author: Some("remram".into()),
+            text: "Done?".into(),
+            time: Some(1_540_000_000),
+        },
+        Comment { author: None, text: "Not yet".into(), time: None },
+    ];
+    send.external = Some("taskwarrior:8ad2e3db-914d-4832-b0e6-72fa04f6e331"
+                         .into());
+    storage.add_card(&work.id, &send).await.unwrap();
+    let mut expected = vec![write.clone(), send.clone()];
+    expected.sort_by_key(|c| c.id);
+    assert_eq!(sorted_cards(storage, &work.id).await, expected);
+
+    storage.delete_card(&work.id, &write.id).await.unwrap();
+    assert_eq!(sorted_cards(storage, &work.id).await, vec![send]);
+    assert_eq!(sorted_cards(storage, &home.id).await, vec![dishes]);
+    // Deleting something that's not there is not an error
+    storage.delete_card(&work.id, &write.id).await.unwrap();
+}
+
+pub async fn check_delete_board<S: Storage>(storage: &S)
+    where S::Error: Debug
+{
+    let work = new_board(storage, "Work").await;
+    let home = new_board(storage, "Home").await;
+    let todo = new_list(storage, &work.id, "todo").await;
+    new_card(storage, &work.id, &todo, "Write report").await;
+    let chores = new_list(storage, &home.id, "chores").await;
+    let dishes = new_card(storage, &home.id, &chores, "Dishes").await;
+
+    storage.delete_board(&work.id).await.unwrap();
+    assert_eq!(storage.get_board(&work.id).await.unwrap(), None);
+    assert_eq!(storage.get_lists(&work.id).await.unwrap(), vec![]);
+    assert_eq!(storage.get_cards(&work.id).await.unwrap(), vec![]);
+    assert_eq!(storage.get_cards(&home.id).await.unwrap(), vec![dishes]);
+    assert_eq!(storage.get_board(&home.id).await.unwrap(), Some(home.clone()));
+    assert_eq!(storage.get_lists(&home.id).await.unwrap(), vec![chores]);
+    storage.delete_board(&work.id).await.unwrap();
+}
+
+pub async fn check_operations<S: Storage>(storage: &S) where S::Error: Debug {
+    let board_id = Uuid::new_v4();
+    let other_id = Uuid::new_v4();
+    let first = add_list_op(&board_id, "todo");
+    let second = add_list_op(&board_id, "done");
+    let other = add_list_op(&other_id, "chores");
+    let received = add_list_op(&board_id, "ideas");
+
+    assert!(storage.add_operation(&first, true).await.unwrap());
+    assert!(storage.add_operation(&second, true).await.unwrap());
+    assert!(storage.add_operation(&other, true).await.unwrap());
+    assert!(storage.add_operation(&received, false).await.unwrap());
+    // Operations are only added once
+    assert!(!storage.add_operation(&first, true).await.unwrap());
+    assert!(!storage.add_operation(&received, true).await.unwrap());
+
+    // Pending operations are in order, and only for that board
+    assert_eq!(
+        storage.get_pending_operations(&board_id).await.unwrap(),
+        vec![first.clone(), second.clone()],
+    );
+    assert_eq!(
+        storage.get_pending_operations(&other_id).await.unwrap(),
+        vec![other.clone()],
+    );
+
+    storage.acknowledge_operations(&[first.id, other.id]).await.unwrap();
+    assert_eq!(
+        storage.get_pending_operations(&board_id).await.unwrap(),
+        vec![second],
+    );
+    assert_eq!(
+        storage.get_pending_operations(&other_id).await.unwrap(),
+        vec![],
+    );
+    // Acknowledged operations are still known
+    assert!(!storage.add_operation(&first, true).await.unwrap());
+}
+
+pub async fn check_cancel_operation<S: Storage>(storage: &S)
+    where S::Error: Debug
+{
+    let board_id = Uuid::new_v4();
+    let pending = add_list_op(&board_id, "todo");
+    let acknowledged = add_list_op(&board_id, "done");
+    storage.add_operation(&pending, true).await.unwrap();
+    storage.add_operation(&acknowledged, true).await.unwrap();
+    storage.acknowledge_operations(&[acknowledged.id]).await.unwrap();
+
+    // Only pending operations can be canceled
+    assert!(!storage.cancel_operation(&acknowledged.id).await.unwrap());
+    assert!(!storage.cancel_operation(&Uuid::new_v4()).await.unwrap());
+    assert!(storage.cancel_operation(&pending.id).await.unwrap());
+    assert!(!storage.cancel_operation(&pending.id).await.unwrap());
+    assert_eq!(
+        storage.get_pending_operations(&board_id).await.unwrap(),
+        vec![],
+    );
+    // It's gone, so it can be added again
+    assert!(storage.add_operation(&pending, true).await.unwrap());
+}
+
+pub async fn check_prune_operations<S: Storage>(storage: &S)
+    where S::Error: Debug
+{
+    let board_id = Uuid::new_v4();
+    let other_id = Uuid::new_v4();
+    let pending = add_list_op(&board_id, "todo");
+    let acknowledged = add_list_op(&board_id, "done");
+    let other = add_list_op(&other_id, "chores");
+    storage.add_operation(&pending, true).await.unwrap();
+    storage.add_operation(&acknowledged, false).await.unwrap();
+    storage.add_operation(&other, false).await.unwrap();
+
+    storage.prune_operations(&board_id).await.unwrap();
+    // Pending operations are kept
+    assert_eq!(
+        storage.get_pending_operations(&board_id).await.unwrap(),
+        vec![pending.clone()],
+    );
+    assert!(!storage.add_operation(&pending, true).await.unwrap());
+    // Acknowledged ones are forgotten, only for that board
+    assert!(storage.add_operation(&acknowledged, false).await.unwrap());
+    assert!(!storage.add_operation(&other, false).await.unwrap());
+}
+
+pub async fn check_sync_cursor<S: Storage>(storage: &S) where S::Error: Debug {
+    let board_id = Uuid::new_v4();
+    let other_id = Uuid::new_v4();
+    assert_eq!(storage.get_sync_cursor(&board_id).await.unwrap(), 0);
+    storage.set_sync_cursor(&board_id, 12).await.unwrap();
+    storage.set_sync_cursor(&other_id, 3).await.unwrap();
+    assert_eq!(storage.get_sync_cursor(&board_id).await.unwrap(), 12);
+    storage.set_sync_cursor(&board_id, 15).await.unwrap();
+    assert_eq!(storage.get_sync_cursor(&board_id).await.unwrap(), 15);
+    assert_eq!(storage.get_sync_cursor(&other_id).await.unwrap(), 3);
+}
+
+pub async fn check_replica_id<S: Storage>(storage: &S) where S::Error: Debug {
+    let id = storage.get_replica_id().await.unwrap();
+    assert_eq!(storage.get_replica_id().await.unwrap(), id);
+}
+
+pub async fn check_activity<S: Storage>(storage: &S) where S::Error: Debug {
+    let board_id = Uuid::new_v4();
+    let mut add_board = Operation::new(board_id, Change::AddBoard {
+        name: "Work".into(),
+    });
+    add_board.actor = Some(Actor::User { name: "remram".into() });
+    let add_list = add_list_op(&board_id, "todo");
+    let other = add_list_op(&Uuid::new_v4(), "chores");
+    let mut entries = Vec::new();
+    for op in &[add_board, add_list, other] {
+        let activity = Activity::from_operation(op, &[]).remove(0);
+        storage.add_activity(&activity).await.unwrap();
+        entries.push(activity);
+    }
+    let mut anonymous = entries[1].clone();
+    anonymous.actor = None;
+    anonymous.time = None;
+    storage.add_activity(&anonymous).await.unwrap();
+
+    // The board's activity includes what's on it, oldest first
+    assert_eq!(
+        storage.get_activity(&board_id).await.unwrap(),
+        vec![entries[0].clone(), entries[1].clone(), anonymous.clone()],
+    );
+    assert_eq!(
+        storage.get_activity(&entries[1].subject).await.unwrap(),
+        vec![entries[1].clone(), anonymous],
+    );
+    assert_eq!(storage.get_activity(&Uuid::new_v4()).await.unwrap(), vec![]);
+}
+
+pub async fn check_transactions<S: Storage>(storage: &S)
+    where S::Error: Debug
+{
+    // Committed changes are kept
+    let tx = storage.begin().await.unwrap();
+    let work = new_board(&tx, "Work").await;
+    let todo = new_list(&tx, &work.id, "todo").await;
+    let op = add_list_op(&work.id, "todo");
+    assert!(tx.add_operation(&op, true).await.unwrap());
+    tx.commit().await.unwrap();
+    assert_eq!(storage.get_board(&work.id).await.unwrap(), Some(work.clone()));
+    assert_eq!(storage.get_lists(&work.id).await.unwrap(), vec![todo.clone()]);
+    assert_eq!(
+        storage.get_pending_operations(&work.id).await.unwrap(),
+        vec![op.clone()],
+    );
+
+    // Rolled back changes are all undone
+    let tx = storage.begin().await.unwrap();
+    let home = new_board(&tx, "Home").await;
+    new_list(&tx, &home.id, "chores").await;
+    new_list(&tx, &work.id, "done").await;
+    tx.delete_list(&work.id, &todo.id).await.unwrap();
+    let other = add_list_op(&work.id, "done");
+    assert!(tx.add_operation(&other, true).await.unwrap());
+    tx.rollback().await.unwrap();
+    assert_eq!(storage.get_board(&home.id).await.unwrap(), None);
+    assert_eq!(storage.get_lists(&home.id).await.unwrap(), vec![]);
+    assert_eq!(storage.get_lists(&work.id).await.unwrap(), vec![todo.clone()]);
+    assert_eq!(
+        storage.get_pending_operations(&work.id).await.unwrap(),
+        vec![op.clone()],
+    );
+
+    // So are those of a transaction that is dropped
+    let tx = storage.begin().await.unwrap();
+    new_list(&tx, &work.id, "done").await;
+    drop(tx);
+    assert_eq!(storage.get_lists(&work.id).await.unwrap(), vec![todo.clone()]);
+
+    // Changes made directly are not part of the transaction, they wait for
+    // it to be over
+    let tx = storage.begin().await.unwrap();
+    new_list(&tx, &work.id, "done").await;
+    let chores = List { id: Uuid::new_v4(), name: "chores".into() };
+    let mut direct = Box::pin(storage.add_list(&work.id, &chores));
+    assert!(poll!(&mut direct).is_pending());
+    tx.rollback().await.unwrap();
+    direct.await.unwrap();
+    assert_eq!(storage.get_lists(&work.id).await.unwrap(),
+               vec![todo, chores]);
+}
@@ -0,0 +1,469 @@
+//! End-to-end encryption of operations.
+//!
+//! When a board is encrypted, the change of each operation is serialized and
+//! encrypted with a 256-bit board key before it is sent to the server, which
+//! only stores and relays it as a `Change::Encrypted`. The operation's ID,
+//! board ID, actor and time stay in the clear so that the server can route
+//! and attribute it, but they are authenticated along with the ciphertext, so
+//! the server can't change them. Snapshots are encrypted the same way, bound
+//! to their board and position in the log.
+//!
+//! The key is either derived from a passphrase (with Argon2id, salted with the
+//! board ID) or generated randomly and shared out of band. To rotate it, a new
+//! key becomes current in the `Keyring`, and the previous ones are kept to
+//! read older operations.
+//!
+//! Data is encrypted with XChaCha20-Poly1305, each message getting a random
+//! 192-bit nonce.
+
+use argon2::Argon2;
+use base64ct::{Base64, Encoding};
+use blake2::Blake2bMac;
+use blake2::digest::Mac;
+use blake2::digest::consts::U32;
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305, XNonce};
+use chacha20poly1305::aead::{Aead, Payload};
+use rand_core::{OsRng, RngCore};
+use uuid::Uuid;
+
+use super::{Change, Operation};
+use super::snapshot::{Snapshot, SnapshotData};
+
+/// Version of the format of encrypted data.
+const VERSION: u8 = 2;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+
+type Blake2bMac256 = Blake2bMac<U32>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CryptoError {
+    /// A key couldn't be parsed
+    InvalidKey,
+    /// The data was encrypted with a key we don't have
+    UnknownKey { key_id: String },
+    /// The data was corrupted or tampered with
+    Corrupted,
+    /// Data that should have been encrypted wasn't
+    NotEncrypted,
+}
+
+impl std::fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            CryptoError::InvalidKey => write!(f, "Invalid key"),
+            CryptoError::UnknownKey { ref key_id } => {
+                write!(f, "Data is encrypted with unknown key {}", key_id)
+            }
+            CryptoError::Corrupted => {
+                write!(f, "Encrypted data is corrupted")
+            }
+            CryptoError::NotEncrypted => {
+                write!(f, "Received data that is not encrypted")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Encode the associated data of a message unambiguously, each item prefixed
+/// with its length.
+fn encode_associated(associated: &[&[u8]]) -> Vec<u8> {
+    let mut encoded = vec![VERSION];
+    for data in associated {
+        encoded.extend_from_slice(&(data.len() as u64).to_be_bytes());
+        encoded.extend_from_slice(data);
+    }
+    encoded
+}
+
+/// The associated data of an operation: everything but its change.
+fn operation_associated(op: &Operation) -> [Vec<u8>; 4] {
+    [
+        op.id.as_bytes().to_vec(),
+        op.board_id.as_bytes().to_vec(),
+        serde_json::to_vec(&op.actor).unwrap(),
+        serde_json::to_vec(&op.time).unwrap(),
+    ]
+}
+
+fn keyed(key: &[u8], persona: &[u8]) -> Blake2bMac256 {
+    Blake2bMac256::new_with_salt_and_personal(key, &[], persona)
+        .expect("Invalid BLAKE2b parameters")
+}
+
+/// Key used to encrypt the operations of a board.
+#[derive(Clone, PartialEq)]
+pub struct BoardKey {
+    bytes: [u8; KEY_LEN],
+}
+
+impl std::fmt::Debug for BoardKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // Don't leak the key in logs
+        write!(f, "BoardKey({})", self.id())
+    }
+}
+
+impl BoardKey {
+    /// Generate a random key, to be shared out of band.
+    pub fn generate() -> BoardKey {
+        let mut bytes = [0u8; KEY_LEN];
+        OsRng.fill_bytes(&mut bytes);
+        BoardKey { bytes }
+    }
+
+    /// Derive the key from a passphrase.
+    ///
+    /// The board ID is used as salt, so every replica derives the same key.
+    pub fn from_passphrase(passphrase: &str, board_id: &Uuid) -> BoardKey {
+        let mut salt = b"tripledeck".to_vec();
+        salt.extend_from_slice(board_id.as_bytes());
+        let mut bytes = [0u8; KEY_LEN];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut bytes)
+            .expect("Deriving key");
+        BoardKey { bytes }
+    }
+
+    pub fn from_hex(hex: &str) -> Result<BoardKey, CryptoError> {
+        let hex = hex.trim();
+        if hex.len() != KEY_LEN * 2 || !hex.is_ascii() {
+            return Err(CryptoError::InvalidKey);
+        }
+        let mut bytes = [0u8; KEY_LEN];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .map_err(|_| CryptoError::InvalidKey)?;
+        }
+        Ok(BoardKey { bytes })
+    }
+
+    pub fn to_hex(&self) -> String {
+        to_hex(&self.bytes)
+    }
+
+    /// Public identifier of the key, indicating which key encrypted an
+    /// operation.
+    pub fn id(&self) -> String {
+        let mac = keyed(&self.bytes, b"tripledeck-id");
+        to_hex(&mac.finalize().into_bytes()[..8])
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.bytes.into())
+    }
+
+    /// Encrypt data, authenticating it along with the associated data.
+    ///
+    /// The first associated item is a label, so that ciphertext can't be
+    /// passed for something else.
+    fn seal(&self, plaintext: &[u8], associated: &[&[u8]]) -> String {
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = self.cipher()
+            .encrypt(XNonce::from_slice(&nonce), Payload {
+                msg: plaintext,
+                aad: &encode_associated(associated),
+            })
+            .expect("Encrypting");
+
+        let mut data = Vec::with_capacity(1 + NONCE_LEN + ciphertext.len());
+        data.push(VERSION);
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&ciphertext);
+        Base64::encode_string(&data)
+    }
+
+    fn open(&self, data: &str, associated: &[&[u8]])
+        -> Result<Vec<u8>, CryptoError>
+    {
+        let data = Base64::decode_vec(data)
+            .map_err(|_| CryptoError::Corrupted)?;
+        if data.len() < 1 + NONCE_LEN + TAG_LEN || data[0] != VERSION {
+            return Err(CryptoError::Corrupted);
+        }
+        let (nonce, ciphertext) = data[1..].split_at(NONCE_LEN);
+        self.cipher()
+            .decrypt(XNonce::from_slice(nonce), Payload {
+                msg: ciphertext,
+                aad: &encode_associated(associated),
+            })
+            .map_err(|_| CryptoError::Corrupted)
+    }
+
+    /// Encrypt the change of an operation.
+    pub fn encrypt(&self, op: &Operation) -> Operation {
+        let plaintext = serde_json::to_vec(&op.change).unwrap();
+        let [id, board_id, actor, time] = operation_associated(op);
+        let data = self.seal(&plaintext, &[
+            b"operation", &id, &board_id, &actor, &time,
+        ]);
+        Operation {
+            id: op.id,
+            board_id: op.board_id,
+            change: Change::Encrypted { key_id: self.id(), data },
+            actor: op.actor.clone(),
+            time: op.time,
+            group: None,
+        }
+    }
+
+    /// Decrypt an operation encrypted with this key.
+    pub fn decrypt(&self, op: &Operation) -> Result<Operation, CryptoError> {
+        let data = match op.change {
+            Change::Encrypted { ref data, .. } => data,
+            _ => return Err(CryptoError::NotEncrypted),
+        };
+        let [id, board_id, actor, time] = operation_associated(op);
+        let plaintext = self.open(data, &[
+            b"operation", &id, &board_id, &actor, &time,
+        ])?;
+        let change = serde_json::from_slice(&plaintext)
+            .map_err(|_| CryptoError::Corrupted)?;
+        Ok(Operation {
+            id: op.id,
+            board_id: op.board_id,
+            change,
+            actor: op.actor.clone(),
+            time: op.time,
+            group: None,
+        })
+    }
+
+    /// Encrypt the snapshot of a board up to a position in the log.
+    pub fn encrypt_snapshot(&self, snapshot: &Snapshot, cursor: u64)
+        -> SnapshotData
+    {
+        let plaintext = serde_json::to_vec(snapshot).unwrap();
+        SnapshotData::Encrypted {
+            key_id: self.id(),
+            data: self.seal(&plaintext, &[
+                b"snapshot", snapshot.board.id.as_bytes(),
+                &cursor.to_be_bytes(),
+            ]),
+        }
+    }
+
+    /// Decrypt a snapshot, checking that it is for that board and position.
+    pub fn decrypt_snapshot(&self, data: &SnapshotData, board_id: &Uuid,
+                            cursor: u64)
+        -> Result<Snapshot, CryptoError>
+    {
+        let data = match *data {
+            SnapshotData::Encrypted { ref data, .. } => data,
+            _ => return Err(CryptoError::NotEncrypted),
+        };
+        let plaintext = self.open(data, &[
+            b"snapshot", board_id.as_bytes(), &cursor.to_be_bytes(),
+        ])?;
+        serde_json::from_slice(&plaintext).map_err(|_| CryptoError::Corrupted)
+    }
+}
+
+/// The keys of a board: the current one, used to encrypt, and the previous
+/// ones, still used to decrypt older operations.
+#[derive(Clone, Debug)]
+pub struct Keyring {
+    /// The first key is the current one
+    keys: Vec<BoardKey>,
+}
+
+impl Keyring {
+    pub fn new(current: BoardKey) -> Keyring {
+        Keyring { keys: vec![current] }
+    }
+
+    /// Add a previous key, only used to decrypt older operations.
+    pub fn add_previous(&mut self, key: BoardKey) {
+        if !self.keys.contains(&key) {
+            self.keys.push(key);
+        }
+    }
+
+    /// Make a new key current, keeping the previous ones for decryption.
+    pub fn rotate(&mut self, key: BoardKey) {
+        self.keys.retain(|k| *k != key);
+        self.keys.insert(0, key);
+    }
+
+    pub fn current(&self) -> &BoardKey {
+        &self.keys[0]
+    }
+
+    /// All the keys, starting with the current one.
+    pub fn keys(&self) -> &[BoardKey] {
+        &self.keys
+    }
+
+    fn find(&self, key_id: &str) -> Result<&BoardKey, CryptoError> {
+        self.keys.iter().find(|k| k.id() == key_id)
+            .ok_or_else(|| CryptoError::UnknownKey { key_id: key_id.into() })
+    }
+
+    pub fn encrypt(&self, op: &Operation) -> Operation {
+        self.current().encrypt(op)
+    }
+
+    pub fn decrypt(&self, op: &Operation) -> Result<Operation, CryptoError> {
+        match op.change {
+            Change::Encrypted { ref key_id, .. } => {
+                self.find(key_id)?.decrypt(op)
+            }
+            _ => Err(CryptoError::NotEncrypted),
+        }
+    }
+
+    pub fn encrypt_snapshot(&self, snapshot: &Snapshot, cursor: u64)
+        -> SnapshotData
+    {
+        self.current().encrypt_snapshot(snapshot, cursor)
+    }
+
+    pub fn decrypt_snapshot(&self, data: &SnapshotData, board_id: &Uuid,
+                            cursor: u64)
+        -> Result<Snapshot, CryptoError>
+    {
+        match *data {
+            SnapshotData::Encrypted { ref key_id, .. } => {
+                self.find(key_id)?.decrypt_snapshot(data, board_id, cursor)
+            }
+            _ => Err(CryptoError::NotEncrypted),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use crate::{Board, Change, List, Operation};
+    use crate::activity::Actor;
+    use crate::snapshot::Snapshot;
+    use super::{BoardKey, CryptoError, Keyring};
+
+    fn operation(name: &str) -> Operation {
+        Operation::new(Uuid::new_v4(), Change::AddBoard { name: name.into() })
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let key = BoardKey::generate();
+        let op = operation("secret board");
+        let encrypted = key.encrypt(&op);
+        assert_eq!(encrypted.id, op.id);
+        match encrypted.change {
+            Change::Encrypted { ref key_id, ref data } => {
+                assert_eq!(*key_id, key.id());
+                assert!(!data.contains("secret"));
+            }
+            ref c => panic!("Unexpected change {:?}", c),
+        }
+        assert_eq!(key.decrypt(&encrypted), Ok(op.clone()));
+
+        // Nonces are random
+        assert_ne!(key.encrypt(&op), encrypted);
+
+        assert_eq!(BoardKey::from_hex(&key.to_hex()), Ok(key));
+        assert_eq!(BoardKey::from_hex("abc"), Err(CryptoError::InvalidKey));
+    }
+
+    #[test]
+    fn test_tampering() {
+        let key = BoardKey::generate();
+        let op = operation("board");
+        let encrypted = key.encrypt(&op);
+
+        // Ciphertext can't be moved to another operation
+        let mut moved = encrypted.clone();
+        moved.id = Uuid::new_v4();
+        assert_eq!(key.decrypt(&moved), Err(CryptoError::Corrupted));
+        let mut moved = encrypted.clone();
+        moved.board_id = Uuid::new_v4();
+        assert_eq!(key.decrypt(&moved), Err(CryptoError::Corrupted));
+
+        // Nor can who made it and when be changed
+        let mut changed = encrypted.clone();
+        changed.actor = Some(Actor::User { name: "mallory".into() });
+        assert_eq!(key.decrypt(&changed), Err(CryptoError::Corrupted));
+        let mut changed = encrypted.clone();
+        changed.time = changed.time.map(|t| t + 1);
+        assert_eq!(key.decrypt(&changed), Err(CryptoError::Corrupted));
+        let mut changed = encrypted.clone();
+        changed.time = None;
+        assert_eq!(key.decrypt(&changed), Err(CryptoError::Corrupted));
+
+        // Wrong key
+        let other = BoardKey::generate();
+        assert_eq!(other.decrypt(&encrypted), Err(CryptoError::Corrupted));
+
+        assert_eq!(key.decrypt(&op), Err(CryptoError::NotEncrypted));
+    }
+
+    #[test]
+    fn test_rotation() {
+        let old = BoardKey::generate();
+        let mut keyring = Keyring::new(old.clone());
+        let op1 = operation("one");
+        let encrypted1 = keyring.encrypt(&op1);
+
+        let new = BoardKey::generate();
+        keyring.rotate(new.clone());
+        let op2 = operation("two");
+        let encrypted2 = keyring.encrypt(&op2);
+        assert_eq!(new.decrypt(&encrypted2), Ok(op2.clone()));
+        assert_eq!(keyring.decrypt(&encrypted1), Ok(op1));
+        assert_eq!(keyring.decrypt(&encrypted2), Ok(op2));
+
+        // A replica that only got the new key
+        let keyring = Keyring::new(new);
+        assert_eq!(
+            keyring.decrypt(&encrypted1),
+            Err(CryptoError::UnknownKey { key_id: old.id() }),
+        );
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let board_id = Uuid::new_v4();
+        let snapshot = Snapshot {
+            board: Board { id: board_id, name: "secret board".into() },
+            lists: vec![List { id: Uuid::new_v4(), name: "todo".into() }],
+            cards: vec![],
+        };
+        let keyring = Keyring::new(BoardKey::generate());
+        let data = keyring.encrypt_snapshot(&snapshot, 12);
+        assert_eq!(
+            keyring.decrypt_snapshot(&data, &board_id, 12),
+            Ok(snapshot),
+        );
+
+        // Snapshot can't be passed for another position or board
+        assert_eq!(
+            keyring.decrypt_snapshot(&data, &board_id, 13),
+            Err(CryptoError::Corrupted),
+        );
+        assert_eq!(
+            keyring.decrypt_snapshot(&data, &Uuid::new_v4(), 12),
+            Err(CryptoError::Corrupted),
+        );
+    }
+
+    #[test]
+    fn test_passphrase() {
+        let board_id = Uuid::new_v4();
+        let key = BoardKey::from_passphrase("hunter2", &board_id);
+        assert_eq!(BoardKey::from_passphrase("hunter2", &board_id), key);
+        assert_ne!(BoardKey::from_passphrase("hunter3", &board_id), key);
+        assert_ne!(
+            BoardKey::from_passphrase("hunter2", &Uuid::new_v4()),
+            key,
+        );
+    }
+}
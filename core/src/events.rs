@@ -0,0 +1,129 @@
+//! Notifications of changes to boards.
+//!
+//! `App` and `BoardHandle` let callers subscribe to the changes made to
+//! boards, whether they were made locally, undone, or received from the
+//! server, so that views can be updated.
+
+use serde::Serialize;
+use std::rc::Rc;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use super::Operation;
+use super::threading::{Local, Shared, Threading};
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// An operation was applied. Local changes are reported right away,
+    /// before they are stored
+    Applied { operation: Operation },
+    /// A local change couldn't be stored, and was rolled back
+    Reverted { operation: Operation },
+    /// A board was restored from a snapshot; handles to it were dropped from
+    /// the cache and it should be loaded again
+    Restored { board_id: Uuid },
+}
+
+impl Event {
+    pub fn board_id(&self) -> &Uuid {
+        match *self {
+            Event::Applied { ref operation } |
+            Event::Reverted { ref operation } => &operation.board_id,
+            Event::Restored { ref board_id } => board_id,
+        }
+    }
+}
+
+/// Identifies a subscription, to cancel it.
+pub type SubscriptionId = u32;
+
+/// A list of callbacks to call on changes.
+pub struct Subscribers<P: Threading = Local> {
+    next_id: P::Cell<SubscriptionId>,
+    callbacks: P::Cell<Vec<(SubscriptionId, P::Callback)>>,
+}
+
+impl<P: Threading> Default for Subscribers<P> {
+    fn default() -> Subscribers<P> {
+        Subscribers {
+            next_id: P::cell(0),
+            callbacks: P::cell(Vec::new()),
+        }
+    }
+}
+
+impl<P: Threading> Subscribers<P> {
+    fn add(&self, callback: P::Callback) -> SubscriptionId {
+        let id = {
+            let mut next_id = P::borrow_mut(&self.next_id);
+            *next_id += 1;
+            *next_id - 1
+        };
+        P::borrow_mut(&self.callbacks).push((id, callback));
+        id
+    }
+
+    /// Cancel a subscription. Returns false if there was no such
+    /// subscription.
+    pub fn unsubscribe(&self, id: SubscriptionId) -> bool {
+        let mut callbacks = P::borrow_mut(&self.callbacks);
+        let len = callbacks.len();
+        callbacks.retain(|&(i, _)| i != id);
+        callbacks.len() != len
+    }
+
+    pub fn notify(&self, event: &Event) {
+        // Callbacks can subscribe and unsubscribe
+        let callbacks: Vec<_> = P::borrow(&self.callbacks).iter()
+            .map(|(_, callback)| callback.clone())
+            .collect();
+        for callback in &callbacks {
+            P::call(callback, event);
+        }
+    }
+}
+
+impl Subscribers<Local> {
+    pub fn subscribe<F: Fn(&Event) + 'static>(&self, callback: F)
+        -> SubscriptionId
+    {
+        self.add(Rc::new(callback))
+    }
+}
+
+impl Subscribers<Shared> {
+    pub fn subscribe<F>(&self, callback: F) -> SubscriptionId
+        where F: Fn(&Event) + Send + Sync + 'static
+    {
+        self.add(Arc::new(callback))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use uuid::Uuid;
+
+    use super::{Event, Subscribers};
+
+    #[test]
+    fn test_subscribers() {
+        let subscribers: Subscribers = Subscribers::default();
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let received_ = received.clone();
+        let id = subscribers.subscribe(move |event: &Event| {
+            received_.borrow_mut().push(*event.board_id());
+        });
+
+        let board_id = Uuid::new_v4();
+        subscribers.notify(&Event::Restored { board_id });
+        assert_eq!(*received.borrow(), vec![board_id]);
+
+        assert!(subscribers.unsubscribe(id));
+        assert!(!subscribers.unsubscribe(id));
+        subscribers.notify(&Event::Restored { board_id });
+        assert_eq!(received.borrow().len(), 1);
+    }
+}
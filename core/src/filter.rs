@@ -35,7 +35,7 @@ pub trait Expression: Debug {
 }
 
 #[derive(Debug)]
-struct And(Box<Expression>, Box<Expression>);
+struct And(Box<dyn Expression>, Box<dyn Expression>);
 
 impl Expression for And {
     fn matches(&self, card: &Card) -> bool {
@@ -44,7 +44,7 @@ impl Expression for And {
 }
 
 #[derive(Debug)]
-struct Or(Box<Expression>, Box<Expression>);
+struct Or(Box<dyn Expression>, Box<dyn Expression>);
 
 impl Expression for Or {
     fn matches(&self, card: &Card) -> bool {
@@ -143,7 +143,7 @@ impl<'a> Iterator for Tokenizer<'a> {
             }
             let token = if c == '"' {
                 self.iter.next().unwrap();
-                while let Some((end, c)) = self.iter.next() {
+                for (end, c) in self.iter.by_ref() {
                     if c == '"' {
                         return Some(Ok(TokenItem {
                             pos: start,
@@ -192,7 +192,7 @@ struct Parser<'a> {
 }
 
 impl<'a> Parser<'a> {
-    fn parse_all(&mut self) -> Result<Box<Expression>> {
+    fn parse_all(&mut self) -> Result<Box<dyn Expression>> {
         let expr = self.parse(0)?;
         match self.tokens.next() {
             None => {}, // Good, input is exhausted
@@ -207,7 +207,7 @@ impl<'a> Parser<'a> {
         Ok(expr)
     }
 
-    fn parse(&mut self, mut precedence: i8) -> Result<Box<Expression>> {
+    fn parse(&mut self, mut precedence: i8) -> Result<Box<dyn Expression>> {
         let &TokenItem { ref token, pos } = match self.tokens.peek() {
             None => return Err(Error {
                 pos: None,
@@ -292,7 +292,7 @@ impl<'a> Parser<'a> {
         Ok(expr)
     }
 
-    fn parse_pred(&mut self) -> Result<Box<Expression>> {
+    fn parse_pred(&mut self) -> Result<Box<dyn Expression>> {
         let TokenItem { token, pos } = match self.tokens.next() {
             None => {
                 return Err(Error {
@@ -337,10 +337,10 @@ impl<'a> Parser<'a> {
                     self.recognize_pred(word, arg, pos)
                 }
                 _ => {
-                    return Err(Error {
+                    Err(Error {
                         pos: Some(pos),
                         what: "Missing argument after operation".into(),
-                    });
+                    })
                 }
             }
         } else {
@@ -349,25 +349,25 @@ impl<'a> Parser<'a> {
     }
 
     fn recognize_pred(&self, op: &'a str, arg: &'a str, pos: usize,
-    ) -> Result<Box<Expression>>
+    ) -> Result<Box<dyn Expression>>
     {
         match op {
             "list" => {
                 match self.lists.get(arg) {
                     None => {
-                        return Err(Error {
+                        Err(Error {
                             pos: Some(pos),
                             what: format!("Unknown list {}", arg),
-                        });
+                        })
                     }
-                    Some(list_id) => Ok(Box::new(InList(list_id.clone()))),
+                    Some(list_id) => Ok(Box::new(InList(*list_id))),
                 }
             }
             _ => {
-                return Err(Error {
+                Err(Error {
                     pos: Some(pos),
                     what: format!("Unknown operator {}", op),
-                });
+                })
             }
         }
     }
@@ -378,7 +378,7 @@ impl<'a> Parser<'a> {
 /// This takes in a mapping for the lists, so that the user can enter list
 /// names instead of UUIDs
 pub fn parse(text: &str, lists: &HashMap<String, Uuid>,
-) -> Result<Box<Expression>>
+) -> Result<Box<dyn Expression>>
 {
     let mut parser = Parser {
         tokens: Tokenizer::new(text).peekable(),
@@ -421,7 +421,7 @@ mod tests {
             ("todo", "936DA01F9ABD4D9D80C7000011110001"),
             ("doing", "936DA01F9ABD4D9D80C7000011110002"),
             ("done", "936DA01F9ABD4D9D80C7000011110003"),
-        ].into_iter().map(|(n, i)| {
+        ].iter().map(|(n, i)| {
             ((*n).into(), Uuid::parse_str(i).unwrap())
         }).collect::<HashMap<String, Uuid>>();
 
@@ -429,7 +429,7 @@ mod tests {
         parse(string, &map).expect("Parsing failed");
 
         if let Err(Error { pos, what }) = parse("", &map) {
-            assert!(pos == None);
+            assert!(pos.is_none());
             assert!(what == "Empty input");
         } else {
             panic!("Parsing empty input succeeded");
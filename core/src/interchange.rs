@@ -0,0 +1,335 @@
+//! Exporting boards to files, and importing them back.
+//!
+//! A `BoardExport` holds a whole board, and is written as JSON with a format
+//! name and version, so it can be used for backups and to move boards between
+//! the program and the browser:
+//!
+//! ```text
+//! {
+//!   "format": "tripledeck",
+//!   "version": 1,
+//!   "board": {"id": "...", "name": "Work"},
+//!   "lists": [{"id": "...", "name": "todo"}],
+//!   "cards": [{"id": "...", "title": "Write report", "list": "..."}]
+//! }
+//! ```
+//!
+//! Cards are written with all their fields, leaving out the empty ones. Labels
+//! are only names on the cards: boards don't have label definitions or
+//! automation rules of their own yet; they will be added to the format, with
+//! a new version, when they do. Fields this version doesn't know are ignored
+//! on import.
+//!
+//! Boards can also be imported from other applications, see the submodules.
+//! Those produce a `BoardExport` with new IDs, and a `Report` of what
+//! couldn't be brought over.
+
+pub mod csv;
+pub mod markdown;
+pub mod taskwarrior;
+pub mod todotxt;
+pub mod trello;
+
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::UNIX_EPOCH;
+use uuid::Uuid;
+
+use super::{Board, Card, List, Storage};
+
+/// Name of the format, recorded in the files so other JSON is refused.
+pub const FORMAT_NAME: &str = "tripledeck";
+
+/// Version of the format written by this program.
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum ImportError {
+    Json(serde_json::Error),
+    /// The file is JSON, but not a board export
+    NotAnExport,
+    /// The file was written by a newer version of the program
+    TooNew { version: u32, supported: u32 },
+    /// The file can't be read, in formats other than JSON
+    Invalid(String),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ImportError::Json(ref e) => write!(f, "Invalid JSON: {}", e),
+            ImportError::NotAnExport => write!(f, "Not a tripledeck export"),
+            ImportError::TooNew { version, supported } => write!(
+                f,
+                "Export has format version {}, only {} is supported",
+                version, supported,
+            ),
+            ImportError::Invalid(ref what) => write!(f, "{}", what),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+impl From<serde_json::Error> for ImportError {
+    fn from(e: serde_json::Error) -> ImportError {
+        ImportError::Json(e)
+    }
+}
+
+/// What an import from another application couldn't bring over as it was.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Report {
+    /// Things that were left out
+    pub skipped: Vec<String>,
+    /// Things that were imported, but not entirely
+    pub lossy: Vec<String>,
+}
+
+impl Report {
+    pub fn is_empty(&self) -> bool {
+        self.skipped.is_empty() && self.lossy.is_empty()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for what in &self.skipped {
+            writeln!(f, "Skipped {}", what)?;
+        }
+        for what in &self.lossy {
+            writeln!(f, "Changed {}", what)?;
+        }
+        Ok(())
+    }
+}
+
+/// Read an RFC 3339 date, in UTC, as seconds since the epoch. Dates without
+/// a time are read as midnight.
+fn parse_time(text: &str) -> Option<u64> {
+    let time = if text.len() == 10 {
+        humantime::parse_rfc3339_weak(&format!("{}T00:00:00Z", text))
+    } else {
+        humantime::parse_rfc3339_weak(text)
+    };
+    let time = time.ok()?;
+    time.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
+
+/// The lists of a board for open and completed tasks, for formats that only
+/// know whether a task is done. Completed tasks go in the list with the
+/// given name, the others in the first other list; they are created when
+/// first needed.
+struct TaskLists {
+    lists: Vec<List>,
+    done_list: String,
+    /// The lists that the board didn't have
+    new: Vec<List>,
+}
+
+impl TaskLists {
+    fn new(lists: &[List], done_list: &str) -> TaskLists {
+        TaskLists {
+            lists: lists.to_vec(),
+            done_list: done_list.into(),
+            new: Vec::new(),
+        }
+    }
+
+    fn get(&mut self, done: bool) -> Uuid {
+        let found = self.lists.iter()
+            .find(|l| (l.name == self.done_list) == done);
+        if let Some(list) = found {
+            return list.id;
+        }
+        let name = if done { self.done_list.as_str() } else { "todo" };
+        let list = List { id: Uuid::new_v4(), name: name.into() };
+        self.lists.push(list.clone());
+        self.new.push(list.clone());
+        list.id
+    }
+
+    fn is_done(&self, card: &Card) -> bool {
+        self.lists.iter()
+            .any(|l| l.id == card.list && l.name == self.done_list)
+    }
+}
+
+/// A board with everything on it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BoardExport {
+    pub board: Board,
+    pub lists: Vec<List>,
+    #[serde(default)]
+    pub cards: Vec<Card>,
+}
+
+/// The header read first, to check the format before reading the rest.
+#[derive(Deserialize)]
+struct Header {
+    format: Option<String>,
+    version: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct File<'a> {
+    format: &'static str,
+    version: u32,
+    #[serde(flatten)]
+    export: &'a BoardExport,
+}
+
+impl BoardExport {
+    /// Read a board from storage, or None if it doesn't exist.
+    pub async fn take<S: Storage>(storage: &S, board_id: &Uuid)
+        -> Result<Option<BoardExport>, S::Error>
+    {
+        let board = storage.get_board(board_id).await?;
+        let lists = storage.get_lists(board_id).await?;
+        let cards = storage.get_cards(board_id).await?;
+        Ok(board.map(|board| BoardExport { board, lists, cards }))
+    }
+
+    /// Write the export as (pretty-printed) JSON.
+    pub fn to_json(&self) -> String {
+        let file = File {
+            format: FORMAT_NAME,
+            version: FORMAT_VERSION,
+            export: self,
+        };
+        let mut json = serde_json::to_string_pretty(&file)
+            .expect("Serializing export");
+        json.push('\n');
+        json
+    }
+
+    /// Read an export written by `to_json()`, by this version of the program
+    /// or an older one.
+    pub fn from_json(json: &str) -> Result<BoardExport, ImportError> {
+        let header: Header = serde_json::from_str(json)?;
+        match header {
+            Header { format: Some(ref format), version: Some(version) }
+            if format == FORMAT_NAME => {
+                if version > FORMAT_VERSION {
+                    return Err(ImportError::TooNew {
+                        version,
+                        supported: FORMAT_VERSION,
+                    });
+                }
+            }
+            _ => return Err(ImportError::NotAnExport),
+        }
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// Give the board, lists and cards new IDs, so the board can be imported
+    /// next to the one it was exported from.
+    ///
+    /// Cards in a list that's not on the board are left out, they wouldn't
+    /// show anywhere on the copy.
+    pub fn with_new_ids(self) -> BoardExport {
+        let BoardExport { board, lists, cards } = self;
+        let mut list_ids = HashMap::new();
+        let lists = lists.into_iter().map(|list| {
+            let id = Uuid::new_v4();
+            list_ids.insert(list.id, id);
+            List { id, ..list }
+        }).collect();
+        let cards = cards.into_iter().filter_map(|card| {
+            let list = *list_ids.get(&card.list)?;
+            Some(Card { id: Uuid::new_v4(), list, ..card })
+        }).collect();
+        BoardExport {
+            board: Board { id: Uuid::new_v4(), ..board },
+            lists,
+            cards,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use crate::{Board, Card, List};
+    use super::{BoardExport, FORMAT_VERSION, ImportError, parse_time};
+
+    fn export() -> BoardExport {
+        let todo = List { id: Uuid::new_v4(), name: "todo".into() };
+        let done = List { id: Uuid::new_v4(), name: "done".into() };
+        BoardExport {
+            board: Board { id: Uuid::new_v4(), name: "Work".into() },
+            cards: vec![
+                Card::new(todo.id, "Write report"),
+                Card::new(done.id, "Send report"),
+            ],
+            lists: vec![todo, done],
+        }
+    }
+
+    #[test]
+    fn test_json() {
+        let export = export();
+        let json = export.to_json();
+        assert!(json.starts_with(&format!(
+            "{{\n  \"format\": \"tripledeck\",\n  \"version\": {},\n",
+            FORMAT_VERSION,
+        )));
+        assert_eq!(BoardExport::from_json(&json).unwrap(), export);
+
+        match BoardExport::from_json(&json.replacen(
+            &format!("\"version\": {}", FORMAT_VERSION),
+            &format!("\"version\": {}", FORMAT_VERSION + 1),
+            1,
+        )) {
+            Err(ImportError::TooNew { version, supported }) => {
+                assert_eq!(version, FORMAT_VERSION + 1);
+                assert_eq!(supported, FORMAT_VERSION);
+            }
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        match BoardExport::from_json("{\"board\": {}}") {
+            Err(ImportError::NotAnExport) => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+        match BoardExport::from_json("[") {
+            Err(ImportError::Json(_)) => {}
+            r => panic!("Unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_new_ids() {
+        let export = export();
+        let copy = export.clone().with_new_ids();
+        assert_ne!(copy.board.id, export.board.id);
+        assert_eq!(copy.board.name, export.board.name);
+        for (old, new) in export.lists.iter().zip(&copy.lists) {
+            assert_ne!(new.id, old.id);
+            assert_eq!(new.name, old.name);
+        }
+        for (old, new) in export.cards.iter().zip(&copy.cards) {
+            assert_ne!(new.id, old.id);
+            assert_eq!(new.title, old.title);
+        }
+        // Cards stay in the same lists
+        assert_eq!(copy.cards[0].list, copy.lists[0].id);
+        assert_eq!(copy.cards[1].list, copy.lists[1].id);
+
+        // Cards in no list are left out
+        let mut export = export;
+        export.cards.push(Card::new(Uuid::new_v4(), "Lost"));
+        let copy = export.with_new_ids();
+        assert_eq!(copy.cards.iter().map(|c| &c.title[..]).collect::<Vec<_>>(),
+                   vec!["Write report", "Send report"]);
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("2019-03-01T12:00:00.000Z"), Some(1551441600));
+        assert_eq!(parse_time("2019-03-01 12:00:00"), Some(1551441600));
+        assert_eq!(parse_time("2019-03-01"), Some(1551398400));
+        assert_eq!(parse_time("March 1st"), None);
+    }
+}
@@ -0,0 +1,478 @@
+//! Cards as CSV, for spreadsheets.
+//!
+//! Exports have a header row, then one row per card with the chosen
+//! `Column`s. Labels and assignees are joined with commas, with backslashes
+//! before the commas and backslashes they contain, and dates are written in
+//! RFC 3339 (UTC). Fields that spreadsheets would run as formulas, starting
+//! with `=`, `+`, `-` or `@`, are written with a `'` in front, which is
+//! removed on import.
+//!
+//! Imports read the header row to know what each column holds: headers are
+//! matched with the column names ("title", "list", ...) unless they are
+//! mapped explicitly, and the other columns are skipped. Cards go in the list
+//! named in their row, which is created if the board doesn't have it yet.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+
+use crate::{Card, List};
+use crate::activity::{Activity, Event, Kind};
+use super::{ImportError, Report, parse_time};
+
+/// What a CSV column holds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Column {
+    Title,
+    /// Name of the card's list
+    List,
+    Description,
+    Labels,
+    Assignees,
+    Due,
+    /// When the card was created, from the activity feed; export only
+    Created,
+}
+
+pub const ALL_COLUMNS: &[Column] = &[
+    Column::Title, Column::List, Column::Description, Column::Labels,
+    Column::Assignees, Column::Due, Column::Created,
+];
+
+/// The columns exported when none are chosen.
+pub const DEFAULT_COLUMNS: &[Column] = &[
+    Column::Title, Column::List, Column::Labels, Column::Assignees,
+    Column::Due, Column::Created,
+];
+
+impl Column {
+    pub fn name(self) -> &'static str {
+        match self {
+            Column::Title => "title",
+            Column::List => "list",
+            Column::Description => "description",
+            Column::Labels => "labels",
+            Column::Assignees => "assignees",
+            Column::Due => "due",
+            Column::Created => "created",
+        }
+    }
+}
+
+impl fmt::Display for Column {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Column {
+    type Err = ImportError;
+
+    /// Read a column name, ignoring case.
+    fn from_str(name: &str) -> Result<Column, ImportError> {
+        let name = name.trim().to_lowercase();
+        ALL_COLUMNS.iter().cloned()
+            .find(|c| c.name() == name)
+            .ok_or_else(|| {
+                ImportError::Invalid(format!("Unknown column '{}'", name))
+            })
+    }
+}
+
+/// Characters that make spreadsheets read a field as a formula.
+const FORMULA_START: &[char] = &['=', '+', '-', '@'];
+
+/// Whether a field would be read as a formula, or has the quotes put in
+/// front of one.
+fn is_formula(field: &str) -> bool {
+    field.trim_start_matches('\'').starts_with(FORMULA_START)
+}
+
+/// Quote a field if needed, and put a quote in front of formulas.
+fn write_field(out: &mut String, field: &str) {
+    let protected;
+    let field = if is_formula(field) {
+        protected = format!("'{}", field);
+        &protected
+    } else {
+        field
+    };
+    if field.contains(&[',', '"', '\r', '\n'][..]) {
+        out.push('"');
+        out.push_str(&field.replace('"', "\"\""));
+        out.push('"');
+    } else {
+        out.push_str(field);
+    }
+}
+
+fn write_row<I: IntoIterator<Item=S>, S: AsRef<str>>(out: &mut String,
+                                                     fields: I) {
+    for (i, field) in fields.into_iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_field(out, field.as_ref());
+    }
+    out.push('\n');
+}
+
+fn format_time(time: u64) -> String {
+    let time = std::time::UNIX_EPOCH + std::time::Duration::from_secs(time);
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+/// When each card was created, from the activity feed of their board.
+pub fn created_times(activity: &[Activity]) -> HashMap<Uuid, u64> {
+    let mut created = HashMap::new();
+    for entry in activity {
+        if let (Kind::Card, Event::Created { .. }, Some(time)) =
+            (entry.kind, &entry.event, entry.time)
+        {
+            // A card added again is replaced, it was created the first time
+            created.entry(entry.subject).or_insert(time);
+        }
+    }
+    created
+}
+
+/// Write cards as CSV.
+///
+/// `created` has the creation times of the cards, see `created_times()`.
+pub fn export<'a, I>(lists: &[List], cards: I, columns: &[Column],
+                     created: &HashMap<Uuid, u64>)
+    -> String
+    where I: IntoIterator<Item=&'a Card>
+{
+    let list_names: HashMap<Uuid, &str> = lists.iter()
+        .map(|l| (l.id, l.name.as_str()))
+        .collect();
+    let mut out = String::new();
+    write_row(&mut out, columns.iter().map(|c| c.name()));
+    for card in cards {
+        write_row(&mut out, columns.iter().map(|column| match *column {
+            Column::Title => card.title.clone(),
+            Column::List => {
+                list_names.get(&card.list).cloned().unwrap_or("").into()
+            }
+            Column::Description => card.description.clone(),
+            Column::Labels => join_list(&card.labels),
+            Column::Assignees => join_list(&card.assignees),
+            Column::Due => card.due.map(format_time).unwrap_or_default(),
+            Column::Created => {
+                created.get(&card.id).cloned().map(format_time)
+                    .unwrap_or_default()
+            }
+        }));
+    }
+    out
+}
+
+/// Split CSV into rows of fields, following RFC 4180.
+///
+/// Returns the rows with the line they start on.
+fn parse(text: &str) -> Result<Vec<(usize, Vec<String>)>, ImportError> {
+    // Spreadsheets often start UTF-8 files with a byte order mark
+    let text = text.trim_start_matches('\u{feff}');
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut line = 1;
+    let mut row_line = 1;
+    let mut chars = text.chars().peekable();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => quoted = false,
+                c => {
+                    if c == '\n' {
+                        line += 1;
+                    }
+                    field.push(c);
+                }
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => quoted = true,
+            ',' => row.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                row.push(std::mem::take(&mut field));
+                rows.push((row_line, std::mem::take(&mut row)));
+                line += 1;
+                row_line = line;
+            }
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(ImportError::Invalid(format!(
+            "Unterminated quoted field on line {}", row_line,
+        )));
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push((row_line, row));
+    }
+    // Skip blank lines
+    rows.retain(|(_, row)| !(row.len() == 1 && row[0].trim().is_empty()));
+    Ok(rows)
+}
+
+/// Join labels or assignees with commas, escaping the ones they contain.
+fn join_list(items: &[String]) -> String {
+    let items: Vec<String> = items.iter()
+        .map(|s| s.replace('\\', "\\\\").replace(',', "\\,"))
+        .collect();
+    items.join(", ")
+}
+
+/// Split labels or assignees joined by `join_list()`.
+fn split_list(field: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut item = String::new();
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => item.extend(chars.next()),
+            ',' => items.push(std::mem::take(&mut item)),
+            c => item.push(c),
+        }
+    }
+    items.push(item);
+    items.into_iter()
+        .map(|s| s.trim().to_owned())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// Cards read from CSV, to add to a board.
+#[derive(Debug)]
+pub struct CsvImport {
+    /// Lists the cards are in that the board didn't have
+    pub lists: Vec<List>,
+    pub cards: Vec<Card>,
+    pub report: Report,
+}
+
+/// Read cards from CSV, to add to a board with the given lists.
+///
+/// `mapping` gives the column for headers, in lower case, that don't have
+/// the name of the column. Cards without a list go in the first list of the
+/// board, or in a new "Imported" list if it has none.
+pub fn import(text: &str, lists: &[List],
+              mapping: &HashMap<String, Column>)
+    -> Result<CsvImport, ImportError>
+{
+    let mut rows = parse(text)?.into_iter();
+    let header = match rows.next() {
+        Some((_, header)) => header,
+        None => return Err(ImportError::Invalid("Empty file".into())),
+    };
+    let mut report = Report::default();
+    let mut columns = Vec::new();
+    for name in header {
+        let column = mapping.get(&name.trim().to_lowercase()).cloned()
+            .or_else(|| name.parse().ok());
+        match column {
+            Some(Column::Created) => {
+                report.skipped.push(
+                    "column 'created', cards are created now".into(),
+                );
+                columns.push(None);
+            }
+            Some(column) => columns.push(Some(column)),
+            None => {
+                report.skipped.push(format!("column '{}'", name));
+                columns.push(None);
+            }
+        }
+    }
+    if !columns.contains(&Some(Column::Title)) {
+        return Err(ImportError::Invalid("No title column".into()));
+    }
+
+    let mut list_ids: HashMap<String, Uuid> = lists.iter()
+        .map(|l| (l.name.clone(), l.id))
+        .collect();
+    let mut new_lists = Vec::new();
+    let mut get_list = |name: &str| -> Uuid {
+        if let Some(&id) = list_ids.get(name) {
+            return id;
+        }
+        let list = List { id: Uuid::new_v4(), name: name.into() };
+        list_ids.insert(list.name.clone(), list.id);
+        new_lists.push(list.clone());
+        list.id
+    };
+    let default_list = lists.first().map(|l| l.name.clone())
+        .unwrap_or_else(|| "Imported".into());
+
+    let mut cards = Vec::new();
+    for (line, row) in rows {
+        let mut card = Card::new(Uuid::nil(), "");
+        let mut list = None;
+        for (column, field) in columns.iter().zip(row) {
+            let raw = if is_formula(&field) && field.starts_with('\'') {
+                &field[1..]
+            } else {
+                &field[..]
+            };
+            let field = raw.trim();
+            match *column {
+                Some(Column::Title) => card.title = field.into(),
+                Some(Column::List) if !field.is_empty() => {
+                    list = Some(field.to_owned());
+                }
+                Some(Column::List) => {}
+                // Descriptions are kept as written, with their spaces and
+                // line breaks
+                Some(Column::Description) => card.description = raw.into(),
+                Some(Column::Labels) => card.labels = split_list(field),
+                Some(Column::Assignees) => {
+                    card.assignees = split_list(field);
+                }
+                Some(Column::Due) if field.is_empty() => {}
+                Some(Column::Due) => {
+                    card.due = parse_time(field);
+                    if card.due.is_none() {
+                        report.skipped.push(format!(
+                            "line {}: invalid due date '{}'", line, field,
+                        ));
+                    }
+                }
+                Some(Column::Created) | None => {}
+            }
+        }
+        if card.title.is_empty() {
+            report.skipped.push(format!("line {}: no title", line));
+            continue;
+        }
+        card.list = get_list(list.as_ref().unwrap_or(&default_list));
+        cards.push(card);
+    }
+
+    Ok(CsvImport { lists: new_lists, cards, report })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use uuid::Uuid;
+
+    use crate::{Card, List};
+    use super::{Column, DEFAULT_COLUMNS, export, import, parse};
+    use super::ALL_COLUMNS;
+
+    #[test]
+    fn test_parse() {
+        let rows = parse(
+            "\u{feff}a,\"b,c\",\"say \"\"hi\"\"\"\r\n\
+             \n\
+             \"multi\nline\",,x\n\
+             last",
+        ).unwrap();
+        assert_eq!(rows, vec![
+            (1, vec!["a".into(), "b,c".into(), "say \"hi\"".into()]),
+            (3, vec!["multi\nline".into(), "".into(), "x".into()]),
+            (5, vec!["last".into()]),
+        ]);
+        assert!(parse("a,\"b\nc").is_err());
+    }
+
+    #[test]
+    fn test_export() {
+        let todo = List { id: Uuid::new_v4(), name: "todo".into() };
+        let mut write = Card::new(todo.id, "Write \"the\" report");
+        write.labels = vec!["urgent".into(), "q1".into()];
+        write.due = Some(1551441600);
+        let send = Card::new(todo.id, "Send report");
+        let mut created = HashMap::new();
+        created.insert(write.id, 1549015200);
+
+        let csv = export(&[todo], &[write, send], DEFAULT_COLUMNS, &created);
+        assert_eq!(
+            csv,
+            "title,list,labels,assignees,due,created\n\
+             \"Write \"\"the\"\" report\",todo,\"urgent, q1\",,\
+             2019-03-01T12:00:00Z,2019-02-01T10:00:00Z\n\
+             Send report,todo,,,,\n",
+        );
+    }
+
+    #[test]
+    fn test_import() {
+        let lists = vec![List { id: Uuid::new_v4(), name: "todo".into() }];
+        let todo = &lists[0];
+        let mut mapping = HashMap::new();
+        mapping.insert("task".into(), Column::Title);
+        let csv = "Task,List,Labels,Due,Notes,Created\n\
+                   Write report,todo,\"urgent, q1\",2019-03-01,,\n\
+                   Send report,done,,,later,\n\
+                   Call,,,soon,,\n\
+                   ,todo,,,,\n";
+        let result = import(csv, &lists, &mapping).unwrap();
+
+        assert_eq!(result.lists.len(), 1);
+        let done = &result.lists[0];
+        assert_eq!(done.name, "done");
+        let cards: Vec<(&str, Uuid)> = result.cards.iter()
+            .map(|c| (c.title.as_str(), c.list))
+            .collect();
+        assert_eq!(cards, vec![
+            ("Write report", todo.id),
+            ("Send report", done.id),
+            ("Call", todo.id),
+        ]);
+        assert_eq!(result.cards[0].labels, vec!["urgent", "q1"]);
+        assert_eq!(result.cards[0].due, Some(1551398400));
+        assert_eq!(result.report.skipped, vec![
+            "column 'Notes'",
+            "column 'created', cards are created now",
+            "line 4: invalid due date 'soon'",
+            "line 5: no title",
+        ]);
+
+        assert!(import("List\ntodo\n", &[], &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let lists = vec![List { id: Uuid::new_v4(), name: "todo".into() }];
+        let todo = &lists[0];
+        let mut formula = Card::new(todo.id, "=HYPERLINK(\"x\")");
+        formula.description = "  - first\n  - second\n".into();
+        formula.labels = vec!["a, b".into(), "back\\slash".into(),
+                              "-1".into()];
+        formula.assignees = vec!["@remram".into()];
+        let quoted = Card::new(todo.id, "'=quoted");
+        let plain = Card::new(todo.id, "'Tis the season");
+        let cards = vec![formula, quoted, plain];
+        let columns: Vec<Column> = ALL_COLUMNS.iter().cloned()
+            .filter(|&c| c != Column::Created)
+            .collect();
+
+        let csv = export(&lists, &cards, &columns, &HashMap::new());
+        assert_eq!(
+            csv,
+            "title,list,description,labels,assignees,due\n\
+             \"'=HYPERLINK(\"\"x\"\")\",todo,\"  - first\n  - second\n\",\
+             \"a\\, b, back\\\\slash, -1\",'@remram,\n\
+             ''=quoted,todo,,,,\n\
+             'Tis the season,todo,,,,\n",
+        );
+        let result = import(&csv, &lists, &HashMap::new()).unwrap();
+        assert!(result.report.is_empty());
+        let imported: Vec<Card> = result.cards.into_iter().zip(&cards)
+            .map(|(card, original)| Card { id: original.id, ..card })
+            .collect();
+        assert_eq!(imported, cards);
+    }
+}
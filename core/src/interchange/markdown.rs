@@ -0,0 +1,292 @@
+//! Boards as Markdown documents.
+//!
+//! A board is written as a title, then a heading per list with a task list
+//! item per card, the card's description indented below it:
+//!
+//! ```text
+//! # Work
+//!
+//! ## todo
+//!
+//! - [ ] Write report
+//!   Quarterly numbers
+//! - [ ] Send report
+//! ```
+//!
+//! Labels, due dates, assignees and comments are not written.
+//!
+//! Importing reads the same structure, so boards can round-trip through
+//! plain text documents, but also other checklists (such as a README's): the
+//! first top-level heading names the board, and every other heading starts a
+//! list. Task list items and plain bullet or numbered items are cards; items
+//! before the first list go in a "Cards" list. Cards don't have a checked
+//! state, so checked items are imported like the others and reported.
+
+use uuid::Uuid;
+
+use crate::{Board, Card, List};
+use super::{BoardExport, Report};
+
+/// Write a board as Markdown.
+pub fn export(export: &BoardExport) -> String {
+    let mut out = format!("# {}\n", one_line(&export.board.name));
+    for list in &export.lists {
+        out.push_str(&format!("\n## {}\n", one_line(&list.name)));
+        let mut cards = export.cards.iter()
+            .filter(|c| c.list == list.id)
+            .peekable();
+        if cards.peek().is_some() {
+            out.push('\n');
+        }
+        for card in cards {
+            out.push_str(&format!("- [ ] {}\n", one_line(&card.title)));
+            for line in card.description.lines() {
+                if line.is_empty() {
+                    out.push('\n');
+                } else {
+                    out.push_str(&format!("  {}\n", line));
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Names and titles have to fit on their line.
+fn one_line(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Read a heading, returning its level and text.
+///
+/// A closing sequence of `#` is only one if it follows a space, so "C#"
+/// keeps its `#`.
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.chars().take_while(|&c| c == '#').count();
+    let rest = &line[level..];
+    if level == 0 || level > 6 || !(rest.is_empty() || rest.starts_with(' ')) {
+        return None;
+    }
+    let text = rest.trim();
+    let open = text.trim_end_matches('#');
+    if open.is_empty() || open.ends_with(char::is_whitespace) {
+        Some((level, open.trim_end()))
+    } else {
+        Some((level, text))
+    }
+}
+
+/// Read a list item, returning the width of its marker, whether it is
+/// checked, and its text.
+fn item(line: &str) -> Option<(usize, bool, &str)> {
+    let marker = if line.starts_with("- ") || line.starts_with("* ")
+        || line.starts_with("+ ")
+    {
+        2
+    } else {
+        let digits = line.chars().take_while(|c| c.is_ascii_digit()).count();
+        let rest = &line[digits..];
+        if digits == 0 || !(rest.starts_with(". ") || rest.starts_with(") ")) {
+            return None;
+        }
+        digits + 2
+    };
+    let text = line[marker..].trim_start();
+    let (checked, text) = if let Some(text) = text.strip_prefix("[ ]") {
+        (false, text)
+    } else if let Some(text) = text.strip_prefix("[x]")
+        .or_else(|| text.strip_prefix("[X]"))
+    {
+        (true, text)
+    } else {
+        (false, text)
+    };
+    Some((marker, checked, text.trim()))
+}
+
+/// Read a board from Markdown, with new IDs.
+///
+/// `name` is used for the board if the document has no title.
+pub fn import(text: &str, name: &str) -> (BoardExport, Report) {
+    let mut report = Report::default();
+    let mut board = Board { id: Uuid::new_v4(), name: name.into() };
+    let mut titled = false;
+    let mut lists: Vec<List> = Vec::new();
+    let mut cards: Vec<Card> = Vec::new();
+    // Width of the marker of the item being read, its description follows
+    let mut current: Option<usize> = None;
+    let mut fenced = false;
+
+    for (number, line) in text.lines().enumerate() {
+        let number = number + 1;
+        let line = line.trim_end();
+        let indent = line.len() - line.trim_start().len();
+
+        // Indented lines, and blank lines, belong to the current item
+        if let Some(marker) = current {
+            if line.is_empty() || indent > 0 {
+                let card = cards.last_mut().unwrap();
+                // Up to the marker's width of spaces is the item's
+                // indentation, the rest is the description's
+                let spaces = line.bytes().take(marker)
+                    .take_while(|&b| b == b' ')
+                    .count();
+                card.description.push_str(&line[spaces..]);
+                card.description.push('\n');
+                continue;
+            }
+            current = None;
+        }
+
+        if line.trim_start().starts_with("```") {
+            fenced = !fenced;
+            report.skipped.push(format!("line {}: code block", number));
+            continue;
+        }
+        if fenced || line.is_empty() {
+            continue;
+        }
+
+        if let Some((level, text)) = heading(line) {
+            if level == 1 && !titled && lists.is_empty() {
+                board.name = text.into();
+                titled = true;
+            } else {
+                lists.push(List { id: Uuid::new_v4(), name: text.into() });
+            }
+        } else if let Some((marker, checked, title)) = item(line) {
+            if lists.is_empty() {
+                lists.push(List { id: Uuid::new_v4(), name: "Cards".into() });
+            }
+            if checked {
+                report.lossy.push(format!(
+                    "line {}: item '{}' is checked, cards can't be",
+                    number, title,
+                ));
+            }
+            cards.push(Card::new(lists.last().unwrap().id, title));
+            current = Some(marker);
+        } else {
+            report.skipped.push(format!(
+                "line {}: text outside of a list item", number,
+            ));
+        }
+    }
+
+    for card in &mut cards {
+        let description = card.description.trim_end().to_owned();
+        card.description = description;
+    }
+    (BoardExport { board, lists, cards }, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use crate::{Board, Card, List};
+    use crate::interchange::BoardExport;
+    use super::{export, import};
+
+    #[test]
+    fn test_round_trip() {
+        let todo = List { id: Uuid::new_v4(), name: "todo".into() };
+        let done = List { id: Uuid::new_v4(), name: "done".into() };
+        let empty = List { id: Uuid::new_v4(), name: "Learn C#".into() };
+        let mut write = Card::new(todo.id, "Write report");
+        write.description = "Quarterly numbers\n\n## Steps\n\n- [x] Draft"
+            .into();
+        let board = BoardExport {
+            board: Board { id: Uuid::new_v4(), name: "Work".into() },
+            cards: vec![
+                write,
+                Card::new(done.id, "Send\nreport"),
+                Card::new(todo.id, "Proofread"),
+            ],
+            lists: vec![todo, done, empty],
+        };
+
+        let text = export(&board);
+        assert_eq!(text, "# Work\n\
+                          \n\
+                          ## todo\n\
+                          \n\
+                          - [ ] Write report\n  \
+                            Quarterly numbers\n\
+                          \n  \
+                            ## Steps\n\
+                          \n  \
+                            - [x] Draft\n\
+                          - [ ] Proofread\n\
+                          \n\
+                          ## done\n\
+                          \n\
+                          - [ ] Send report\n\
+                          \n\
+                          ## Learn C#\n");
+
+        let (imported, report) = import(&text, "Untitled");
+        assert!(report.is_empty());
+        assert_eq!(imported.board.name, "Work");
+        let lists: Vec<&str> = imported.lists.iter()
+            .map(|l| l.name.as_str())
+            .collect();
+        assert_eq!(lists, vec!["todo", "done", "Learn C#"]);
+        let cards: Vec<(&str, &str, Uuid)> = imported.cards.iter()
+            .map(|c| (c.title.as_str(), c.description.as_str(), c.list))
+            .collect();
+        assert_eq!(cards, vec![
+            ("Write report", board.cards[0].description.as_str(),
+             imported.lists[0].id),
+            ("Proofread", "", imported.lists[0].id),
+            ("Send report", "", imported.lists[1].id),
+        ]);
+    }
+
+    #[test]
+    fn test_checklist() {
+        let text = "Some introduction.\n\
+                    \n\
+                    * [x] Set up CI\n\
+                    * Write docs\n\
+                    \n\
+                    ### Later ###\n\
+                    \n\
+                    1. [ ] Release\n\
+                    2) [X] Announce\n\
+                    ```\n\
+                    - [ ] not a card\n\
+                    ```\n";
+        let (imported, report) = import(text, "README");
+        assert_eq!(imported.board.name, "README");
+        let lists: Vec<&str> = imported.lists.iter()
+            .map(|l| l.name.as_str())
+            .collect();
+        assert_eq!(lists, vec!["Cards", "Later"]);
+        let titles: Vec<&str> = imported.cards.iter()
+            .map(|c| c.title.as_str())
+            .collect();
+        assert_eq!(titles, vec!["Set up CI", "Write docs", "Release",
+                                "Announce"]);
+        assert_eq!(report.skipped, vec![
+            "line 1: text outside of a list item",
+            "line 10: code block",
+            "line 12: code block",
+        ]);
+        assert_eq!(report.lossy, vec![
+            "line 3: item 'Set up CI' is checked, cards can't be",
+            "line 9: item 'Announce' is checked, cards can't be",
+        ]);
+    }
+
+    #[test]
+    fn test_wide_indent() {
+        // Only spaces are taken off as indentation, not other whitespace
+        let text = "# B\n\n## todo\n\n- [ ] card\n\u{3000}indented\n";
+        let (imported, report) = import(text, "Untitled");
+        assert!(report.is_empty());
+        assert_eq!(imported.cards.len(), 1);
+        assert_eq!(imported.cards[0].title, "card");
+        assert_eq!(imported.cards[0].description, "\u{3000}indented");
+    }
+}
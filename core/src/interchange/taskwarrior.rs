@@ -0,0 +1,397 @@
+//! Cards from and to Taskwarrior, with the JSON of `task export` and
+//! `task import`.
+//!
+//! A task's description is the card's title, its tags are labels, its
+//! project and priority are labels like "project:home.garden" and
+//! "priority:H", and its annotations are comments. Completed tasks are the
+//! cards in the "done" list, whose name is chosen by the caller.
+//!
+//! Cards keep the task's UUID as their `external` reference, so importing
+//! the same tasks again updates the cards rather than adding new ones. The
+//! fields Taskwarrior has are replaced with its values; descriptions and
+//! assignees stay. Exported cards have the UUID they were imported with, or
+//! their own ID.
+
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, UNIX_EPOCH};
+use uuid::Uuid;
+
+use crate::{Card, Comment, List};
+use crate::activity;
+use super::{ImportError, Report, TaskLists, parse_time};
+
+/// Prefix of the `external` reference of cards imported from Taskwarrior.
+const PREFIX: &str = "taskwarrior:";
+
+/// Fields that are only Taskwarrior's bookkeeping.
+const IGNORED: &[&str] = &["id", "entry", "modified", "end", "urgency",
+                           "mask", "imask", "parent"];
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Task {
+    uuid: Uuid,
+    description: String,
+    #[serde(default = "pending")]
+    status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    project: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    priority: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    due: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    annotations: Vec<Annotation>,
+    /// The fields that aren't read
+    #[serde(flatten, skip_serializing)]
+    other: BTreeMap<String, serde_json::Value>,
+}
+
+fn pending() -> String {
+    "pending".into()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Annotation {
+    entry: String,
+    description: String,
+}
+
+/// Read a time like "20190301T120000Z", or in RFC 3339.
+fn parse_tw_time(text: &str) -> Option<u64> {
+    let b = text.as_bytes();
+    if b.len() == 16 && b[8] == b'T' && b[15] == b'Z' {
+        parse_time(&format!("{}-{}-{}T{}:{}:{}Z",
+                            &text[0..4], &text[4..6], &text[6..8],
+                            &text[9..11], &text[11..13], &text[13..15]))
+    } else {
+        parse_time(text)
+    }
+}
+
+fn format_tw_time(time: u64) -> String {
+    let time = UNIX_EPOCH + Duration::from_secs(time);
+    humantime::format_rfc3339_seconds(time).to_string()
+        .replace(['-', ':'], "")
+}
+
+/// The UUID of a card's task: the one it was imported with, or its ID.
+fn task_uuid(card: &Card) -> Uuid {
+    card.external.as_ref()
+        .and_then(|e| e.strip_prefix(PREFIX))
+        .and_then(|u| Uuid::parse_str(u).ok())
+        .unwrap_or(card.id)
+}
+
+/// Read tasks, from a JSON array or from one JSON object per line.
+fn parse(json: &str) -> Result<Vec<Task>, ImportError> {
+    if json.trim_start().starts_with('[') {
+        return Ok(serde_json::from_str(json)?);
+    }
+    json.lines()
+        .map(|l| l.trim().trim_end_matches(','))
+        .filter(|l| !l.is_empty())
+        .map(|l| Ok(serde_json::from_str(l)?))
+        .collect()
+}
+
+/// Cards read from Taskwarrior, to add to a board or to update.
+#[derive(Debug)]
+pub struct TaskwarriorImport {
+    /// Lists the cards are in that the board didn't have
+    pub lists: Vec<List>,
+    /// New cards, and the cards to replace
+    pub cards: Vec<Card>,
+    /// Cards whose task was deleted
+    pub removed: Vec<Uuid>,
+    pub report: Report,
+}
+
+/// Read the output of `task export`, as changes to a board with the given
+/// lists and cards.
+///
+/// Tasks that have a card on the board update it, the others are added:
+/// completed tasks in the list named `done_list`, the others in the first
+/// other list. Either is created if the board doesn't have it. Deleted tasks
+/// remove their card.
+pub fn import(json: &str, lists: &[List], cards: &[Card], done_list: &str)
+    -> Result<TaskwarriorImport, ImportError>
+{
+    let tasks = parse(json)?;
+    let mut places = TaskLists::new(lists, done_list);
+    let mut report = Report::default();
+    let mut skipped_fields: BTreeMap<String, usize> = BTreeMap::new();
+    let mut changed = Vec::new();
+    let mut removed = Vec::new();
+
+    // The cards from earlier imports, or exported from the board
+    let existing: HashMap<Uuid, &Card> = cards.iter()
+        .map(|c| (task_uuid(c), c))
+        .collect();
+
+    for task in tasks {
+        let card = existing.get(&task.uuid).cloned();
+        let done = match task.status.as_str() {
+            "pending" | "waiting" => false,
+            "completed" => true,
+            "deleted" => {
+                removed.extend(card.map(|c| c.id));
+                continue;
+            }
+            status => {
+                report.skipped.push(format!(
+                    "task '{}', its status is {}",
+                    task.description, status,
+                ));
+                continue;
+            }
+        };
+        for field in task.other.keys() {
+            if !IGNORED.contains(&field.as_str()) {
+                *skipped_fields.entry(field.clone()).or_insert(0) += 1;
+            }
+        }
+
+        let mut new = match card {
+            Some(card) => card.clone(),
+            None => Card::new(places.get(done), ""),
+        };
+        new.title = task.description.clone();
+        new.labels = task.project.iter().map(|p| format!("project:{}", p))
+            .chain(task.priority.iter().map(|p| format!("priority:{}", p)))
+            .chain(task.tags.iter().cloned())
+            .collect();
+        new.due = match task.due {
+            Some(ref due) => {
+                let time = parse_tw_time(due);
+                if time.is_none() {
+                    report.skipped.push(format!(
+                        "due date of '{}', can't read '{}'",
+                        task.description, due,
+                    ));
+                }
+                time
+            }
+            None => None,
+        };
+        new.comments = task.annotations.iter().map(|a| Comment {
+            author: None,
+            text: a.description.clone(),
+            time: parse_tw_time(&a.entry),
+        }).collect();
+        new.external = Some(format!("{}{}", PREFIX, task.uuid));
+        if places.is_done(&new) != done {
+            new.list = places.get(done);
+        }
+        if card != Some(&new) {
+            changed.push(new);
+        }
+    }
+
+    for (field, count) in skipped_fields {
+        report.skipped.push(format!("field '{}' of {} tasks", field, count));
+    }
+    Ok(TaskwarriorImport {
+        lists: places.new,
+        cards: changed,
+        removed,
+        report,
+    })
+}
+
+/// Write cards as JSON that `task import` reads. The cards in the list
+/// named `done_list` are completed.
+pub fn export<'a, I>(lists: &[List], cards: I, done_list: &str) -> String
+    where I: IntoIterator<Item=&'a Card>
+{
+    let places = TaskLists::new(lists, done_list);
+    let tasks: Vec<Task> = cards.into_iter().map(|card| {
+        let mut task = Task {
+            uuid: task_uuid(card),
+            description: card.title.clone(),
+            status: if places.is_done(card) { "completed" } else { "pending" }
+                .into(),
+            project: None,
+            priority: None,
+            tags: Vec::new(),
+            due: card.due.map(format_tw_time),
+            annotations: Vec::new(),
+            other: BTreeMap::new(),
+        };
+        for label in &card.labels {
+            if let Some(project) = label.strip_prefix("project:") {
+                task.project = Some(project.into());
+            } else if let Some(priority) = label.strip_prefix("priority:") {
+                task.priority = Some(priority.into());
+            } else {
+                task.tags.push(label.clone());
+            }
+        }
+        // Annotations are told apart by their time
+        let mut last = 0;
+        for comment in &card.comments {
+            let time = comment.time.unwrap_or_else(activity::now);
+            last = time.max(last + 1);
+            task.annotations.push(Annotation {
+                entry: format_tw_time(last),
+                description: comment.text.clone(),
+            });
+        }
+        task
+    }).collect();
+    let mut json = serde_json::to_string_pretty(&tasks)
+        .expect("Serializing tasks");
+    json.push('\n');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use crate::{Card, Comment, List};
+    use super::{export, import};
+
+    const EXPORT: &str = r#"[
+{"id":1,"description":"Plant tomatoes","entry":"20190301T120000Z",
+ "modified":"20190302T120000Z","project":"home.garden","priority":"H",
+ "status":"pending","tags":["outside","spring"],"due":"20190315T000000Z",
+ "uuid":"8ad2e3db-914d-4832-b0e6-72fa04f6e331","urgency":9.5,
+ "annotations":[{"entry":"20190302T120000Z","description":"Buy seeds"}]},
+{"id":0,"description":"Call plumber","end":"20190303T120000Z",
+ "entry":"20190301T120000Z","status":"completed","scheduled":"20190302",
+ "uuid":"0f4d3d2e-5d5c-4f0b-9b1e-3c2f1e6a7b8c"},
+{"id":0,"description":"Old chore","status":"deleted",
+ "uuid":"5e0b8a1c-2f3d-4e5f-8a9b-0c1d2e3f4a5b"},
+{"id":0,"description":"Weekly","status":"recurring","recur":"weekly",
+ "uuid":"6e0b8a1c-2f3d-4e5f-8a9b-0c1d2e3f4a5b"}
+]"#;
+
+    #[test]
+    fn test_import() {
+        let result = import(EXPORT, &[], &[], "done").unwrap();
+        let names: Vec<&str> = result.lists.iter()
+            .map(|l| l.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["todo", "done"]);
+        assert!(result.removed.is_empty());
+        assert_eq!(result.report.skipped, vec![
+            "task 'Weekly', its status is recurring",
+            "field 'scheduled' of 1 tasks",
+        ]);
+        let plant = &result.cards[0];
+        assert_eq!(plant.title, "Plant tomatoes");
+        assert_eq!(plant.list, result.lists[0].id);
+        assert_eq!(plant.labels, vec!["project:home.garden", "priority:H",
+                                      "outside", "spring"]);
+        assert_eq!(plant.due, Some(1552608000));
+        assert_eq!(plant.comments, vec![Comment {
+            author: None,
+            text: "Buy seeds".into(),
+            time: Some(1551528000),
+        }]);
+        assert_eq!(plant.external.as_deref(),
+                   Some("taskwarrior:8ad2e3db-914d-4832-b0e6-72fa04f6e331"));
+        let call = &result.cards[1];
+        assert_eq!(call.title, "Call plumber");
+        assert_eq!(call.list, result.lists[1].id);
+        assert_eq!(result.cards.len(), 2);
+
+        // Importing again updates the cards
+        let mut lists = result.lists;
+        let mut cards = result.cards;
+        cards[0].description = "Kept".into();
+        let json = EXPORT
+            .replace("\"status\":\"pending\"", "\"status\":\"completed\"")
+            .replace("\"status\":\"deleted\"", "\"status\":\"pending\"")
+            .replace("\"id\":0,\"description\":\"Call plumber\"",
+                     "\"id\":0,\"description\":\"Call the plumber\"");
+        let result = import(&json, &lists, &cards, "done").unwrap();
+        assert!(result.lists.is_empty());
+        assert_eq!(result.cards.len(), 3);
+        assert_eq!(result.cards[0].id, cards[0].id);
+        assert_eq!(result.cards[0].list, lists[1].id);
+        assert_eq!(result.cards[0].description, "Kept");
+        assert_eq!(result.cards[1].id, cards[1].id);
+        assert_eq!(result.cards[1].title, "Call the plumber");
+        assert_eq!(result.cards[2].title, "Old chore");
+        assert_eq!(result.cards[2].list, lists[0].id);
+
+        // Unchanged tasks are left alone, deleted ones are removed
+        cards.push(result.cards[2].clone());
+        let json = EXPORT
+            .replace("\"status\":\"deleted\"", "\"status\":\"pending\"")
+            .replace("\"status\":\"completed\"", "\"status\":\"deleted\"");
+        let result = import(&json, &lists, &cards, "done").unwrap();
+        assert!(result.cards.is_empty());
+        assert_eq!(result.removed, vec![cards[1].id]);
+
+        // One task per line, as older versions write
+        lists.truncate(1);
+        let json = "{\"description\":\"A\",\
+                     \"uuid\":\"8ad2e3db-914d-4832-b0e6-72fa04f6e331\"},\n\
+                    {\"description\":\"B\",\"due\":\"soon\",\
+                     \"uuid\":\"0f4d3d2e-5d5c-4f0b-9b1e-3c2f1e6a7b8c\"}\n";
+        let result = import(json, &lists, &[], "done").unwrap();
+        assert!(result.lists.is_empty());
+        assert_eq!(result.cards.len(), 2);
+        assert_eq!(result.report.skipped, vec![
+            "due date of 'B', can't read 'soon'",
+        ]);
+        assert!(import("{\"description\": 1}", &lists, &[], "done").is_err());
+    }
+
+    #[test]
+    fn test_export() {
+        let todo = List { id: Uuid::new_v4(), name: "todo".into() };
+        let done = List { id: Uuid::new_v4(), name: "done".into() };
+        let mut plant = Card::new(todo.id, "Plant tomatoes");
+        plant.labels = vec!["project:home.garden".into(), "priority:H".into(),
+                            "outside".into()];
+        plant.due = Some(1552608000);
+        plant.comments = vec![
+            Comment { author: None, text: "Buy seeds".into(),
+                      time: Some(1551528000) },
+            Comment { author: Some("remram".into()), text: "Done".into(),
+                      time: Some(1551528000) },
+        ];
+        plant.external =
+            Some("taskwarrior:8ad2e3db-914d-4832-b0e6-72fa04f6e331".into());
+        let call = Card::new(done.id, "Call plumber");
+        let cards = vec![plant, call];
+
+        let lists = vec![todo, done];
+        let json = export(&lists, &cards, "done");
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value, serde_json::json!([
+            {
+                "uuid": "8ad2e3db-914d-4832-b0e6-72fa04f6e331",
+                "description": "Plant tomatoes",
+                "status": "pending",
+                "project": "home.garden",
+                "priority": "H",
+                "tags": ["outside"],
+                "due": "20190315T000000Z",
+                "annotations": [
+                    {"entry": "20190302T120000Z",
+                     "description": "Buy seeds"},
+                    {"entry": "20190302T120001Z", "description": "Done"},
+                ],
+            },
+            {
+                "uuid": cards[1].id.to_string(),
+                "description": "Call plumber",
+                "status": "completed",
+            },
+        ]));
+
+        // Importing the export updates the cards it came from
+        let result = import(&json, &lists[1..], &cards[1..], "done")
+            .unwrap();
+        assert_eq!(result.cards[1].id, cards[1].id);
+        assert_eq!(result.cards[1].external,
+                   Some(format!("taskwarrior:{}", cards[1].id)));
+    }
+}
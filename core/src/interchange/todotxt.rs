@@ -0,0 +1,638 @@
+//! Cards as todo.txt tasks.
+//!
+//! Each card is a line of the file:
+//!
+//! ```text
+//! (A) Ask @mom about +party food due:2019-03-01
+//! x 2019-03-02 2019-02-20 Book the room +party
+//! ```
+//!
+//! The priority, `+project` and `@context` tags are the card's labels,
+//! named like they are written ("(A)", "+party", "@mom"), and `due:` is its
+//! due date, without the time. Completed tasks are the cards in the "done"
+//! list, whose name is chosen by the caller. Tags at the end of a line are
+//! only labels, the ones in the middle stay in the title too. Other
+//! `key:value` tags are kept in the title.
+//!
+//! Descriptions, assignees, comments and the lists other than the "done" one
+//! are not written, and creation and completion dates are not imported.
+//!
+//! `sync()` reconciles a file with a board both ways: it is given the lines
+//! written by the last sync, to know which side changed.
+
+use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, UNIX_EPOCH};
+use uuid::Uuid;
+
+use crate::{Card, List};
+use crate::activity;
+use super::{Report, TaskLists, parse_time};
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// The fields of a task, as written in the file.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Task {
+    done: bool,
+    priority: Option<char>,
+    completed: Option<String>,
+    created: Option<String>,
+    title: String,
+    /// The projects and contexts
+    tags: Vec<String>,
+    due: Option<u64>,
+}
+
+impl Task {
+    /// Whether the tasks are the same on a card; dates aren't kept.
+    fn same(&self, other: &Task) -> bool {
+        self.done == other.done
+            && self.priority == other.priority
+            && self.title == other.title
+            && self.tags == other.tags
+            && self.due == other.due
+    }
+}
+
+fn is_date(word: &str) -> bool {
+    word.len() == 10 && word.char_indices().all(|(i, c)| match i {
+        4 | 7 => c == '-',
+        _ => c.is_ascii_digit(),
+    })
+}
+
+/// Read a priority, written like "(A)".
+fn priority(word: &str) -> Option<char> {
+    let letter = word.strip_prefix('(')?.strip_suffix(')')?;
+    let mut chars = letter.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii_uppercase() => Some(c),
+        _ => None,
+    }
+}
+
+/// Whether a word is a project or a context.
+fn is_tag(word: &str) -> bool {
+    (word.starts_with('+') || word.starts_with('@')) && word.len() > 1
+}
+
+fn format_date(time: u64) -> String {
+    let time = UNIX_EPOCH + Duration::from_secs(time);
+    humantime::format_rfc3339_seconds(time).to_string()[..10].to_owned()
+}
+
+fn parse_task(line: &str) -> Task {
+    let mut task = Task::default();
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let mut i = 0;
+    if words.first() == Some(&"x") {
+        task.done = true;
+        i += 1;
+        if words.get(i).is_some_and(|w| is_date(w)) {
+            task.completed = Some(words[i].into());
+            i += 1;
+        }
+    }
+    if let Some(p) = words.get(i).and_then(|w| priority(w)) {
+        task.priority = Some(p);
+        i += 1;
+    }
+    if words.get(i).is_some_and(|w| is_date(w)) {
+        task.created = Some(words[i].into());
+        i += 1;
+    }
+
+    let mut title = Vec::new();
+    for &word in &words[i..] {
+        if let Some(date) = word.strip_prefix("due:") {
+            if let (true, Some(due)) = (is_date(date), parse_time(date)) {
+                task.due = Some(due);
+                continue;
+            }
+        }
+        // Completed tasks keep their priority as a tag
+        if let Some(p) = word.strip_prefix("pri:") {
+            if let (None, Some(p)) = (task.priority,
+                                      priority(&format!("({})", p))) {
+                task.priority = Some(p);
+                continue;
+            }
+        }
+        if is_tag(word) && !task.tags.iter().any(|t| t == word) {
+            task.tags.push(word.into());
+        }
+        title.push(word);
+    }
+    // Tags at the end are only labels, unless there is nothing else
+    while title.len() > 1 && title.last().is_some_and(|w| is_tag(w)) {
+        title.pop();
+    }
+    task.title = title.join(" ");
+    task
+}
+
+fn format_task(task: &Task) -> String {
+    let mut words: Vec<String> = Vec::new();
+    if task.done {
+        words.push("x".into());
+        words.extend(task.completed.clone());
+    } else if let Some(p) = task.priority {
+        words.push(format!("({})", p));
+    }
+    words.extend(task.created.clone());
+    if !task.title.is_empty() {
+        words.push(task.title.clone());
+    }
+    let in_title: HashSet<&str> = task.title.split_whitespace().collect();
+    for tag in &task.tags {
+        if !in_title.contains(tag.as_str()) {
+            words.push(tag.clone());
+        }
+    }
+    if let Some(due) = task.due {
+        words.push(format!("due:{}", format_date(due)));
+    }
+    if let (true, Some(p)) = (task.done, task.priority) {
+        words.push(format!("pri:{}", p));
+    }
+    words.join(" ")
+}
+
+/// The task of a card, as it reads once written.
+fn card_task(places: &TaskLists, card: &Card) -> Task {
+    let task = Task {
+        done: places.is_done(card),
+        priority: card.labels.iter().find_map(|l| priority(l)),
+        completed: None,
+        created: None,
+        title: card.title.clone(),
+        tags: card.labels.iter().filter(|l| is_tag(l)).cloned().collect(),
+        due: card.due,
+    };
+    parse_task(&format_task(&task))
+}
+
+/// Change a card to match a task, moving it in or out of the "done" list.
+fn update(places: &mut TaskLists, card: &mut Card, task: &Task) {
+    if card_task(places, card).same(task) {
+        return;
+    }
+    card.title = task.title.clone();
+    if card.due.map(|d| d - d % DAY) != task.due {
+        // Only the day is written
+        card.due = task.due;
+    }
+    // Keep the labels that don't come from todo.txt
+    card.labels.retain(|l| priority(l).is_none() && !is_tag(l));
+    card.labels.extend(task.priority.map(|p| format!("({})", p)));
+    card.labels.extend(task.tags.iter().cloned());
+    if places.is_done(card) != task.done {
+        card.list = places.get(task.done);
+    }
+}
+
+fn new_card(places: &mut TaskLists, task: &Task) -> Card {
+    let mut card = Card::new(places.get(task.done), &task.title);
+    update(places, &mut card, task);
+    card
+}
+
+/// Write cards as todo.txt, one task per card. The cards in the list named
+/// `done_list` are completed.
+pub fn export<'a, I>(lists: &[List], cards: I, done_list: &str) -> String
+    where I: IntoIterator<Item=&'a Card>
+{
+    let places = TaskLists::new(lists, done_list);
+    let mut out = String::new();
+    for card in cards {
+        out.push_str(&format_task(&card_task(&places, card)));
+        out.push('\n');
+    }
+    out
+}
+
+/// Cards read from todo.txt, to add to a board.
+#[derive(Debug)]
+pub struct TodoTxtImport {
+    /// Lists the cards are in that the board didn't have
+    pub lists: Vec<List>,
+    pub cards: Vec<Card>,
+    pub report: Report,
+}
+
+/// Read tasks as cards, to add to a board with the given lists.
+///
+/// Completed tasks go in the list named `done_list`, the others in the first
+/// other list. Either is created if the board doesn't have it.
+pub fn import(text: &str, lists: &[List], done_list: &str) -> TodoTxtImport {
+    let mut places = TaskLists::new(lists, done_list);
+    let mut report = Report::default();
+    let mut cards = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let task = parse_task(line);
+        if task.created.is_some() || task.completed.is_some() {
+            report.skipped.push(format!(
+                "line {}: dates of '{}', cards are created now",
+                number + 1, task.title,
+            ));
+        }
+        cards.push(new_card(&mut places, &task));
+    }
+    TodoTxtImport { lists: places.new, cards, report }
+}
+
+/// A task written by the last sync.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SyncedTask {
+    pub card: Uuid,
+    pub line: String,
+}
+
+/// What the last sync wrote, kept between syncs to know what changed on
+/// each side.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SyncState {
+    pub board: Uuid,
+    pub tasks: Vec<SyncedTask>,
+}
+
+impl SyncState {
+    /// The state before the first sync.
+    pub fn new(board: Uuid) -> SyncState {
+        SyncState { board, tasks: Vec::new() }
+    }
+}
+
+/// The changes to make on both sides.
+#[derive(Debug)]
+pub struct Sync {
+    /// Lists to add to the board
+    pub lists: Vec<List>,
+    /// Cards to add to the board, or to replace
+    pub cards: Vec<Card>,
+    /// Cards to remove from the board
+    pub removed: Vec<Uuid>,
+    /// The new content of the file
+    pub text: String,
+    /// The state to give the next sync
+    pub state: SyncState,
+    pub report: Report,
+}
+
+/// Reconcile a todo.txt file with the cards of a board.
+///
+/// Changes on either side since the last sync are brought to the other one.
+/// A task changed on both sides keeps the file's version, and a task removed
+/// on one side but changed on the other is kept. Lines are matched with the
+/// ones written by the last sync, or with the same title, so tasks that are
+/// retitled in the file are replaced by new cards.
+///
+/// The first sync doesn't know what was removed, it adds everything to both
+/// sides, but cards and tasks with the same title are matched.
+pub fn sync(text: &str, lists: &[List], cards: &[Card], state: &SyncState,
+            done_list: &str)
+    -> Sync
+{
+    let mut places = TaskLists::new(lists, done_list);
+    let mut report = Report::default();
+    let base = &state.tasks;
+    let base_tasks: Vec<Task> = base.iter()
+        .map(|s| parse_task(&s.line))
+        .collect();
+    let by_id: HashMap<Uuid, &Card> = cards.iter()
+        .map(|c| (c.id, c))
+        .collect();
+    let today = format_date(activity::now());
+
+    let lines: Vec<(usize, &str)> = text.lines().enumerate()
+        .map(|(n, l)| (n + 1, l.trim()))
+        .filter(|&(_, l)| !l.is_empty())
+        .collect();
+    let tasks: Vec<Task> = lines.iter().map(|&(_, l)| parse_task(l)).collect();
+
+    // Match the lines with the last sync: same line, then same title
+    let mut matched: Vec<Option<usize>> = vec![None; lines.len()];
+    let mut used = vec![false; base.len()];
+    for (i, &(_, line)) in lines.iter().enumerate() {
+        if let Some(j) = (0..base.len())
+            .find(|&j| !used[j] && base[j].line == line)
+        {
+            matched[i] = Some(j);
+            used[j] = true;
+        }
+    }
+    for (i, task) in tasks.iter().enumerate() {
+        if matched[i].is_some() {
+            continue;
+        }
+        if let Some(j) = (0..base.len())
+            .find(|&j| !used[j] && base_tasks[j].title == task.title)
+        {
+            matched[i] = Some(j);
+            used[j] = true;
+        }
+    }
+    // Cards that are new on the board, which new lines can match
+    let synced: HashSet<Uuid> = base.iter().map(|s| s.card).collect();
+    let mut new_cards: Vec<&Card> = cards.iter()
+        .filter(|c| !synced.contains(&c.id))
+        .collect();
+
+    let mut changed = Vec::new();
+    let mut removed = Vec::new();
+    let mut out: Vec<SyncedTask> = Vec::new();
+
+    for (i, task) in tasks.iter().enumerate() {
+        let (number, line) = lines[i];
+        let previous = matched[i].map(|j| (&base[j], &base_tasks[j]));
+        let card = match previous {
+            Some((synced, _)) => by_id.get(&synced.card).cloned(),
+            None => {
+                let found = new_cards.iter()
+                    .position(|c| c.title == task.title);
+                found.map(|k| new_cards.remove(k))
+            }
+        };
+        let card = match (card, previous) {
+            (Some(card), Some((_, before))) => {
+                let file_changed = !task.same(before);
+                let card_changed = !card_task(&places, card).same(before);
+                if file_changed && card_changed {
+                    report.lossy.push(format!(
+                        "line {}: '{}' changed on both sides, kept the \
+                         file's version",
+                        number, task.title,
+                    ));
+                }
+                if !file_changed && card_changed {
+                    // Write the card, keeping the dates
+                    let mut new = card_task(&places, card);
+                    new.created = task.created.clone();
+                    if new.done {
+                        new.completed = match task.completed {
+                            Some(ref date) if task.done => Some(date.clone()),
+                            _ => Some(today.clone()),
+                        };
+                    }
+                    out.push(SyncedTask {
+                        card: card.id,
+                        line: format_task(&new),
+                    });
+                    continue;
+                }
+                card.clone()
+            }
+            (Some(card), None) => card.clone(),
+            (None, Some((_, before))) if task.same(before) => {
+                // Removed from the board
+                continue;
+            }
+            (None, Some(_)) => {
+                report.lossy.push(format!(
+                    "line {}: '{}' changed, but its card was removed, \
+                     added it again",
+                    number, task.title,
+                ));
+                new_card(&mut places, task)
+            }
+            (None, None) => new_card(&mut places, task),
+        };
+        let mut updated = card.clone();
+        update(&mut places, &mut updated, task);
+        if !by_id.contains_key(&card.id) || updated != card {
+            changed.push(updated);
+        }
+        out.push(SyncedTask { card: card.id, line: line.into() });
+    }
+
+    // Lines removed from the file
+    for (j, synced) in base.iter().enumerate() {
+        if used[j] {
+            continue;
+        }
+        if let Some(card) = by_id.get(&synced.card) {
+            let task = card_task(&places, card);
+            if task.same(&base_tasks[j]) {
+                removed.push(card.id);
+            } else {
+                report.lossy.push(format!(
+                    "task '{}' was removed from the file, but its card \
+                     changed, added it again",
+                    card.title,
+                ));
+                out.push(SyncedTask {
+                    card: card.id,
+                    line: format_task(&task),
+                });
+            }
+        }
+    }
+
+    // Cards new on the board
+    for card in new_cards {
+        out.push(SyncedTask {
+            card: card.id,
+            line: format_task(&card_task(&places, card)),
+        });
+    }
+
+    let mut text = String::new();
+    for synced in &out {
+        text.push_str(&synced.line);
+        text.push('\n');
+    }
+    Sync {
+        lists: places.new,
+        cards: changed,
+        removed,
+        text,
+        state: SyncState { board: state.board, tasks: out },
+        report,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use crate::{Card, List};
+    use super::{SyncState, export, format_date, import, parse_task, sync};
+
+    #[test]
+    fn test_parse() {
+        let task = parse_task("x 2019-03-02 2019-02-20 Ask @mom about \
+                               +party food due:2019-03-01 pri:B +home \
+                               @phone");
+        assert!(task.done);
+        assert_eq!(task.priority, Some('B'));
+        assert_eq!(task.completed.as_deref(), Some("2019-03-02"));
+        assert_eq!(task.created.as_deref(), Some("2019-02-20"));
+        assert_eq!(task.title, "Ask @mom about +party food");
+        assert_eq!(task.tags, vec!["@mom", "+party", "+home", "@phone"]);
+        assert_eq!(task.due, Some(1551398400));
+
+        let task = parse_task("(A) 2019-02-20 Pay rent rec:1m due:soon");
+        assert!(!task.done);
+        assert_eq!(task.priority, Some('A'));
+        assert_eq!(task.created.as_deref(), Some("2019-02-20"));
+        assert_eq!(task.title, "Pay rent rec:1m due:soon");
+        assert_eq!(task.due, None);
+
+        assert_eq!(parse_task("+party").title, "+party");
+        assert_eq!(parse_task("xylophone (A)").title, "xylophone (A)");
+    }
+
+    #[test]
+    fn test_export_import() {
+        let todo = List { id: Uuid::new_v4(), name: "todo".into() };
+        let done = List { id: Uuid::new_v4(), name: "finished".into() };
+        let mut call = Card::new(todo.id, "Ask @mom about +party food");
+        call.labels = vec!["(A)".into(), "@mom".into(), "+party".into(),
+                           "+home".into(), "urgent".into()];
+        call.due = Some(1551441600);
+        call.description = "Not written".into();
+        let mut book = Card::new(done.id, "Book the room");
+        book.labels = vec!["(C)".into()];
+        let lists = vec![todo, done];
+
+        let text = export(&lists, &[call, book], "finished");
+        assert_eq!(text, "(A) Ask @mom about +party food +home \
+                          due:2019-03-01\n\
+                          x Book the room pri:C\n");
+
+        // Into a board without lists
+        let result = import(&format!("\n{}2019-02-20 Buy cake\n", text),
+                            &[], "finished");
+        let names: Vec<&str> = result.lists.iter()
+            .map(|l| l.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["todo", "finished"]);
+        let cards: Vec<_> = result.cards.iter()
+            .map(|c| (c.title.as_str(), c.list, &c.labels, c.due))
+            .collect();
+        let (todo, done) = (result.lists[0].id, result.lists[1].id);
+        assert_eq!(cards, vec![
+            ("Ask @mom about +party food", todo,
+             &vec!["(A)".to_owned(), "@mom".into(), "+party".into(),
+                   "+home".into()],
+             Some(1551398400)),
+            ("Book the room", done, &vec!["(C)".to_owned()], None),
+            ("Buy cake", todo, &vec![], None),
+        ]);
+        assert_eq!(result.report.skipped, vec![
+            "line 4: dates of 'Buy cake', cards are created now",
+        ]);
+
+        // Into a board that has lists
+        let lists = vec![
+            List { id: Uuid::new_v4(), name: "finished".into() },
+            List { id: Uuid::new_v4(), name: "doing".into() },
+        ];
+        let result = import(&text, &lists, "finished");
+        assert!(result.lists.is_empty());
+        assert_eq!(result.cards[0].list, lists[1].id);
+        assert_eq!(result.cards[1].list, lists[0].id);
+    }
+
+    /// Sync, check the new file, and apply the changes to the cards.
+    fn step(text: &str, lists: &mut Vec<List>, cards: &mut Vec<Card>,
+            state: &mut SyncState, expected: &str)
+        -> Vec<String>
+    {
+        let sync = sync(text, lists, cards, state, "done");
+        assert_eq!(sync.text, expected);
+        lists.extend(sync.lists);
+        let removed = sync.removed;
+        cards.retain(|c| !removed.contains(&c.id));
+        for card in sync.cards {
+            match cards.iter_mut().find(|c| c.id == card.id) {
+                Some(c) => *c = card,
+                None => cards.push(card),
+            }
+        }
+        *state = sync.state;
+        sync.report.lossy
+    }
+
+    #[test]
+    fn test_sync() {
+        let mut lists = vec![List { id: Uuid::new_v4(), name: "todo".into() }];
+        let todo = lists[0].id;
+        let mut cards = vec![Card::new(todo, "Write report"),
+                             Card::new(todo, "Buy cake")];
+        let mut state = SyncState::new(Uuid::new_v4());
+
+        // First sync: both sides are added to, same titles are matched
+        let report = step("(B) 2019-02-20 Buy cake\nCall mom @phone\n",
+                          &mut lists, &mut cards, &mut state,
+                          "(B) 2019-02-20 Buy cake\n\
+                           Call mom @phone\n\
+                           Write report\n");
+        assert!(report.is_empty());
+        assert_eq!(cards.len(), 3);
+        assert_eq!(cards[1].labels, vec!["(B)"]);
+        assert_eq!(cards[2].labels, vec!["@phone"]);
+        assert_eq!(lists.len(), 1);
+
+        // Nothing changed
+        let text = state.tasks.iter()
+            .map(|s| format!("{}\n", s.line))
+            .collect::<String>();
+        let before = cards.clone();
+        step(&text, &mut lists, &mut cards, &mut state, &text);
+        assert_eq!(cards, before);
+
+        // Completed in the file, removed from the file, changed on the board
+        cards[0].description = "Not in the file".into();
+        cards[0].due = Some(1551441600);
+        let report = step("x 2019-02-21 (B) 2019-02-20 Buy cake\n\
+              Call mom @phone\n",
+             &mut lists, &mut cards, &mut state,
+             "x 2019-02-21 (B) 2019-02-20 Buy cake\n\
+              Call mom @phone\n\
+              Write report due:2019-03-01\n");
+        assert_eq!(report, vec![
+            "task 'Write report' was removed from the file, but its card \
+             changed, added it again",
+        ]);
+        assert_eq!(lists[1].name, "done");
+        assert_eq!(cards.len(), 3);
+        assert_eq!(cards[1].list, lists[1].id);
+        assert_eq!(cards[0].description, "Not in the file");
+
+        // Removed from the board, moved out of done, changed on both sides
+        let call = cards.remove(2);
+        cards[0].list = lists[1].id;
+        cards[1].list = todo;
+        cards[1].labels.clear();
+        let today = format_date(crate::activity::now());
+        let report = step("x 2019-02-21 (B) 2019-02-20 Buy cake +party\n\
+                           Call mom @phone\n\
+                           Write report due:2019-03-01\n",
+                          &mut lists, &mut cards, &mut state,
+                          &format!("x 2019-02-21 (B) 2019-02-20 Buy cake \
+                                    +party\n\
+                                    x {} Write report due:2019-03-01\n",
+                                   today));
+        assert_eq!(report, vec![
+            "line 1: 'Buy cake' changed on both sides, kept the file's \
+             version",
+        ]);
+        assert!(cards.iter().all(|c| c.id != call.id));
+        assert_eq!(cards[1].list, lists[1].id);
+        assert_eq!(cards[1].labels, vec!["(B)", "+party"]);
+
+        // Removed from the file
+        let report = step("Write report due:2019-03-01\n",
+                          &mut lists, &mut cards, &mut state,
+                          "Write report due:2019-03-01\n");
+        assert!(report.is_empty());
+        assert_eq!(cards.len(), 1);
+        assert_eq!(cards[0].title, "Write report");
+        assert_eq!(cards[0].list, todo);
+    }
+}
@@ -0,0 +1,438 @@
+//! Importing boards exported from Trello.
+//!
+//! Trello exports a board as JSON from its menu ("Print and export", then
+//! "Export as JSON"). Open lists and cards are imported in order, with the
+//! cards' descriptions, labels, due dates, members and comments:
+//!
+//! * Archived lists and cards are skipped
+//! * Labels are kept by name, or by color if they have no name; the colors
+//!   themselves are lost
+//! * Members are kept by username
+//! * Checklists are added to the end of the description, as Markdown task
+//!   lists
+//! * Start dates, completion of due dates and attachments are lost
+//!
+//! Trello only exports the last actions on a board, so the older comments
+//! can be missing from the file.
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::{Board, Card, Comment, List};
+use super::{BoardExport, ImportError, Report, parse_time};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TrelloBoard {
+    name: String,
+    #[serde(default)]
+    desc: String,
+    #[serde(default)]
+    lists: Vec<TrelloList>,
+    #[serde(default)]
+    cards: Vec<TrelloCard>,
+    #[serde(default)]
+    members: Vec<TrelloMember>,
+    #[serde(default)]
+    checklists: Vec<TrelloChecklist>,
+    #[serde(default)]
+    actions: Vec<TrelloAction>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TrelloList {
+    id: String,
+    name: String,
+    #[serde(default)]
+    closed: bool,
+    #[serde(default)]
+    pos: f64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TrelloCard {
+    id: String,
+    name: String,
+    #[serde(default)]
+    desc: String,
+    #[serde(default)]
+    closed: bool,
+    id_list: String,
+    #[serde(default)]
+    pos: f64,
+    #[serde(default)]
+    labels: Vec<TrelloLabel>,
+    due: Option<String>,
+    #[serde(default)]
+    due_complete: bool,
+    start: Option<String>,
+    #[serde(default)]
+    id_members: Vec<String>,
+    #[serde(default)]
+    attachments: Vec<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct TrelloLabel {
+    #[serde(default)]
+    name: String,
+    color: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TrelloMember {
+    #[serde(default)]
+    id: String,
+    username: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TrelloChecklist {
+    id_card: String,
+    name: String,
+    #[serde(default)]
+    pos: f64,
+    #[serde(default)]
+    check_items: Vec<TrelloCheckItem>,
+}
+
+#[derive(Deserialize)]
+struct TrelloCheckItem {
+    name: String,
+    #[serde(default)]
+    state: String,
+    #[serde(default)]
+    pos: f64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TrelloAction {
+    #[serde(rename = "type")]
+    type_: String,
+    date: Option<String>,
+    #[serde(default)]
+    data: TrelloActionData,
+    member_creator: Option<TrelloMember>,
+}
+
+#[derive(Default, Deserialize)]
+struct TrelloActionData {
+    text: Option<String>,
+    card: Option<TrelloActionCard>,
+}
+
+#[derive(Deserialize)]
+struct TrelloActionCard {
+    id: String,
+}
+
+fn by_pos<T, F: Fn(&T) -> f64>(items: &mut [T], pos: F) {
+    items.sort_by(|a, b| {
+        pos(a).partial_cmp(&pos(b)).unwrap_or(std::cmp::Ordering::Equal)
+    });
+}
+
+/// Read a Trello export, giving everything new IDs.
+pub fn import(json: &str) -> Result<(BoardExport, Report), ImportError> {
+    let mut trello: TrelloBoard = serde_json::from_str(json)?;
+    let mut report = Report::default();
+
+    if !trello.desc.is_empty() {
+        report.skipped.push("the description of the board".into());
+    }
+
+    let board = Board { id: Uuid::new_v4(), name: trello.name };
+
+    by_pos(&mut trello.lists, |l| l.pos);
+    let mut lists = Vec::new();
+    let mut list_ids = HashMap::new();
+    for list in trello.lists {
+        if list.closed {
+            report.skipped.push(format!("archived list '{}'", list.name));
+            continue;
+        }
+        let id = Uuid::new_v4();
+        list_ids.insert(list.id, id);
+        lists.push(List { id, name: list.name });
+    }
+
+    let members: HashMap<String, String> = trello.members.into_iter()
+        .map(|m| (m.id, m.username))
+        .collect();
+
+    by_pos(&mut trello.checklists, |c| c.pos);
+    let mut checklists: HashMap<String, Vec<TrelloChecklist>> =
+        HashMap::new();
+    for checklist in trello.checklists {
+        checklists.entry(checklist.id_card.clone())
+            .or_default()
+            .push(checklist);
+    }
+
+    // Actions are newest first
+    let mut comments: HashMap<String, Vec<Comment>> = HashMap::new();
+    for action in trello.actions.into_iter().rev() {
+        if action.type_ != "commentCard" {
+            continue;
+        }
+        let (card, text) = match (action.data.card, action.data.text) {
+            (Some(card), Some(text)) => (card, text),
+            _ => continue,
+        };
+        comments.entry(card.id).or_default().push(Comment {
+            author: action.member_creator.map(|m| m.username),
+            text,
+            time: action.date.as_ref().and_then(|d| parse_time(d)),
+        });
+    }
+
+    // Cards are ordered by list, then by position in their list
+    let list_order: HashMap<Uuid, usize> = lists.iter().enumerate()
+        .map(|(i, l)| (l.id, i))
+        .collect();
+    by_pos(&mut trello.cards, |c| c.pos);
+    let mut cards = Vec::new();
+    for trello_card in trello.cards {
+        let what = format!("card '{}'", trello_card.name);
+        if trello_card.closed {
+            report.skipped.push(format!("archived {}", what));
+            continue;
+        }
+        let list = match list_ids.get(&trello_card.id_list) {
+            Some(&id) => id,
+            None => {
+                report.skipped.push(format!("{}, in an archived list", what));
+                continue;
+            }
+        };
+        let mut card = Card::new(list, &trello_card.name);
+        card.description = trello_card.desc;
+
+        let mut colors = false;
+        for label in trello_card.labels {
+            if !label.name.is_empty() {
+                colors |= label.color.is_some();
+                card.labels.push(label.name);
+            } else if let Some(color) = label.color {
+                report.lossy.push(format!(
+                    "{}: label without a name, named '{}' after its color",
+                    what, color,
+                ));
+                card.labels.push(color);
+            }
+        }
+        if colors {
+            report.lossy.push(format!("{}: colors of labels", what));
+        }
+
+        if let Some(due) = trello_card.due {
+            card.due = parse_time(&due);
+            if card.due.is_none() {
+                report.skipped.push(format!(
+                    "{}: invalid due date '{}'", what, due,
+                ));
+            } else if trello_card.due_complete {
+                report.skipped.push(format!(
+                    "{}: completion of the due date", what,
+                ));
+            }
+        }
+        if trello_card.start.is_some() {
+            report.skipped.push(format!("{}: start date", what));
+        }
+
+        for member in trello_card.id_members {
+            match members.get(&member) {
+                Some(username) => card.assignees.push(username.clone()),
+                None => report.skipped.push(format!(
+                    "{}: unknown member {}", what, member,
+                )),
+            }
+        }
+
+        if let Some(checklists) = checklists.remove(&trello_card.id) {
+            for mut checklist in checklists {
+                by_pos(&mut checklist.check_items, |i| i.pos);
+                if !card.description.is_empty() {
+                    card.description.push_str("\n\n");
+                }
+                card.description.push_str(&format!(
+                    "## {}\n", checklist.name,
+                ));
+                for item in checklist.check_items {
+                    let done = item.state == "complete";
+                    let mark = if done { 'x' } else { ' ' };
+                    card.description.push_str(&format!(
+                        "\n- [{}] {}", mark, item.name,
+                    ));
+                }
+            }
+            report.lossy.push(format!(
+                "{}: checklists, added to the description", what,
+            ));
+        }
+
+        if !trello_card.attachments.is_empty() {
+            report.skipped.push(format!(
+                "{}: {} attachment(s)", what, trello_card.attachments.len(),
+            ));
+        }
+
+        card.comments = comments.remove(&trello_card.id).unwrap_or_default();
+        cards.push(card);
+    }
+    // Sorting is stable, so cards stay in order in each list
+    cards.sort_by_key(|c| list_order[&c.list]);
+
+    Ok((BoardExport { board, lists, cards }, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Comment;
+    use super::import;
+
+    const EXPORT: &str = r#"{
+        "id": "5c7a00000000000000000001",
+        "name": "Work",
+        "desc": "",
+        "closed": false,
+        "labels": [
+            {"id": "l1", "name": "urgent", "color": "red"},
+            {"id": "l2", "name": "", "color": "green"}
+        ],
+        "lists": [
+            {"id": "list2", "name": "done", "closed": false, "pos": 32768},
+            {"id": "list1", "name": "todo", "closed": false, "pos": 16384},
+            {"id": "list3", "name": "old", "closed": true, "pos": 65536}
+        ],
+        "cards": [
+            {
+                "id": "card2", "name": "Send report", "desc": "",
+                "closed": false, "idList": "list1", "pos": 2,
+                "labels": [], "due": null, "dueComplete": false,
+                "idMembers": [], "attachments": []
+            },
+            {
+                "id": "card1", "name": "Write report", "desc": "Quarterly",
+                "closed": false, "idList": "list1", "pos": 1,
+                "labels": [
+                    {"id": "l1", "name": "urgent", "color": "red"},
+                    {"id": "l2", "name": "", "color": "green"}
+                ],
+                "due": "2019-03-01T12:00:00.000Z", "dueComplete": false,
+                "idMembers": ["m1", "m2"],
+                "attachments": [{"id": "a1", "name": "draft.pdf"}]
+            },
+            {
+                "id": "card3", "name": "Archive", "desc": "",
+                "closed": true, "idList": "list2", "pos": 1
+            },
+            {
+                "id": "card4", "name": "Clean up", "desc": "",
+                "closed": false, "idList": "list3", "pos": 1
+            },
+            {
+                "id": "card5", "name": "Plan", "desc": "",
+                "closed": false, "idList": "list2", "pos": 1,
+                "due": "2019-02-01T12:00:00.000Z", "dueComplete": true,
+                "start": "2019-01-01T12:00:00.000Z"
+            }
+        ],
+        "members": [
+            {"id": "m1", "username": "remram", "fullName": "Remi Rampin"}
+        ],
+        "checklists": [
+            {
+                "id": "c1", "idCard": "card1", "name": "Steps", "pos": 1,
+                "checkItems": [
+                    {"id": "i2", "name": "Proofread", "state": "incomplete",
+                     "pos": 2},
+                    {"id": "i1", "name": "Draft", "state": "complete",
+                     "pos": 1}
+                ]
+            }
+        ],
+        "actions": [
+            {
+                "type": "commentCard", "date": "2019-02-02T10:00:00.000Z",
+                "data": {"text": "Nearly there", "card": {"id": "card1"}},
+                "memberCreator": {"id": "m1", "username": "remram"}
+            },
+            {
+                "type": "updateCard", "date": "2019-02-01T11:00:00.000Z",
+                "data": {"card": {"id": "card1"}}
+            },
+            {
+                "type": "commentCard", "date": "2019-02-01T10:00:00.000Z",
+                "data": {"text": "Started", "card": {"id": "card1"}},
+                "memberCreator": {"id": "m1", "username": "remram"}
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn test_import() {
+        let (export, report) = import(EXPORT).unwrap();
+        assert_eq!(export.board.name, "Work");
+        let lists: Vec<&str> = export.lists.iter()
+            .map(|l| l.name.as_str())
+            .collect();
+        assert_eq!(lists, vec!["todo", "done"]);
+        let titles: Vec<&str> = export.cards.iter()
+            .map(|c| c.title.as_str())
+            .collect();
+        assert_eq!(titles, vec!["Write report", "Send report", "Plan"]);
+
+        let card = &export.cards[0];
+        assert_eq!(card.list, export.lists[0].id);
+        assert_eq!(
+            card.description,
+            "Quarterly\n\n## Steps\n\n- [x] Draft\n- [ ] Proofread",
+        );
+        assert_eq!(card.labels, vec!["urgent", "green"]);
+        assert_eq!(card.due, Some(1551441600));
+        assert_eq!(card.assignees, vec!["remram"]);
+        assert_eq!(card.comments, vec![
+            Comment {
+                author: Some("remram".into()),
+                text: "Started".into(),
+                time: Some(1549015200),
+            },
+            Comment {
+                author: Some("remram".into()),
+                text: "Nearly there".into(),
+                time: Some(1549101600),
+            },
+        ]);
+        assert_eq!(export.cards[2].list, export.lists[1].id);
+
+        assert_eq!(report.skipped, vec![
+            "archived list 'old'",
+            "card 'Write report': unknown member m2",
+            "card 'Write report': 1 attachment(s)",
+            "archived card 'Archive'",
+            "card 'Clean up', in an archived list",
+            "card 'Plan': completion of the due date",
+            "card 'Plan': start date",
+        ]);
+        assert_eq!(report.lossy, vec![
+            "card 'Write report': label without a name, named 'green' after \
+             its color",
+            "card 'Write report': colors of labels",
+            "card 'Write report': checklists, added to the description",
+        ]);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(import("{\"lists\": []}").is_err());
+        assert!(import("[]").is_err());
+    }
+}
@@ -1,170 +1,1479 @@
+extern crate argon2;
+extern crate base64ct;
+extern crate blake2;
+extern crate chacha20poly1305;
 extern crate futures;
+extern crate humantime;
+#[cfg(target_arch = "wasm32")]
+extern crate js_sys;
+extern crate rand_core;
 extern crate serde;
+extern crate serde_json;
+#[cfg(feature = "server")]
+extern crate sha2;
 extern crate uuid;
 
+pub mod activity;
+#[cfg(any(test, feature = "conformance"))]
+pub mod conformance;
+pub mod crypto;
+pub mod events;
 pub mod filter;
+pub mod interchange;
+pub mod memory;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod snapshot;
+pub mod sync;
+pub mod threading;
 
-use futures::{Future, future};
 use serde::{Serialize, Deserialize};
-use std::cell::RefCell;
 use std::collections::BTreeMap;
-use std::rc::{Rc, Weak};
+use std::future::Future;
+use std::ops::AsyncFnOnce;
 use uuid::Uuid;
 
-#[derive(Debug, Serialize, Deserialize)]
+use activity::{Activity, Actor};
+use events::{Event, Subscribers, SubscriptionId};
+use interchange::BoardExport;
+use threading::{Local, Shared, Threading};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Card {
     pub id: Uuid,
     pub title: String,
     pub list: Uuid,
+    // The other fields are left out of the JSON when empty, and can be
+    // missing from operations made before they were added
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub description: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub labels: Vec<String>,
+    /// When it is due, in seconds since the epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub due: Option<u64>,
+    /// Names of the users it is assigned to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub assignees: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub comments: Vec<Comment>,
+    /// What the card is in the application it was imported from, such as
+    /// "taskwarrior:<uuid>", so importing again updates it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external: Option<String>,
+}
+
+impl Card {
+    /// A new card with only a title.
+    pub fn new(list: Uuid, title: &str) -> Card {
+        Card {
+            id: Uuid::new_v4(),
+            title: title.into(),
+            list,
+            description: String::new(),
+            labels: Vec::new(),
+            due: None,
+            assignees: Vec::new(),
+            comments: Vec::new(),
+            external: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Comment {
+    /// Who wrote it, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    pub text: String,
+    /// When it was written, in seconds since the epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<u64>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct List {
     pub id: Uuid,
     pub name: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Board {
     pub id: Uuid,
     pub name: String,
 }
 
-pub trait Storage {
-    type Error: 'static;
+/// Reading and changing what is kept, either directly on a `Storage` or in
+/// one of its transactions.
+///
+/// The futures don't have to be `Send`: in the browser everything runs on
+/// a single thread. Backends that can be shared between threads make `Send`
+/// futures, so a `SharedApp`'s can be too.
+#[allow(async_fn_in_trait)]
+pub trait Store {
+    type Error: std::error::Error + 'static;
+
+    async fn add_board(&self, board: &Board) -> Result<(), Self::Error>;
+    async fn get_board(&self, id: &Uuid) -> Result<Option<Board>, Self::Error>;
+    /// Get the lists on a board, in the order they were added.
+    async fn get_lists(&self, board_id: &Uuid)
+        -> Result<Vec<List>, Self::Error>;
+    async fn add_list(&self, board_id: &Uuid, list: &List)
+        -> Result<(), Self::Error>;
+    /// Delete a board, with its lists and cards.
+    async fn delete_board(&self, id: &Uuid) -> Result<(), Self::Error>;
+    async fn delete_list(&self, board_id: &Uuid, list_id: &Uuid)
+        -> Result<(), Self::Error>;
+    /// Get the cards on a board.
+    async fn get_cards(&self, board_id: &Uuid)
+        -> Result<Vec<Card>, Self::Error>;
+    /// Add a card, or replace the card with the same ID.
+    async fn add_card(&self, board_id: &Uuid, card: &Card)
+        -> Result<(), Self::Error>;
+    async fn delete_card(&self, board_id: &Uuid, card_id: &Uuid)
+        -> Result<(), Self::Error>;
+
+    /// Record an operation in the log.
+    ///
+    /// `pending` indicates an operation made locally, that still has to be
+    /// sent to the server. Returns false if the operation was already known.
+    async fn add_operation(&self, op: &Operation, pending: bool)
+        -> Result<bool, Self::Error>;
+    /// Get the operations on a board that haven't been acknowledged by the
+    /// server yet, in the order they were made.
+    async fn get_pending_operations(&self, board_id: &Uuid)
+        -> Result<Vec<Operation>, Self::Error>;
+    async fn acknowledge_operations(&self, ids: &[Uuid])
+        -> Result<(), Self::Error>;
+    /// Remove an operation from the log if it is still pending, so it never
+    /// gets sent. Returns false if it was already acknowledged.
+    async fn cancel_operation(&self, id: &Uuid) -> Result<bool, Self::Error>;
+    /// Get the position in the server's log up to which operations on a
+    /// board have been received.
+    async fn get_sync_cursor(&self, board_id: &Uuid)
+        -> Result<u64, Self::Error>;
+    async fn set_sync_cursor(&self, board_id: &Uuid, cursor: u64)
+        -> Result<(), Self::Error>;
+    /// Forget the operations on a board that the server acknowledged; it
+    /// keeps them, or a snapshot of their result.
+    async fn prune_operations(&self, board_id: &Uuid)
+        -> Result<(), Self::Error>;
+    /// Get the ID identifying this replica to the server, creating it the
+    /// first time.
+    async fn get_replica_id(&self) -> Result<Uuid, Self::Error>;
+
+    /// Record an entry in the activity feed.
+    async fn add_activity(&self, activity: &Activity)
+        -> Result<(), Self::Error>;
+    /// Get the activity about an object, oldest first. For a board, this
+    /// includes the activity about everything on it.
+    async fn get_activity(&self, id: &Uuid)
+        -> Result<Vec<Activity>, Self::Error>;
+}
+
+/// Where boards and the operation log are kept.
+#[allow(async_fn_in_trait)]
+pub trait Storage: Store {
+    type Transaction<'a>: Transaction<Error=Self::Error> where Self: 'a;
+
+    /// Start a transaction: the changes made through it are applied
+    /// together when it is committed, or not at all if it is rolled back or
+    /// dropped.
+    ///
+    /// Only the changes made through the transaction are part of it. Calls
+    /// made on the storage directly wait until it is over, so they must not
+    /// be awaited while holding it. See `transaction()`.
+    async fn begin(&self) -> Result<Self::Transaction<'_>, Self::Error>;
+}
+
+/// A transaction, started by `Storage::begin()`.
+#[allow(async_fn_in_trait)]
+pub trait Transaction: Store {
+    async fn commit(self) -> Result<(), Self::Error>;
+    async fn rollback(self) -> Result<(), Self::Error>;
+}
+
+/// Make changes to storage in a transaction.
+///
+/// `changes` makes them through the transaction it is given. The
+/// transaction is committed if it succeeds, and rolled back if it fails.
+pub async fn transaction<'a, S, F, T>(storage: &'a S, changes: F)
+    -> Result<T, S::Error>
+    where S: Storage, F: AsyncFnOnce(&S::Transaction<'a>)
+                           -> Result<T, S::Error>
+{
+    let tx = storage.begin().await?;
+    let res = changes(&tx).await;
+    finish(tx, res).await
+}
+
+/// Commit a transaction if the changes made in it succeeded, roll it back if
+/// they failed.
+async fn finish<X: Transaction, T>(tx: X, res: Result<T, X::Error>)
+    -> Result<T, X::Error>
+{
+    match res {
+        Ok(v) => {
+            tx.commit().await?;
+            Ok(v)
+        }
+        // Report the error that caused the rollback, rather than one from the
+        // rollback itself
+        Err(e) => {
+            tx.rollback().await.ok();
+            Err(e)
+        }
+    }
+}
+
+/// A change made to a board.
+///
+/// Operations are what replicas exchange when syncing; each has a unique ID so
+/// that it is only ever applied once.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Operation {
+    pub id: Uuid,
+    pub board_id: Uuid,
+    pub change: Change,
+    /// Who made the change, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub actor: Option<Actor>,
+    /// When the change was made, in seconds since the epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<u64>,
+    /// The action this is part of, so that the operations of an action can
+    /// be undone together in a later session. Only kept in the local log, it
+    /// isn't sent to the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<Uuid>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Change {
+    AddBoard { name: String },
+    AddList { list: List },
+    RemoveBoard { name: String },
+    RemoveList { list: List },
+    /// Add a card, or replace the card with the same ID, in which case
+    /// `previous` is the card it replaced, so the change can be undone
+    AddCard {
+        card: Card,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        previous: Option<Card>,
+    },
+    RemoveCard { card: Card },
+    /// A change encrypted with a board key, see `crypto`
+    Encrypted { key_id: String, data: String },
+}
+
+impl Operation {
+    pub fn new(board_id: Uuid, change: Change) -> Operation {
+        Operation {
+            id: Uuid::new_v4(),
+            board_id,
+            change,
+            actor: None,
+            time: Some(activity::now()),
+            group: None,
+        }
+    }
+
+    /// Position of the operation in the order replicas syncing without a
+    /// server apply operations in: by time, then actor, then ID.
+    pub fn order_key(&self) -> (Option<u64>, Option<&Actor>, Uuid) {
+        (self.time, self.actor.as_ref(), self.id)
+    }
+
+    /// The change undoing this one, if it can be undone.
+    pub fn inverse(&self) -> Option<Change> {
+        match self.change {
+            Change::AddBoard { ref name } => {
+                Some(Change::RemoveBoard { name: name.clone() })
+            }
+            Change::RemoveBoard { ref name } => {
+                Some(Change::AddBoard { name: name.clone() })
+            }
+            Change::AddList { ref list } => {
+                Some(Change::RemoveList { list: list.clone() })
+            }
+            Change::RemoveList { ref list } => {
+                Some(Change::AddList { list: list.clone() })
+            }
+            Change::AddCard { ref card, previous: None } => {
+                Some(Change::RemoveCard { card: card.clone() })
+            }
+            Change::AddCard { ref card, previous: Some(ref previous) } => {
+                Some(Change::AddCard {
+                    card: previous.clone(),
+                    previous: Some(card.clone()),
+                })
+            }
+            Change::RemoveCard { ref card } => {
+                Some(Change::AddCard { card: card.clone(), previous: None })
+            }
+            Change::Encrypted { .. } => None,
+        }
+    }
+
+    /// Apply the change to storage, and add it to the activity feed.
+    pub async fn apply<S: Store>(&self, storage: &S) -> Result<(), S::Error> {
+        self.apply_change(storage).await?;
+        // Only needed to name the lists a card moved between
+        let lists = match self.change {
+            Change::AddCard { ref card, previous: Some(ref previous) }
+                if card.list != previous.list =>
+            {
+                storage.get_lists(&self.board_id).await?
+            }
+            _ => Vec::new(),
+        };
+        for activity in Activity::from_operation(self, &lists) {
+            storage.add_activity(&activity).await?;
+        }
+        Ok(())
+    }
+
+    async fn apply_change<S: Store>(&self, storage: &S)
+        -> Result<(), S::Error>
+    {
+        match self.change {
+            Change::AddBoard { ref name } => {
+                storage.add_board(&Board {
+                    id: self.board_id,
+                    name: name.clone(),
+                }).await
+            }
+            Change::AddList { ref list } => {
+                storage.add_list(&self.board_id, list).await
+            }
+            Change::RemoveBoard { .. } => {
+                storage.delete_board(&self.board_id).await
+            }
+            Change::RemoveList { ref list } => {
+                storage.delete_list(&self.board_id, &list.id).await
+            }
+            Change::AddCard { ref card, .. } => {
+                storage.add_card(&self.board_id, card).await
+            }
+            Change::RemoveCard { ref card } => {
+                storage.delete_card(&self.board_id, &card.id).await
+            }
+            // Only relayed, by a server that doesn't have the key
+            Change::Encrypted { .. } => Ok(()),
+        }
+    }
+}
+
+/// A value shared between the `App`, its board handles and notifiers.
+type SharedCell<P, T> = <P as Threading>::Ptr<<P as Threading>::Cell<T>>;
+
+/// The cache of boards in use, by ID.
+type BoardCache<P> = SharedCell<P, BTreeMap<Uuid, WeakState<P>>>;
+type StatePtr<P> = <P as Threading>::Ptr<BoardState<P>>;
+type WeakState<P> = <P as Threading>::Weak<BoardState<P>>;
+type HandlePtr<S, P> = <P as Threading>::Ptr<BoardHandle<S, P>>;
+
+/// Reported the changes applied to storage, see `Notifier`.
+pub trait Observer {
+    /// Report an operation that was applied to storage.
+    fn applied(&self, op: &Operation);
+
+    /// Report that a board was restored from a snapshot.
+    fn restored(&self, board_id: &Uuid);
+}
+
+/// Updates the cached board handles and notifies subscribers when changes
+/// are applied to storage.
+///
+/// `App` uses it for the changes it makes; get one from `App::notifier()` to
+/// give to code that changes storage directly, such as `SyncClient`.
+pub struct Notifier<P: Threading = Local> {
+    boards: BoardCache<P>,
+    subscribers: P::Ptr<Subscribers<P>>,
+}
+
+impl<P: Threading> Clone for Notifier<P> {
+    fn clone(&self) -> Notifier<P> {
+        Notifier {
+            boards: self.boards.clone(),
+            subscribers: self.subscribers.clone(),
+        }
+    }
+}
+
+/// State of a cached board before an optimistic update, to roll it back.
+struct SavedBoard<P: Threading> {
+    state: StatePtr<P>,
+    board: Board,
+    lists: Vec<List>,
+    cards: Vec<Card>,
+}
+
+impl<P: Threading> Observer for Notifier<P> {
+    fn applied(&self, op: &Operation) {
+        self.update(op);
+    }
+
+    fn restored(&self, board_id: &Uuid) {
+        let event = Event::Restored { board_id: *board_id };
+        let board = P::borrow_mut(&self.boards).remove(board_id)
+            .and_then(|weak| P::upgrade(&weak));
+        if let Some(board) = board {
+            board.subscribers.notify(&event);
+        }
+        self.subscribers.notify(&event);
+    }
+}
+
+impl<P: Threading> Notifier<P> {
+    /// Update the cached board with an operation, returning its previous
+    /// state.
+    fn update(&self, op: &Operation) -> Option<SavedBoard<P>> {
+        let event = Event::Applied { operation: op.clone() };
+        let board = P::borrow(&self.boards).get(&op.board_id)
+            .and_then(|weak| P::upgrade(weak));
+        let saved = board.map(|state| {
+            let board: &BoardState<P> = &state;
+            let saved = SavedBoard {
+                board: P::borrow(&board.inner).clone(),
+                lists: P::borrow(&board.lists).clone(),
+                cards: P::borrow(&board.cards).clone(),
+                state: state.clone(),
+            };
+            board.update(op);
+            board.subscribers.notify(&event);
+            saved
+        });
+        self.subscribers.notify(&event);
+        saved
+    }
+
+    /// Put a cached board back the way it was before an operation that
+    /// couldn't be stored.
+    fn revert(&self, op: &Operation, saved: Option<SavedBoard<P>>) {
+        let event = Event::Reverted { operation: op.clone() };
+        if let Some(saved) = saved {
+            *P::borrow_mut(&saved.state.inner) = saved.board;
+            *P::borrow_mut(&saved.state.lists) = saved.lists;
+            *P::borrow_mut(&saved.state.cards) = saved.cards;
+            saved.state.subscribers.notify(&event);
+        }
+        self.subscribers.notify(&event);
+    }
+}
+
+/// Update the cached boards with operations right away, then store them
+/// with the given future, rolling the cached boards back if it fails.
+///
+/// The future should store the operations in a transaction, so that none of
+/// them stay in storage if it fails.
+fn optimistic<P, F, E>(notifier: &Notifier<P>, ops: &[Operation], fut: F)
+    -> impl Future<Output=Result<(), E>>
+    where P: Threading, F: Future<Output=Result<(), E>>
+{
+    let saved: Vec<_> = ops.iter().map(|op| notifier.update(op)).collect();
+    let ops = ops.to_vec();
+    let notifier = notifier.clone();
+    async move {
+        let res = fut.await;
+        if res.is_err() {
+            for (op, saved) in ops.iter().zip(saved).rev() {
+                notifier.revert(op, saved);
+            }
+        }
+        res
+    }
+}
+
+/// Apply changes made locally and record them, so they get sent to the
+/// server.
+fn apply_local<S, P>(storage: &P::Ptr<S>, notifier: &Notifier<P>,
+                     ops: Vec<Operation>)
+    -> impl Future<Output=Result<(), S::Error>>
+    where S: Storage, P: Threading
+{
+    let storage = storage.clone();
+    let ops_ = ops.clone();
+    // Not using `transaction()`, the compiler can't tell that the futures
+    // of async closures are `Send`
+    let fut = async move {
+        let tx = storage.begin().await?;
+        let res = store_local(&tx, &ops_).await;
+        finish(tx, res).await
+    };
+    optimistic(notifier, &ops, fut)
+}
 
-    fn add_board(&self, board: &Board)
-        -> Box<Future<Item=(), Error=Self::Error>>;
-    fn get_board(&self, id: &Uuid)
-        -> Box<Future<Item=Option<Board>, Error=Self::Error>>;
-    fn get_lists(&self, board_id: &Uuid)
-        -> Box<Future<Item=Vec<List>, Error=Self::Error>>;
-    fn add_list(&self, board_id: &Uuid, list: &List)
-        -> Box<Future<Item=(), Error=Self::Error>>;
+async fn store_local<S: Store>(storage: &S, ops: &[Operation])
+    -> Result<(), S::Error>
+{
+    for op in ops {
+        op.apply(storage).await?;
+        storage.add_operation(op, true).await?;
+    }
+    Ok(())
+}
+
+/// Undo and redo stacks of a session.
+///
+/// Each entry is the group of operations making up one action, including
+/// the side effects it triggered, so they are undone together.
+#[derive(Default)]
+struct History {
+    undo: Vec<Vec<Operation>>,
+    redo: Vec<Vec<Operation>>,
 }
 
-pub struct BoardHandle<S: Storage> {
-    storage: Rc<S>,
-    inner: Rc<RefCell<Board>>,
-    lists: Rc<RefCell<Vec<List>>>,
+/// Attribute operations made in this session to its actor, unless they
+/// already have one (such as the automation rule that made them).
+fn stamp<P: Threading>(ops: &mut [Operation],
+                       actor: &SharedCell<P, Option<Actor>>) {
+    let actor = P::borrow(actor);
+    for op in ops {
+        if op.actor.is_none() {
+            op.actor = actor.clone();
+        }
+    }
 }
 
-impl<S: Storage> BoardHandle<S> {
-    pub fn board<'a>(&'a self) -> std::cell::Ref<'a, Board> {
-        self.inner.borrow()
+/// Mark operations as making up one action, see `Operation::group`.
+fn mark_group(ops: &mut [Operation]) {
+    let group = Uuid::new_v4();
+    for op in ops {
+        op.group = Some(group);
     }
+}
 
-    pub fn lists<'a>(&'a self) -> std::cell::Ref<'a, Vec<List>> {
-        self.lists.borrow()
+/// Perform an action, recording it so it can be undone.
+fn perform<S, P>(storage: &P::Ptr<S>,
+                 history: &SharedCell<P, History>,
+                 actor: &SharedCell<P, Option<Actor>>,
+                 notifier: &Notifier<P>,
+                 mut ops: Vec<Operation>)
+    -> impl Future<Output=Result<(), S::Error>>
+    where S: Storage, P: Threading
+{
+    stamp::<P>(&mut ops, actor);
+    mark_group(&mut ops);
+    let history = history.clone();
+    let fut = apply_local(storage, notifier, ops.clone());
+    async move {
+        let res = fut.await;
+        if res.is_ok() {
+            let mut history = P::borrow_mut(&history);
+            history.undo.push(ops);
+            history.redo.clear();
+        }
+        res
+    }
+}
+
+/// Undo an action.
+///
+/// The operations that haven't been sent yet are simply dropped, for the
+/// others the inverse operation is recorded.
+fn revert<S, P>(storage: &P::Ptr<S>,
+                actor: &SharedCell<P, Option<Actor>>,
+                notifier: &Notifier<P>,
+                group: Vec<Operation>)
+    -> impl Future<Output=Result<(), S::Error>>
+    where S: Storage, P: Threading
+{
+    let (ops, mut inverses): (Vec<_>, Vec<_>) = group.into_iter().rev()
+        .filter_map(|op| {
+            let inverse = Operation::new(op.board_id, op.inverse()?);
+            Some((op, inverse))
+        })
+        .unzip();
+    stamp::<P>(&mut inverses, actor);
+    mark_group(&mut inverses);
+    let pairs: Vec<_> = ops.into_iter().zip(inverses.clone()).collect();
+    let storage = storage.clone();
+    let fut = async move {
+        let tx = storage.begin().await?;
+        let res = store_inverses(&tx, &pairs).await;
+        finish(tx, res).await
+    };
+    optimistic(notifier, &inverses, fut)
+}
+
+async fn store_inverses<S: Store>(storage: &S,
+                                  pairs: &[(Operation, Operation)])
+    -> Result<(), S::Error>
+{
+    for (op, inverse) in pairs {
+        let cancelled = storage.cancel_operation(&op.id).await?;
+        inverse.apply(storage).await?;
+        if !cancelled {
+            storage.add_operation(inverse, true).await?;
+        }
+    }
+    Ok(())
+}
+
+/// A cached board, shared by the handles on it.
+struct BoardState<P: Threading> {
+    inner: P::Cell<Board>,
+    lists: P::Cell<Vec<List>>,
+    cards: P::Cell<Vec<Card>>,
+    subscribers: Subscribers<P>,
+}
+
+impl<P: Threading> BoardState<P> {
+    /// Update the board after an operation was applied to storage.
+    fn update(&self, op: &Operation) {
+        match op.change {
+            Change::AddBoard { ref name } => {
+                P::borrow_mut(&self.inner).name = name.clone();
+            }
+            Change::AddList { ref list } => {
+                let mut lists = P::borrow_mut(&self.lists);
+                if !lists.iter().any(|l| l.id == list.id) {
+                    lists.push(list.clone());
+                }
+            }
+            Change::RemoveBoard { .. } => {
+                P::borrow_mut(&self.lists).clear();
+                P::borrow_mut(&self.cards).clear();
+            }
+            Change::RemoveList { ref list } => {
+                P::borrow_mut(&self.lists).retain(|l| l.id != list.id);
+            }
+            Change::AddCard { ref card, .. } => {
+                let mut cards = P::borrow_mut(&self.cards);
+                match cards.iter_mut().find(|c| c.id == card.id) {
+                    Some(c) => *c = card.clone(),
+                    None => cards.push(card.clone()),
+                }
+            }
+            Change::RemoveCard { ref card } => {
+                P::borrow_mut(&self.cards).retain(|c| c.id != card.id);
+            }
+            Change::Encrypted { .. } => {}
+        }
+    }
+}
+
+/// A board, as seen from an `App`: changes made through it are recorded in
+/// the `App`'s history and attributed to its actor.
+pub struct BoardHandle<S: Storage, P: Threading = Local> {
+    storage: P::Ptr<S>,
+    history: SharedCell<P, History>,
+    actor: SharedCell<P, Option<Actor>>,
+    notifier: Notifier<P>,
+    state: StatePtr<P>,
+}
+
+/// A board handle that can be shared between threads, see `SharedApp`.
+pub type SharedBoardHandle<S> = BoardHandle<S, Shared>;
+
+impl<S: Storage, P: Threading> BoardHandle<S, P> {
+    pub fn board(&self) -> P::Ref<'_, Board> {
+        P::borrow(&self.state.inner)
+    }
+
+    pub fn lists(&self) -> P::Ref<'_, Vec<List>> {
+        P::borrow(&self.state.lists)
+    }
+
+    pub fn cards(&self) -> P::Ref<'_, Vec<Card>> {
+        P::borrow(&self.state.cards)
     }
 
     pub fn add_list(&self, name: &str)
-        -> Box<Future<Item=(), Error=S::Error>>
+        -> impl Future<Output=Result<(), S::Error>>
     {
         let list = List {
             id: Uuid::new_v4(),
             name: name.into(),
         };
-        self.storage.add_list(&self.board().id, &list)
+        let op = Operation::new(self.board().id, Change::AddList { list });
+        perform(&self.storage, &self.history, &self.actor, &self.notifier,
+                vec![op])
+    }
+
+    pub fn add_card(&self, list: &Uuid, title: &str)
+        -> impl Future<Output=Result<(), S::Error>>
+    {
+        let card = Card::new(*list, title);
+        let op = Operation::new(self.board().id, Change::AddCard {
+            card,
+            previous: None,
+        });
+        perform(&self.storage, &self.history, &self.actor, &self.notifier,
+                vec![op])
+    }
+
+    /// Add lists and cards read from a file, as one action.
+    pub fn add_all(&self, lists: Vec<List>, cards: Vec<Card>)
+        -> impl Future<Output=Result<(), S::Error>>
+    {
+        self.update_all(lists, cards, &[])
+    }
+
+    /// Add lists, add or replace cards, and remove cards, as one action.
+    ///
+    /// A card that is on the board already is replaced, recording the old
+    /// one so undoing brings it back.
+    pub fn update_all(&self, lists: Vec<List>, cards: Vec<Card>,
+                      removed: &[Uuid])
+        -> impl Future<Output=Result<(), S::Error>>
+    {
+        let id = self.board().id;
+        let mut ops: Vec<Operation> = lists.into_iter()
+            .map(|list| Operation::new(id, Change::AddList { list }))
+            .collect();
+        {
+            let current = self.cards();
+            for card in cards {
+                let previous = current.iter().find(|c| c.id == card.id)
+                    .cloned();
+                ops.push(Operation::new(id, Change::AddCard {
+                    card,
+                    previous,
+                }));
+            }
+            for card in current.iter().filter(|c| removed.contains(&c.id)) {
+                let card = card.clone();
+                ops.push(Operation::new(id, Change::RemoveCard { card }));
+            }
+        }
+        perform(&self.storage, &self.history, &self.actor, &self.notifier,
+                ops)
+    }
+
+    pub fn unsubscribe(&self, id: SubscriptionId) -> bool {
+        self.state.subscribers.unsubscribe(id)
+    }
+
+    /// Get the activity feed of the board.
+    pub fn activity(&self)
+        -> impl Future<Output=Result<Vec<Activity>, S::Error>>
+    {
+        let storage = self.storage.clone();
+        let id = self.board().id;
+        async move { storage.get_activity(&id).await }
     }
 }
 
-pub struct App<S: Storage + 'static> {
-    storage: Rc<S>,
-    boards: Rc<RefCell<BTreeMap<Uuid, Weak<BoardHandle<S>>>>>,
+impl<S: Storage> BoardHandle<S, Local> {
+    /// Call a function every time the board changes.
+    pub fn subscribe<F: Fn(&Event) + 'static>(&self, callback: F)
+        -> SubscriptionId
+    {
+        self.state.subscribers.subscribe(callback)
+    }
 }
 
-impl<S: Storage> App<S> {
-    pub fn new(storage: S) -> App<S> {
+impl<S: Storage> BoardHandle<S, Shared> {
+    /// Call a function every time the board changes.
+    pub fn subscribe<F>(&self, callback: F) -> SubscriptionId
+        where F: Fn(&Event) + Send + Sync + 'static
+    {
+        self.state.subscribers.subscribe(callback)
+    }
+}
+
+/// The application, caching the boards in use.
+///
+/// Local changes show on the cached boards as soon as they are made, before
+/// they are stored. The futures returned don't borrow the `App`, so they can
+/// be handed to an executor; clones share the same storage and boards.
+///
+/// By default it can only be used from one thread, see `SharedApp`.
+pub struct App<S: Storage, P: Threading = Local> {
+    storage: P::Ptr<S>,
+    history: SharedCell<P, History>,
+    actor: SharedCell<P, Option<Actor>>,
+    boards: BoardCache<P>,
+    subscribers: P::Ptr<Subscribers<P>>,
+}
+
+/// An `App` that can be shared between threads, for example by a server
+/// handling many connections.
+///
+/// The storage has to be `Send` and `Sync`, like `MemoryStorage` and the
+/// SQLite and PostgreSQL backends. The futures are `Send` when those of the
+/// storage are, which is the case for these backends, so they can be run on
+/// another thread than the one that made them. Give each connection its own
+/// `session()`, so they don't undo each other's changes.
+pub type SharedApp<S> = App<S, Shared>;
+
+impl<S: Storage, P: Threading> Clone for App<S, P> {
+    fn clone(&self) -> App<S, P> {
+        App {
+            storage: self.storage.clone(),
+            history: self.history.clone(),
+            actor: self.actor.clone(),
+            boards: self.boards.clone(),
+            subscribers: self.subscribers.clone(),
+        }
+    }
+}
+
+impl<S: Storage, P: Threading> App<S, P> {
+    fn with_storage(storage: S) -> App<S, P> {
+        App {
+            storage: P::new(storage),
+            history: P::new(P::cell(History::default())),
+            actor: P::new(P::cell(None)),
+            boards: P::new(P::cell(BTreeMap::new())),
+            subscribers: P::new(Subscribers::default()),
+        }
+    }
+
+    /// Get a `Notifier` to report changes made to storage without going
+    /// through the `App`.
+    pub fn notifier(&self) -> Notifier<P> {
+        Notifier {
+            boards: self.boards.clone(),
+            subscribers: self.subscribers.clone(),
+        }
+    }
+
+    pub fn unsubscribe(&self, id: SubscriptionId) -> bool {
+        self.subscribers.unsubscribe(id)
+    }
+
+    /// Get an `App` on the same storage and cached boards, with its own
+    /// history and actor, for another user such as a server connection.
+    ///
+    /// Clones share their history and actor, and undo each other's changes.
+    pub fn session(&self) -> App<S, P> {
         App {
-            storage: Rc::new(storage),
-            boards: Rc::new(RefCell::new(BTreeMap::new())),
+            history: P::new(P::cell(History::default())),
+            actor: P::new(P::cell(None)),
+            ..self.clone()
         }
     }
 
+    /// Add a board to the cache, and wrap it in a handle.
+    fn handle(&self, board: Board, lists: Vec<List>, cards: Vec<Card>)
+        -> HandlePtr<S, P>
+    {
+        let id = board.id;
+        let state = P::new(BoardState {
+            inner: P::cell(board),
+            lists: P::cell(lists),
+            cards: P::cell(cards),
+            subscribers: Subscribers::default(),
+        });
+        P::borrow_mut(&self.boards).insert(id, P::downgrade(&state));
+        self.wrap(state)
+    }
+
+    /// Make a handle on a cached board.
+    fn wrap(&self, state: StatePtr<P>) -> HandlePtr<S, P> {
+        P::new(BoardHandle {
+            storage: self.storage.clone(),
+            history: self.history.clone(),
+            actor: self.actor.clone(),
+            notifier: self.notifier(),
+            state,
+        })
+    }
+
     pub fn new_board(&self, name: &str)
-        -> Box<Future<Item=Rc<BoardHandle<S>>, Error=S::Error>>
+        -> impl Future<Output=Result<HandlePtr<S, P>, S::Error>>
     {
         // Make it
         let id = Uuid::new_v4();
         let inner = Board {
-            id: id.clone(),
+            id,
             name: name.into(),
         };
 
         // Add it to storage
-        let fut = self.storage.add_board(&inner);
+        let fut = perform(&self.storage, &self.history, &self.actor,
+                          &self.notifier(), vec![
+            Operation::new(id, Change::AddBoard { name: inner.name.clone() }),
+        ]);
 
         // Wrap it
-        let board = BoardHandle {
-            storage: self.storage.clone(),
-            inner: Rc::new(RefCell::new(inner)),
-            lists: Rc::new(RefCell::new(Vec::new())),
-        };
-        let rc = Rc::new(board);
+        let ptr = self.handle(inner, Vec::new(), Vec::new());
 
-        // Add it to the cache
-        self.boards.borrow_mut().insert(id, Rc::downgrade(&rc));
-
-        let fut = fut.map(move |()| rc);
-        Box::new(fut)
+        async move { fut.await.map(|()| ptr) }
     }
 
     pub fn get_board(&self, id: &Uuid)
-        -> Box<Future<Item=Option<Rc<BoardHandle<S>>>, Error=S::Error>>
+        -> impl Future<Output=Result<Option<HandlePtr<S, P>>, S::Error>>
+    {
+        let app = self.clone();
+        let id = *id;
+        async move { app.load_board(&id).await }
+    }
+
+    async fn load_board(&self, id: &Uuid)
+        -> Result<Option<HandlePtr<S, P>>, S::Error>
     {
         // Get from cache
-        let opt = self.boards.borrow().get(id).cloned();
-        if let Some(weak) = opt {
-            if let Some(rc) = weak.upgrade() {
-                return Box::new(future::ok(Some(rc)));
-            }
+        let cached = P::borrow(&self.boards).get(id)
+            .and_then(|weak| P::upgrade(weak));
+        if let Some(state) = cached {
+            return Ok(Some(self.wrap(state)));
         }
 
         // Get it from storage
-        let fut = self.storage.get_board(id);
-        // Wrap it
+        let board = match self.storage.get_board(id).await? {
+            Some(b) => b,
+            None => return Ok(None),
+        };
+        let lists = self.storage.get_lists(id).await?;
+        let cards = self.storage.get_cards(id).await?;
+        Ok(Some(self.handle(board, lists, cards)))
+    }
+
+    pub fn add_list(&self, board: &BoardHandle<S, P>, name: &str)
+        -> impl Future<Output=Result<(), S::Error>>
+    {
+        board.add_list(name)
+    }
+
+    /// Get a whole board, to write it to a file. None if it doesn't exist.
+    pub fn export_board(&self, id: &Uuid)
+        -> impl Future<Output=Result<Option<BoardExport>, S::Error>>
+    {
         let storage = self.storage.clone();
-        let id = id.clone();
-        let fut = fut.and_then(move |opt| {
-            if let Some(b) = opt {
-                let fut = storage.get_lists(&id).map(|lists|
-                    Some(Rc::new(BoardHandle {
-                        storage: storage,
-                        inner: Rc::new(RefCell::new(b)),
-                        lists: Rc::new(RefCell::new(lists)),
-                    }))
-                );
-                future::Either::A(fut)
-            } else {
-                future::Either::B(future::ok(None))
-            }
+        let id = *id;
+        async move { BoardExport::take(&*storage, &id).await }
+    }
+
+    /// Add a board read from a file, as one action that can be undone.
+    ///
+    /// The board keeps its IDs, so it can be moved from one replica to
+    /// another, unless there is already a board with the same ID: then a copy
+    /// with new IDs is added instead. Only the board's ID is checked; a file
+    /// whose lists or cards have the IDs of ones on another board should be
+    /// given new IDs with `BoardExport::with_new_ids()` before importing it.
+    pub fn import_board(&self, export: BoardExport)
+        -> impl Future<Output=Result<HandlePtr<S, P>, S::Error>>
+    {
+        let app = self.clone();
+        async move {
+            let exists = app.storage.get_board(&export.board.id).await?
+                .is_some();
+            let export = if exists { export.with_new_ids() } else { export };
+            let BoardExport { board, lists, cards } = export;
+
+            let id = board.id;
+            let mut ops = vec![
+                Operation::new(id, Change::AddBoard { name: board.name.clone() }),
+            ];
+            ops.extend(lists.iter().map(|list| {
+                Operation::new(id, Change::AddList { list: list.clone() })
+            }));
+            ops.extend(cards.iter().map(|card| {
+                Operation::new(id, Change::AddCard {
+                    card: card.clone(),
+                    previous: None,
+                })
+            }));
+            let fut = perform(&app.storage, &app.history, &app.actor,
+                              &app.notifier(), ops);
+
+            let ptr = app.handle(board, lists, cards);
+            fut.await.map(|()| ptr)
+        }
+    }
+
+    /// Set who the changes made from now on are attributed to.
+    pub fn set_actor(&self, actor: Option<Actor>) {
+        *P::borrow_mut(&self.actor) = actor;
+    }
+
+    /// Get the activity about a board, list or card, oldest first.
+    pub fn get_activity(&self, id: &Uuid)
+        -> impl Future<Output=Result<Vec<Activity>, S::Error>>
+    {
+        let storage = self.storage.clone();
+        let id = *id;
+        async move { storage.get_activity(&id).await }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !P::borrow(&self.history).undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !P::borrow(&self.history).redo.is_empty()
+    }
+
+    /// Undo the last action. Returns false if there was nothing to undo.
+    pub fn undo(&self) -> impl Future<Output=Result<bool, S::Error>> {
+        let group = P::borrow_mut(&self.history).undo.pop();
+        let started = group.map(|group| {
+            let fut = revert(&self.storage, &self.actor, &self.notifier(),
+                             group.clone());
+            (group, fut)
         });
-        // Add it to the cache
-        let boards_map = self.boards.clone();
-        let fut = fut.map(move |opt| {
-            if let Some(ref rc) = opt {
-                boards_map.borrow_mut().insert(
-                    id,
-                    Rc::downgrade(rc),
-                );
-            }
-            opt
+        let history = self.history.clone();
+        async move {
+            let (group, fut) = match started {
+                Some(started) => started,
+                None => return Ok(false),
+            };
+            let res = fut.await;
+            let mut history = P::borrow_mut(&history);
+            match res {
+                Ok(()) => {
+                    history.redo.push(group);
+                    Ok(true)
+                }
+                Err(e) => {
+                    history.undo.push(group);
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    /// Redo the last action that was undone. Returns false if there was
+    /// nothing to redo.
+    pub fn redo(&self) -> impl Future<Output=Result<bool, S::Error>> {
+        let group = P::borrow_mut(&self.history).redo.pop();
+        let started = group.map(|group| {
+            // Operations are only applied once, make new ones
+            let mut ops: Vec<Operation> = group.iter()
+                .map(|op| Operation::new(op.board_id, op.change.clone()))
+                .collect();
+            stamp::<P>(&mut ops, &self.actor);
+            mark_group(&mut ops);
+            let fut = apply_local(&self.storage, &self.notifier(),
+                                  ops.clone());
+            (group, ops, fut)
         });
-        Box::new(fut)
+        let history = self.history.clone();
+        async move {
+            let (group, ops, fut) = match started {
+                Some(started) => started,
+                None => return Ok(false),
+            };
+            let res = fut.await;
+            let mut history = P::borrow_mut(&history);
+            match res {
+                Ok(()) => {
+                    history.undo.push(ops);
+                    Ok(true)
+                }
+                Err(e) => {
+                    history.redo.push(group);
+                    Err(e)
+                }
+            }
+        }
     }
 
-    pub fn add_list(&self, board: Rc<Board>, name: &str)
-        -> Box<Future<Item=(), Error=S::Error>>
+    /// Make the local changes to a board that haven't been sent to the
+    /// server yet undoable, for example when starting a new session.
+    ///
+    /// The operations of an action are undone together, as they were in the
+    /// session that made them.
+    pub fn load_pending_history(&self, board_id: &Uuid)
+        -> impl Future<Output=Result<(), S::Error>>
     {
-        let list = List {
-            id: Uuid::new_v4(),
-            name: name.into(),
+        let storage = self.storage.clone();
+        let history = self.history.clone();
+        let board_id = *board_id;
+        async move {
+            let ops = storage.get_pending_operations(&board_id).await?;
+            let mut groups: Vec<Vec<Operation>> = Vec::new();
+            for op in ops.into_iter().filter(|op| op.inverse().is_some()) {
+                match groups.last_mut() {
+                    Some(last) if op.group.is_some() &&
+                        last[0].group == op.group => last.push(op),
+                    _ => groups.push(vec![op]),
+                }
+            }
+            P::borrow_mut(&history).undo.extend(groups);
+            Ok(())
+        }
+    }
+}
+
+impl<S: Storage> App<S, Local> {
+    pub fn new(storage: S) -> App<S> {
+        App::with_storage(storage)
+    }
+
+    /// Call a function every time a board changes.
+    pub fn subscribe<F: Fn(&Event) + 'static>(&self, callback: F)
+        -> SubscriptionId
+    {
+        self.subscribers.subscribe(callback)
+    }
+}
+
+impl<S: Storage + Send + Sync> App<S, Shared> {
+    /// Make an `App` that can be shared between threads.
+    pub fn shared(storage: S) -> SharedApp<S> {
+        App::with_storage(storage)
+    }
+
+    /// Call a function every time a board changes.
+    pub fn subscribe<F>(&self, callback: F) -> SubscriptionId
+        where F: Fn(&Event) + Send + Sync + 'static
+    {
+        self.subscribers.subscribe(callback)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use futures::task::noop_waker;
+    use std::cell::RefCell;
+    use std::fmt::Debug;
+    use std::future::Future;
+    use std::rc::Rc;
+    use std::task::Context;
+    use uuid::Uuid;
+
+    use crate::activity::Actor;
+    use crate::events::Event;
+    use crate::memory::MemoryStorage;
+    use super::{App, BoardHandle, Card, Change, List, Operation, SharedApp,
+                SharedBoardHandle, Storage};
+
+    fn wait<T, E, F>(future: F) -> T
+        where E: Debug, F: Future<Output=Result<T, E>>
+    {
+        block_on(future).unwrap()
+    }
+
+    fn list_names(lists: &[List]) -> Vec<&str> {
+        lists.iter().map(|l| l.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_inverse() {
+        let list = List { id: Uuid::new_v4(), name: "todo".into() };
+        let op = Operation::new(Uuid::new_v4(), Change::AddList {
+            list: list.clone(),
+        });
+        let inverse = op.inverse().unwrap();
+        assert_eq!(inverse, Change::RemoveList { list });
+        let inverse = Operation::new(op.board_id, inverse);
+        assert_eq!(inverse.inverse(), Some(op.change));
+
+        // Replacing a card is undone by putting the old one back
+        let old = Card::new(Uuid::new_v4(), "old");
+        let new = Card { title: "new".into(), ..old.clone() };
+        let op = Operation::new(Uuid::new_v4(), Change::AddCard {
+            card: new.clone(),
+            previous: Some(old.clone()),
+        });
+        assert_eq!(op.inverse(), Some(Change::AddCard {
+            card: old,
+            previous: Some(new),
+        }));
+
+        let op = Operation::new(Uuid::new_v4(), Change::Encrypted {
+            key_id: "0011223344556677".into(),
+            data: "".into(),
+        });
+        assert_eq!(op.inverse(), None);
+    }
+
+    #[test]
+    fn test_undo_redo() {
+        let app = App::new(MemoryStorage::new());
+        app.set_actor(Some(Actor::User { name: "remram".into() }));
+        let board = wait(app.new_board("Work"));
+        wait(board.add_list("todo"));
+        wait(board.add_list("done"));
+        assert_eq!(list_names(&board.lists()), vec!["todo", "done"]);
+
+        assert!(wait(app.undo()));
+        assert_eq!(list_names(&board.lists()), vec!["todo"]);
+        assert!(app.can_redo());
+        assert!(wait(app.redo()));
+        assert_eq!(list_names(&board.lists()), vec!["todo", "done"]);
+        assert!(!app.can_redo());
+
+        // Stored lists match the handle
+        let id = board.board().id;
+        drop(board);
+        let board = wait(app.get_board(&id)).unwrap();
+        assert_eq!(list_names(&board.lists()), vec!["todo", "done"]);
+
+        let activity: Vec<String> = wait(app.get_activity(&id)).iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert_eq!(activity, vec![
+            "remram created board 'Work'",
+            "remram created list 'todo'",
+            "remram created list 'done'",
+            "remram removed list 'done'",
+            "remram created list 'done'",
+        ]);
+    }
+
+    #[test]
+    fn test_undo_replace() {
+        let app = App::new(MemoryStorage::new());
+        let board = wait(app.new_board("Work"));
+        wait(board.add_list("todo"));
+        let list = board.lists()[0].id;
+        wait(board.add_card(&list, "old"));
+        let card = board.cards()[0].clone();
+        let titles = |board: &BoardHandle<MemoryStorage>| {
+            let mut titles: Vec<String> = board.cards().iter()
+                .map(|c| c.title.clone())
+                .collect();
+            titles.sort();
+            titles
         };
-        let fut = self.storage.add_list(
-            &board.id,
-            &list,
-        );
-        Box::new(fut)
+
+        wait(board.update_all(vec![], vec![
+            Card { title: "new".into(), ..card.clone() },
+            Card::new(list, "added"),
+        ], &[]));
+        assert_eq!(titles(&board), vec!["added", "new"]);
+        assert!(wait(app.undo()));
+        assert_eq!(titles(&board), vec!["old"]);
+        assert!(wait(app.redo()));
+        assert_eq!(titles(&board), vec!["added", "new"]);
+
+        // Actions are still undone as a whole in a new session
+        app.history.borrow_mut().undo.clear();
+        wait(app.load_pending_history(&board.board().id));
+        assert!(wait(app.undo()));
+        assert_eq!(titles(&board), vec!["old"]);
+    }
+
+    #[test]
+    fn test_export_import() {
+        let app = App::new(MemoryStorage::new());
+        let board = wait(app.new_board("Work"));
+        wait(board.add_list("todo"));
+        let todo = board.lists()[0].id;
+        wait(board.add_card(&todo, "Write report"));
+        let id = board.board().id;
+        let export = wait(app.export_board(&id)).unwrap();
+        assert_eq!(wait(app.export_board(&Uuid::new_v4())), None);
+
+        // Into another replica, the IDs are kept
+        let other = App::new(MemoryStorage::new());
+        let imported = wait(other.import_board(export.clone()));
+        assert_eq!(*imported.board(), *board.board());
+        assert_eq!(*imported.lists(), *board.lists());
+        assert_eq!(*imported.cards(), *board.cards());
+
+        // Next to the original, it's a copy
+        let copy = wait(app.import_board(export));
+        assert_ne!(copy.board().id, id);
+        assert_eq!(copy.board().name, "Work");
+        assert_eq!(list_names(&copy.lists()), vec!["todo"]);
+        assert_eq!(copy.cards()[0].title, "Write report");
+        assert_eq!(copy.cards()[0].list, copy.lists()[0].id);
+        let copy_id = copy.board().id;
+        drop(copy);
+        let copy = wait(app.get_board(&copy_id)).unwrap();
+        assert_eq!(copy.cards().len(), 1);
+        drop(copy);
+
+        // The import is undone at once
+        assert!(wait(app.undo()));
+        assert!(wait(app.get_board(&copy_id)).is_none());
+        assert_eq!(board.cards().len(), 1);
+
+        // Lists and cards can be added to a board the same way
+        let done = List { id: Uuid::new_v4(), name: "done".into() };
+        let cards = vec![Card::new(done.id, "Send report"),
+                         Card::new(todo, "Proofread")];
+        wait(board.add_all(vec![done], cards));
+        assert_eq!(list_names(&board.lists()), vec!["todo", "done"]);
+        assert_eq!(board.cards().len(), 3);
+        assert!(wait(app.undo()));
+        assert_eq!(list_names(&board.lists()), vec!["todo"]);
+        assert_eq!(board.cards().len(), 1);
+
+        // Or replaced and removed
+        let mut changed = board.cards()[0].clone();
+        changed.title = "Write summary".into();
+        let new = Card::new(todo, "Proofread");
+        let removed = [board.cards()[0].id];
+        wait(board.update_all(vec![], vec![changed.clone()], &[]));
+        assert_eq!(*board.cards(), vec![changed.clone()]);
+        wait(board.update_all(vec![], vec![new.clone()], &removed));
+        assert_eq!(*board.cards(), vec![new]);
+        assert!(wait(app.undo()));
+        assert_eq!(*board.cards(), vec![changed]);
+        assert!(wait(app.undo()));
+        assert_eq!(board.cards()[0].title, "Write report");
+        let stored = wait(app.export_board(&id)).unwrap();
+        assert_eq!(stored.cards, *board.cards());
+    }
+
+    #[test]
+    fn test_rollback() {
+        let storage = MemoryStorage::new();
+        let faults = storage.faults();
+        let app = App::new(storage);
+        let board = wait(app.new_board("Work"));
+        wait(board.add_list("todo"));
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let events_ = events.clone();
+        board.subscribe(move |event: &Event| {
+            events_.borrow_mut().push(event.clone());
+        });
+
+        // The change shows right away, and is reverted when storing fails
+        faults.fail_after(0);
+        let fut = board.add_list("done");
+        assert_eq!(list_names(&board.lists()), vec!["todo", "done"]);
+        assert!(block_on(fut).is_err());
+        assert_eq!(list_names(&board.lists()), vec!["todo"]);
+        match &events.borrow()[..] {
+            [Event::Applied { operation: a }, Event::Reverted { operation: r }]
+                => assert_eq!(a, r),
+            events => panic!("Unexpected events: {:?}", events),
+        }
+
+        // Failed undo stays on the stack
+        assert!(block_on(app.undo()).is_err());
+        assert_eq!(list_names(&board.lists()), vec!["todo"]);
+        assert!(app.can_undo());
+        faults.heal();
+        assert!(wait(app.undo()));
+        assert_eq!(board.lists().len(), 0);
+
+        // Failing halfway through doesn't leave anything in storage: the
+        // list is added, but recording the activity fails
+        faults.fail_after(2);
+        assert!(block_on(board.add_list("done")).is_err());
+        faults.heal();
+        let id = board.board().id;
+        drop(board);
+        let board = wait(app.get_board(&id)).unwrap();
+        assert_eq!(board.lists().len(), 0);
+        let activity = wait(app.get_activity(&id));
+        assert_eq!(activity.len(), 3);
+    }
+
+    #[test]
+    fn test_pending() {
+        let storage = MemoryStorage::new();
+        let faults = storage.faults();
+        let app = App::new(storage);
+        let board = wait(app.new_board("Work"));
+
+        faults.pause();
+        let mut fut = Box::pin(board.add_list("todo"));
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(fut.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(list_names(&board.lists()), vec!["todo"]);
+        faults.resume();
+        assert!(fut.as_mut().poll(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn test_concurrent() {
+        let storage = MemoryStorage::new();
+        let faults = storage.faults();
+        let app = App::new(storage);
+        let board = wait(app.new_board("Work"));
+
+        // The second action waits for the transaction of the first
+        faults.pause();
+        let mut first = Box::pin(board.add_list("todo"));
+        let mut second = Box::pin(board.add_list("done"));
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(first.as_mut().poll(&mut cx).is_pending());
+        assert!(second.as_mut().poll(&mut cx).is_pending());
+        faults.resume();
+        wait(futures::future::try_join(first, second));
+
+        let id = board.board().id;
+        drop(board);
+        let board = wait(app.get_board(&id)).unwrap();
+        assert_eq!(list_names(&board.lists()), vec!["todo", "done"]);
+    }
+
+    /// Only compiles if the shared variant can be sent.
+    fn assert_shared_is_send_sync<S: Storage + Send + Sync>() {
+        fn is_send_sync<T: Send + Sync>() {}
+        is_send_sync::<SharedApp<S>>();
+        is_send_sync::<SharedBoardHandle<S>>();
+    }
+
+    /// Only compiles if the future can be sent to another thread.
+    fn sendable<F: Future + Send>(future: F) -> F {
+        future
+    }
+
+    #[test]
+    fn test_shared() {
+        assert_shared_is_send_sync::<MemoryStorage>();
+        let app = App::shared(MemoryStorage::new());
+        let board = wait(app.new_board("Work"));
+        let events = std::sync::Arc::new(std::sync::Mutex::new(0));
+        let events_ = events.clone();
+        app.subscribe(move |_: &Event| *events_.lock().unwrap() += 1);
+        wait(board.add_list("todo"));
+        assert_eq!(list_names(&board.lists()), vec!["todo"]);
+        assert!(wait(app.undo()));
+        assert_eq!(board.lists().len(), 0);
+        assert_eq!(*events.lock().unwrap(), 2);
+
+        // The cache hands out the same board
+        let id = board.board().id;
+        let again = wait(app.get_board(&id)).unwrap();
+        wait(again.add_list("done"));
+        assert_eq!(list_names(&board.lists()), vec!["done"]);
+
+        // Each session has its own history and actor
+        let other = app.session();
+        let actor = Actor::User { name: "other".into() };
+        other.set_actor(Some(actor.clone()));
+        let theirs = wait(other.get_board(&id)).unwrap();
+        wait(theirs.add_list("later"));
+        assert_eq!(list_names(&board.lists()), vec!["done", "later"]);
+        assert!(wait(app.undo()));
+        assert_eq!(list_names(&theirs.lists()), vec!["later"]);
+        let activity = wait(app.get_activity(&id));
+        assert_eq!(activity.last().unwrap().actor, None);
+        assert_eq!(activity[activity.len() - 2].actor, Some(actor));
+        assert!(wait(other.undo()));
+        assert!(!other.can_undo());
+        assert_eq!(board.lists().len(), 0);
+
+        // Sessions on other threads change the same cached board
+        std::thread::scope(|scope| {
+            for i in 0..4 {
+                let session = app.session();
+                scope.spawn(move || {
+                    let board = wait(session.get_board(&id)).unwrap();
+                    wait(board.add_list(&format!("thread {}", i)));
+                    assert!(session.can_undo());
+                });
+            }
+        });
+        let mut names = list_names(&board.lists())
+            .into_iter().map(String::from).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["thread 0", "thread 1", "thread 2",
+                               "thread 3"]);
+
+        // A future made on one thread can be run on another
+        let future = sendable(app.session().undo());
+        let other = sendable(app.get_board(&id));
+        std::thread::scope(|scope| {
+            scope.spawn(move || {
+                assert!(!wait(future));
+                assert!(wait(other).is_some());
+            });
+        });
+        let future = sendable(board.add_list("moved"));
+        std::thread::scope(|scope| {
+            scope.spawn(move || wait(future));
+        });
+        assert!(list_names(&board.lists()).contains(&"moved"));
+        assert!(wait(app.undo()));
+        assert!(!list_names(&board.lists()).contains(&"moved"));
     }
 }
@@ -0,0 +1,459 @@
+//! Storage in memory, for tests and for embedding without persistence.
+//!
+//! `MemoryStorage` can also simulate a misbehaving backend through its
+//! `FaultInjector`: calls can be made to fail, and futures can be held back
+//! to observe the state of the application while they are pending.
+//!
+//! It is `Send` and `Sync`, so it can be used with a `SharedApp`.
+
+use futures::lock::{Mutex, MutexGuard};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{self, Arc, PoisonError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll, Waker};
+use uuid::Uuid;
+
+use super::{Board, Card, List, Operation, Storage, Store, Transaction};
+use super::activity::Activity;
+
+#[derive(Debug)]
+pub enum MemoryError {
+    /// A failure caused by the `FaultInjector`
+    Injected,
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MemoryError::Injected => write!(f, "Injected failure"),
+        }
+    }
+}
+
+impl std::error::Error for MemoryError {}
+
+/// Lock a value, even if a thread panicked while holding it: the values
+/// locked here are changed at once, and can't be left half done.
+fn locked<T>(mutex: &sync::Mutex<T>) -> sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[derive(Default)]
+struct FaultState {
+    /// Number of calls that will succeed before calls start failing
+    fail_after: sync::Mutex<Option<usize>>,
+    paused: AtomicBool,
+    waiting: sync::Mutex<Vec<Waker>>,
+}
+
+/// Controls the failures of a `MemoryStorage`.
+#[derive(Clone, Default)]
+pub struct FaultInjector {
+    state: Arc<FaultState>,
+}
+
+impl FaultInjector {
+    /// Make calls fail after a number of successful ones, until `heal()` is
+    /// called.
+    pub fn fail_after(&self, calls: usize) {
+        *locked(&self.state.fail_after) = Some(calls);
+    }
+
+    /// Stop failing calls.
+    pub fn heal(&self) {
+        *locked(&self.state.fail_after) = None;
+    }
+
+    /// Hold back the futures returned from now on until `resume()` is
+    /// called.
+    ///
+    /// The changes are made to the storage right away, only their completion
+    /// is delayed.
+    pub fn pause(&self) {
+        self.state.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.state.paused.store(false, Ordering::SeqCst);
+        let waiting = std::mem::take(&mut *locked(&self.state.waiting));
+        for waker in waiting {
+            waker.wake();
+        }
+    }
+
+    /// Count a call, returning whether it should fail.
+    fn should_fail(&self) -> bool {
+        let mut fail_after = locked(&self.state.fail_after);
+        match *fail_after {
+            Some(0) => true,
+            Some(n) => {
+                *fail_after = Some(n - 1);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+/// A future that is held back while the storage is paused.
+struct Delayed<T> {
+    result: Option<Result<T, MemoryError>>,
+    paused: bool,
+    faults: FaultInjector,
+}
+
+// The result is never pinned
+impl<T> Unpin for Delayed<T> {}
+
+impl<T> Future for Delayed<T> {
+    type Output = Result<T, MemoryError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let state = &this.faults.state;
+        if this.paused && state.paused.load(Ordering::SeqCst) {
+            locked(&state.waiting).push(cx.waker().clone());
+            return Poll::Pending;
+        }
+        Poll::Ready(this.result.take().expect("Polled after completion"))
+    }
+}
+
+#[derive(Clone, Default)]
+struct State {
+    boards: BTreeMap<Uuid, Board>,
+    lists: BTreeMap<Uuid, Vec<List>>,
+    cards: BTreeMap<Uuid, Vec<Card>>,
+    /// The operation log, with the pending flag
+    operations: Vec<(Operation, bool)>,
+    sync_cursors: HashMap<Uuid, u64>,
+    replica_id: Option<Uuid>,
+    activity: Vec<Activity>,
+}
+
+#[derive(Default)]
+pub struct MemoryStorage {
+    state: sync::Mutex<State>,
+    /// Held by the transaction in progress
+    lock: Mutex<()>,
+    faults: FaultInjector,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        Default::default()
+    }
+
+    /// Get the `FaultInjector` controlling this storage.
+    pub fn faults(&self) -> FaultInjector {
+        self.faults.clone()
+    }
+
+    /// Make a call once no transaction is in progress.
+    async fn run<T, F>(&self, f: F) -> Result<T, MemoryError>
+        where F: FnOnce(&mut State) -> T
+    {
+        let _lock = self.lock.lock().await;
+        self.run_unlocked(f).await
+    }
+
+    fn run_unlocked<T, F>(&self, f: F) -> Delayed<T>
+        where F: FnOnce(&mut State) -> T
+    {
+        let result = self.check()
+            .map(|()| f(&mut locked(&self.state)));
+        self.delayed(result)
+    }
+
+    fn check(&self) -> Result<(), MemoryError> {
+        if self.faults.should_fail() {
+            Err(MemoryError::Injected)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn delayed<T>(&self, result: Result<T, MemoryError>) -> Delayed<T> {
+        Delayed {
+            result: Some(result),
+            paused: self.faults.state.paused.load(Ordering::SeqCst),
+            faults: self.faults.clone(),
+        }
+    }
+}
+
+/// The methods of `Store`, which are the same on the storage and its
+/// transactions: they make their changes with `run()`.
+macro_rules! store_methods {
+    () => {
+        async fn add_board(&self, board: &Board) -> Result<(), MemoryError> {
+            let board = board.clone();
+            self.run(move |state| {
+                state.boards.insert(board.id, board);
+            }).await
+        }
+
+        async fn get_board(&self, id: &Uuid)
+            -> Result<Option<Board>, MemoryError>
+        {
+            self.run(|state| state.boards.get(id).cloned()).await
+        }
+
+        async fn get_lists(&self, board_id: &Uuid)
+            -> Result<Vec<List>, MemoryError>
+        {
+            self.run(|state| {
+                state.lists.get(board_id).cloned().unwrap_or_default()
+            }).await
+        }
+
+        async fn add_list(&self, board_id: &Uuid, list: &List)
+            -> Result<(), MemoryError>
+        {
+            let list = list.clone();
+            self.run(|state| {
+                let lists = state.lists.entry(*board_id).or_default();
+                lists.retain(|l| l.id != list.id);
+                lists.push(list);
+            }).await
+        }
+
+        async fn delete_board(&self, id: &Uuid) -> Result<(), MemoryError> {
+            self.run(|state| {
+                state.boards.remove(id);
+                state.lists.remove(id);
+                state.cards.remove(id);
+            }).await
+        }
+
+        async fn delete_list(&self, board_id: &Uuid, list_id: &Uuid)
+            -> Result<(), MemoryError>
+        {
+            self.run(|state| {
+                if let Some(lists) = state.lists.get_mut(board_id) {
+                    lists.retain(|l| l.id != *list_id);
+                }
+            }).await
+        }
+
+        async fn get_cards(&self, board_id: &Uuid)
+            -> Result<Vec<Card>, MemoryError>
+        {
+            self.run(|state| {
+                state.cards.get(board_id).cloned().unwrap_or_default()
+            }).await
+        }
+
+        async fn add_card(&self, board_id: &Uuid, card: &Card)
+            -> Result<(), MemoryError>
+        {
+            let card = card.clone();
+            self.run(|state| {
+                let cards = state.cards.entry(*board_id).or_default();
+                match cards.iter_mut().find(|c| c.id == card.id) {
+                    Some(c) => *c = card,
+                    None => cards.push(card),
+                }
+            }).await
+        }
+
+        async fn delete_card(&self, board_id: &Uuid, card_id: &Uuid)
+            -> Result<(), MemoryError>
+        {
+            self.run(|state| {
+                if let Some(cards) = state.cards.get_mut(board_id) {
+                    cards.retain(|c| c.id != *card_id);
+                }
+            }).await
+        }
+
+        async fn add_operation(&self, op: &Operation, pending: bool)
+            -> Result<bool, MemoryError>
+        {
+            let op = op.clone();
+            self.run(move |state| {
+                if state.operations.iter().any(|(o, _)| o.id == op.id) {
+                    false
+                } else {
+                    state.operations.push((op, pending));
+                    true
+                }
+            }).await
+        }
+
+        async fn get_pending_operations(&self, board_id: &Uuid)
+            -> Result<Vec<Operation>, MemoryError>
+        {
+            self.run(|state| {
+                state.operations.iter()
+                    .filter(|(op, pending)| {
+                        *pending && op.board_id == *board_id
+                    })
+                    .map(|(op, _)| op.clone())
+                    .collect()
+            }).await
+        }
+
+        async fn acknowledge_operations(&self, ids: &[Uuid])
+            -> Result<(), MemoryError>
+        {
+            self.run(|state| {
+                for (op, pending) in &mut state.operations {
+                    if ids.contains(&op.id) {
+                        *pending = false;
+                    }
+                }
+            }).await
+        }
+
+        async fn cancel_operation(&self, id: &Uuid)
+            -> Result<bool, MemoryError>
+        {
+            self.run(|state| {
+                let len = state.operations.len();
+                state.operations.retain(|(op, pending)| {
+                    !*pending || op.id != *id
+                });
+                state.operations.len() != len
+            }).await
+        }
+
+        async fn get_sync_cursor(&self, board_id: &Uuid)
+            -> Result<u64, MemoryError>
+        {
+            self.run(|state| {
+                state.sync_cursors.get(board_id).cloned().unwrap_or(0)
+            }).await
+        }
+
+        async fn set_sync_cursor(&self, board_id: &Uuid, cursor: u64)
+            -> Result<(), MemoryError>
+        {
+            self.run(|state| {
+                state.sync_cursors.insert(*board_id, cursor);
+            }).await
+        }
+
+        async fn prune_operations(&self, board_id: &Uuid)
+            -> Result<(), MemoryError>
+        {
+            self.run(|state| {
+                state.operations.retain(|(op, pending)| {
+                    *pending || op.board_id != *board_id
+                });
+            }).await
+        }
+
+        async fn get_replica_id(&self) -> Result<Uuid, MemoryError> {
+            self.run(|state| {
+                *state.replica_id.get_or_insert_with(Uuid::new_v4)
+            }).await
+        }
+
+        async fn add_activity(&self, activity: &Activity)
+            -> Result<(), MemoryError>
+        {
+            let activity = activity.clone();
+            self.run(move |state| state.activity.push(activity)).await
+        }
+
+        async fn get_activity(&self, id: &Uuid)
+            -> Result<Vec<Activity>, MemoryError>
+        {
+            self.run(|state| {
+                state.activity.iter()
+                    .filter(|a| a.board_id == *id || a.subject == *id)
+                    .cloned()
+                    .collect()
+            }).await
+        }
+    };
+}
+
+impl Store for MemoryStorage {
+    type Error = MemoryError;
+
+    store_methods!();
+}
+
+impl Storage for MemoryStorage {
+    type Transaction<'a> = MemoryTransaction<'a>;
+
+    async fn begin(&self) -> Result<MemoryTransaction<'_>, MemoryError> {
+        let lock = self.lock.lock().await;
+        self.check()?;
+        Ok(MemoryTransaction {
+            storage: self,
+            saved: Some(locked(&self.state).clone()),
+            _lock: lock,
+        })
+    }
+}
+
+/// A transaction on a `MemoryStorage`.
+///
+/// Changes are made to the storage right away, and undone if the transaction
+/// is rolled back; other calls wait until it is over, so they can't see them.
+pub struct MemoryTransaction<'a> {
+    storage: &'a MemoryStorage,
+    /// The state before the transaction, to roll back to
+    saved: Option<State>,
+    _lock: MutexGuard<'a, ()>,
+}
+
+impl MemoryTransaction<'_> {
+    fn run<T, F>(&self, f: F) -> Delayed<T>
+        where F: FnOnce(&mut State) -> T
+    {
+        self.storage.run_unlocked(f)
+    }
+}
+
+impl Store for MemoryTransaction<'_> {
+    type Error = MemoryError;
+
+    store_methods!();
+}
+
+impl Transaction for MemoryTransaction<'_> {
+    async fn commit(mut self) -> Result<(), MemoryError> {
+        let result = self.storage.check();
+        if result.is_ok() {
+            self.saved = None;
+        }
+        // Dropping the transaction rolls it back if that failed
+        let delayed = self.storage.delayed(result);
+        drop(self);
+        delayed.await
+    }
+
+    /// Rolling back never fails, so failures can be injected in the middle of
+    /// a transaction.
+    async fn rollback(self) -> Result<(), MemoryError> {
+        let delayed = self.storage.delayed(Ok(()));
+        drop(self);
+        delayed.await
+    }
+}
+
+impl Drop for MemoryTransaction<'_> {
+    fn drop(&mut self) {
+        if let Some(state) = self.saved.take() {
+            *locked(&self.storage.state) = state;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conformance;
+    use super::MemoryStorage;
+
+    #[test]
+    fn test_conformance() {
+        conformance::run(MemoryStorage::new);
+    }
+}
@@ -0,0 +1,132 @@
+//! Storage for the sync server.
+//!
+//! Besides the operation log of each board, the server keeps the latest
+//! snapshot of each board and the position up to which each replica received
+//! operations, so it can prune the operations every replica has; and the
+//! accounts of its users, with their roles on boards. Backends that can run
+//! a server implement `ServerStorage`.
+//!
+//! Passwords are hashed with Argon2; tokens are random, so a plain SHA-256 is
+//! enough to avoid storing them.
+
+use argon2::Argon2;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier,
+                            SaltString};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+use uuid::Uuid;
+
+use super::Operation;
+use super::snapshot::SnapshotData;
+use super::sync::{Credentials, Role};
+
+pub struct StoredSnapshot {
+    /// Position in the log the snapshot is at
+    pub cursor: u64,
+    /// Position up to which operations were pruned
+    pub pruned: u64,
+    pub data: SnapshotData,
+}
+
+/// The storage of a sync server.
+///
+/// The server calls it from the thread of each client, waiting for each
+/// call, so its methods are not async.
+pub trait ServerStorage: Send + Sync {
+    type Error: std::error::Error + 'static;
+
+    /// Create a user, or change their password.
+    fn set_password(&self, name: &str, password: &str)
+        -> Result<(), Self::Error>;
+    /// Create a new token for a user, or None if there is no such user.
+    fn create_token(&self, user: &str) -> Result<Option<String>, Self::Error>;
+    /// Check credentials, returning the name of the user if they are valid.
+    fn authenticate(&self, credentials: &Credentials)
+        -> Result<Option<String>, Self::Error>;
+    fn get_role(&self, board_id: &Uuid, user: &str)
+        -> Result<Option<Role>, Self::Error>;
+    /// Give a user a role on a board, or remove their access if `role` is
+    /// None. Returns false if there is no such user.
+    fn set_role(&self, board_id: &Uuid, user: &str, role: Option<Role>)
+        -> Result<bool, Self::Error>;
+    /// Make a user the owner of a board the server doesn't have yet, so
+    /// whoever creates a board owns it.
+    ///
+    /// Returns false if the board has operations or members already: those
+    /// have to be given access explicitly, with `set_role()`.
+    fn claim_board(&self, board_id: &Uuid, user: &str)
+        -> Result<bool, Self::Error>;
+
+    /// Get the operations for a board recorded after the given sequence
+    /// number, in order.
+    fn get_operations(&self, board_id: &Uuid, since: u64)
+        -> Result<Vec<(u64, Operation)>, Self::Error>;
+    /// Get the sequence number of the last operation recorded for a board.
+    ///
+    /// If the operations were all pruned, this is the snapshot's position.
+    fn get_last_seq(&self, board_id: &Uuid) -> Result<u64, Self::Error>;
+    /// Record operations with their pending flag, and apply those that
+    /// weren't known already, all in one transaction.
+    ///
+    /// Returns the number of operations that weren't known already.
+    fn receive_operations<I>(&self, operations: I)
+        -> Result<usize, Self::Error>
+        where I: IntoIterator<Item=(Operation, bool)>;
+
+    /// Record the position a replica reported for a board.
+    fn record_replica(&self, board_id: &Uuid, replica_id: &Uuid, cursor: u64)
+        -> Result<(), Self::Error>;
+    fn get_snapshot(&self, board_id: &Uuid)
+        -> Result<Option<StoredSnapshot>, Self::Error>;
+    /// Store a snapshot, unless we already have a more recent one.
+    fn store_snapshot(&self, board_id: &Uuid, cursor: u64,
+                      data: &SnapshotData)
+        -> Result<(), Self::Error>;
+    /// Count the operations recorded for a board after a position.
+    fn count_operations_since(&self, board_id: &Uuid, since: u64)
+        -> Result<u64, Self::Error>;
+    /// Delete the operations on a board that are covered by its snapshot
+    /// and that every replica seen in `forget_replicas_after` has received.
+    ///
+    /// Returns the number of operations deleted.
+    fn prune(&self, board_id: &Uuid, forget_replicas_after: Duration)
+        -> Result<usize, Self::Error>;
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Hash a password, to store it.
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Hashing password")
+        .to_string()
+}
+
+/// Check a password against a hash from `hash_password()`.
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        }
+        Err(_) => false,
+    }
+}
+
+/// Generate a new token.
+pub fn new_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+/// Hash a token, to store it or look it up.
+pub fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
@@ -0,0 +1,67 @@
+//! Snapshots of board state.
+//!
+//! A snapshot holds the state of a board after the operations up to some
+//! position in the server's log. Once every replica it knows has received
+//! those operations, the server can forget them, and new replicas start from
+//! the snapshot instead of replaying the whole history.
+//!
+//! Snapshots are taken by clients rather than by the server, since the
+//! server can't read the operations of encrypted boards.
+
+use serde::{Serialize, Deserialize};
+use uuid::Uuid;
+
+use super::{Board, Card, List, Store};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub board: Board,
+    pub lists: Vec<List>,
+    /// Missing from snapshots taken before boards had cards
+    #[serde(default)]
+    pub cards: Vec<Card>,
+}
+
+/// A snapshot as stored by the server, encrypted if the board is.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SnapshotData {
+    Plain { snapshot: Snapshot },
+    /// See `crypto`
+    Encrypted { key_id: String, data: String },
+}
+
+impl Snapshot {
+    /// Read the state of a board from storage, or None if it doesn't exist.
+    pub async fn take<S: Store>(storage: &S, board_id: &Uuid)
+        -> Result<Option<Snapshot>, S::Error>
+    {
+        let board = storage.get_board(board_id).await?;
+        let lists = storage.get_lists(board_id).await?;
+        let cards = storage.get_cards(board_id).await?;
+        Ok(board.map(|board| Snapshot { board, lists, cards }))
+    }
+
+    /// Replace the state of the board in storage with the snapshot's.
+    ///
+    /// Lists and cards the snapshot doesn't have are removed and the others
+    /// overwritten, so this can be used on a replica that missed operations
+    /// as well as on a new one. Run it in a transaction, so a failure doesn't
+    /// leave the board half restored.
+    pub async fn restore<S: Store>(self, storage: &S)
+        -> Result<(), S::Error>
+    {
+        let Snapshot { board, lists, cards } = self;
+        if storage.get_board(&board.id).await?.is_some() {
+            storage.delete_board(&board.id).await?;
+        }
+        storage.add_board(&board).await?;
+        for list in lists {
+            storage.add_list(&board.id, &list).await?;
+        }
+        for card in cards {
+            storage.add_card(&board.id, &card).await?;
+        }
+        Ok(())
+    }
+}
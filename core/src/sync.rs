@@ -0,0 +1,895 @@
+//! Protocol used to exchange operations between replicas and a server.
+//!
+//! Messages are encoded as JSON. A client opens the conversation with
+//! `ClientMessage::Hello`, indicating the protocol version it speaks, the
+//! board it wants to sync, and the cursor up to which it has already received
+//! operations from that server (0 if it never synced).
+//!
+//! The server answers with `ServerMessage::Welcome`, indicating the latest
+//! position in its log, then streams `ServerMessage::Operations` containing
+//! everything after the client's cursor, followed by new operations as they
+//! come in (including the ones the client pushed, so the cursor always moves
+//! forward in order). Operations the client pushes are acknowledged with
+//! `ServerMessage::Ack` once they have been stored.
+//!
+//! Clients identify as a replica, so the server knows which operations every
+//! replica has received. When a board's log gets long, the server asks a
+//! client that is up to date for a `Snapshot` of the board, which it then
+//! sends to new replicas (and to replicas it had forgotten) ahead of the
+//! remaining operations, letting it prune the operations the snapshot covers.
+//!
+//! If the server requires authentication, the client includes `Credentials`
+//! in its hello. Each user then has a `Role` on each board, which determines
+//! whether they can read it and push operations to it.
+//!
+//! `SyncClient` implements the client side of the conversation, over any
+//! `Transport`. If it is given a `Keyring`, the operations it pushes are
+//! encrypted, and it refuses to apply operations that aren't. If it is given
+//! a `Notifier` (or another `Observer`), the changes it receives are
+//! reported to the `App`.
+
+use serde::{Serialize, Deserialize};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::rc::Rc;
+use uuid::Uuid;
+
+use super::{Change, Observer, Operation, Storage, Store, transaction};
+use super::crypto::{CryptoError, Keyring};
+use super::snapshot::{Snapshot, SnapshotData};
+
+/// Version of the protocol implemented by this crate.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum Credentials {
+    Password { user: String, password: String },
+    Token { token: String },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Can read and write, manage other users' access, and send snapshots
+    Owner,
+    /// Can read and write
+    Editor,
+    /// Can only read
+    Viewer,
+}
+
+impl Role {
+    pub fn can_write(self) -> bool {
+        match self {
+            Role::Owner | Role::Editor => true,
+            Role::Viewer => false,
+        }
+    }
+
+    /// Whether the user can send snapshots, letting the server forget the
+    /// operations they cover.
+    pub fn can_snapshot(self) -> bool {
+        self == Role::Owner
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Owner => "owner",
+            Role::Editor => "editor",
+            Role::Viewer => "viewer",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Role> {
+        match name {
+            "owner" => Some(Role::Owner),
+            "editor" => Some(Role::Editor),
+            "viewer" => Some(Role::Viewer),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Hello {
+        version: u32,
+        board_id: Uuid,
+        cursor: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        credentials: Option<Credentials>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        replica_id: Option<Uuid>,
+    },
+    Push { operations: Vec<Operation> },
+    /// State of the board after the operations up to `cursor`
+    Snapshot { cursor: u64, snapshot: SnapshotData },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Welcome {
+        version: u32,
+        cursor: u64,
+        /// Whether the client should send a snapshot once it is up to date
+        #[serde(default)]
+        wants_snapshot: bool,
+    },
+    /// State of the board after the operations up to `cursor`, sent before
+    /// the following operations
+    Snapshot { cursor: u64, snapshot: SnapshotData },
+    Operations { operations: Vec<Operation>, cursor: u64 },
+    Ack { ids: Vec<Uuid> },
+    Error { error: SyncError },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SyncError {
+    /// The client speaks a version of the protocol the server doesn't know
+    UnsupportedVersion { supported: u32 },
+    /// The message couldn't be decoded
+    InvalidMessage { what: String },
+    /// The message is not valid at this point of the conversation
+    UnexpectedMessage,
+    /// An operation was pushed for a different board than the one being
+    /// synced
+    WrongBoard { board_id: Uuid },
+    /// The server failed to store the operations
+    Storage { what: String },
+    /// The credentials are missing or invalid
+    AuthenticationFailed,
+    /// The user's role on the board doesn't allow this (no role means they
+    /// don't have access to the board at all)
+    PermissionDenied { board_id: Uuid, role: Option<Role> },
+}
+
+impl std::error::Error for SyncError {}
+
+impl Display for SyncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            SyncError::UnsupportedVersion { supported } => {
+                write!(f, "Unsupported protocol version (server speaks {})",
+                       supported)
+            }
+            SyncError::InvalidMessage { ref what } => {
+                write!(f, "Invalid message: {}", what)
+            }
+            SyncError::UnexpectedMessage => write!(f, "Unexpected message"),
+            SyncError::WrongBoard { board_id } => {
+                write!(f, "Operation for wrong board {}",
+                       board_id.to_simple_ref())
+            }
+            SyncError::Storage { ref what } => {
+                write!(f, "Storage error: {}", what)
+            }
+            SyncError::AuthenticationFailed => {
+                write!(f, "Authentication failed")
+            }
+            SyncError::PermissionDenied { board_id, role: Some(role) } => {
+                write!(f, "Permission denied on board {} (you are {})",
+                       board_id.to_simple_ref(), role.name())
+            }
+            SyncError::PermissionDenied { board_id, role: None } => {
+                write!(f, "Permission denied on board {}",
+                       board_id.to_simple_ref())
+            }
+        }
+    }
+}
+
+impl ClientMessage {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Serializing message")
+    }
+
+    pub fn from_json(text: &str) -> Result<ClientMessage, SyncError> {
+        serde_json::from_str(text).map_err(|e| SyncError::InvalidMessage {
+            what: e.to_string(),
+        })
+    }
+}
+
+impl ServerMessage {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Serializing message")
+    }
+
+    pub fn from_json(text: &str) -> Result<ServerMessage, SyncError> {
+        serde_json::from_str(text).map_err(|e| SyncError::InvalidMessage {
+            what: e.to_string(),
+        })
+    }
+}
+
+/// Connection to a server, carrying encoded messages.
+#[allow(async_fn_in_trait)]
+pub trait Transport {
+    type Error: std::error::Error + 'static;
+
+    async fn send(&self, message: String) -> Result<(), Self::Error>;
+    /// Receive the next message, or None if the connection was closed.
+    async fn receive(&self) -> Result<Option<String>, Self::Error>;
+}
+
+#[derive(Debug)]
+pub enum ClientError<S, T> {
+    Storage(S),
+    Transport(T),
+    /// The server reported an error, or didn't follow the protocol
+    Sync(SyncError),
+    /// The server closed the connection
+    Disconnected,
+    /// An operation couldn't be decrypted
+    Crypto(CryptoError),
+}
+
+impl<S: Display, T: Display> Display for ClientError<S, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            ClientError::Storage(ref e) => write!(f, "Storage error: {}", e),
+            ClientError::Transport(ref e) => {
+                write!(f, "Connection error: {}", e)
+            }
+            ClientError::Sync(ref e) => write!(f, "{}", e),
+            ClientError::Disconnected => write!(f, "Disconnected by server"),
+            ClientError::Crypto(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl<S, T> std::error::Error for ClientError<S, T>
+    where S: std::error::Error + 'static, T: std::error::Error + 'static
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            ClientError::Storage(ref e) => Some(e),
+            ClientError::Transport(ref e) => Some(e),
+            ClientError::Sync(ref e) => Some(e),
+            ClientError::Disconnected => None,
+            ClientError::Crypto(ref e) => Some(e),
+        }
+    }
+}
+
+type ClientResult<I, S, T> = Result<
+    I,
+    ClientError<<S as Store>::Error, <T as Transport>::Error>,
+>;
+
+struct ClientState {
+    /// Position in the server's log up to which we received operations
+    cursor: u64,
+    /// Position of the server's log when we connected
+    server_cursor: u64,
+    /// Operations we sent that the server hasn't acknowledged yet
+    sent: HashSet<Uuid>,
+    /// Whether we pushed anything, in which case our state might be ahead
+    /// of our cursor
+    pushed: bool,
+    /// Whether the server asked for a snapshot
+    wants_snapshot: bool,
+}
+
+/// Client side of the sync protocol, for one board.
+pub struct SyncClient<S: Storage, T: Transport> {
+    storage: Rc<S>,
+    transport: T,
+    board_id: Uuid,
+    keyring: Option<Keyring>,
+    notifier: Option<Box<dyn Observer>>,
+    state: RefCell<ClientState>,
+}
+
+async fn receive_message<S: Storage, T: Transport>(transport: &T)
+    -> ClientResult<ServerMessage, S, T>
+{
+    match transport.receive().await.map_err(ClientError::Transport)? {
+        None => Err(ClientError::Disconnected),
+        Some(text) => {
+            ServerMessage::from_json(&text).map_err(ClientError::Sync)
+        }
+    }
+}
+
+/// Record and apply operations received from the server, and store the new
+/// cursor. Returns the operations that were new to us.
+async fn store_received<S: Store>(storage: &S, board_id: &Uuid,
+                                  operations: Vec<Operation>, cursor: u64)
+    -> Result<Vec<Operation>, S::Error>
+{
+    let mut applied = Vec::new();
+    for op in operations {
+        // Our own operations come back, we already applied them
+        if storage.add_operation(&op, false).await? {
+            op.apply(storage).await?;
+            applied.push(op);
+        }
+    }
+    storage.set_sync_cursor(board_id, cursor).await?;
+    Ok(applied)
+}
+
+impl<S: Storage + 'static, T: Transport> SyncClient<S, T> {
+    /// Open the conversation, resuming from the last cursor we stored.
+    pub async fn connect(storage: Rc<S>, transport: T, board_id: Uuid,
+                         credentials: Option<Credentials>)
+        -> ClientResult<SyncClient<S, T>, S, T>
+    {
+        // Send hello
+        let cursor = storage.get_sync_cursor(&board_id).await
+            .map_err(ClientError::Storage)?;
+        let replica_id = storage.get_replica_id().await
+            .map_err(ClientError::Storage)?;
+        let hello = ClientMessage::Hello {
+            version: PROTOCOL_VERSION,
+            board_id,
+            cursor,
+            credentials,
+            replica_id: Some(replica_id),
+        };
+        transport.send(hello.to_json()).await
+            .map_err(ClientError::Transport)?;
+
+        // Get welcome
+        match receive_message::<S, T>(&transport).await? {
+            ServerMessage::Welcome {
+                version, cursor: server_cursor, wants_snapshot,
+            }
+            if version == PROTOCOL_VERSION => {
+                Ok(SyncClient {
+                    storage,
+                    transport,
+                    board_id,
+                    keyring: None,
+                    notifier: None,
+                    state: RefCell::new(ClientState {
+                        cursor,
+                        server_cursor,
+                        sent: HashSet::new(),
+                        pushed: false,
+                        wants_snapshot,
+                    }),
+                })
+            }
+            ServerMessage::Error { error } => Err(ClientError::Sync(error)),
+            _ => Err(ClientError::Sync(SyncError::UnexpectedMessage)),
+        }
+    }
+
+    /// Encrypt the operations we push, and decrypt the ones we receive.
+    pub fn encrypted(mut self, keyring: Keyring) -> SyncClient<S, T> {
+        self.keyring = Some(keyring);
+        self
+    }
+
+    /// Report the changes we receive, so views get updated.
+    pub fn notifying<N>(mut self, notifier: N) -> SyncClient<S, T>
+        where N: Observer + 'static
+    {
+        self.notifier = Some(Box::new(notifier));
+        self
+    }
+
+    pub fn board_id(&self) -> &Uuid {
+        &self.board_id
+    }
+
+    /// Whether we received everything the server had when we connected, and
+    /// all the operations we sent have been acknowledged.
+    pub fn is_up_to_date(&self) -> bool {
+        let state = self.state.borrow();
+        state.cursor >= state.server_cursor && state.sent.is_empty()
+    }
+
+    /// Send the local operations that the server hasn't acknowledged yet.
+    pub async fn push(&self) -> ClientResult<(), S, T> {
+        let operations = self.storage.get_pending_operations(&self.board_id)
+            .await
+            .map_err(ClientError::Storage)?;
+        if operations.is_empty() {
+            return Ok(());
+        }
+        {
+            let mut state = self.state.borrow_mut();
+            state.sent.extend(operations.iter().map(|op| op.id));
+            state.pushed = true;
+        }
+        let operations = match self.keyring {
+            Some(ref keyring) => {
+                operations.iter().map(|op| keyring.encrypt(op)).collect()
+            }
+            // Groups are only used locally
+            None => {
+                operations.into_iter()
+                    .map(|op| Operation { group: None, ..op })
+                    .collect()
+            }
+        };
+        let push = ClientMessage::Push { operations };
+        self.transport.send(push.to_json()).await
+            .map_err(ClientError::Transport)
+    }
+
+    /// Wait for a message from the server and handle it.
+    pub async fn receive(&self) -> ClientResult<(), S, T> {
+        match receive_message::<S, T>(&self.transport).await? {
+            ServerMessage::Operations { operations, cursor } => {
+                self.receive_operations(operations, cursor).await?;
+                self.state.borrow_mut().cursor = cursor;
+                Ok(())
+            }
+            ServerMessage::Snapshot { cursor, snapshot } => {
+                self.receive_snapshot(snapshot, cursor).await?;
+                self.state.borrow_mut().cursor = cursor;
+                Ok(())
+            }
+            ServerMessage::Ack { ids } => {
+                {
+                    let mut state = self.state.borrow_mut();
+                    for id in &ids {
+                        state.sent.remove(id);
+                    }
+                }
+                self.storage.acknowledge_operations(&ids).await
+                    .map_err(ClientError::Storage)
+            }
+            ServerMessage::Error { error } => Err(ClientError::Sync(error)),
+            ServerMessage::Welcome { .. } => {
+                Err(ClientError::Sync(SyncError::UnexpectedMessage))
+            }
+        }
+    }
+
+    /// Apply operations received from the server, and store the new cursor.
+    async fn receive_operations(&self, operations: Vec<Operation>,
+                                cursor: u64)
+        -> ClientResult<(), S, T>
+    {
+        // Decrypt everything first, so we don't apply only part of a batch
+        let operations = self.decrypt_operations(operations)
+            .map_err(ClientError::Crypto)?;
+        // The batch and the new cursor are stored together
+        let storage = &*self.storage;
+        let applied = transaction(storage, async |tx| {
+            store_received(tx, &self.board_id, operations, cursor).await
+        }).await.map_err(ClientError::Storage)?;
+        // Only report the changes once they are committed
+        if let Some(ref notifier) = self.notifier {
+            for op in &applied {
+                notifier.applied(op);
+            }
+        }
+        Ok(())
+    }
+
+    /// Restore a snapshot received from the server, and store its cursor.
+    async fn receive_snapshot(&self, data: SnapshotData, cursor: u64)
+        -> ClientResult<(), S, T>
+    {
+        let board_id = self.board_id;
+        let snapshot = match (&self.keyring, data) {
+            (Some(keyring), data) => {
+                keyring.decrypt_snapshot(&data, &board_id, cursor)
+            }
+            (None, SnapshotData::Plain { snapshot }) => Ok(snapshot),
+            (None, SnapshotData::Encrypted { key_id, .. }) => {
+                Err(CryptoError::UnknownKey { key_id })
+            }
+        };
+        let snapshot = snapshot.map_err(ClientError::Crypto)?;
+        if snapshot.board.id != board_id {
+            return Err(ClientError::Sync(SyncError::InvalidMessage {
+                what: "Snapshot is for another board".into(),
+            }));
+        }
+        let storage = &*self.storage;
+        transaction(storage, async |tx| {
+            snapshot.restore(tx).await?;
+            tx.set_sync_cursor(&board_id, cursor).await
+        }).await.map_err(ClientError::Storage)?;
+        if let Some(ref notifier) = self.notifier {
+            notifier.restored(&board_id);
+        }
+        Ok(())
+    }
+
+    /// Send a snapshot of the board at our cursor.
+    ///
+    /// Our state has to match our cursor, so this is only done if we are up
+    /// to date and haven't pushed anything: the server might have put our
+    /// operations after other ones we haven't received yet.
+    async fn send_snapshot(&self) -> ClientResult<(), S, T> {
+        let (cursor, can_snapshot) = {
+            let state = self.state.borrow();
+            (
+                state.cursor,
+                state.wants_snapshot && !state.pushed &&
+                    state.cursor >= state.server_cursor,
+            )
+        };
+        if !can_snapshot {
+            return Ok(());
+        }
+        let snapshot = Snapshot::take(&*self.storage, &self.board_id).await
+            .map_err(ClientError::Storage)?;
+        let snapshot = match snapshot {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        let snapshot = match self.keyring {
+            Some(ref keyring) => keyring.encrypt_snapshot(&snapshot, cursor),
+            None => SnapshotData::Plain { snapshot },
+        };
+        let msg = ClientMessage::Snapshot { cursor, snapshot };
+        self.transport.send(msg.to_json()).await
+            .map_err(ClientError::Transport)
+    }
+
+    /// Forget the operations the server acknowledged.
+    ///
+    /// This keeps the local log small, but those operations can then no
+    /// longer be sent to peers directly.
+    pub async fn prune(&self) -> ClientResult<(), S, T> {
+        self.storage.prune_operations(&self.board_id).await
+            .map_err(ClientError::Storage)
+    }
+
+    fn decrypt_operations(&self, operations: Vec<Operation>)
+        -> Result<Vec<Operation>, CryptoError>
+    {
+        match self.keyring {
+            Some(ref keyring) => {
+                operations.iter().map(|op| keyring.decrypt(op)).collect()
+            }
+            None => {
+                // Without a key, we'd record operations we can't apply
+                for op in &operations {
+                    if let Change::Encrypted { ref key_id, .. } = op.change {
+                        return Err(CryptoError::UnknownKey {
+                            key_id: key_id.clone(),
+                        });
+                    }
+                }
+                Ok(operations)
+            }
+        }
+    }
+
+    /// Push our operations, then receive operations until we are up to date,
+    /// and send a snapshot if the server asked for one.
+    pub async fn sync(&self) -> ClientResult<(), S, T> {
+        self.push().await?;
+        while !self.is_up_to_date() {
+            self.receive().await?;
+        }
+        self.send_snapshot().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use std::cell::{Cell, RefCell};
+    use std::collections::VecDeque;
+    use std::convert::Infallible;
+    use std::rc::Rc;
+    use uuid::Uuid;
+
+    use crate::{Change, List, Operation, Store};
+    use crate::memory::MemoryStorage;
+    use super::{ClientError, ClientMessage, Credentials, PROTOCOL_VERSION,
+                ServerMessage, SyncClient, SyncError, Transport};
+
+    /// The server's log of operations for one board, shared by connections.
+    #[derive(Default)]
+    struct Server {
+        log: RefCell<Vec<Operation>>,
+    }
+
+    impl Server {
+        fn operations(&self, from: u64) -> ServerMessage {
+            let log = self.log.borrow();
+            ServerMessage::Operations {
+                operations: log[from as usize..].to_vec(),
+                cursor: log.len() as u64,
+            }
+        }
+
+        /// Handle a message from a client, returning the answers.
+        fn handle(&self, msg: ClientMessage) -> Vec<ServerMessage> {
+            match msg {
+                ClientMessage::Hello { version, cursor, .. } => {
+                    if version != PROTOCOL_VERSION {
+                        return vec![ServerMessage::Error {
+                            error: SyncError::UnsupportedVersion {
+                                supported: PROTOCOL_VERSION,
+                            },
+                        }];
+                    }
+                    let len = self.log.borrow().len() as u64;
+                    let mut answers = vec![ServerMessage::Welcome {
+                        version: PROTOCOL_VERSION,
+                        cursor: len,
+                        wants_snapshot: false,
+                    }];
+                    if cursor < len {
+                        answers.push(self.operations(cursor));
+                    }
+                    answers
+                }
+                ClientMessage::Push { operations } => {
+                    let from = self.log.borrow().len() as u64;
+                    let ids = operations.iter().map(|op| op.id).collect();
+                    self.log.borrow_mut().extend(operations);
+                    vec![ServerMessage::Ack { ids }, self.operations(from)]
+                }
+                ClientMessage::Snapshot { .. } => vec![],
+            }
+        }
+    }
+
+    /// Connection to the in-memory server, that can be set to drop after a
+    /// number of messages.
+    struct MemoryTransport {
+        server: Rc<Server>,
+        sent: RefCell<Vec<ClientMessage>>,
+        inbox: RefCell<VecDeque<String>>,
+        remaining: Cell<Option<usize>>,
+    }
+
+    impl MemoryTransport {
+        fn new(server: &Rc<Server>) -> MemoryTransport {
+            MemoryTransport {
+                server: server.clone(),
+                sent: RefCell::new(Vec::new()),
+                inbox: RefCell::new(VecDeque::new()),
+                remaining: Cell::new(None),
+            }
+        }
+
+        fn closing_after(server: &Rc<Server>, messages: usize)
+            -> MemoryTransport
+        {
+            let transport = MemoryTransport::new(server);
+            transport.remaining.set(Some(messages));
+            transport
+        }
+    }
+
+    type Client<'a> = SyncClient<MemoryStorage, &'a MemoryTransport>;
+
+    impl Transport for &MemoryTransport {
+        type Error = Infallible;
+
+        async fn send(&self, message: String) -> Result<(), Infallible> {
+            let msg = ClientMessage::from_json(&message).unwrap();
+            self.sent.borrow_mut().push(msg.clone());
+            let mut inbox = self.inbox.borrow_mut();
+            for answer in self.server.handle(msg) {
+                inbox.push_back(answer.to_json());
+            }
+            Ok(())
+        }
+
+        async fn receive(&self) -> Result<Option<String>, Infallible> {
+            match self.remaining.get() {
+                Some(0) => return Ok(None),
+                Some(n) => self.remaining.set(Some(n - 1)),
+                None => {}
+            }
+            Ok(self.inbox.borrow_mut().pop_front())
+        }
+    }
+
+    fn connect<'a>(storage: &Rc<MemoryStorage>,
+                   transport: &'a MemoryTransport, board_id: Uuid)
+        -> Client<'a>
+    {
+        block_on(SyncClient::connect(storage.clone(), transport, board_id,
+                                     None)).unwrap()
+    }
+
+    /// Make a change locally, as the app would.
+    fn change(storage: &MemoryStorage, board_id: Uuid, change: Change) {
+        let op = Operation::new(board_id, change);
+        assert!(block_on(storage.add_operation(&op, true)).unwrap());
+        block_on(op.apply(storage)).unwrap();
+    }
+
+    fn add_list(storage: &MemoryStorage, board_id: Uuid, name: &str) {
+        change(storage, board_id, Change::AddList {
+            list: List { id: Uuid::new_v4(), name: name.into() },
+        });
+    }
+
+    fn list_names(storage: &MemoryStorage, board_id: &Uuid) -> Vec<String> {
+        let mut names: Vec<String> = block_on(storage.get_lists(board_id))
+            .unwrap().into_iter().map(|l| l.name).collect();
+        names.sort();
+        names
+    }
+
+    fn hello_cursor(transport: &MemoryTransport) -> u64 {
+        match transport.sent.borrow()[0] {
+            ClientMessage::Hello { cursor, replica_id: Some(_), .. } => cursor,
+            ref m => panic!("Unexpected message {:?}", m),
+        }
+    }
+
+    #[test]
+    fn test_client_handshake() {
+        let server = Rc::new(Server::default());
+        let storage = Rc::new(MemoryStorage::new());
+        let board_id = Uuid::new_v4();
+
+        let transport = MemoryTransport::new(&server);
+        let client = connect(&storage, &transport, board_id);
+        assert_eq!(hello_cursor(&transport), 0);
+        assert!(client.is_up_to_date());
+        block_on(client.sync()).unwrap();
+        assert_eq!(transport.sent.borrow().len(), 1);
+
+        // The client gives up if the server doesn't say welcome
+        let transport = MemoryTransport::new(&server);
+        transport.inbox.borrow_mut().push_back(ServerMessage::Error {
+            error: SyncError::AuthenticationFailed,
+        }.to_json());
+        match block_on(SyncClient::connect(storage.clone(), &transport,
+                                           board_id, None)) {
+            Err(ClientError::Sync(SyncError::AuthenticationFailed)) => {}
+            r => panic!("Unexpected result {:?}", r.map(|_| ())),
+        }
+        let transport = MemoryTransport::closing_after(&server, 0);
+        match block_on(SyncClient::connect(storage.clone(), &transport,
+                                           board_id, None)) {
+            Err(ClientError::Disconnected) => {}
+            r => panic!("Unexpected result {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_client_push_receive() {
+        let server = Rc::new(Server::default());
+        let first = Rc::new(MemoryStorage::new());
+        let second = Rc::new(MemoryStorage::new());
+        let board_id = Uuid::new_v4();
+        change(&first, board_id, Change::AddBoard { name: "Work".into() });
+        add_list(&first, board_id, "todo");
+
+        // Pushing sends the pending operations, which get acknowledged
+        let transport = MemoryTransport::new(&server);
+        let client = connect(&first, &transport, board_id);
+        block_on(client.push()).unwrap();
+        assert!(!client.is_up_to_date());
+        assert_eq!(server.log.borrow().len(), 2);
+        block_on(client.receive()).unwrap();
+        assert!(block_on(first.get_pending_operations(&board_id))
+                .unwrap().is_empty());
+        // Our own operations come back, and are not applied twice
+        block_on(client.receive()).unwrap();
+        assert!(client.is_up_to_date());
+        assert_eq!(list_names(&first, &board_id), vec!["todo"]);
+        assert_eq!(block_on(first.get_sync_cursor(&board_id)).unwrap(), 2);
+
+        // Another replica receives them
+        let transport = MemoryTransport::new(&server);
+        let client = connect(&second, &transport, board_id);
+        assert!(!client.is_up_to_date());
+        block_on(client.sync()).unwrap();
+        let board = block_on(second.get_board(&board_id)).unwrap().unwrap();
+        assert_eq!(board.name, "Work");
+        assert_eq!(list_names(&second, &board_id), vec!["todo"]);
+        assert_eq!(block_on(second.get_sync_cursor(&board_id)).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_client_resume() {
+        let server = Rc::new(Server::default());
+        let first = Rc::new(MemoryStorage::new());
+        let second = Rc::new(MemoryStorage::new());
+        let board_id = Uuid::new_v4();
+        change(&first, board_id, Change::AddBoard { name: "Work".into() });
+        add_list(&first, board_id, "todo");
+        let transport = MemoryTransport::new(&server);
+        block_on(connect(&first, &transport, board_id).sync()).unwrap();
+
+        // The connection drops before we receive anything
+        let transport = MemoryTransport::closing_after(&server, 1);
+        let client = connect(&second, &transport, board_id);
+        match block_on(client.sync()) {
+            Err(ClientError::Disconnected) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+        assert_eq!(block_on(second.get_sync_cursor(&board_id)).unwrap(), 0);
+
+        // Reconnecting starts over
+        let transport = MemoryTransport::new(&server);
+        block_on(connect(&second, &transport, board_id).sync()).unwrap();
+        assert_eq!(hello_cursor(&transport), 0);
+        assert_eq!(list_names(&second, &board_id), vec!["todo"]);
+
+        // Then later connections only get what's new
+        add_list(&first, board_id, "done");
+        let transport = MemoryTransport::new(&server);
+        block_on(connect(&first, &transport, board_id).sync()).unwrap();
+        let transport = MemoryTransport::new(&server);
+        block_on(connect(&second, &transport, board_id).sync()).unwrap();
+        assert_eq!(hello_cursor(&transport), 2);
+        match transport.sent.borrow().len() {
+            1 => {}
+            n => panic!("Client sent {} messages", n),
+        }
+        assert_eq!(list_names(&second, &board_id), vec!["done", "todo"]);
+        assert_eq!(block_on(second.get_sync_cursor(&board_id)).unwrap(), 3);
+
+        // Nothing to receive when up to date
+        let transport = MemoryTransport::new(&server);
+        let client = connect(&second, &transport, board_id);
+        assert_eq!(hello_cursor(&transport), 3);
+        assert!(client.is_up_to_date());
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let board_id = Uuid::parse_str("936DA01F9ABD4D9D80C70000BBBB0000")
+            .unwrap();
+        let msg = ClientMessage::Push {
+            operations: vec![
+                Operation::new(board_id, Change::AddBoard {
+                    name: "board".into(),
+                }),
+                Operation::new(board_id, Change::AddList {
+                    list: List {
+                        id: Uuid::new_v4(),
+                        name: "todo".into(),
+                    },
+                }),
+            ],
+        };
+        assert_eq!(ClientMessage::from_json(&msg.to_json()).unwrap(), msg);
+
+        let msg = ServerMessage::Error {
+            error: SyncError::UnsupportedVersion { supported: 1 },
+        };
+        assert_eq!(ServerMessage::from_json(&msg.to_json()).unwrap(), msg);
+    }
+
+    #[test]
+    fn test_invalid() {
+        match ClientMessage::from_json("{\"type\": \"hello\"}") {
+            Err(SyncError::InvalidMessage { .. }) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_credentials() {
+        // Credentials are optional
+        let msg = ClientMessage::from_json(
+            "{\"type\": \"hello\", \"version\": 1, \
+              \"board_id\": \"936DA01F9ABD4D9D80C70000BBBB0000\", \
+              \"cursor\": 0}",
+        ).unwrap();
+        match msg {
+            ClientMessage::Hello {
+                credentials: None, replica_id: None, ..
+            } => {}
+            m => panic!("Unexpected message {:?}", m),
+        }
+
+        let msg = ClientMessage::Hello {
+            version: 1,
+            board_id: Uuid::new_v4(),
+            cursor: 12,
+            credentials: Some(Credentials::Token { token: "abc".into() }),
+            replica_id: Some(Uuid::new_v4()),
+        };
+        assert_eq!(ClientMessage::from_json(&msg.to_json()).unwrap(), msg);
+    }
+}
@@ -0,0 +1,120 @@
+//! Pointer and cell types used by `App`.
+//!
+//! In the browser everything happens on one thread, so `Local` uses `Rc` and
+//! `RefCell`. A native server handling many connections can use `Shared`
+//! instead, which uses `Arc` and `RwLock`, to share a `SharedApp` between its
+//! threads.
+
+use std::cell::{self, RefCell};
+use std::ops::{Deref, DerefMut};
+use std::rc::{self, Rc};
+use std::sync::{self, Arc, PoisonError, RwLock, RwLockReadGuard,
+                RwLockWriteGuard};
+
+use super::events::Event;
+
+pub trait Threading: Sized + 'static {
+    type Ptr<T>: Clone + Deref<Target=T>;
+    type Weak<T>;
+    type Cell<T>;
+    type Ref<'a, T: 'a>: Deref<Target=T>;
+    type RefMut<'a, T: 'a>: DerefMut<Target=T>;
+    /// A function called with change events, see `events::Subscribers`
+    type Callback: Clone;
+
+    fn new<T>(value: T) -> Self::Ptr<T>;
+    fn downgrade<T>(ptr: &Self::Ptr<T>) -> Self::Weak<T>;
+    fn upgrade<T>(weak: &Self::Weak<T>) -> Option<Self::Ptr<T>>;
+
+    fn cell<T>(value: T) -> Self::Cell<T>;
+    fn borrow<T>(cell: &Self::Cell<T>) -> Self::Ref<'_, T>;
+    fn borrow_mut<T>(cell: &Self::Cell<T>) -> Self::RefMut<'_, T>;
+
+    fn call(callback: &Self::Callback, event: &Event);
+}
+
+/// Single-threaded, for the browser.
+pub struct Local;
+
+impl Threading for Local {
+    type Ptr<T> = Rc<T>;
+    type Weak<T> = rc::Weak<T>;
+    type Cell<T> = RefCell<T>;
+    type Ref<'a, T: 'a> = cell::Ref<'a, T>;
+    type RefMut<'a, T: 'a> = cell::RefMut<'a, T>;
+    type Callback = Rc<dyn Fn(&Event)>;
+
+    fn new<T>(value: T) -> Rc<T> {
+        Rc::new(value)
+    }
+
+    fn downgrade<T>(ptr: &Rc<T>) -> rc::Weak<T> {
+        Rc::downgrade(ptr)
+    }
+
+    fn upgrade<T>(weak: &rc::Weak<T>) -> Option<Rc<T>> {
+        weak.upgrade()
+    }
+
+    fn cell<T>(value: T) -> RefCell<T> {
+        RefCell::new(value)
+    }
+
+    fn borrow<T>(cell: &RefCell<T>) -> cell::Ref<'_, T> {
+        cell.borrow()
+    }
+
+    fn borrow_mut<T>(cell: &RefCell<T>) -> cell::RefMut<'_, T> {
+        cell.borrow_mut()
+    }
+
+    fn call(callback: &Self::Callback, event: &Event) {
+        callback(event)
+    }
+}
+
+/// Thread-safe, for sharing an `App` between the threads of a server.
+///
+/// The storage has to be `Send` and `Sync` too, like `MemoryStorage` and the
+/// SQLite and PostgreSQL backends.
+pub struct Shared;
+
+impl Threading for Shared {
+    type Ptr<T> = Arc<T>;
+    type Weak<T> = sync::Weak<T>;
+    type Cell<T> = RwLock<T>;
+    type Ref<'a, T: 'a> = RwLockReadGuard<'a, T>;
+    type RefMut<'a, T: 'a> = RwLockWriteGuard<'a, T>;
+    type Callback = Arc<dyn Fn(&Event) + Send + Sync>;
+
+    fn new<T>(value: T) -> Arc<T> {
+        Arc::new(value)
+    }
+
+    fn downgrade<T>(ptr: &Arc<T>) -> sync::Weak<T> {
+        Arc::downgrade(ptr)
+    }
+
+    fn upgrade<T>(weak: &sync::Weak<T>) -> Option<Arc<T>> {
+        weak.upgrade()
+    }
+
+    fn cell<T>(value: T) -> RwLock<T> {
+        RwLock::new(value)
+    }
+
+    // A panic while a lock was held doesn't leave the cached state invalid,
+    // each change is made at once
+
+    fn borrow<T>(cell: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+        cell.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn borrow_mut<T>(cell: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+        cell.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn call(callback: &Self::Callback, event: &Event) {
+        callback(event)
+    }
+}
@@ -0,0 +1,1201 @@
+//! Plain-file storage for tripledeck.
+//!
+//! `FileStorage` keeps each board in a directory as a JSON file, written with
+//! stable key ordering and one line per field, so boards can be kept in a
+//! git repository and their changes reviewed as diffs:
+//!
+//! ```text
+//! boards/<board id>.json       the board, its lists, then its cards
+//! .tripledeck/state.json       replica ID, sync cursors
+//! .tripledeck/operations.jsonl operation log, one change per line
+//! .tripledeck/activity.jsonl   activity, one entry per line
+//! .tripledeck/journal.json     files being changed together, if interrupted
+//! .tripledeck/lock             held while the directory is open
+//! ```
+//!
+//! The `.tripledeck` directory describes this copy of the boards (which
+//! operations still have to be sent, the replica's ID) and is ignored by git.
+//!
+//! The whole directory is read when it is opened, and files are replaced
+//! atomically when changes are made: the new files are written next to the
+//! old ones, then the journal lists them before they are moved in place, so
+//! that if this is interrupted it can be finished when the directory is
+//! opened again. The operation log and the activity only grow, so changes
+//! are appended to them instead; the log is only written again when
+//! operations are pruned from it. A lock is held until the storage is
+//! dropped, so another process trying to open the same directory fails
+//! instead of overwriting its changes.
+
+extern crate fs2;
+extern crate futures;
+extern crate serde;
+extern crate serde_json;
+extern crate tripledeck_core;
+extern crate uuid;
+
+use fs2::FileExt;
+use futures::lock::{Mutex, MutexGuard};
+use serde::{Serialize, Deserialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+use tripledeck_core::{Board, Card, List, Operation, Storage, Store,
+                      Transaction};
+use tripledeck_core::activity::Activity;
+
+/// Version of the files' format this code writes.
+///
+/// Version 1 kept the operation log and activity in the state file.
+const FORMAT_VERSION: u32 = 2;
+
+#[derive(Debug)]
+pub enum FileError {
+    Io(PathBuf, io::Error),
+    /// A file couldn't be read or written as JSON
+    Json(PathBuf, serde_json::Error),
+    /// Another process has the directory open
+    Locked(PathBuf),
+    /// The directory was written by a newer version of the program
+    TooNew { version: u32, supported: u32 },
+    /// Lists and cards can only be added to boards that exist, they would
+    /// have no file to go in
+    NoBoard(Uuid),
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FileError::Io(ref path, ref e) => {
+                write!(f, "{}: {}", path.display(), e)
+            }
+            FileError::Json(ref path, ref e) => {
+                write!(f, "Invalid file {}: {}", path.display(), e)
+            }
+            FileError::Locked(ref path) => write!(
+                f,
+                "{} is in use by another process",
+                path.display(),
+            ),
+            FileError::TooNew { version, supported } => write!(
+                f,
+                "Files have format version {}, but this version of \
+                 tripledeck only supports up to {}; please upgrade",
+                version, supported,
+            ),
+            FileError::NoBoard(ref id) => write!(f, "No board {}", id),
+        }
+    }
+}
+
+impl std::error::Error for FileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            FileError::Io(_, ref e) => Some(e),
+            FileError::Json(_, ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// A board file, the board with its lists and cards.
+#[derive(Clone, Serialize, Deserialize)]
+struct BoardFile {
+    id: Uuid,
+    name: String,
+    lists: Vec<List>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    cards: Vec<Card>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct LoggedOperation {
+    operation: Operation,
+    pending: bool,
+}
+
+/// A change to the operation log, one per line of its file.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum LogEntry {
+    Add(Box<LoggedOperation>),
+    /// The operations were sent, they are no longer pending
+    Acknowledge(Vec<Uuid>),
+    /// The pending operation was removed
+    Cancel(Uuid),
+}
+
+impl LogEntry {
+    /// Make the change to the log, returning whether anything changed.
+    fn apply(&self, operations: &mut Vec<LoggedOperation>) -> bool {
+        match *self {
+            LogEntry::Add(ref op) => {
+                operations.push((**op).clone());
+                true
+            }
+            LogEntry::Acknowledge(ref ids) => {
+                let mut changed = false;
+                for o in operations.iter_mut() {
+                    if o.pending && ids.contains(&o.operation.id) {
+                        o.pending = false;
+                        changed = true;
+                    }
+                }
+                changed
+            }
+            LogEntry::Cancel(ref id) => {
+                let len = operations.len();
+                operations.retain(|o| !o.pending || o.operation.id != *id);
+                operations.len() != len
+            }
+        }
+    }
+}
+
+/// The state of this copy of the boards, kept out of the board files.
+#[derive(Clone, Serialize, Deserialize)]
+struct State {
+    version: u32,
+    replica_id: Option<Uuid>,
+    sync_cursors: BTreeMap<Uuid, u64>,
+}
+
+impl Default for State {
+    fn default() -> State {
+        State {
+            version: FORMAT_VERSION,
+            replica_id: None,
+            sync_cursors: BTreeMap::new(),
+        }
+    }
+}
+
+/// What the state file also had in version 1.
+#[derive(Deserialize)]
+struct StateV1 {
+    operations: Vec<LoggedOperation>,
+    activity: Vec<Activity>,
+}
+
+#[derive(Clone, Default)]
+struct Data {
+    boards: BTreeMap<Uuid, BoardFile>,
+    state: State,
+    operations: Vec<LoggedOperation>,
+    activity: Vec<Activity>,
+}
+
+/// Changes to a file of JSON lines.
+struct Lines<T> {
+    /// Lines to append
+    added: Vec<T>,
+    /// Whether the file has to be written again entirely instead
+    rewrite: bool,
+}
+
+impl<T> Default for Lines<T> {
+    fn default() -> Lines<T> {
+        Lines { added: Vec::new(), rewrite: false }
+    }
+}
+
+impl<T> Lines<T> {
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && !self.rewrite
+    }
+}
+
+/// What changed since the files were last written.
+#[derive(Default)]
+struct Dirty {
+    boards: BTreeSet<Uuid>,
+    state: bool,
+    operations: Lines<LogEntry>,
+    activity: Lines<Activity>,
+}
+
+impl Dirty {
+    fn is_empty(&self) -> bool {
+        self.boards.is_empty() && !self.state
+            && self.operations.is_empty() && self.activity.is_empty()
+    }
+
+    /// Forget the lines to append, after the data they were added to was
+    /// restored.
+    fn forget_added(&mut self) {
+        self.operations.added.clear();
+        self.activity.added.clear();
+    }
+}
+
+/// Files being changed together, relative to the directory.
+#[derive(Default, Serialize, Deserialize)]
+struct Journal {
+    /// Files to move over these, from the same path with `.tmp` added
+    replace: Vec<PathBuf>,
+    remove: Vec<PathBuf>,
+    #[serde(default)]
+    append: Vec<Append>,
+}
+
+/// Lines to append to a file, once it is cut back to the length it had
+/// before, so appending them again after an interruption doesn't repeat
+/// them.
+#[derive(Serialize, Deserialize)]
+struct Append {
+    path: PathBuf,
+    length: u64,
+    lines: String,
+}
+
+pub struct FileStorage {
+    path: PathBuf,
+    data: RefCell<Data>,
+    dirty: RefCell<Dirty>,
+    /// The data before the current transaction, to roll back to
+    saved: RefCell<Option<Data>>,
+    /// Held by the transaction in progress
+    transaction: Mutex<()>,
+    /// Held open to keep the lock
+    _lock: File,
+}
+
+/// The storage, for a call made while no other transaction is in progress.
+struct Locked<'a> {
+    storage: &'a FileStorage,
+    _lock: Option<MutexGuard<'a, ()>>,
+}
+
+impl Deref for Locked<'_> {
+    type Target = FileStorage;
+
+    fn deref(&self) -> &FileStorage {
+        self.storage
+    }
+}
+
+fn io_error(path: &Path) -> impl FnOnce(io::Error) -> FileError + '_ {
+    move |e| FileError::Io(path.to_owned(), e)
+}
+
+fn read_json<T>(path: &Path) -> Result<T, FileError>
+    where T: serde::de::DeserializeOwned
+{
+    let file = File::open(path).map_err(io_error(path))?;
+    serde_json::from_reader(io::BufReader::new(file))
+        .map_err(|e| FileError::Json(path.to_owned(), e))
+}
+
+/// Read a file of JSON lines.
+///
+/// A last line without a line ending was only partly written, it is removed.
+fn read_lines<T>(path: &Path) -> Result<Vec<T>, FileError>
+    where T: serde::de::DeserializeOwned
+{
+    let content = match fs::read(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(Vec::new());
+        }
+        res => res.map_err(io_error(path))?,
+    };
+    let end = content.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+    if end < content.len() {
+        let file = OpenOptions::new().write(true).open(path)
+            .map_err(io_error(path))?;
+        file.set_len(end as u64).map_err(io_error(path))?;
+    }
+    content[..end].split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            serde_json::from_slice(line)
+                .map_err(|e| FileError::Json(path.to_owned(), e))
+        })
+        .collect()
+}
+
+fn to_json<T: Serialize>(path: &Path, value: &T)
+    -> Result<Vec<u8>, FileError>
+{
+    let mut content = serde_json::to_vec_pretty(value)
+        .map_err(|e| FileError::Json(path.to_owned(), e))?;
+    content.push(b'\n');
+    Ok(content)
+}
+
+fn to_lines<I>(path: &Path, values: I) -> Result<String, FileError>
+    where I: IntoIterator, I::Item: Serialize
+{
+    let mut content = String::new();
+    for value in values {
+        content += &serde_json::to_string(&value)
+            .map_err(|e| FileError::Json(path.to_owned(), e))?;
+        content.push('\n');
+    }
+    Ok(content)
+}
+
+fn temporary(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    tmp.into()
+}
+
+/// Write a temporary file next to the one it will replace.
+fn write_temporary(path: &Path, content: &[u8]) -> Result<(), FileError> {
+    let tmp = temporary(path);
+    let mut file = File::create(&tmp).map_err(io_error(&tmp))?;
+    file.write_all(content).map_err(io_error(&tmp))?;
+    file.sync_all().map_err(io_error(&tmp))
+}
+
+/// Replace a file atomically: write a temporary file next to it, then move
+/// it over the original.
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), FileError> {
+    write_temporary(path, &to_json(path, value)?)?;
+    fs::rename(temporary(path), path).map_err(io_error(path))
+}
+
+/// Append lines to a file, after cutting it back to `length`.
+///
+/// If they can't all be written, the file is cut back again.
+fn append_lines(path: &Path, length: u64, lines: &str)
+    -> Result<(), FileError>
+{
+    let mut file = OpenOptions::new().create(true).write(true)
+        .truncate(false)
+        .open(path)
+        .map_err(io_error(path))?;
+    file.set_len(length).map_err(io_error(path))?;
+    let res = file.seek(SeekFrom::Start(length))
+        .and_then(|_| file.write_all(lines.as_bytes()))
+        .and_then(|_| file.sync_all());
+    if res.is_err() {
+        file.set_len(length).ok();
+    }
+    res.map_err(io_error(path))
+}
+
+fn remove_file(path: &Path) -> Result<(), FileError> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res.map_err(io_error(path)),
+    }
+}
+
+impl FileStorage {
+    /// Open a directory of boards, creating it if needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<FileStorage, FileError> {
+        let path = path.as_ref().to_owned();
+        let private = path.join(".tripledeck");
+        let boards = path.join("boards");
+        for dir in &[&private, &boards] {
+            fs::create_dir_all(dir).map_err(io_error(dir))?;
+        }
+        let gitignore = private.join(".gitignore");
+        if !gitignore.exists() {
+            fs::write(&gitignore, "*\n").map_err(io_error(&gitignore))?;
+        }
+
+        // Lock before reading anything
+        let lock_path = private.join("lock");
+        let lock = OpenOptions::new().create(true).write(true)
+            .truncate(false)
+            .open(&lock_path)
+            .map_err(io_error(&lock_path))?;
+        if let Err(e) = lock.try_lock_exclusive() {
+            return Err(if e.kind() == fs2::lock_contended_error().kind() {
+                FileError::Locked(path)
+            } else {
+                FileError::Io(lock_path, e)
+            });
+        }
+
+        finish_journal(&path)?;
+
+        let state_path = private.join("state.json");
+        let state: State = if state_path.exists() {
+            read_json(&state_path)?
+        } else {
+            State::default()
+        };
+        if state.version > FORMAT_VERSION {
+            return Err(FileError::TooNew {
+                version: state.version,
+                supported: FORMAT_VERSION,
+            });
+        }
+
+        let mut data = Data {
+            boards: BTreeMap::new(),
+            state,
+            operations: Vec::new(),
+            activity: Vec::new(),
+        };
+        let mut dirty = Dirty::default();
+        if data.state.version < FORMAT_VERSION {
+            // Move the operation log and activity to their own files
+            let old: StateV1 = read_json(&state_path)?;
+            data.operations = old.operations;
+            data.activity = old.activity;
+            data.state.version = FORMAT_VERSION;
+            dirty.state = true;
+            dirty.operations.rewrite = true;
+            dirty.activity.rewrite = true;
+        } else {
+            let log: Vec<LogEntry> =
+                read_lines(&private.join("operations.jsonl"))?;
+            for entry in &log {
+                entry.apply(&mut data.operations);
+            }
+            data.activity = read_lines(&private.join("activity.jsonl"))?;
+        }
+        for entry in fs::read_dir(&boards).map_err(io_error(&boards))? {
+            let file_path = entry.map_err(io_error(&boards))?.path();
+            if file_path.extension().is_some_and(|e| e == "json") {
+                let board: BoardFile = read_json(&file_path)?;
+                data.boards.insert(board.id, board);
+            }
+        }
+
+        let storage = FileStorage {
+            path,
+            data: RefCell::new(data),
+            dirty: RefCell::new(dirty),
+            saved: RefCell::new(None),
+            transaction: Mutex::new(()),
+            _lock: lock,
+        };
+        storage.flush()?;
+        Ok(storage)
+    }
+
+    /// The directory the boards are stored in.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Get the storage once no transaction is in progress.
+    async fn storage(&self) -> Locked<'_> {
+        Locked {
+            storage: self,
+            _lock: Some(self.transaction.lock().await),
+        }
+    }
+
+    /// Change a board, and write it out unless a transaction is open.
+    ///
+    /// If it can't be written, the change is undone.
+    fn change_board<T, F>(&self, id: &Uuid, f: F) -> Result<T, FileError>
+        where F: FnOnce(&mut BTreeMap<Uuid, BoardFile>) -> T
+    {
+        let before = self.data.borrow().boards.get(id).cloned();
+        let res = f(&mut self.data.borrow_mut().boards);
+        self.dirty.borrow_mut().boards.insert(*id);
+        if let Err(e) = self.flush() {
+            if self.dirty.borrow().boards.contains(id) {
+                let mut data = self.data.borrow_mut();
+                match before {
+                    Some(board) => data.boards.insert(*id, board),
+                    None => data.boards.remove(id),
+                };
+            }
+            return Err(e);
+        }
+        Ok(res)
+    }
+
+    /// Change the state, and write it out unless a transaction is open.
+    ///
+    /// If it can't be written, the change is undone.
+    fn change_state<T, F>(&self, f: F) -> Result<T, FileError>
+        where F: FnOnce(&mut State) -> T
+    {
+        let before = self.data.borrow().state.clone();
+        let res = f(&mut self.data.borrow_mut().state);
+        self.dirty.borrow_mut().state = true;
+        if let Err(e) = self.flush() {
+            if self.dirty.borrow().state {
+                self.data.borrow_mut().state = before;
+            }
+            return Err(e);
+        }
+        Ok(res)
+    }
+
+    /// Change the operation log, and append the change to its file unless a
+    /// transaction is open. Returns whether anything changed.
+    ///
+    /// If it can't be written, the change is undone.
+    fn change_operations(&self, entry: LogEntry) -> Result<bool, FileError> {
+        let before = self.data.borrow().operations.clone();
+        if !entry.apply(&mut self.data.borrow_mut().operations) {
+            return Ok(false);
+        }
+        let added = {
+            let mut dirty = self.dirty.borrow_mut();
+            dirty.operations.added.push(entry);
+            dirty.operations.added.len()
+        };
+        if let Err(e) = self.flush() {
+            let mut dirty = self.dirty.borrow_mut();
+            if dirty.operations.added.len() == added {
+                dirty.operations.added.pop();
+                self.data.borrow_mut().operations = before;
+            }
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Remove operations from the log, and write its file again unless a
+    /// transaction is open.
+    ///
+    /// If it can't be written, the change is undone.
+    fn remove_operations<F>(&self, keep: F) -> Result<(), FileError>
+        where F: FnMut(&LoggedOperation) -> bool
+    {
+        let before = self.data.borrow().operations.clone();
+        self.data.borrow_mut().operations.retain(keep);
+        if self.data.borrow().operations.len() == before.len() {
+            return Ok(());
+        }
+        self.dirty.borrow_mut().operations.rewrite = true;
+        if let Err(e) = self.flush() {
+            if self.dirty.borrow().operations.rewrite {
+                self.data.borrow_mut().operations = before;
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Add activity, and append it to its file unless a transaction is open.
+    ///
+    /// If it can't be written, it is removed again.
+    fn log_activity(&self, activity: &Activity) -> Result<(), FileError> {
+        self.data.borrow_mut().activity.push(activity.clone());
+        let added = {
+            let mut dirty = self.dirty.borrow_mut();
+            dirty.activity.added.push(activity.clone());
+            dirty.activity.added.len()
+        };
+        if let Err(e) = self.flush() {
+            let mut dirty = self.dirty.borrow_mut();
+            if dirty.activity.added.len() == added {
+                dirty.activity.added.pop();
+                self.data.borrow_mut().activity.pop();
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Write the changed files, unless a transaction is open.
+    ///
+    /// If the new files can't all be written, none of them replaces the old
+    /// one, and they are still marked as changed. Once they are written,
+    /// they are put in place even if this is interrupted.
+    fn flush(&self) -> Result<(), FileError> {
+        if self.saved.borrow().is_some() {
+            return Ok(());
+        }
+        // Finish replacing files from an earlier change first, this one
+        // reuses their temporary files
+        finish_journal(&self.path)?;
+
+        let data = self.data.borrow();
+        let mut dirty = self.dirty.borrow_mut();
+        let mut journal = Journal::default();
+        for id in &dirty.boards {
+            let path = Path::new("boards")
+                .join(format!("{}.json", id.to_hyphenated_ref()));
+            match data.boards.get(id) {
+                Some(board) => {
+                    let file = self.path.join(&path);
+                    write_temporary(&file, &to_json(&file, board)?)?;
+                    journal.replace.push(path);
+                }
+                None => journal.remove.push(path),
+            }
+        }
+        if dirty.state {
+            let path = Path::new(".tripledeck").join("state.json");
+            let file = self.path.join(&path);
+            write_temporary(&file, &to_json(&file, &data.state)?)?;
+            journal.replace.push(path);
+        }
+        self.journal_lines(
+            &mut journal,
+            Path::new(".tripledeck").join("operations.jsonl"),
+            &dirty.operations,
+            data.operations.iter().map(|o| LogEntry::Add(Box::new(o.clone()))),
+        )?;
+        self.journal_lines(
+            &mut journal,
+            Path::new(".tripledeck").join("activity.jsonl"),
+            &dirty.activity,
+            &data.activity,
+        )?;
+
+        // Moving a single file or appending to it is atomic already
+        let changes = journal.replace.len() + journal.remove.len()
+            + journal.append.len();
+        if changes > 1 {
+            write_json(&journal_path(&self.path), &journal)?;
+        }
+        *dirty = Dirty::default();
+        apply_journal(&self.path, &journal)?;
+        remove_file(&journal_path(&self.path))
+    }
+
+    /// Add the changes to a file of JSON lines to the journal: the lines to
+    /// append, or a temporary file with `all` of them to replace it.
+    fn journal_lines<T, I>(&self, journal: &mut Journal, path: PathBuf,
+                           changes: &Lines<T>, all: I)
+        -> Result<(), FileError>
+        where T: Serialize, I: IntoIterator, I::Item: Serialize
+    {
+        let file = self.path.join(&path);
+        if changes.rewrite {
+            write_temporary(&file, to_lines(&file, all)?.as_bytes())?;
+            journal.replace.push(path);
+        } else if !changes.added.is_empty() {
+            let length = match fs::metadata(&file) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => 0,
+                res => res.map_err(io_error(&file))?.len(),
+            };
+            let lines = to_lines(&file, &changes.added)?;
+            journal.append.push(Append { path, length, lines });
+        }
+        Ok(())
+    }
+}
+
+fn journal_path(path: &Path) -> PathBuf {
+    path.join(".tripledeck").join("journal.json")
+}
+
+/// Put the files listed in a journal in place.
+fn apply_journal(path: &Path, journal: &Journal) -> Result<(), FileError> {
+    for file in &journal.replace {
+        let file = path.join(file);
+        let tmp = temporary(&file);
+        // Already moved if we are finishing an interrupted change
+        if tmp.exists() {
+            fs::rename(&tmp, &file).map_err(io_error(&file))?;
+        }
+    }
+    for file in &journal.remove {
+        remove_file(&path.join(file))?;
+    }
+    for append in &journal.append {
+        append_lines(&path.join(&append.path), append.length, &append.lines)?;
+    }
+    Ok(())
+}
+
+/// Finish replacing files if it was interrupted.
+fn finish_journal(path: &Path) -> Result<(), FileError> {
+    let journal_path = journal_path(path);
+    if journal_path.exists() {
+        apply_journal(path, &read_json(&journal_path)?)?;
+        remove_file(&journal_path)?;
+    }
+    Ok(())
+}
+
+/// The methods of `Store`, which are the same on the storage and its
+/// transactions: `storage()` gives the storage to make them on.
+macro_rules! store_methods {
+    () => {
+        async fn add_board(&self, board: &Board) -> Result<(), FileError> {
+            let storage = self.storage().await;
+            storage.change_board(&board.id, |boards| {
+                let (lists, cards) = boards.remove(&board.id)
+                    .map(|b| (b.lists, b.cards))
+                    .unwrap_or_default();
+                boards.insert(board.id, BoardFile {
+                    id: board.id,
+                    name: board.name.clone(),
+                    lists,
+                    cards,
+                });
+            })
+        }
+
+        async fn get_board(&self, id: &Uuid)
+            -> Result<Option<Board>, FileError>
+        {
+            let storage = self.storage().await;
+            let data = storage.data.borrow();
+            Ok(data.boards.get(id).map(|b| Board {
+                id: b.id,
+                name: b.name.clone(),
+            }))
+        }
+
+        async fn get_lists(&self, board_id: &Uuid)
+            -> Result<Vec<List>, FileError>
+        {
+            let storage = self.storage().await;
+            let data = storage.data.borrow();
+            Ok(data.boards.get(board_id)
+                .map(|b| b.lists.clone())
+                .unwrap_or_default())
+        }
+
+        /// Lists can only be added to boards that exist, they would have no
+        /// file to go in.
+        async fn add_list(&self, board_id: &Uuid, list: &List)
+            -> Result<(), FileError>
+        {
+            let storage = self.storage().await;
+            storage.change_board(board_id, |boards| {
+                let board = boards.get_mut(board_id)
+                    .ok_or(FileError::NoBoard(*board_id))?;
+                board.lists.retain(|l| l.id != list.id);
+                board.lists.push(list.clone());
+                Ok(())
+            })?
+        }
+
+        async fn get_cards(&self, board_id: &Uuid)
+            -> Result<Vec<Card>, FileError>
+        {
+            let storage = self.storage().await;
+            let data = storage.data.borrow();
+            Ok(data.boards.get(board_id)
+                .map(|b| b.cards.clone())
+                .unwrap_or_default())
+        }
+
+        /// Like lists, cards can only be added to boards that exist.
+        async fn add_card(&self, board_id: &Uuid, card: &Card)
+            -> Result<(), FileError>
+        {
+            let storage = self.storage().await;
+            storage.change_board(board_id, |boards| {
+                let board = boards.get_mut(board_id)
+                    .ok_or(FileError::NoBoard(*board_id))?;
+                match board.cards.iter_mut().find(|c| c.id == card.id) {
+                    Some(c) => *c = card.clone(),
+                    None => board.cards.push(card.clone()),
+                }
+                Ok(())
+            })?
+        }
+
+        async fn delete_card(&self, board_id: &Uuid, card_id: &Uuid)
+            -> Result<(), FileError>
+        {
+            let storage = self.storage().await;
+            storage.change_board(board_id, |boards| {
+                if let Some(board) = boards.get_mut(board_id) {
+                    board.cards.retain(|c| c.id != *card_id);
+                }
+            })
+        }
+
+        async fn delete_board(&self, id: &Uuid) -> Result<(), FileError> {
+            let storage = self.storage().await;
+            storage.change_board(id, |boards| {
+                boards.remove(id);
+            })
+        }
+
+        async fn delete_list(&self, board_id: &Uuid, list_id: &Uuid)
+            -> Result<(), FileError>
+        {
+            let storage = self.storage().await;
+            storage.change_board(board_id, |boards| {
+                if let Some(board) = boards.get_mut(board_id) {
+                    board.lists.retain(|l| l.id != *list_id);
+                }
+            })
+        }
+
+        async fn add_operation(&self, op: &Operation, pending: bool)
+            -> Result<bool, FileError>
+        {
+            let storage = self.storage().await;
+            if storage.data.borrow().operations.iter()
+                .any(|o| o.operation.id == op.id)
+            {
+                return Ok(false);
+            }
+            storage.change_operations(LogEntry::Add(Box::new(LoggedOperation {
+                operation: op.clone(),
+                pending,
+            })))
+        }
+
+        async fn get_pending_operations(&self, board_id: &Uuid)
+            -> Result<Vec<Operation>, FileError>
+        {
+            let storage = self.storage().await;
+            let data = storage.data.borrow();
+            Ok(data.operations.iter()
+                .filter(|o| o.pending && o.operation.board_id == *board_id)
+                .map(|o| o.operation.clone())
+                .collect())
+        }
+
+        async fn acknowledge_operations(&self, ids: &[Uuid])
+            -> Result<(), FileError>
+        {
+            let storage = self.storage().await;
+            storage.change_operations(LogEntry::Acknowledge(ids.to_vec()))?;
+            Ok(())
+        }
+
+        async fn cancel_operation(&self, id: &Uuid)
+            -> Result<bool, FileError>
+        {
+            let storage = self.storage().await;
+            storage.change_operations(LogEntry::Cancel(*id))
+        }
+
+        async fn get_sync_cursor(&self, board_id: &Uuid)
+            -> Result<u64, FileError>
+        {
+            let storage = self.storage().await;
+            let data = storage.data.borrow();
+            Ok(data.state.sync_cursors.get(board_id)
+                .cloned()
+                .unwrap_or(0))
+        }
+
+        async fn set_sync_cursor(&self, board_id: &Uuid, cursor: u64)
+            -> Result<(), FileError>
+        {
+            let storage = self.storage().await;
+            storage.change_state(|state| {
+                state.sync_cursors.insert(*board_id, cursor);
+            })
+        }
+
+        async fn prune_operations(&self, board_id: &Uuid)
+            -> Result<(), FileError>
+        {
+            let storage = self.storage().await;
+            storage.remove_operations(|o| {
+                o.pending || o.operation.board_id != *board_id
+            })
+        }
+
+        async fn get_replica_id(&self) -> Result<Uuid, FileError> {
+            let storage = self.storage().await;
+            if let Some(id) = storage.data.borrow().state.replica_id {
+                return Ok(id);
+            }
+            storage.change_state(|state| {
+                *state.replica_id.get_or_insert_with(Uuid::new_v4)
+            })
+        }
+
+        async fn add_activity(&self, activity: &Activity)
+            -> Result<(), FileError>
+        {
+            let storage = self.storage().await;
+            storage.log_activity(activity)
+        }
+
+        async fn get_activity(&self, id: &Uuid)
+            -> Result<Vec<Activity>, FileError>
+        {
+            let storage = self.storage().await;
+            let data = storage.data.borrow();
+            Ok(data.activity.iter()
+                .filter(|a| a.board_id == *id || a.subject == *id)
+                .cloned()
+                .collect())
+        }
+    };
+}
+
+impl Store for FileStorage {
+    type Error = FileError;
+
+    store_methods!();
+}
+
+impl Storage for FileStorage {
+    type Transaction<'a> = FileTransaction<'a>;
+
+    async fn begin(&self) -> Result<FileTransaction<'_>, FileError> {
+        let lock = self.transaction.lock().await;
+        *self.saved.borrow_mut() = Some(self.data.borrow().clone());
+        Ok(FileTransaction { storage: self, _lock: lock })
+    }
+}
+
+/// A transaction on a `FileStorage`.
+///
+/// Changes are made in memory, and only written out when the transaction is
+/// committed; other calls wait until it is over, so they can't see them.
+pub struct FileTransaction<'a> {
+    storage: &'a FileStorage,
+    _lock: MutexGuard<'a, ()>,
+}
+
+impl<'a> FileTransaction<'a> {
+    async fn storage(&self) -> Locked<'a> {
+        Locked { storage: self.storage, _lock: None }
+    }
+
+    /// Go back to the data from before the transaction, if it is still open.
+    fn restore(&self) -> Result<(), FileError> {
+        let saved = self.storage.saved.borrow_mut().take();
+        match saved {
+            Some(data) => {
+                *self.storage.data.borrow_mut() = data;
+                self.storage.dirty.borrow_mut().forget_added();
+                // Files are only written outside of transactions, but a
+                // failed write can leave changes from before it to write
+                self.storage.flush()
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl Store for FileTransaction<'_> {
+    type Error = FileError;
+
+    store_methods!();
+}
+
+impl Transaction for FileTransaction<'_> {
+    async fn commit(self) -> Result<(), FileError> {
+        let saved = self.storage.saved.borrow_mut().take();
+        let res = self.storage.flush();
+        let mut dirty = self.storage.dirty.borrow_mut();
+        if res.is_err() && !dirty.is_empty() {
+            // None of the changes were written
+            if let Some(data) = saved {
+                *self.storage.data.borrow_mut() = data;
+                dirty.forget_added();
+            }
+        }
+        res
+    }
+
+    async fn rollback(self) -> Result<(), FileError> {
+        self.restore()
+    }
+}
+
+impl Drop for FileTransaction<'_> {
+    fn drop(&mut self) {
+        self.restore().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use std::cell::RefCell;
+    use std::fs;
+    use std::path::PathBuf;
+    use uuid::Uuid;
+
+    use tripledeck_core::{Board, Card, Change, List, Operation, Storage,
+                          Store, Transaction};
+    use tripledeck_core::activity::Activity;
+    use tripledeck_core::conformance;
+
+    use super::{FileError, FileStorage};
+
+    /// A temporary directory, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> TempDir {
+            TempDir(std::env::temp_dir().join(format!(
+                "tripledeck-test-{}", Uuid::new_v4(),
+            )))
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    #[test]
+    fn test_conformance() {
+        let dirs = RefCell::new(Vec::new());
+        conformance::run(|| {
+            let dir = TempDir::new();
+            let storage = FileStorage::open(&dir.0).unwrap();
+            dirs.borrow_mut().push(dir);
+            storage
+        });
+    }
+
+    #[test]
+    fn test_files() {
+        let dir = TempDir::new();
+        let board = Board { id: Uuid::new_v4(), name: "Work".into() };
+        let todo = List { id: Uuid::new_v4(), name: "todo".into() };
+        {
+            let storage = FileStorage::open(&dir.0).unwrap();
+            block_on(storage.add_board(&board)).unwrap();
+            block_on(storage.add_list(&board.id, &todo)).unwrap();
+
+            // The directory can't be opened twice
+            match FileStorage::open(&dir.0) {
+                Err(FileError::Locked(_)) => {}
+                Err(e) => panic!("Unexpected error: {}", e),
+                Ok(_) => panic!("Opened a locked directory"),
+            }
+        }
+
+        let path = dir.0.join("boards").join(format!("{}.json", board.id));
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!(
+            "{{\n  \"id\": \"{}\",\n  \"name\": \"Work\",\n  \"lists\": [\n    \
+             {{\n      \"id\": \"{}\",\n      \"name\": \"todo\"\n    }}\n  \
+             ]\n}}\n",
+            board.id, todo.id,
+        ));
+
+        // Reopening reads the files
+        let storage = FileStorage::open(&dir.0).unwrap();
+        assert_eq!(block_on(storage.get_board(&board.id)).unwrap(),
+                   Some(board.clone()));
+        assert_eq!(block_on(storage.get_lists(&board.id)).unwrap(),
+                   vec![todo]);
+        block_on(storage.delete_board(&board.id)).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_failures() {
+        let dir = TempDir::new();
+        let storage = FileStorage::open(&dir.0).unwrap();
+        let board = Board { id: Uuid::new_v4(), name: "Work".into() };
+        let todo = List { id: Uuid::new_v4(), name: "todo".into() };
+
+        // Lists need a board to go in
+        match block_on(storage.add_list(&board.id, &todo)) {
+            Err(FileError::NoBoard(id)) => assert_eq!(id, board.id),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        block_on(storage.add_board(&board)).unwrap();
+
+        // A change that can't be written is undone, the temporary file being
+        // in the way
+        let path = dir.0.join("boards").join(format!("{}.json", board.id));
+        let tmp = path.with_extension("json.tmp");
+        fs::create_dir(&tmp).unwrap();
+        let card = Card::new(todo.id, "card");
+        assert!(block_on(storage.add_card(&board.id, &card)).is_err());
+        assert!(block_on(storage.get_cards(&board.id)).unwrap().is_empty());
+
+        // So are the changes of a transaction
+        let result = block_on(async {
+            let tx = storage.begin().await?;
+            tx.add_list(&board.id, &todo).await?;
+            tx.add_card(&board.id, &card).await?;
+            tx.commit().await
+        });
+        assert!(result.is_err());
+        assert!(block_on(storage.get_lists(&board.id)).unwrap().is_empty());
+        fs::remove_dir(&tmp).unwrap();
+        block_on(storage.add_list(&board.id, &todo)).unwrap();
+        drop(storage);
+
+        // Files listed in the journal are put in place when opening
+        let renamed = format!(
+            "{{\"id\": \"{}\", \"name\": \"Renamed\", \"lists\": []}}",
+            board.id,
+        );
+        fs::write(&tmp, renamed).unwrap();
+        fs::write(
+            dir.0.join(".tripledeck").join("journal.json"),
+            format!(
+                "{{\"replace\": [\"boards/{}.json\"], \"remove\": []}}",
+                board.id,
+            ),
+        ).unwrap();
+        let storage = FileStorage::open(&dir.0).unwrap();
+        assert_eq!(block_on(storage.get_board(&board.id)).unwrap().unwrap()
+                       .name,
+                   "Renamed");
+        assert!(!tmp.exists());
+        assert!(!dir.0.join(".tripledeck").join("journal.json").exists());
+    }
+    #[test]
+    fn test_logs() {
+        let dir = TempDir::new();
+        let private = dir.0.join(".tripledeck");
+        let log = private.join("operations.jsonl");
+        let lines = |path: &PathBuf| {
+            fs::read_to_string(path).unwrap().lines().count()
+        };
+        let board_id = Uuid::new_v4();
+        let op = Operation::new(board_id, Change::AddBoard {
+            name: "Work".into(),
+        });
+        let activity = Activity::from_operation(&op, &[]);
+        {
+            let storage = FileStorage::open(&dir.0).unwrap();
+            block_on(storage.add_operation(&op, true)).unwrap();
+            block_on(storage.add_activity(&activity[0])).unwrap();
+
+            // Changes to the log are appended to it, the state file isn't
+            // written
+            block_on(storage.acknowledge_operations(&[op.id])).unwrap();
+            assert_eq!(lines(&log), 2);
+            assert_eq!(lines(&private.join("activity.jsonl")), 1);
+            assert!(!private.join("state.json").exists());
+        }
+
+        // A line that was only partly written is dropped
+        let mut content = fs::read_to_string(&log).unwrap();
+        content += "{\"cancel\":";
+        fs::write(&log, content).unwrap();
+        {
+            let storage = FileStorage::open(&dir.0).unwrap();
+            assert!(block_on(storage.get_pending_operations(&board_id))
+                        .unwrap().is_empty());
+            assert_eq!(block_on(storage.get_activity(&board_id)).unwrap(),
+                       activity);
+            assert_eq!(lines(&log), 2);
+
+            // Pruning writes the log again
+            block_on(storage.prune_operations(&board_id)).unwrap();
+            assert_eq!(lines(&log), 0);
+        }
+
+        // Version 1 kept the log and activity in the state file
+        fs::remove_file(&log).unwrap();
+        fs::remove_file(private.join("activity.jsonl")).unwrap();
+        fs::write(
+            private.join("state.json"),
+            format!(
+                "{{\"version\": 1, \"replica_id\": null, \
+                 \"sync_cursors\": {{}}, \"operations\": \
+                 [{{\"operation\": {}, \"pending\": true}}], \
+                 \"activity\": [{}]}}",
+                serde_json::to_string(&op).unwrap(),
+                serde_json::to_string(&activity[0]).unwrap(),
+            ),
+        ).unwrap();
+        let storage = FileStorage::open(&dir.0).unwrap();
+        assert_eq!(block_on(storage.get_pending_operations(&board_id))
+                       .unwrap(),
+                   vec![op]);
+        assert_eq!(block_on(storage.get_activity(&board_id)).unwrap(),
+                   activity);
+        assert_eq!(lines(&log), 1);
+        assert!(!fs::read_to_string(private.join("state.json")).unwrap()
+                    .contains("operations"));
+    }
+}
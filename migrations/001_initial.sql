@@ -0,0 +1,86 @@
+-- These files are run by both the SQLite and the PostgreSQL backends, so
+-- they stick to SQL both understand; the PostgreSQL backend only replaces
+-- AUTOINCREMENT keys with BIGSERIAL ones, and rowid with ctid.
+
+-- Boards
+CREATE TABLE IF NOT EXISTS boards(
+    id TEXT PRIMARY KEY,
+    name TEXT
+);
+CREATE TABLE IF NOT EXISTS lists(
+    id TEXT PRIMARY KEY,
+    board_id TEXT,
+    name TEXT
+);
+CREATE TABLE IF NOT EXISTS cards(
+    id TEXT PRIMARY KEY,
+    board_id TEXT,
+    list_id TEXT,
+    title TEXT
+);
+
+-- Sync
+CREATE TABLE IF NOT EXISTS operations(
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    id TEXT UNIQUE,
+    board_id TEXT,
+    change TEXT,
+    pending BOOLEAN,
+    actor TEXT,
+    time BIGINT
+);
+CREATE TABLE IF NOT EXISTS sync_cursors(
+    board_id TEXT PRIMARY KEY,
+    cursor BIGINT
+);
+CREATE TABLE IF NOT EXISTS replica(
+    id TEXT
+);
+CREATE TABLE IF NOT EXISTS board_keys(
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    board_id TEXT,
+    key TEXT
+);
+
+-- Activity feed
+CREATE TABLE IF NOT EXISTS activity(
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    operation TEXT,
+    board_id TEXT,
+    subject TEXT,
+    kind TEXT,
+    actor TEXT,
+    time BIGINT,
+    event TEXT
+);
+CREATE INDEX IF NOT EXISTS activity_board ON activity(board_id);
+CREATE INDEX IF NOT EXISTS activity_subject ON activity(subject);
+
+-- Server
+CREATE TABLE IF NOT EXISTS users(
+    name TEXT PRIMARY KEY,
+    password_hash TEXT
+);
+CREATE TABLE IF NOT EXISTS tokens(
+    token_hash TEXT PRIMARY KEY,
+    "user" TEXT
+);
+CREATE TABLE IF NOT EXISTS board_roles(
+    board_id TEXT,
+    "user" TEXT,
+    role TEXT,
+    PRIMARY KEY(board_id, "user")
+);
+CREATE TABLE IF NOT EXISTS replicas(
+    board_id TEXT,
+    replica_id TEXT,
+    cursor BIGINT,
+    last_seen BIGINT,
+    PRIMARY KEY(board_id, replica_id)
+);
+CREATE TABLE IF NOT EXISTS snapshots(
+    board_id TEXT PRIMARY KEY,
+    cursor BIGINT,
+    pruned BIGINT,
+    data TEXT
+);
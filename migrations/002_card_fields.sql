@@ -0,0 +1,6 @@
+-- Fields of cards besides their title; lists are stored as JSON
+ALTER TABLE cards ADD COLUMN description TEXT NOT NULL DEFAULT '';
+ALTER TABLE cards ADD COLUMN labels TEXT NOT NULL DEFAULT '[]';
+ALTER TABLE cards ADD COLUMN due BIGINT;
+ALTER TABLE cards ADD COLUMN assignees TEXT NOT NULL DEFAULT '[]';
+ALTER TABLE cards ADD COLUMN comments TEXT NOT NULL DEFAULT '[]';
@@ -0,0 +1,2 @@
+-- Reference of cards in the application they were imported from
+ALTER TABLE cards ADD COLUMN external TEXT;
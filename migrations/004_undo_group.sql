@@ -0,0 +1,2 @@
+-- Action that local operations are part of, to undo them together
+ALTER TABLE operations ADD COLUMN undo_group TEXT;
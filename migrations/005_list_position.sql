@@ -0,0 +1,7 @@
+-- Position of lists on their board, lists added before keep the order they
+-- were added in
+ALTER TABLE lists ADD COLUMN position BIGINT NOT NULL DEFAULT 0;
+UPDATE lists SET position = (
+    SELECT COUNT(*) FROM lists AS other
+    WHERE other.board_id = lists.board_id AND other.rowid <= lists.rowid
+);
@@ -0,0 +1,71 @@
+//! Schema of the database, and its upgrades.
+//!
+//! This follows the SQLite database: the schema version is the number of
+//! migrations applied, recorded in the `schema_version` table, and the
+//! missing migrations are applied in order, in a single transaction, when
+//! the database is opened. Databases upgraded by a newer version of the
+//! program are refused.
+//!
+//! The migrations are the SQLite database's, written in SQL both databases
+//! understand, except for SQLite's auto-incrementing keys which are turned
+//! into PostgreSQL's, and its row IDs. Those only order rows, which the
+//! rows' locations in the table do too, as long as they weren't changed.
+
+use postgres::Client;
+
+use super::PostgresError;
+
+/// Migrations, in order.
+const MIGRATIONS: &[&str] = &[
+    include_str!("../../migrations/001_initial.sql"),
+    include_str!("../../migrations/002_card_fields.sql"),
+    include_str!("../../migrations/003_external_ref.sql"),
+    include_str!("../../migrations/004_undo_group.sql"),
+    include_str!("../../migrations/005_list_position.sql"),
+];
+
+/// Key of the advisory lock taken while upgrading, so two programs opening
+/// the same database don't both upgrade it.
+const MIGRATION_LOCK: i64 = 0x7472_6970_6c65;
+
+/// The schema version this program creates.
+pub fn latest_version() -> u32 {
+    MIGRATIONS.len() as u32
+}
+
+/// Bring a database up to the latest schema version.
+pub fn migrate(client: &mut Client) -> Result<(), PostgresError> {
+    let mut tx = client.transaction()?;
+    tx.execute("SELECT pg_advisory_xact_lock($1);", &[&MIGRATION_LOCK])?;
+    tx.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_version(version INTEGER);",
+    )?;
+    let version: Option<i32> = tx.query_one(
+        "SELECT MAX(version) FROM schema_version;",
+        &[],
+    )?.get(0);
+    let version = version.unwrap_or(0) as u32;
+    if version > latest_version() {
+        return Err(PostgresError::TooNew {
+            version,
+            supported: latest_version(),
+        });
+    }
+    if version == latest_version() {
+        return Ok(());
+    }
+
+    for migration in &MIGRATIONS[version as usize..] {
+        tx.batch_execute(&migration.replace(
+            "INTEGER PRIMARY KEY AUTOINCREMENT",
+            "BIGSERIAL PRIMARY KEY",
+        ).replace("rowid", "ctid"))?;
+    }
+    tx.execute("DELETE FROM schema_version;", &[])?;
+    tx.execute(
+        "INSERT INTO schema_version(version) VALUES($1);",
+        &[&(latest_version() as i32)],
+    )?;
+    tx.commit()?;
+    Ok(())
+}
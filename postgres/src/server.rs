@@ -0,0 +1,470 @@
+//! Storage for the sync server, in the same tables as the SQLite database.
+//!
+//! Calls wait for the transaction in progress, if any, since they would go
+//! through its connection.
+
+use futures::executor::block_on;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use uuid::Uuid;
+
+use tripledeck_core::{Change, Operation, Storage, Store, Transaction};
+use tripledeck_core::activity::now;
+use tripledeck_core::server::{ServerStorage, StoredSnapshot, hash_password,
+                              hash_token, new_token, verify_password};
+use tripledeck_core::snapshot::SnapshotData;
+use tripledeck_core::sync::{Credentials, Role};
+
+use super::{PostgresError, PostgresStorage, PostgresTransaction, Result,
+            parse_json, parse_optional_json, parse_optional_uuid,
+            parse_time, parse_uuid, to_json, uuid2str};
+
+impl PostgresStorage {
+    fn user_exists(&self, name: &str) -> Result<bool> {
+        let storage = block_on(self.storage());
+        let rows = storage.query(
+            "SELECT 1 FROM users WHERE name=$1;",
+            &[&name],
+        )?;
+        Ok(!rows.is_empty())
+    }
+
+    /// Get the operations recorded for a board, in any order.
+    fn board_operations(tx: &PostgresTransaction, board_id: &Uuid)
+        -> Result<Vec<Operation>>
+    {
+        let rows = block_on(tx.storage()).query(
+            "SELECT id, change, actor, time, undo_group FROM operations
+             WHERE board_id=$1;",
+            &[&uuid2str(board_id)],
+        )?;
+        rows.iter().map(|row| {
+            Ok(Operation {
+                id: parse_uuid(row, "id")?,
+                board_id: *board_id,
+                change: parse_json(row, "change")?,
+                actor: parse_optional_json(row, "actor")?,
+                time: parse_time(row, "time")?,
+                group: parse_optional_uuid(row, "undo_group")?,
+            })
+        }).collect()
+    }
+}
+
+impl ServerStorage for PostgresStorage {
+    type Error = PostgresError;
+
+    fn set_password(&self, name: &str, password: &str) -> Result<()> {
+        let hash = hash_password(password);
+        block_on(self.storage()).execute(
+            "INSERT INTO users(name, password_hash) VALUES($1, $2)
+             ON CONFLICT (name) DO UPDATE
+             SET password_hash=EXCLUDED.password_hash;",
+            &[&name, &hash],
+        ).map(|_| ())
+    }
+
+    fn create_token(&self, user: &str) -> Result<Option<String>> {
+        if !self.user_exists(user)? {
+            return Ok(None);
+        }
+        let token = new_token();
+        block_on(self.storage()).execute(
+            "INSERT INTO tokens(token_hash, \"user\") VALUES($1, $2);",
+            &[&hash_token(&token), &user],
+        )?;
+        Ok(Some(token))
+    }
+
+    fn authenticate(&self, credentials: &Credentials)
+        -> Result<Option<String>>
+    {
+        let storage = block_on(self.storage());
+        match *credentials {
+            Credentials::Password { ref user, ref password } => {
+                let rows = storage.query(
+                    "SELECT password_hash FROM users WHERE name=$1;",
+                    &[user],
+                )?;
+                let hash: String = match rows.first() {
+                    Some(row) => row.try_get("password_hash")?,
+                    None => return Ok(None),
+                };
+                if verify_password(password, &hash) {
+                    Ok(Some(user.clone()))
+                } else {
+                    Ok(None)
+                }
+            }
+            Credentials::Token { ref token } => {
+                let rows = storage.query(
+                    "SELECT \"user\" FROM tokens WHERE token_hash=$1;",
+                    &[&hash_token(token)],
+                )?;
+                match rows.first() {
+                    Some(row) => Ok(Some(row.try_get("user")?)),
+                    None => Ok(None),
+                }
+            }
+        }
+    }
+
+    fn get_role(&self, board_id: &Uuid, user: &str) -> Result<Option<Role>> {
+        let rows = block_on(self.storage()).query(
+            "SELECT role FROM board_roles WHERE board_id=$1 AND \"user\"=$2;",
+            &[&uuid2str(board_id), &user],
+        )?;
+        match rows.first() {
+            Some(row) => {
+                let role: String = row.try_get("role")?;
+                Ok(Role::from_name(&role))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn set_role(&self, board_id: &Uuid, user: &str, role: Option<Role>)
+        -> Result<bool>
+    {
+        if !self.user_exists(user)? {
+            return Ok(false);
+        }
+        let storage = block_on(self.storage());
+        if let Some(role) = role {
+            storage.execute(
+                "INSERT INTO board_roles(board_id, \"user\", role)
+                 VALUES($1, $2, $3)
+                 ON CONFLICT (board_id, \"user\") DO UPDATE
+                 SET role=EXCLUDED.role;",
+                &[&uuid2str(board_id), &user, &role.name()],
+            )?;
+        } else {
+            storage.execute(
+                "DELETE FROM board_roles WHERE board_id=$1 AND \"user\"=$2;",
+                &[&uuid2str(board_id), &user],
+            )?;
+        }
+        Ok(true)
+    }
+
+    fn claim_board(&self, board_id: &Uuid, user: &str) -> Result<bool> {
+        let inserted = block_on(self.storage()).execute(
+            "INSERT INTO board_roles(board_id, \"user\", role)
+             SELECT $1::TEXT, $2::TEXT, 'owner'
+             WHERE NOT EXISTS (SELECT 1 FROM board_roles WHERE board_id=$1)
+             AND NOT EXISTS (SELECT 1 FROM operations WHERE board_id=$1)
+             AND NOT EXISTS (SELECT 1 FROM snapshots WHERE board_id=$1)
+             ON CONFLICT DO NOTHING;",
+            &[&uuid2str(board_id), &user],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    fn get_operations(&self, board_id: &Uuid, since: u64)
+        -> Result<Vec<(u64, Operation)>>
+    {
+        let rows = block_on(self.storage()).query(
+            "SELECT seq, id, change, actor, time FROM operations
+             WHERE board_id=$1 AND seq>$2
+             ORDER BY seq;",
+            &[&uuid2str(board_id), &(since as i64)],
+        )?;
+        rows.iter().map(|row| {
+            let seq: i64 = row.try_get("seq")?;
+            Ok((seq as u64, Operation {
+                id: parse_uuid(row, "id")?,
+                board_id: *board_id,
+                change: parse_json(row, "change")?,
+                actor: parse_optional_json(row, "actor")?,
+                time: parse_time(row, "time")?,
+                group: None,
+            }))
+        }).collect()
+    }
+
+    fn get_last_seq(&self, board_id: &Uuid) -> Result<u64> {
+        let rows = block_on(self.storage()).query(
+            "SELECT GREATEST(
+                 (SELECT MAX(seq) FROM operations WHERE board_id=$1),
+                 (SELECT cursor FROM snapshots WHERE board_id=$1)
+             ) AS seq;",
+            &[&uuid2str(board_id)],
+        )?;
+        let seq: Option<i64> = rows[0].try_get("seq")?;
+        Ok(seq.unwrap_or(0) as u64)
+    }
+
+    /// Replicas can receive the same operations in different orders, so to
+    /// converge they apply them sorted by `Operation::order_key()`, like
+    /// `SqliteStorage::receive_operations()` does.
+    fn receive_operations<I>(&self, operations: I) -> Result<usize>
+        where I: IntoIterator<Item=(Operation, bool)>
+    {
+        // Our futures are always ready, waiting on them doesn't block
+        let tx = block_on(self.begin())?;
+        let mut new: HashMap<Uuid, Vec<Operation>> = HashMap::new();
+        for (op, pending) in operations {
+            if block_on(tx.add_operation(&op, pending))? {
+                new.entry(op.board_id).or_default().push(op);
+            }
+        }
+        let mut received = 0;
+        for (board_id, mut ops) in new {
+            received += ops.len();
+            ops.sort_by(|a, b| a.order_key().cmp(&b.order_key()));
+            let ids: HashSet<Uuid> = ops.iter().map(|op| op.id).collect();
+            let mut log = PostgresStorage::board_operations(&tx, &board_id)?;
+            let in_order = log.iter()
+                .filter(|op| !ids.contains(&op.id))
+                .all(|op| op.order_key() < ops[0].order_key());
+            let complete = log.iter()
+                .any(|op| matches!(op.change, Change::AddBoard { .. }));
+            if in_order || !complete {
+                for op in &ops {
+                    block_on(op.apply(&tx))?;
+                }
+            } else {
+                // Clear the board and its activity, and apply its
+                // operations again
+                log.sort_by(|a, b| a.order_key().cmp(&b.order_key()));
+                block_on(tx.delete_board(&board_id))?;
+                block_on(tx.storage()).execute(
+                    "DELETE FROM activity WHERE board_id=$1;",
+                    &[&uuid2str(&board_id)],
+                )?;
+                for op in &log {
+                    block_on(op.apply(&tx))?;
+                }
+            }
+        }
+        block_on(tx.commit())?;
+        Ok(received)
+    }
+
+    fn record_replica(&self, board_id: &Uuid, replica_id: &Uuid, cursor: u64)
+        -> Result<()>
+    {
+        block_on(self.storage()).execute(
+            "INSERT INTO replicas(board_id, replica_id, cursor, last_seen)
+             VALUES($1, $2, $3, $4)
+             ON CONFLICT (board_id, replica_id) DO UPDATE
+             SET cursor=EXCLUDED.cursor, last_seen=EXCLUDED.last_seen;",
+            &[&uuid2str(board_id), &uuid2str(replica_id), &(cursor as i64),
+              &(now() as i64)],
+        ).map(|_| ())
+    }
+
+    fn get_snapshot(&self, board_id: &Uuid) -> Result<Option<StoredSnapshot>> {
+        let rows = block_on(self.storage()).query(
+            "SELECT cursor, pruned, data FROM snapshots WHERE board_id=$1;",
+            &[&uuid2str(board_id)],
+        )?;
+        match rows.first() {
+            Some(row) => {
+                let cursor: i64 = row.try_get("cursor")?;
+                let pruned: i64 = row.try_get("pruned")?;
+                Ok(Some(StoredSnapshot {
+                    cursor: cursor as u64,
+                    pruned: pruned as u64,
+                    data: parse_json(row, "data")?,
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn store_snapshot(&self, board_id: &Uuid, cursor: u64,
+                      data: &SnapshotData)
+        -> Result<()>
+    {
+        block_on(self.storage()).execute(
+            "INSERT INTO snapshots(board_id, cursor, pruned, data)
+             VALUES($1, $2, 0, $3)
+             ON CONFLICT (board_id) DO UPDATE
+             SET cursor=EXCLUDED.cursor, data=EXCLUDED.data
+             WHERE snapshots.cursor<EXCLUDED.cursor;",
+            &[&uuid2str(board_id), &(cursor as i64), &to_json(data)],
+        ).map(|_| ())
+    }
+
+    fn count_operations_since(&self, board_id: &Uuid, since: u64)
+        -> Result<u64>
+    {
+        let rows = block_on(self.storage()).query(
+            "SELECT COUNT(*) AS count FROM operations
+             WHERE board_id=$1 AND seq>$2;",
+            &[&uuid2str(board_id), &(since as i64)],
+        )?;
+        let count: i64 = rows[0].try_get("count")?;
+        Ok(count as u64)
+    }
+
+    fn prune(&self, board_id: &Uuid, forget_replicas_after: Duration)
+        -> Result<usize>
+    {
+        let snapshot_cursor = match self.get_snapshot(board_id)? {
+            Some(s) => s.cursor as i64,
+            None => return Ok(0),
+        };
+        let board_id = uuid2str(board_id);
+        let seen_since = now() as i64 - forget_replicas_after.as_secs() as i64;
+        let storage = block_on(self.storage());
+        let rows = storage.query(
+            "SELECT MIN(cursor) AS cursor FROM replicas
+             WHERE board_id=$1 AND last_seen>=$2;",
+            &[&board_id, &seen_since],
+        )?;
+        let replicas_cursor: Option<i64> = rows[0].try_get("cursor")?;
+        let limit = match replicas_cursor {
+            Some(c) if c < snapshot_cursor => c,
+            _ => snapshot_cursor,
+        };
+        let deleted = storage.execute(
+            "DELETE FROM operations WHERE board_id=$1 AND seq<=$2;",
+            &[&board_id, &limit],
+        )?;
+        storage.execute(
+            "UPDATE snapshots SET pruned=GREATEST(pruned, $1)
+             WHERE board_id=$2;",
+            &[&limit, &board_id],
+        )?;
+        Ok(deleted as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    use tripledeck_core::{Board, Change, List, Operation};
+    use tripledeck_core::server::ServerStorage;
+    use tripledeck_core::snapshot::{Snapshot, SnapshotData};
+    use tripledeck_core::sync::{Credentials, Role};
+
+    use crate::{PostgresStorage, uuid2str};
+    use crate::tests::TestSchema;
+
+    const DAY: Duration = Duration::from_secs(24 * 3600);
+
+    #[test]
+    fn test_tokens() {
+        let schema = match TestSchema::new() {
+            Some(s) => s,
+            None => return,
+        };
+        let storage = schema.storage();
+        assert_eq!(storage.create_token("alice").unwrap(), None);
+        storage.set_password("alice", "hunter1").unwrap();
+        storage.set_password("alice", "hunter2").unwrap();
+        let token = storage.create_token("alice").unwrap().unwrap();
+        assert_eq!(storage.authenticate(&Credentials::Token { token })
+                   .unwrap(),
+                   Some("alice".into()));
+        let password = |password: &str| Credentials::Password {
+            user: "alice".into(),
+            password: password.into(),
+        };
+        assert_eq!(storage.authenticate(&password("hunter2")).unwrap(),
+                   Some("alice".into()));
+        assert_eq!(storage.authenticate(&password("hunter1")).unwrap(), None);
+    }
+
+    #[test]
+    fn test_claim() {
+        let schema = match TestSchema::new() {
+            Some(s) => s,
+            None => return,
+        };
+        let storage = schema.storage();
+        storage.set_password("alice", "a").unwrap();
+        storage.set_password("bob", "b").unwrap();
+
+        let board_id = Uuid::new_v4();
+        assert!(storage.claim_board(&board_id, "alice").unwrap());
+        assert!(!storage.claim_board(&board_id, "bob").unwrap());
+        assert_eq!(storage.get_role(&board_id, "alice").unwrap(),
+                   Some(Role::Owner));
+
+        // Boards that exist can't be claimed
+        let board_id = Uuid::new_v4();
+        let op = Operation::new(board_id, Change::AddBoard {
+            name: "Work".into(),
+        });
+        storage.receive_operations(vec![(op, false)]).unwrap();
+        assert!(!storage.claim_board(&board_id, "bob").unwrap());
+        assert!(storage.set_role(&board_id, "bob", Some(Role::Viewer))
+                .unwrap());
+        assert!(storage.set_role(&board_id, "bob", Some(Role::Editor))
+                .unwrap());
+        assert_eq!(storage.get_role(&board_id, "bob").unwrap(),
+                   Some(Role::Editor));
+        assert!(storage.set_role(&board_id, "bob", None).unwrap());
+        assert_eq!(storage.get_role(&board_id, "bob").unwrap(), None);
+        assert!(!storage.set_role(&board_id, "carol", Some(Role::Owner))
+                .unwrap());
+    }
+
+    fn seqs(storage: &PostgresStorage, board_id: &Uuid) -> Vec<u64> {
+        storage.get_operations(board_id, 0).unwrap().into_iter()
+            .map(|(seq, _)| seq)
+            .collect()
+    }
+
+    fn snapshot(board_id: Uuid, name: &str) -> SnapshotData {
+        SnapshotData::Plain {
+            snapshot: Snapshot {
+                board: Board { id: board_id, name: name.into() },
+                lists: vec![],
+                cards: vec![],
+            },
+        }
+    }
+
+    #[test]
+    fn test_prune() {
+        let schema = match TestSchema::new() {
+            Some(s) => s,
+            None => return,
+        };
+        let storage = schema.storage();
+        let board_id = Uuid::new_v4();
+        let operations = (0..5).map(|i| {
+            let op = Operation::new(board_id, Change::AddList {
+                list: List { id: Uuid::new_v4(), name: format!("{}", i) },
+            });
+            (op, false)
+        });
+        assert_eq!(storage.receive_operations(operations).unwrap(), 5);
+        assert_eq!(storage.prune(&board_id, DAY).unwrap(), 0);
+        assert_eq!(seqs(&storage, &board_id), vec![1, 2, 3, 4, 5]);
+        assert_eq!(storage.count_operations_since(&board_id, 2).unwrap(), 3);
+
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        storage.record_replica(&board_id, &first, 2).unwrap();
+        storage.record_replica(&board_id, &second, 5).unwrap();
+        storage.store_snapshot(&board_id, 3, &snapshot(board_id, "3"))
+            .unwrap();
+        assert_eq!(storage.prune(&board_id, DAY).unwrap(), 2);
+        assert_eq!(seqs(&storage, &board_id), vec![3, 4, 5]);
+        let stored = storage.get_snapshot(&board_id).unwrap().unwrap();
+        assert_eq!((stored.cursor, stored.pruned), (3, 2));
+
+        // An older snapshot doesn't replace a newer one
+        storage.store_snapshot(&board_id, 2, &snapshot(board_id, "2"))
+            .unwrap();
+        let stored = storage.get_snapshot(&board_id).unwrap().unwrap();
+        assert_eq!(stored.data, snapshot(board_id, "3"));
+
+        // Replicas that haven't been seen for a while are not waited for
+        storage.store_snapshot(&board_id, 5, &snapshot(board_id, "5"))
+            .unwrap();
+        schema.client().execute(
+            "UPDATE replicas SET last_seen=0 WHERE replica_id=$1;",
+            &[&uuid2str(&first)],
+        ).unwrap();
+        assert_eq!(storage.prune(&board_id, DAY).unwrap(), 3);
+        assert!(seqs(&storage, &board_id).is_empty());
+        assert_eq!(storage.get_last_seq(&board_id).unwrap(), 5);
+    }
+}
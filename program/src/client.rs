@@ -0,0 +1,53 @@
+//! WebSocket client, used to sync with a server.
+
+use std::cell::RefCell;
+use std::net::TcpStream;
+use tungstenite::{Message, WebSocket};
+use tungstenite::stream::MaybeTlsStream;
+
+use tripledeck_core::sync::Transport;
+
+/// Blocking WebSocket connection.
+pub struct WebSocketTransport {
+    ws: RefCell<WebSocket<MaybeTlsStream<TcpStream>>>,
+}
+
+impl WebSocketTransport {
+    pub fn connect(url: &str) -> tungstenite::Result<WebSocketTransport> {
+        let (ws, _) = tungstenite::connect(url)?;
+        Ok(WebSocketTransport {
+            ws: RefCell::new(ws),
+        })
+    }
+}
+
+impl Drop for WebSocketTransport {
+    fn drop(&mut self) {
+        let mut ws = self.ws.borrow_mut();
+        if ws.close(None).is_ok() {
+            // Wait for the server to acknowledge
+            while ws.read().is_ok() {}
+        }
+    }
+}
+
+impl Transport for WebSocketTransport {
+    type Error = tungstenite::Error;
+
+    async fn send(&self, message: String) -> tungstenite::Result<()> {
+        self.ws.borrow_mut().send(Message::Text(message))
+    }
+
+    async fn receive(&self) -> tungstenite::Result<Option<String>> {
+        let mut ws = self.ws.borrow_mut();
+        loop {
+            match ws.read() {
+                Ok(Message::Text(text)) => return Ok(Some(text)),
+                // Control frames are handled by tungstenite
+                Ok(_) => {}
+                Err(tungstenite::Error::ConnectionClosed) => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
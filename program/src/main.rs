@@ -1,128 +1,1089 @@
+// tungstenite's error type is large, but it's only ever built once per
+// connection
+#![allow(clippy::result_large_err)]
+
 extern crate clap;
 extern crate futures;
+extern crate humantime;
 extern crate rusqlite;
+extern crate serde;
+extern crate serde_json;
 extern crate tripledeck_core;
+extern crate tripledeck_files;
+#[cfg(feature = "postgres")]
+extern crate tripledeck_postgres;
+extern crate tripledeck_sqlite;
+extern crate tungstenite;
 extern crate uuid;
 
-use clap::{App, Arg};
-use futures::{Future, future};
-use rusqlite::Connection;
-use rusqlite::types::ToSql;
-use std::path::Path;
+mod client;
+mod peer;
+#[cfg(feature = "server")]
+mod server;
+
+use clap::{App, AppSettings, Arg, SubCommand};
+use futures::executor::block_on;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, UNIX_EPOCH};
 use uuid::Uuid;
 
-use tripledeck_core::{List, Board, BoardHandle, Storage};
+use tripledeck_core::{BoardHandle, Storage};
+use tripledeck_core::crypto::BoardKey;
+use tripledeck_core::filter;
+use tripledeck_core::interchange::{BoardExport, markdown, taskwarrior,
+                                   todotxt, trello};
+use tripledeck_core::interchange::csv::{self, Column};
+use tripledeck_core::sync::{ClientError, Credentials, SyncClient};
+use tripledeck_files::FileStorage;
+use tripledeck_sqlite::SqliteStorage;
+
+#[cfg(feature = "postgres")]
+use tripledeck_postgres::PostgresStorage;
+
+#[cfg(feature = "server")]
+use tripledeck_core::server::ServerStorage;
+#[cfg(feature = "server")]
+use tripledeck_core::sync::Role;
 
-fn uuid2str(id: &Uuid) -> String {
-    format!("{:X}", id.to_simple_ref())
+fn show<S: Storage>(app: &tripledeck_core::App<S>, board_id: Option<&str>) {
+    if let Some(board_id) = board_id {
+        let fut = app.get_board(&Uuid::parse_str(board_id)
+                                  .expect("Invalid UUID"));
+        match block_on(fut).unwrap() {
+            None => println!("No such board"),
+            Some(board) => {
+                println!("Board: {}", board.board().name);
+                println!("TODO: print lists, cards");
+            }
+        }
+    } else {
+        println!("TODO: print boards");
+    }
 }
 
-struct SqliteStorage {
-    sql_connection: Connection,
+/// Read a line from stdin, for passwords and passphrases.
+fn read_line() -> String {
+    let mut line = String::new();
+    if let Err(e) = std::io::stdin().read_line(&mut line) {
+        eprintln!("Can't read from stdin: {}", e);
+        std::process::exit(1);
+    }
+    line.trim_end_matches(&['\r', '\n'][..]).to_owned()
 }
 
-impl SqliteStorage {
-    fn new<P: AsRef<Path>>(path: P) -> rusqlite::Result<SqliteStorage> {
-        let sql_connection = Connection::open(path.as_ref())?;
-        if !path.as_ref().exists() {
-            sql_connection.execute(
-                "
-                CREATE TABLE boards(id TEXT PRIMARY KEY, name TEXT);
-                CREATE TABLE lists(id TEXT PRIMARY KEY, board_id TEXT, name TEXT);
-                CREATE TABLE cards(id TEXT PRIMARY KEY, board_id TEXT, list_id TEXT, title TEXT);
-                ",
-                rusqlite::NO_PARAMS,
-            );
+fn read_password() -> String {
+    eprint!("Password: ");
+    read_line()
+}
+
+fn credentials(matches: &clap::ArgMatches) -> Option<Credentials> {
+    if let Some(token) = matches.value_of("token") {
+        return Some(Credentials::Token { token: token.to_owned() });
+    }
+    matches.value_of("user").map(|user| Credentials::Password {
+        user: user.to_owned(),
+        password: read_password(),
+    })
+}
+
+fn connect(storage: SqliteStorage, url: &str, board_id: &str,
+           credentials: Option<Credentials>, prune: bool)
+{
+    let board_id = Uuid::parse_str(board_id).expect("Invalid UUID");
+    let keyring = match storage.get_keyring(&board_id) {
+        Ok(k) => k,
+        Err(e) => {
+            eprintln!("Can't read board keys: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let transport = match client::WebSocketTransport::connect(url) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("Can't connect to server: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let fut = async {
+        let client = SyncClient::connect(Rc::new(storage), transport,
+                                         board_id, credentials).await?;
+        let client = match keyring {
+            Some(keyring) => client.encrypted(keyring),
+            None => client,
+        };
+        client.sync().await?;
+        if prune {
+            client.prune().await?;
+        }
+        Ok(())
+    };
+    let res: Result<(), ClientError<_, _>> = block_on(fut);
+    match res {
+        Ok(_) => println!("Board is up to date"),
+        Err(e) => {
+            eprintln!("Sync failed: {}", e);
+            std::process::exit(1);
         }
-        Ok(SqliteStorage {
-            sql_connection,
-        })
     }
 }
 
-impl Storage for SqliteStorage {
-    type Error = rusqlite::Error;
+fn undo<S: Storage>(storage: S, board_id: &str) {
+    let board_id = Uuid::parse_str(board_id).expect("Invalid UUID");
+    // Each invocation is a new session, so this undoes the changes that
+    // weren't synced yet
+    let app = tripledeck_core::App::new(storage);
+    let fut = async {
+        app.load_pending_history(&board_id).await?;
+        app.undo().await
+    };
+    match block_on(fut) {
+        Ok(true) => println!("Undid the last change"),
+        Ok(false) => println!("Nothing to undo"),
+        Err(e) => {
+            eprintln!("Can't undo: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
 
-    fn add_board(&self, board: &Board)
-        -> Box<Future<Item=(), Error=Self::Error>>
-    {
-        let res = self.sql_connection.execute(
-            "INSERT INTO boards(id, name) VALUES(?, ?);",
-            &[&uuid2str(&board.id) as &ToSql, &board.name as &ToSql],
-        );
-        Box::new(future::result(res.map(|_| ())))
+fn log<S: Storage>(storage: S, id: &str) {
+    let id = Uuid::parse_str(id).expect("Invalid UUID");
+    let app = tripledeck_core::App::new(storage);
+    match block_on(app.get_activity(&id)) {
+        Ok(activity) => {
+            for entry in activity {
+                match entry.time {
+                    Some(time) => print!(
+                        "{}  ",
+                        humantime::format_rfc3339_seconds(
+                            UNIX_EPOCH + Duration::from_secs(time),
+                        ),
+                    ),
+                    None => print!("{:20}  ", "?"),
+                }
+                println!("{}", entry);
+            }
+        }
+        Err(e) => {
+            eprintln!("Can't read activity: {}", e);
+            std::process::exit(1);
+        }
     }
+}
 
-    fn get_board(&self, id: &Uuid)
-        -> Box<Future<Item=Option<Board>, Error=Self::Error>>
-    {
-        let res = self.sql_connection.query_row(
-            "SELECT id, name FROM boards WHERE id=?;",
-            &[&uuid2str(id) as &ToSql],
-            |row| {
-                let id: String = row.get(0);
-                Board {
-                    id: Uuid::parse_str(&id).unwrap(),
-                    name: row.get(1),
+/// Write a board to a file, or to stdout.
+fn export<S: Storage>(
+    storage: S,
+    board_id: &str,
+    output: Option<&OsStr>,
+    format: &str,
+) {
+    let board_id = Uuid::parse_str(board_id).expect("Invalid UUID");
+    let app = tripledeck_core::App::new(storage);
+    let export = match block_on(app.export_board(&board_id)) {
+        Ok(Some(e)) => e,
+        Ok(None) => {
+            eprintln!("No such board");
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Can't read board: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let text = match format {
+        "json" => export.to_json(),
+        "markdown" => markdown::export(&export),
+        _ => unreachable!(),
+    };
+    write_output(output, &text);
+}
+
+/// Write a file, or to stdout if no path or `-` is given.
+fn write_output(path: Option<&OsStr>, text: &str) {
+    let res = match path {
+        Some(path) if path != "-" => std::fs::write(path, text),
+        _ => std::io::stdout().write_all(text.as_bytes()),
+    };
+    if let Err(e) = res {
+        eprintln!("Can't write export: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Read a file, or stdin if the path is `-`.
+fn read_input(path: &OsStr) -> String {
+    let res = if path == "-" {
+        let mut input = String::new();
+        std::io::stdin().read_to_string(&mut input).map(|_| input)
+    } else {
+        std::fs::read_to_string(path)
+    };
+    match res {
+        Ok(i) => i,
+        Err(e) => {
+            eprintln!("Can't read {}: {}", Path::new(path).display(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn import<S: Storage>(storage: S, path: &OsStr, format: &str) {
+    let input = read_input(path);
+    let res = match format {
+        "json" => BoardExport::from_json(&input).map(|e| (e, None)),
+        "trello" => trello::import(&input).map(|(e, r)| (e, Some(r))),
+        "markdown" => {
+            // Documents without a title are named after their file
+            let name = match Path::new(path).file_stem() {
+                Some(stem) if path != "-" => stem.to_string_lossy(),
+                _ => "Imported".into(),
+            };
+            let (export, report) = markdown::import(&input, &name);
+            Ok((export, Some(report)))
+        }
+        _ => unreachable!(),
+    };
+    let (export, report) = match res {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("Can't import: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(report) = report {
+        eprint!("{}", report);
+    }
+    let app = tripledeck_core::App::new(storage);
+    let id = export.board.id;
+    match block_on(app.import_board(export)) {
+        Ok(board) => {
+            if board.board().id != id {
+                eprintln!("Board {} already exists, importing as a copy",
+                          id);
+            }
+            println!("Imported board {}", board.board().id);
+        }
+        Err(e) => {
+            eprintln!("Can't import: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Get a board, exiting if it can't be found.
+fn load_board<S: Storage>(app: &tripledeck_core::App<S>, board_id: &str)
+    -> Rc<BoardHandle<S>>
+{
+    let board_id = Uuid::parse_str(board_id).expect("Invalid UUID");
+    match block_on(app.get_board(&board_id)) {
+        Ok(Some(board)) => board,
+        Ok(None) => {
+            eprintln!("No such board");
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Can't read board: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn csv<S: Storage>(storage: S, matches: &clap::ArgMatches) {
+    let app = tripledeck_core::App::new(storage);
+    let (name, m) = matches.subcommand();
+    let m = m.unwrap();
+    let board = load_board(&app,
+                           m.value_of("board").expect("No value for board"));
+    match name {
+        "export" => {
+            let columns = match m.values_of("columns") {
+                Some(names) => names.map(|n| n.parse::<Column>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .unwrap_or_else(|e| {
+                        eprintln!("{}", e);
+                        std::process::exit(2);
+                    }),
+                None => csv::DEFAULT_COLUMNS.to_vec(),
+            };
+            let filter = m.value_of("filter").map(|text| {
+                let lists = board.lists().iter()
+                    .map(|l| (l.name.clone(), l.id))
+                    .collect();
+                filter::parse(text, &lists).unwrap_or_else(|e| {
+                    eprintln!("Invalid filter: {}", e);
+                    std::process::exit(2);
+                })
+            });
+            let created = if columns.contains(&Column::Created) {
+                match block_on(board.activity()) {
+                    Ok(activity) => csv::created_times(&activity),
+                    Err(e) => {
+                        eprintln!("Can't read activity: {}", e);
+                        std::process::exit(1);
+                    }
+                }
+            } else {
+                HashMap::new()
+            };
+            let cards = board.cards();
+            let cards = cards.iter()
+                .filter(|c| filter.as_ref().is_none_or(|f| f.matches(c)));
+            let text = csv::export(&board.lists(), cards, &columns, &created);
+            write_output(m.value_of_os("output"), &text);
+        }
+        "import" => {
+            let mut mapping = HashMap::new();
+            for map in m.values_of("map").into_iter().flatten() {
+                let (header, column) = match map.rfind('=') {
+                    Some(i) => (&map[..i], &map[i + 1..]),
+                    None => {
+                        eprintln!("Invalid mapping {}, use \
+                                   <header>=<column>", map);
+                        std::process::exit(2);
+                    }
+                };
+                let column = column.parse::<Column>().unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    std::process::exit(2);
+                });
+                mapping.insert(header.trim().to_lowercase(), column);
+            }
+            let input = read_input(m.value_of_os("file")
+                                   .expect("No value for file"));
+            let import = csv::import(&input, &board.lists(), &mapping)
+                .unwrap_or_else(|e| {
+                    eprintln!("Can't import: {}", e);
+                    std::process::exit(1);
+                });
+            eprint!("{}", import.report);
+            let count = import.cards.len();
+            if let Err(e) = block_on(board.add_all(import.lists,
+                                                   import.cards)) {
+                eprintln!("Can't import: {}", e);
+                std::process::exit(1);
+            }
+            println!("Imported {} cards", count);
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn todotxt<S: Storage>(storage: S, matches: &clap::ArgMatches) {
+    let app = tripledeck_core::App::new(storage);
+    let (name, m) = matches.subcommand();
+    let m = m.unwrap();
+    let board = load_board(&app,
+                           m.value_of("board").expect("No value for board"));
+    let done_list = m.value_of("done-list").expect("No value for done-list");
+    match name {
+        "export" => {
+            let text = todotxt::export(&board.lists(), board.cards().iter(),
+                                       done_list);
+            write_output(m.value_of_os("output"), &text);
+        }
+        "import" => {
+            let input = read_input(m.value_of_os("file")
+                                   .expect("No value for file"));
+            let import = todotxt::import(&input, &board.lists(), done_list);
+            eprint!("{}", import.report);
+            let count = import.cards.len();
+            if let Err(e) = block_on(board.add_all(import.lists,
+                                                   import.cards)) {
+                eprintln!("Can't import: {}", e);
+                std::process::exit(1);
+            }
+            println!("Imported {} cards", count);
+        }
+        "sync" => {
+            let path = Path::new(m.value_of_os("file")
+                                 .expect("No value for file"));
+            let state_path = match m.value_of_os("state") {
+                Some(p) => PathBuf::from(p),
+                None => {
+                    let mut p = path.as_os_str().to_owned();
+                    p.push(".tripledeck");
+                    PathBuf::from(p)
+                }
+            };
+            // Both files are created by the first sync
+            let read = |path: &Path| match std::fs::read_to_string(path) {
+                Ok(text) => Some(text),
+                Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    None
+                }
+                Err(e) => {
+                    eprintln!("Can't read {}: {}", path.display(), e);
+                    std::process::exit(1);
+                }
+            };
+            let text = read(path).unwrap_or_default();
+            let state = match read(&state_path) {
+                Some(json) => serde_json::from_str(&json)
+                    .unwrap_or_else(|e| {
+                        eprintln!("Invalid state file {}: {}",
+                                  state_path.display(), e);
+                        std::process::exit(1);
+                    }),
+                None => todotxt::SyncState::new(board.board().id),
+            };
+            if state.board != board.board().id {
+                eprintln!("State file {} is for board {}",
+                          state_path.display(), state.board);
+                std::process::exit(1);
+            }
+
+            let sync = todotxt::sync(&text, &board.lists(), &board.cards(),
+                                     &state, done_list);
+            eprint!("{}", sync.report);
+            let (changed, removed) = (sync.cards.len(), sync.removed.len());
+            if let Err(e) = block_on(board.update_all(sync.lists, sync.cards,
+                                                      &sync.removed)) {
+                eprintln!("Can't update board: {}", e);
+                std::process::exit(1);
+            }
+            let state = serde_json::to_string(&sync.state)
+                .expect("Serializing state");
+            let res = std::fs::write(path, &sync.text)
+                .and_then(|()| std::fs::write(&state_path, state));
+            if let Err(e) = res {
+                eprintln!("Can't write: {}", e);
+                std::process::exit(1);
+            }
+            println!("Updated {} cards, removed {}", changed, removed);
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn taskwarrior<S: Storage>(storage: S, matches: &clap::ArgMatches) {
+    let app = tripledeck_core::App::new(storage);
+    let (name, m) = matches.subcommand();
+    let m = m.unwrap();
+    let board = load_board(&app,
+                           m.value_of("board").expect("No value for board"));
+    let done_list = m.value_of("done-list").expect("No value for done-list");
+    match name {
+        "export" => {
+            let json = taskwarrior::export(&board.lists(),
+                                           board.cards().iter(), done_list);
+            write_output(m.value_of_os("output"), &json);
+        }
+        "import" => {
+            let input = read_input(m.value_of_os("file")
+                                   .expect("No value for file"));
+            let import = taskwarrior::import(&input, &board.lists(),
+                                             &board.cards(), done_list)
+                .unwrap_or_else(|e| {
+                    eprintln!("Can't import: {}", e);
+                    std::process::exit(1);
+                });
+            eprint!("{}", import.report);
+            let (count, removed) = (import.cards.len(), import.removed.len());
+            if let Err(e) = block_on(board.update_all(import.lists,
+                                                      import.cards,
+                                                      &import.removed)) {
+                eprintln!("Can't import: {}", e);
+                std::process::exit(1);
+            }
+            println!("Imported {} cards, removed {}", count, removed);
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn key(storage: SqliteStorage, matches: &clap::ArgMatches) {
+    let (name, m) = matches.subcommand();
+    let m = m.unwrap();
+    let board_id = m.value_of("board").expect("No value for board");
+    let board_id = Uuid::parse_str(board_id).expect("Invalid UUID");
+    let key = match name {
+        "new" => BoardKey::generate(),
+        "set" => match m.value_of("key") {
+            Some(key) => match BoardKey::from_hex(key) {
+                Ok(key) => key,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
                 }
             },
-        );
-        let res = match res {
-            Ok(b) => Ok(Some(b)),
-            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
-            Err(e) => Err(e),
-        };
-        Box::new(future::result(res))
+            None => {
+                eprint!("Passphrase: ");
+                BoardKey::from_passphrase(&read_line(), &board_id)
+            }
+        },
+        "show" => {
+            match storage.get_keyring(&board_id) {
+                Ok(Some(keyring)) => println!("{}", keyring.current().to_hex()),
+                Ok(None) => {
+                    eprintln!("Board is not encrypted");
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    eprintln!("Can't read board keys: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        _ => unreachable!(),
+    };
+    if let Err(e) = storage.add_board_key(&board_id, &key) {
+        eprintln!("Can't store key: {}", e);
+        std::process::exit(1);
+    }
+    if name == "new" {
+        println!("{}", key.to_hex());
     }
+}
 
-    fn get_lists(&self, board_id: &Uuid)
-        -> Box<Future<Item=Vec<List>, Error=Self::Error>>
-    {
-        let res = self.sql_connection.prepare(
-            "SELECT id, name FROM lists WHERE board_id=?;",
-        );
-        let res = res.and_then(|mut stmt| {
-            stmt.query_map(
-                &[&uuid2str(board_id)],
-                |row| {
-                    let id: String = row.get(0);
-                    List {
-                        id: Uuid::parse_str(&id).unwrap(),
-                        name: row.get(1),
-                    }
-                },
-            ).map(
-                |iter| iter.map(Result::unwrap).collect()
-            )
-        });
-        Box::new(future::result(res))
+fn open<P: AsRef<Path>>(path: P) -> SqliteStorage {
+    match SqliteStorage::new(path) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Can't open database: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// The storage `--database` points to.
+enum Database {
+    Sqlite(SqliteStorage),
+    /// A directory of board files
+    Files(Box<FileStorage>),
+    #[cfg(feature = "postgres")]
+    Postgres(PostgresStorage),
+}
+
+fn open_database(path: &std::ffi::OsStr) -> Database {
+    let url = path.to_str().unwrap_or("");
+    if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        return open_postgres(url);
+    }
+    if !Path::new(path).is_dir() {
+        return Database::Sqlite(open(path));
+    }
+    match FileStorage::open(path) {
+        Ok(s) => Database::Files(Box::new(s)),
+        Err(e) => {
+            eprintln!("Can't open directory: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(feature = "postgres")]
+fn open_postgres(url: &str) -> Database {
+    match PostgresStorage::new(url) {
+        Ok(s) => Database::Postgres(s),
+        Err(e) => {
+            eprintln!("Can't open database: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(not(feature = "postgres"))]
+fn open_postgres(_url: &str) -> Database {
+    eprintln!("This program was built without PostgreSQL support");
+    std::process::exit(2);
+}
+
+impl Database {
+    /// Get the SQLite database, for the commands that only work with one.
+    fn sqlite(self) -> SqliteStorage {
+        match self {
+            Database::Sqlite(s) => s,
+            _ => {
+                eprintln!("This command needs a SQLite database");
+                std::process::exit(2);
+            }
+        }
+    }
+}
+
+fn sync(matches: &clap::ArgMatches) {
+    let storage = open(
+        matches.value_of_os("database").expect("No value for database"),
+    );
+    let res = if let Some(other) = matches.value_of_os("other") {
+        let other = open(other);
+        peer::sync_local(&storage, &other).map_err(peer::Error::Storage)
+    } else {
+        let command = matches.value_of("command")
+            .expect("No value for command");
+        peer::sync_command(&storage, command)
+    };
+    match res {
+        Ok((sent, received)) => {
+            println!("Sent {} operations, received {}", sent, received);
+        }
+        Err(e) => {
+            eprintln!("Sync failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn sync_stdio(matches: &clap::ArgMatches) {
+    let storage = open(
+        matches.value_of_os("database").expect("No value for database"),
+    );
+    let stdin = std::io::stdin();
+    let res = peer::respond(&storage, stdin.lock(), std::io::stdout());
+    if let Err(e) = res {
+        eprintln!("Sync failed: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Run one of the server's commands on a database that can hold its data.
+#[cfg(feature = "server")]
+fn server_command(database: Database, command: &str,
+                  matches: &clap::ArgMatches)
+{
+    match database {
+        Database::Sqlite(s) => run_server_command(s, command, matches),
+        #[cfg(feature = "postgres")]
+        Database::Postgres(s) => run_server_command(s, command, matches),
+        Database::Files(_) => {
+            eprintln!("This command needs a SQLite or PostgreSQL database");
+            std::process::exit(2);
+        }
     }
+}
+
+#[cfg(feature = "server")]
+fn run_server_command<S: ServerStorage + 'static>(storage: S, command: &str,
+                                                  matches: &clap::ArgMatches)
+{
+    match command {
+        "serve" => serve(storage, matches),
+        "user" => user(&storage, matches),
+        "grant" => grant(&storage, matches, false),
+        "revoke" => grant(&storage, matches, true),
+        _ => unreachable!(),
+    }
+}
 
-    fn add_list(&self, board_id: &Uuid, list: &List)
-        -> Box<Future<Item=(), Error=Self::Error>>
+#[cfg(feature = "server")]
+fn serve<S: ServerStorage + 'static>(storage: S, matches: &clap::ArgMatches) {
+    let addr = matches.value_of("listen").expect("No value for listen");
+    let require_auth = !matches.is_present("no-auth");
+    let forget_replicas_after = matches.value_of("forget-replicas-after")
+        .expect("No value for forget-replicas-after")
+        .parse::<u64>()
+        .unwrap_or_else(|_| {
+            eprintln!("Invalid number of days");
+            std::process::exit(2);
+        });
+    let forget_replicas_after =
+        std::time::Duration::from_secs(forget_replicas_after * 24 * 3600);
+    if let Err(e) = server::serve(storage, addr, require_auth,
+                                  forget_replicas_after)
     {
-        let res = self.sql_connection.execute(
-            "INSERT INTO lists(board_id, id, name) VALUES(?, ?, ?);",
-            &[&uuid2str(&board_id) as &ToSql, &uuid2str(&list.id) as &ToSql, &list.name as &ToSql],
-        );
-        Box::new(future::result(res.map(|_| ())))
+        eprintln!("Server error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(feature = "server")]
+fn user<S: ServerStorage>(storage: &S, matches: &clap::ArgMatches) {
+    let res = match matches.subcommand() {
+        ("add", Some(m)) => {
+            let name = m.value_of("name").expect("No value for name");
+            storage.set_password(name, &read_password())
+        }
+        ("token", Some(m)) => {
+            let name = m.value_of("name").expect("No value for name");
+            match storage.create_token(name) {
+                Ok(Some(token)) => {
+                    println!("{}", token);
+                    Ok(())
+                }
+                Ok(None) => {
+                    eprintln!("No such user: {}", name);
+                    std::process::exit(1);
+                }
+                Err(e) => Err(e),
+            }
+        }
+        _ => unreachable!(),
+    };
+    if let Err(e) = res {
+        eprintln!("Database error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(feature = "server")]
+fn grant<S: ServerStorage>(storage: &S, matches: &clap::ArgMatches,
+                           revoke: bool)
+{
+    let board_id = matches.value_of("board").expect("No value for board");
+    let board_id = Uuid::parse_str(board_id).expect("Invalid UUID");
+    let user = matches.value_of("user").expect("No value for user");
+    let role = if revoke {
+        None
+    } else {
+        let role = matches.value_of("role").expect("No value for role");
+        // Checked by clap
+        Some(Role::from_name(role).unwrap())
+    };
+    match storage.set_role(&board_id, user, role) {
+        Ok(true) => {}
+        Ok(false) => {
+            eprintln!("No such user: {}", user);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Database error: {}", e);
+            std::process::exit(1);
+        }
     }
 }
 
 fn main() {
-    let mut cli = App::new("tripledeck")
+    let done_list = Arg::with_name("done-list")
+        .long("done-list")
+        .help("List of the completed tasks")
+        .takes_value(true)
+        .default_value("done");
+    let cli = App::new("tripledeck")
         .bin_name("tripledeck")
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
         .about(env!("CARGO_PKG_DESCRIPTION"))
+        .arg(Arg::with_name("database")
+             .long("database")
+             .help("Path to SQLite database, to a directory keeping each \
+                    board in a file, or postgres:// URL, instead of giving \
+                    it first")
+             .takes_value(true))
         .arg(Arg::with_name("db")
-             .help("Path to database")
-             .required(true)
+             .help("Path to SQLite database, to a directory keeping each \
+                    board in a file, or postgres:// URL")
              .takes_value(true))
         .arg(Arg::with_name("board")
-             .help("Board ID")
+             .help("Board ID, to show it when no command is given")
              .required(false)
-             .takes_value(true));
+             .takes_value(true))
+        .subcommand(SubCommand::with_name("connect")
+                    .about("Sync a board with a server")
+                    .arg(Arg::with_name("url")
+                         .help("WebSocket URL of the server")
+                         .required(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("board")
+                         .help("Board ID")
+                         .required(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("user")
+                         .long("user")
+                         .help("User to log in as, reading the password \
+                                from stdin")
+                         .takes_value(true))
+                    .arg(Arg::with_name("token")
+                         .long("token")
+                         .help("Token to authenticate with")
+                         .env("TRIPLEDECK_TOKEN")
+                         .conflicts_with("user")
+                         .takes_value(true))
+                    .arg(Arg::with_name("prune")
+                         .long("prune")
+                         .help("Forget the operations the server has once \
+                                synced; they can't be synced with peers \
+                                directly anymore")))
+        .subcommand(SubCommand::with_name("undo")
+                    .about("Undo the last change to a board that wasn't \
+                            synced yet")
+                    .arg(Arg::with_name("board")
+                         .help("Board ID")
+                         .required(true)
+                         .takes_value(true)))
+        .subcommand(SubCommand::with_name("log")
+                    .about("Show the activity on a board, list or card")
+                    .arg(Arg::with_name("id")
+                         .help("ID of the board, list or card")
+                         .required(true)
+                         .takes_value(true)))
+        .subcommand(SubCommand::with_name("export")
+                    .about("Write a board to a file")
+                    .arg(Arg::with_name("format")
+                         .long("format")
+                         .help("Format of the file: json (for backups and \
+                                import), or markdown (headings and task \
+                                lists)")
+                         .takes_value(true)
+                         .possible_values(&["json", "markdown"])
+                         .default_value("json"))
+                    .arg(Arg::with_name("board")
+                         .help("Board ID")
+                         .required(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("output")
+                         .short("o")
+                         .long("output")
+                         .help("File to write, instead of stdout")
+                         .takes_value(true)))
+        .subcommand(SubCommand::with_name("import")
+                    .about("Add a board from a file, as a copy if it's \
+                            already there")
+                    .arg(Arg::with_name("format")
+                         .long("format")
+                         .help("Format of the file: json (written by \
+                                export), trello (Trello's JSON export), \
+                                or markdown (headings and task lists)")
+                         .takes_value(true)
+                         .possible_values(&["json", "trello", "markdown"])
+                         .default_value("json"))
+                    .arg(Arg::with_name("file")
+                         .help("File to read, or - for stdin")
+                         .required(true)
+                         .takes_value(true)))
+        .subcommand(SubCommand::with_name("csv")
+                    .about("Export cards to CSV, or import them")
+                    .setting(AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(SubCommand::with_name("export")
+                                .about("Write the cards of a board as CSV")
+                                .arg(Arg::with_name("board")
+                                     .help("Board ID")
+                                     .required(true)
+                                     .takes_value(true))
+                                .arg(Arg::with_name("columns")
+                                     .long("columns")
+                                     .help("Columns to write, separated by \
+                                            commas: title, list, \
+                                            description, labels, assignees, \
+                                            due, created")
+                                     .takes_value(true)
+                                     .use_delimiter(true))
+                                .arg(Arg::with_name("filter")
+                                     .long("filter")
+                                     .help("Only write the cards matching \
+                                            this filter, e.g. 'list:todo'")
+                                     .takes_value(true))
+                                .arg(Arg::with_name("output")
+                                     .short("o")
+                                     .long("output")
+                                     .help("File to write, instead of \
+                                            stdout")
+                                     .takes_value(true)))
+                    .subcommand(SubCommand::with_name("import")
+                                .about("Add cards from CSV to a board, \
+                                        creating the missing lists")
+                                .arg(Arg::with_name("board")
+                                     .help("Board ID")
+                                     .required(true)
+                                     .takes_value(true))
+                                .arg(Arg::with_name("file")
+                                     .help("File to read, or - for stdin")
+                                     .required(true)
+                                     .takes_value(true))
+                                .arg(Arg::with_name("map")
+                                     .long("map")
+                                     .help("Read a column as a card field, \
+                                            e.g. 'Task=title'; columns \
+                                            named after a field are read \
+                                            as that field")
+                                     .takes_value(true)
+                                     .multiple(true)
+                                     .number_of_values(1))))
+        .subcommand(SubCommand::with_name("taskwarrior")
+                    .about("Export cards to Taskwarrior, or import tasks")
+                    .setting(AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(SubCommand::with_name("export")
+                                .about("Write the cards of a board as JSON \
+                                        for 'task import'")
+                                .arg(Arg::with_name("board")
+                                     .help("Board ID")
+                                     .required(true)
+                                     .takes_value(true))
+                                .arg(done_list.clone())
+                                .arg(Arg::with_name("output")
+                                     .short("o")
+                                     .long("output")
+                                     .help("File to write, instead of \
+                                            stdout")
+                                     .takes_value(true)))
+                    .subcommand(SubCommand::with_name("import")
+                                .about("Add the output of 'task export' to \
+                                        a board, updating the cards \
+                                        imported before")
+                                .arg(Arg::with_name("board")
+                                     .help("Board ID")
+                                     .required(true)
+                                     .takes_value(true))
+                                .arg(Arg::with_name("file")
+                                     .help("File to read, or - for stdin")
+                                     .required(true)
+                                     .takes_value(true))
+                                .arg(done_list.clone())))
+        .subcommand(SubCommand::with_name("todotxt")
+                    .about("Export cards to todo.txt, import them, or sync \
+                            a todo.txt file with a board")
+                    .setting(AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(SubCommand::with_name("export")
+                                .about("Write the cards of a board as \
+                                        todo.txt")
+                                .arg(Arg::with_name("board")
+                                     .help("Board ID")
+                                     .required(true)
+                                     .takes_value(true))
+                                .arg(done_list.clone())
+                                .arg(Arg::with_name("output")
+                                     .short("o")
+                                     .long("output")
+                                     .help("File to write, instead of \
+                                            stdout")
+                                     .takes_value(true)))
+                    .subcommand(SubCommand::with_name("import")
+                                .about("Add tasks from todo.txt to a board")
+                                .arg(Arg::with_name("board")
+                                     .help("Board ID")
+                                     .required(true)
+                                     .takes_value(true))
+                                .arg(Arg::with_name("file")
+                                     .help("File to read, or - for stdin")
+                                     .required(true)
+                                     .takes_value(true))
+                                .arg(done_list.clone()))
+                    .subcommand(SubCommand::with_name("sync")
+                                .about("Bring the changes to a todo.txt \
+                                        file and to a board since the last \
+                                        sync to the other side")
+                                .arg(Arg::with_name("board")
+                                     .help("Board ID")
+                                     .required(true)
+                                     .takes_value(true))
+                                .arg(Arg::with_name("file")
+                                     .help("todo.txt file, created if it \
+                                            doesn't exist")
+                                     .required(true)
+                                     .takes_value(true))
+                                .arg(done_list)
+                                .arg(Arg::with_name("state")
+                                     .long("state")
+                                     .help("File keeping what was synced, \
+                                            by default the todo.txt file's \
+                                            path with .tripledeck added")
+                                     .takes_value(true))))
+        .subcommand(SubCommand::with_name("key")
+                    .about("Manage the keys of end-to-end encrypted boards")
+                    .setting(AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(SubCommand::with_name("new")
+                                .about("Generate a new key for a board and \
+                                        print it; the previous keys are kept \
+                                        to read older operations")
+                                .arg(Arg::with_name("board")
+                                     .help("Board ID")
+                                     .required(true)
+                                     .takes_value(true)))
+                    .subcommand(SubCommand::with_name("set")
+                                .about("Set the key of a board, or derive \
+                                        it from a passphrase read from stdin")
+                                .arg(Arg::with_name("board")
+                                     .help("Board ID")
+                                     .required(true)
+                                     .takes_value(true))
+                                .arg(Arg::with_name("key")
+                                     .help("Key, as printed by \"key new\"")
+                                     .required(false)
+                                     .takes_value(true)))
+                    .subcommand(SubCommand::with_name("show")
+                                .about("Print the current key of a board")
+                                .arg(Arg::with_name("board")
+                                     .help("Board ID")
+                                     .required(true)
+                                     .takes_value(true))))
+        .subcommand(SubCommand::with_name("sync")
+                    .about("Sync two databases directly")
+                    .arg(Arg::with_name("database")
+                         .help("Path to database")
+                         .required(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("other")
+                         .help("Path to the other database")
+                         .required_unless("command")
+                         .conflicts_with("command")
+                         .takes_value(true))
+                    .arg(Arg::with_name("command")
+                         .long("command")
+                         .help("Command running sync-stdio to talk to, \
+                                e.g. \"ssh host tripledeck sync-stdio db\"")
+                         .takes_value(true)))
+        .subcommand(SubCommand::with_name("sync-stdio")
+                    .about("Sync a database with a peer over stdin/stdout")
+                    .arg(Arg::with_name("database")
+                         .help("Path to database")
+                         .required(true)
+                         .takes_value(true)));
+    #[cfg(feature = "server")]
+    let cli = cli
+        .subcommand(SubCommand::with_name("serve")
+                    .about("Run the sync server")
+                    .arg(Arg::with_name("listen")
+                         .long("listen")
+                         .help("Address to listen on")
+                         .takes_value(true)
+                         .default_value("127.0.0.1:8000"))
+                    .arg(Arg::with_name("no-auth")
+                         .long("no-auth")
+                         .help("Let anyone read and write any board"))
+                    .arg(Arg::with_name("forget-replicas-after")
+                         .long("forget-replicas-after")
+                         .help("Number of days after which replicas that \
+                                haven't connected no longer prevent pruning \
+                                operations")
+                         .takes_value(true)
+                         .default_value("30")))
+        .subcommand(SubCommand::with_name("user")
+                    .about("Manage the server's users")
+                    .setting(AppSettings::SubcommandRequiredElseHelp)
+                    .subcommand(SubCommand::with_name("add")
+                                .about("Add a user or change their \
+                                        password, read from stdin")
+                                .arg(Arg::with_name("name")
+                                     .help("User name")
+                                     .required(true)
+                                     .takes_value(true)))
+                    .subcommand(SubCommand::with_name("token")
+                                .about("Create a token for a user")
+                                .arg(Arg::with_name("name")
+                                     .help("User name")
+                                     .required(true)
+                                     .takes_value(true))))
+        .subcommand(SubCommand::with_name("grant")
+                    .about("Give a user a role on a board")
+                    .arg(Arg::with_name("board")
+                         .help("Board ID")
+                         .required(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("user")
+                         .help("User name")
+                         .required(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("role")
+                         .help("Role to give the user")
+                         .required(true)
+                         .possible_values(&["owner", "editor", "viewer"])
+                         .takes_value(true)))
+        .subcommand(SubCommand::with_name("revoke")
+                    .about("Remove a user's access to a board")
+                    .arg(Arg::with_name("board")
+                         .help("Board ID")
+                         .required(true)
+                         .takes_value(true))
+                    .arg(Arg::with_name("user")
+                         .help("User name")
+                         .required(true)
+                         .takes_value(true)));
+    let mut cli = cli;
     let matches = match cli.get_matches_from_safe_borrow(std::env::args_os()) {
         Ok(m) => m,
         Err(e) => {
@@ -130,26 +1091,115 @@ fn main() {
             std::process::exit(2);
         }
     };
-    let db = matches.value_of_os("db")
-        .expect("No value for db");
+    // Those take their databases as arguments
+    match matches.subcommand() {
+        ("sync", Some(m)) => return sync(m),
+        ("sync-stdio", Some(m)) => return sync_stdio(m),
+        _ => {}
+    }
 
-    let storage = SqliteStorage::new(db).expect("Can't open database");
-    let app = tripledeck_core::App::new(storage);
+    // With --database, the first argument is the board
+    let (db, board) = match matches.value_of_os("database") {
+        Some(db) => (Some(db), matches.value_of("db")),
+        None => (matches.value_of_os("db"), matches.value_of("board")),
+    };
+    let db = match db {
+        Some(db) => db,
+        None => {
+            eprintln!("{}", matches.usage());
+            std::process::exit(2);
+        }
+    };
 
-    if let Some(board_id) = matches.value_of("board") {
-        let fut = app.get_board(&Uuid::parse_str(board_id)
-                                  .expect("Invalid UUID"));
-        let fut = fut.map(|opt| {
-            match opt {
-                None => println!("No such board"),
-                Some(board) => {
-                    println!("Board: {}", board.board().name);
-                    println!("TODO: print lists, cards");
+    let database = open_database(db);
+
+    match matches.subcommand() {
+        ("", None) => {
+            match database {
+                Database::Sqlite(s) => {
+                    show(&tripledeck_core::App::new(s), board)
+                }
+                Database::Files(s) => {
+                    show(&tripledeck_core::App::new(*s), board)
+                }
+                #[cfg(feature = "postgres")]
+                Database::Postgres(s) => {
+                    show(&tripledeck_core::App::new(s), board)
                 }
             }
-        });
-        futures::executor::spawn(fut).wait_future().unwrap();
-    } else {
-        println!("TODO: print boards");
+        }
+        ("connect", Some(m)) => {
+            connect(
+                database.sqlite(),
+                m.value_of("url").expect("No value for url"),
+                m.value_of("board").expect("No value for board"),
+                credentials(m),
+                m.is_present("prune"),
+            );
+        }
+        ("undo", Some(m)) => {
+            let board = m.value_of("board").expect("No value for board");
+            match database {
+                Database::Sqlite(s) => undo(s, board),
+                Database::Files(s) => undo(*s, board),
+                #[cfg(feature = "postgres")]
+                Database::Postgres(s) => undo(s, board),
+            }
+        }
+        ("log", Some(m)) => {
+            let id = m.value_of("id").expect("No value for id");
+            match database {
+                Database::Sqlite(s) => log(s, id),
+                Database::Files(s) => log(*s, id),
+                #[cfg(feature = "postgres")]
+                Database::Postgres(s) => log(s, id),
+            }
+        }
+        ("export", Some(m)) => {
+            let board = m.value_of("board").expect("No value for board");
+            let output = m.value_of_os("output");
+            let format = m.value_of("format").expect("No value for format");
+            match database {
+                Database::Sqlite(s) => export(s, board, output, format),
+                Database::Files(s) => export(*s, board, output, format),
+                #[cfg(feature = "postgres")]
+                Database::Postgres(s) => export(s, board, output, format),
+            }
+        }
+        ("import", Some(m)) => {
+            let file = m.value_of_os("file").expect("No value for file");
+            let format = m.value_of("format").expect("No value for format");
+            match database {
+                Database::Sqlite(s) => import(s, file, format),
+                Database::Files(s) => import(*s, file, format),
+                #[cfg(feature = "postgres")]
+                Database::Postgres(s) => import(s, file, format),
+            }
+        }
+        ("csv", Some(m)) => match database {
+            Database::Sqlite(s) => csv(s, m),
+            Database::Files(s) => csv(*s, m),
+            #[cfg(feature = "postgres")]
+            Database::Postgres(s) => csv(s, m),
+        },
+        ("taskwarrior", Some(m)) => match database {
+            Database::Sqlite(s) => taskwarrior(s, m),
+            Database::Files(s) => taskwarrior(*s, m),
+            #[cfg(feature = "postgres")]
+            Database::Postgres(s) => taskwarrior(s, m),
+        },
+        ("todotxt", Some(m)) => match database {
+            Database::Sqlite(s) => todotxt(s, m),
+            Database::Files(s) => todotxt(*s, m),
+            #[cfg(feature = "postgres")]
+            Database::Postgres(s) => todotxt(s, m),
+        },
+        ("key", Some(m)) => key(database.sqlite(), m),
+        #[cfg(feature = "server")]
+        (command @ "serve", Some(m)) | (command @ "user", Some(m)) |
+        (command @ "grant", Some(m)) | (command @ "revoke", Some(m)) => {
+            server_command(database, command, m)
+        }
+        _ => unreachable!(),
     }
 }
@@ -0,0 +1,314 @@
+//! Syncing two databases directly, without a server.
+//!
+//! Each side sends the operations the other is missing, identified by their
+//! IDs. Over a pipe, the side that initiated the sync sends the IDs of all its
+//! operations, and the other side answers with the operations the initiator
+//! is missing and the IDs of those it wants in return. Only one side writes
+//! at a time, so the pipes can't fill up in both directions.
+//!
+//! There is no server to decide the order of operations, so both sides apply
+//! them in the order of `Operation::order_key()`, and end up with the same
+//! state; see `SqliteStorage::receive_operations()`.
+
+use serde::{Serialize, Deserialize};
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Command, Stdio};
+use uuid::Uuid;
+
+use tripledeck_core::Operation;
+
+use super::SqliteStorage;
+
+/// Version of the protocol used over pipes.
+const PEER_PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct PeerOperation {
+    operation: Operation,
+    /// Whether the operation still has to be sent to a server
+    pending: bool,
+}
+
+/// Messages, exchanged as lines of JSON.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum PeerMessage {
+    Hello { version: u32 },
+    Have { ids: Vec<Uuid> },
+    Want { ids: Vec<Uuid> },
+    Operations { operations: Vec<PeerOperation> },
+    Error { what: String },
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Storage(rusqlite::Error),
+    Protocol(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::Storage(ref e) => write!(f, "Storage error: {}", e),
+            Error::Protocol(ref e) => write!(f, "Protocol error: {}", e),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Error {
+        Error::Storage(e)
+    }
+}
+
+/// Copy the operations that `to` is missing from `from`.
+fn copy_missing(from: &SqliteStorage, to: &SqliteStorage)
+    -> rusqlite::Result<usize>
+{
+    to.receive_operations(from.get_all_operations()?)
+}
+
+/// Sync two databases opened locally.
+///
+/// Returns the number of operations sent to and received from the other
+/// database.
+pub fn sync_local(storage: &SqliteStorage, other: &SqliteStorage)
+    -> rusqlite::Result<(usize, usize)>
+{
+    let sent = copy_missing(storage, other)?;
+    let received = copy_missing(other, storage)?;
+    Ok((sent, received))
+}
+
+fn send<W: Write>(writer: &mut W, message: &PeerMessage)
+    -> Result<(), Error>
+{
+    let line = serde_json::to_string(message).expect("Serializing message");
+    writeln!(writer, "{}", line)?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn receive<R: BufRead>(reader: &mut R) -> Result<PeerMessage, Error> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(Error::Protocol("Connection closed".into()));
+    }
+    match serde_json::from_str(&line) {
+        Ok(PeerMessage::Error { what }) => Err(Error::Protocol(what)),
+        Ok(message) => Ok(message),
+        Err(e) => Err(Error::Protocol(format!("Invalid message: {}", e))),
+    }
+}
+
+fn unexpected<T>() -> Result<T, Error> {
+    Err(Error::Protocol("Unexpected message".into()))
+}
+
+/// Get the operations in the log whose IDs are in the set.
+fn get_operations(storage: &SqliteStorage, ids: &HashSet<Uuid>)
+    -> rusqlite::Result<Vec<PeerOperation>>
+{
+    Ok(storage.get_all_operations()?.into_iter()
+        .filter(|(op, _)| ids.contains(&op.id))
+        .map(|(operation, pending)| PeerOperation { operation, pending })
+        .collect())
+}
+
+/// Record the operations received from the peer.
+fn receive_operations(storage: &SqliteStorage,
+                      operations: Vec<PeerOperation>)
+    -> rusqlite::Result<usize>
+{
+    storage.receive_operations(
+        operations.into_iter()
+            .map(|PeerOperation { operation, pending }| (operation, pending)),
+    )
+}
+
+/// Sync with a peer running `respond()` at the other end of a pipe.
+pub fn initiate<R: BufRead, W: Write>(storage: &SqliteStorage,
+                                      mut reader: R, mut writer: W)
+    -> Result<(usize, usize), Error>
+{
+    send(&mut writer, &PeerMessage::Hello {
+        version: PEER_PROTOCOL_VERSION,
+    })?;
+    match receive(&mut reader)? {
+        PeerMessage::Hello { .. } => {}
+        _ => return unexpected(),
+    }
+
+    // Tell the peer what we have
+    let ids = storage.get_all_operations()?.into_iter()
+        .map(|(op, _)| op.id)
+        .collect();
+    send(&mut writer, &PeerMessage::Have { ids })?;
+
+    // Get what we are missing
+    let received = match receive(&mut reader)? {
+        PeerMessage::Operations { operations } => {
+            receive_operations(storage, operations)?
+        }
+        _ => return unexpected(),
+    };
+
+    // Send what the peer is missing
+    let wanted = match receive(&mut reader)? {
+        PeerMessage::Want { ids } => ids.into_iter().collect(),
+        _ => return unexpected(),
+    };
+    let operations = get_operations(storage, &wanted)?;
+    let sent = operations.len();
+    send(&mut writer, &PeerMessage::Operations { operations })?;
+
+    Ok((sent, received))
+}
+
+/// Sync with a peer running `initiate()` at the other end of a pipe.
+pub fn respond<R: BufRead, W: Write>(storage: &SqliteStorage,
+                                     mut reader: R, mut writer: W)
+    -> Result<(usize, usize), Error>
+{
+    match receive(&mut reader)? {
+        PeerMessage::Hello { version } if version == PEER_PROTOCOL_VERSION => {}
+        PeerMessage::Hello { version } => {
+            let what = format!("Unsupported protocol version {}", version);
+            send(&mut writer, &PeerMessage::Error { what: what.clone() })?;
+            return Err(Error::Protocol(what));
+        }
+        _ => return unexpected(),
+    }
+    send(&mut writer, &PeerMessage::Hello {
+        version: PEER_PROTOCOL_VERSION,
+    })?;
+
+    let theirs: HashSet<Uuid> = match receive(&mut reader)? {
+        PeerMessage::Have { ids } => ids.into_iter().collect(),
+        _ => return unexpected(),
+    };
+
+    // Send what the peer is missing, and ask for what we are missing
+    let ours = storage.get_all_operations()?;
+    let ours_ids: HashSet<Uuid> = ours.iter().map(|(op, _)| op.id).collect();
+    let operations: Vec<_> = ours.into_iter()
+        .filter(|(op, _)| !theirs.contains(&op.id))
+        .map(|(operation, pending)| PeerOperation { operation, pending })
+        .collect();
+    let sent = operations.len();
+    send(&mut writer, &PeerMessage::Operations { operations })?;
+    send(&mut writer, &PeerMessage::Want {
+        ids: theirs.difference(&ours_ids).cloned().collect(),
+    })?;
+
+    let received = match receive(&mut reader)? {
+        PeerMessage::Operations { operations } => {
+            receive_operations(storage, operations)?
+        }
+        _ => return unexpected(),
+    };
+
+    Ok((sent, received))
+}
+
+/// Sync with a peer by running a command, such as
+/// `ssh host tripledeck sync-stdio db`.
+pub fn sync_command(storage: &SqliteStorage, command: &str)
+    -> Result<(usize, usize), Error>
+{
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let res = {
+        let reader = BufReader::new(child.stdout.take().unwrap());
+        let writer = child.stdin.take().unwrap();
+        initiate(storage, reader, writer)
+    };
+    let status = child.wait()?;
+    let counts = res?;
+    if !status.success() {
+        return Err(Error::Protocol(format!("Command failed: {}", status)));
+    }
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use uuid::Uuid;
+
+    use tripledeck_core::{Card, Change, List, Operation, Store};
+
+    use crate::SqliteStorage;
+    use super::sync_local;
+
+    /// Make a change locally at a given time, as the app would.
+    fn change(storage: &SqliteStorage, board_id: Uuid, time: u64,
+              change: Change) {
+        let mut op = Operation::new(board_id, change);
+        op.time = Some(time);
+        assert!(block_on(storage.add_operation(&op, true)).unwrap());
+        block_on(op.apply(storage)).unwrap();
+    }
+
+    fn titles(storage: &SqliteStorage, board_id: &Uuid) -> Vec<String> {
+        let mut titles: Vec<String> = block_on(storage.get_cards(board_id))
+            .unwrap().into_iter().map(|c| c.title).collect();
+        titles.sort();
+        titles
+    }
+
+    #[test]
+    fn test_conflicting_edits() {
+        let first = SqliteStorage::new(":memory:").unwrap();
+        let second = SqliteStorage::new(":memory:").unwrap();
+        let board_id = Uuid::new_v4();
+        let list = List { id: Uuid::new_v4(), name: "todo".into() };
+        let card = Card::new(list.id, "card");
+        change(&first, board_id, 1, Change::AddBoard { name: "B".into() });
+        change(&first, board_id, 2, Change::AddList { list });
+        change(&first, board_id, 3, Change::AddCard {
+            card: card.clone(),
+            previous: None,
+        });
+        assert_eq!(sync_local(&first, &second).unwrap(), (3, 0));
+
+        // Both sides edit the card, the latest edit wins on both
+        let edit = |title: &str| Change::AddCard {
+            card: Card { title: title.into(), ..card.clone() },
+            previous: Some(card.clone()),
+        };
+        change(&first, board_id, 10, edit("first"));
+        change(&second, board_id, 20, edit("second"));
+        assert_eq!(sync_local(&first, &second).unwrap(), (1, 1));
+        assert_eq!(titles(&first, &board_id), vec!["second"]);
+        assert_eq!(titles(&second, &board_id), vec!["second"]);
+
+        // The order the operations arrive in doesn't matter
+        change(&first, board_id, 40, edit("first"));
+        change(&second, board_id, 30, edit("second"));
+        change(&second, board_id, 31,
+               Change::AddCard {
+                   card: Card::new(card.list, "other"),
+                   previous: None,
+               });
+        assert_eq!(sync_local(&first, &second).unwrap(), (1, 2));
+        assert_eq!(titles(&first, &board_id), vec!["first", "other"]);
+        assert_eq!(titles(&second, &board_id), vec!["first", "other"]);
+        assert_eq!(block_on(first.get_activity(&board_id)).unwrap(),
+                   block_on(second.get_activity(&board_id)).unwrap());
+    }
+}
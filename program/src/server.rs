@@ -0,0 +1,907 @@
+//! WebSocket sync server.
+//!
+//! Each client is handled by its own thread. Operations pushed by a client
+//! are recorded in the database and applied to it, then every client syncing
+//! the same board is woken up to send the new operations from the database,
+//! which is the only source of truth for what each client has to receive.
+//!
+//! Once a board has enough new operations, a client owning it is asked for a
+//! snapshot, after which the operations every replica has are pruned; see
+//! `ServerStorage`.
+//!
+//! Unless authentication is disabled, clients have to send valid credentials
+//! and have a role on the board they sync. A board the server doesn't have
+//! yet belongs to the user who creates it by pushing to it; other boards have
+//! to be shared explicitly, with `tripledeck grant`.
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+use tungstenite::{Message, WebSocket};
+use tungstenite::handshake::HandshakeError;
+use uuid::Uuid;
+
+use tripledeck_core::{Change, Operation};
+use tripledeck_core::activity::Actor;
+use tripledeck_core::server::ServerStorage;
+use tripledeck_core::snapshot::SnapshotData;
+use tripledeck_core::sync::{ClientMessage, Credentials, Role, ServerMessage,
+                            SyncError, PROTOCOL_VERSION};
+
+/// How often a client thread checks for new operations while waiting for
+/// messages.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Number of operations after which a board needs a new snapshot.
+const SNAPSHOT_INTERVAL: u64 = 100;
+
+struct Server<S: ServerStorage> {
+    storage: S,
+    /// Channels waking up the client threads, by board
+    subscribers: Mutex<HashMap<Uuid, Vec<Sender<()>>>>,
+    /// Whether clients have to authenticate
+    require_auth: bool,
+    /// How long replicas that don't connect are considered when pruning
+    forget_replicas_after: Duration,
+}
+
+fn storage_error<E: Display>(e: E) -> SyncError {
+    SyncError::Storage { what: e.to_string() }
+}
+
+/// Check that a user has a role on a board, or that the board doesn't exist
+/// yet, in which case they can connect to create it.
+fn check_read<S: ServerStorage>(storage: &S, board_id: &Uuid, user: &str)
+    -> Result<(), SyncError>
+{
+    let role = storage.get_role(board_id, user).map_err(storage_error)?;
+    if role.is_none() &&
+        storage.get_last_seq(board_id).map_err(storage_error)? > 0
+    {
+        return Err(SyncError::PermissionDenied {
+            board_id: *board_id,
+            role: None,
+        });
+    }
+    Ok(())
+}
+
+impl<S: ServerStorage> Server<S> {
+    fn new(storage: S, require_auth: bool, forget_replicas_after: Duration)
+        -> Server<S>
+    {
+        Server {
+            storage,
+            subscribers: Mutex::new(HashMap::new()),
+            require_auth,
+            forget_replicas_after,
+        }
+    }
+
+    fn subscribe(&self, board_id: Uuid) -> Receiver<()> {
+        let (sender, receiver) = channel();
+        self.subscribers.lock().unwrap()
+            .entry(board_id)
+            .or_default()
+            .push(sender);
+        receiver
+    }
+
+    /// Wake up all the clients syncing a board.
+    fn notify(&self, board_id: &Uuid) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if let Some(senders) = subscribers.get_mut(board_id) {
+            // Clients that are gone have dropped their receiver
+            senders.retain(|sender| sender.send(()).is_ok());
+            if senders.is_empty() {
+                subscribers.remove(board_id);
+            }
+        }
+    }
+
+    /// Check a client's credentials and that it can read the board.
+    ///
+    /// Returns the name of the user, or None if authentication is disabled.
+    fn authenticate(&self, board_id: &Uuid,
+                    credentials: Option<&Credentials>)
+        -> Result<Option<String>, SyncError>
+    {
+        if !self.require_auth {
+            return Ok(None);
+        }
+        let storage = &self.storage;
+        let user = match credentials {
+            Some(credentials) => {
+                storage.authenticate(credentials).map_err(storage_error)?
+            }
+            None => None,
+        };
+        let user = user.ok_or(SyncError::AuthenticationFailed)?;
+        check_read(storage, board_id, &user)?;
+        Ok(Some(user))
+    }
+
+    /// Check that a user can still read a board, before sending it new
+    /// operations.
+    fn check_read(&self, board_id: &Uuid, user: Option<&str>)
+        -> Result<(), SyncError>
+    {
+        match user {
+            Some(user) => check_read(&self.storage, board_id, user),
+            None => Ok(()),
+        }
+    }
+
+    /// Check that a user can push to a board, making them its owner if the
+    /// server doesn't have the board yet.
+    ///
+    /// The role is looked up again on each push, so that changes apply to
+    /// connected clients.
+    fn check_write(&self, board_id: &Uuid, user: Option<&str>)
+        -> Result<(), SyncError>
+    {
+        let user = match user {
+            Some(user) => user,
+            None => return Ok(()),
+        };
+        let storage = &self.storage;
+        let mut role = storage.get_role(board_id, user).map_err(storage_error)?;
+        if role.is_none() &&
+            storage.claim_board(board_id, user).map_err(storage_error)?
+        {
+            eprintln!("User {} created and owns board {}", user, board_id);
+            role = Some(Role::Owner);
+        }
+        match role {
+            Some(role) if role.can_write() => Ok(()),
+            role => Err(SyncError::PermissionDenied {
+                board_id: *board_id,
+                role,
+            }),
+        }
+    }
+
+    /// Check that a user can send a snapshot. Since that prunes everyone's
+    /// history, it takes more than being able to write.
+    fn check_snapshot(&self, board_id: &Uuid, user: Option<&str>)
+        -> Result<(), SyncError>
+    {
+        let user = match user {
+            Some(user) => user,
+            None => return Ok(()),
+        };
+        let storage = &self.storage;
+        match storage.get_role(board_id, user).map_err(storage_error)? {
+            Some(role) if role.can_snapshot() => Ok(()),
+            role => Err(SyncError::PermissionDenied {
+                board_id: *board_id,
+                role,
+            }),
+        }
+    }
+
+    /// Record where a replica is, and get what it has to be sent first.
+    ///
+    /// Returns the position of the last operation, and the snapshot to send
+    /// the replica if it missed operations that were pruned or is new.
+    fn start(&self, board_id: &Uuid, replica_id: Option<&Uuid>, cursor: u64)
+        -> Result<(u64, Option<(u64, SnapshotData)>), SyncError>
+    {
+        let storage = &self.storage;
+        if let Some(replica_id) = replica_id {
+            let pruned = storage.record_replica(board_id, replica_id, cursor)
+                .and_then(|()| {
+                    storage.prune(board_id, self.forget_replicas_after)
+                })
+                .map_err(storage_error)?;
+            if pruned > 0 {
+                eprintln!("Pruned {} operations from board {}",
+                          pruned, board_id);
+            }
+        }
+        let last = storage.get_last_seq(board_id).map_err(storage_error)?;
+        let snapshot = storage.get_snapshot(board_id).map_err(storage_error)?;
+        let snapshot = match snapshot {
+            Some(s) if cursor < s.pruned || (cursor == 0 && s.cursor > 0) => {
+                Some((s.cursor, s.data))
+            }
+            _ => None,
+        };
+        Ok((last, snapshot))
+    }
+
+    /// Whether we should ask this client for a snapshot.
+    fn wants_snapshot(&self, board_id: &Uuid, user: Option<&str>)
+        -> Result<bool, SyncError>
+    {
+        let storage = &self.storage;
+        if let Some(user) = user {
+            let role = storage.get_role(board_id, user)
+                .map_err(storage_error)?;
+            if !role.map(Role::can_snapshot).unwrap_or(false) {
+                return Ok(false);
+            }
+        }
+        let since = storage.get_snapshot(board_id).map_err(storage_error)?
+            .map(|s| s.cursor)
+            .unwrap_or(0);
+        let count = storage.count_operations_since(board_id, since)
+            .map_err(storage_error)?;
+        Ok(count >= SNAPSHOT_INTERVAL)
+    }
+
+    /// Store a snapshot sent by a client, and prune the operations it
+    /// covers.
+    fn store_snapshot(&self, board_id: &Uuid, cursor: u64,
+                      snapshot: &SnapshotData)
+        -> Result<(), SyncError>
+    {
+        if let SnapshotData::Plain { ref snapshot } = *snapshot {
+            if snapshot.board.id != *board_id {
+                return Err(SyncError::WrongBoard {
+                    board_id: snapshot.board.id,
+                });
+            }
+        }
+        let storage = &self.storage;
+        let last = storage.get_last_seq(board_id).map_err(storage_error)?;
+        if cursor > last {
+            return Err(SyncError::InvalidMessage {
+                what: "Snapshot is ahead of the log".into(),
+            });
+        }
+        storage.store_snapshot(board_id, cursor, snapshot)
+            .map_err(storage_error)?;
+        let pruned = storage.prune(board_id, self.forget_replicas_after)
+            .map_err(storage_error)?;
+        if pruned > 0 {
+            eprintln!("Pruned {} operations from board {}", pruned, board_id);
+        }
+        Ok(())
+    }
+
+    /// Record and apply operations pushed by a client.
+    ///
+    /// Operations made by users are attributed to the one who pushed them,
+    /// whatever name the client put in. The actor of encrypted operations is
+    /// authenticated with the ciphertext and can't be changed, so those are
+    /// refused if they name another user.
+    fn store(&self, board_id: &Uuid, user: Option<&str>,
+             operations: &[Operation])
+        -> Result<(), SyncError>
+    {
+        if let Some(op) = operations.iter().find(|op| op.board_id != *board_id) {
+            return Err(SyncError::WrongBoard { board_id: op.board_id });
+        }
+        let mut stored = Vec::with_capacity(operations.len());
+        for op in operations {
+            let mut op = op.clone();
+            if let Some(user) = user {
+                match (&op.change, &op.actor) {
+                    (_, Some(Actor::Rule { .. })) => {}
+                    (Change::Encrypted { .. }, None) => {}
+                    (Change::Encrypted { .. }, Some(Actor::User { name }))
+                    if name == user => {}
+                    (Change::Encrypted { .. }, Some(_)) => {
+                        return Err(SyncError::InvalidMessage {
+                            what: "Encrypted operation attributed to \
+                                   another user".into(),
+                        });
+                    }
+                    _ => op.actor = Some(Actor::User { name: user.into() }),
+                }
+            }
+            stored.push((op, false));
+        }
+        let operations = stored;
+        let storage = &self.storage;
+        // Don't apply an operation twice if the client resends it
+        storage.receive_operations(operations).map_err(storage_error)?;
+        Ok(())
+    }
+}
+
+fn send(ws: &mut WebSocket<TcpStream>, msg: &ServerMessage)
+    -> tungstenite::Result<()>
+{
+    ws.send(Message::Text(msg.to_json()))
+}
+
+fn send_error(ws: &mut WebSocket<TcpStream>, error: SyncError)
+    -> tungstenite::Result<()>
+{
+    send(ws, &ServerMessage::Error { error })
+}
+
+/// Send the operations the client doesn't have yet, returning the new cursor.
+fn send_operations<S: ServerStorage>(ws: &mut WebSocket<TcpStream>,
+                                     server: &Server<S>, board_id: &Uuid,
+                                     cursor: u64)
+    -> tungstenite::Result<u64>
+{
+    let res = server.storage.get_operations(board_id, cursor);
+    let operations = match res {
+        Ok(ops) => ops,
+        Err(e) => {
+            send_error(ws, SyncError::Storage { what: e.to_string() })?;
+            return Ok(cursor);
+        }
+    };
+    let cursor = match operations.last() {
+        Some(&(seq, _)) => seq,
+        None => return Ok(cursor),
+    };
+    send(ws, &ServerMessage::Operations {
+        operations: operations.into_iter().map(|(_, op)| op).collect(),
+        cursor,
+    })?;
+    Ok(cursor)
+}
+
+fn is_timeout(error: &tungstenite::Error) -> bool {
+    match *error {
+        tungstenite::Error::Io(ref e) => {
+            e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut
+        }
+        _ => false,
+    }
+}
+
+fn handle_client<S: ServerStorage>(server: &Server<S>, stream: TcpStream)
+    -> tungstenite::Result<()>
+{
+    let mut ws = match tungstenite::accept(stream) {
+        Ok(ws) => ws,
+        Err(HandshakeError::Failure(e)) => return Err(e),
+        Err(HandshakeError::Interrupted(_)) => unreachable!(),
+    };
+
+    // Handshake
+    let (board_id, mut cursor, user, replica_id) = loop {
+        let text = match ws.read()? {
+            Message::Text(text) => text,
+            Message::Close(_) => return Ok(()),
+            _ => continue,
+        };
+        match ClientMessage::from_json(&text) {
+            Ok(ClientMessage::Hello {
+                version, board_id, cursor, credentials, replica_id,
+            }) => {
+                if version != PROTOCOL_VERSION {
+                    return send_error(&mut ws, SyncError::UnsupportedVersion {
+                        supported: PROTOCOL_VERSION,
+                    });
+                }
+                match server.authenticate(&board_id, credentials.as_ref()) {
+                    Ok(user) => break (board_id, cursor, user, replica_id),
+                    Err(e) => return send_error(&mut ws, e),
+                }
+            }
+            Ok(_) => return send_error(&mut ws, SyncError::UnexpectedMessage),
+            Err(e) => return send_error(&mut ws, e),
+        }
+    };
+    let res = server.start(&board_id, replica_id.as_ref(), cursor)
+        .and_then(|(last, snapshot)| {
+            server.wants_snapshot(&board_id, user.as_deref())
+                .map(|wants_snapshot| (last, snapshot, wants_snapshot))
+        });
+    let (last, snapshot, wants_snapshot) = match res {
+        Ok(r) => r,
+        Err(e) => return send_error(&mut ws, e),
+    };
+    send(&mut ws, &ServerMessage::Welcome {
+        version: PROTOCOL_VERSION,
+        cursor: last,
+        wants_snapshot,
+    })?;
+    if let Some((snapshot_cursor, snapshot)) = snapshot {
+        send(&mut ws, &ServerMessage::Snapshot {
+            cursor: snapshot_cursor,
+            snapshot,
+        })?;
+        cursor = snapshot_cursor;
+    }
+
+    // Send what the client missed, then wait for messages and new operations
+    let wakeup = server.subscribe(board_id);
+    cursor = send_operations(&mut ws, server, &board_id, cursor)?;
+    ws.get_ref().set_read_timeout(Some(POLL_INTERVAL))?;
+    loop {
+        match ws.read() {
+            Ok(Message::Text(text)) => {
+                match ClientMessage::from_json(&text) {
+                    Ok(ClientMessage::Push { operations }) => {
+                        let res = server
+                            .check_write(&board_id, user.as_deref())
+                            .and_then(|()| server.store(
+                                &board_id, user.as_deref(), &operations,
+                            ));
+                        match res {
+                            Ok(()) => {
+                                send(&mut ws, &ServerMessage::Ack {
+                                    ids: operations.iter()
+                                        .map(|op| op.id)
+                                        .collect(),
+                                })?;
+                                server.notify(&board_id);
+                            }
+                            Err(e) => send_error(&mut ws, e)?,
+                        }
+                    }
+                    Ok(ClientMessage::Snapshot { cursor, snapshot }) => {
+                        let res = server
+                            .check_snapshot(&board_id, user.as_deref())
+                            .and_then(|()| {
+                                server.store_snapshot(
+                                    &board_id, cursor, &snapshot,
+                                )
+                            });
+                        if let Err(e) = res {
+                            send_error(&mut ws, e)?;
+                        }
+                    }
+                    Ok(ClientMessage::Hello { .. }) => {
+                        send_error(&mut ws, SyncError::UnexpectedMessage)?;
+                    }
+                    Err(e) => send_error(&mut ws, e)?,
+                }
+            }
+            Ok(_) => {}
+            Err(ref e) if is_timeout(e) => {}
+            Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+            Err(e) => return Err(e),
+        }
+
+        let mut woken = false;
+        while wakeup.try_recv().is_ok() {
+            woken = true;
+        }
+        if woken {
+            if let Err(e) = server.check_read(&board_id, user.as_deref()) {
+                return send_error(&mut ws, e);
+            }
+            cursor = send_operations(&mut ws, server, &board_id, cursor)?;
+        }
+    }
+}
+
+/// Accept WebSocket clients on the given address, forever.
+///
+/// If `require_auth` is false, anyone can read and write any board.
+pub fn serve<S, A>(storage: S, addr: A, require_auth: bool,
+                   forget_replicas_after: Duration)
+    -> std::io::Result<()>
+    where S: ServerStorage + 'static, A: ToSocketAddrs
+{
+    let listener = TcpListener::bind(addr)?;
+    eprintln!("Listening on {}", listener.local_addr()?);
+    let server = Server::new(storage, require_auth, forget_replicas_after);
+    run(listener, Arc::new(server))
+}
+
+/// Handle the clients connecting to a listener, each in its own thread.
+fn run<S: ServerStorage + 'static>(listener: TcpListener,
+                                   server: Arc<Server<S>>)
+    -> std::io::Result<()>
+{
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let server = server.clone();
+        thread::spawn(move || {
+            let peer = stream.peer_addr().ok();
+            if let Err(e) = handle_client(&server, stream) {
+                eprintln!("Error with client {:?}: {}", peer, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use std::fs;
+    use std::net::{TcpListener, TcpStream};
+    use std::path::PathBuf;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use tungstenite::{Message, WebSocket};
+    use tungstenite::stream::MaybeTlsStream;
+    use uuid::Uuid;
+
+    use tripledeck_core::{App, Board, Change, List, Operation, Store};
+    use tripledeck_core::activity::Actor;
+    use tripledeck_core::server::ServerStorage;
+    use tripledeck_core::snapshot::{Snapshot, SnapshotData};
+    use tripledeck_core::crypto::{BoardKey, Keyring};
+    use tripledeck_core::sync::{ClientMessage, Credentials, Role,
+                                ServerMessage, SyncClient, SyncError,
+                                PROTOCOL_VERSION};
+
+    use tripledeck_sqlite::{SqliteStorage, uuid2str};
+    use crate::client::WebSocketTransport;
+    use super::{Server, run};
+
+    type Ws = WebSocket<MaybeTlsStream<TcpStream>>;
+
+    /// Start a server on a free port, returning its URL.
+    fn start<S: ServerStorage + 'static>(storage: S, require_auth: bool)
+        -> String
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let server = Server::new(storage, require_auth,
+                                 Duration::from_secs(3600));
+        thread::spawn(move || run(listener, Arc::new(server)));
+        url
+    }
+
+    fn send(ws: &mut Ws, msg: &ClientMessage) {
+        ws.send(Message::Text(msg.to_json())).unwrap();
+    }
+
+    fn receive(ws: &mut Ws) -> ServerMessage {
+        loop {
+            if let Message::Text(text) = ws.read().unwrap() {
+                return ServerMessage::from_json(&text).unwrap();
+            }
+        }
+    }
+
+    /// Connect and say hello, returning the server's answer.
+    fn hello(url: &str, board_id: Uuid, cursor: u64,
+             credentials: Option<Credentials>)
+        -> (Ws, ServerMessage)
+    {
+        let (mut ws, _) = tungstenite::connect(url).unwrap();
+        send(&mut ws, &ClientMessage::Hello {
+            version: PROTOCOL_VERSION,
+            board_id,
+            cursor,
+            credentials,
+            replica_id: None,
+        });
+        let answer = receive(&mut ws);
+        (ws, answer)
+    }
+
+    fn welcome(cursor: u64) -> ServerMessage {
+        ServerMessage::Welcome {
+            version: PROTOCOL_VERSION,
+            cursor,
+            wants_snapshot: false,
+        }
+    }
+
+    #[test]
+    fn test_protocol() {
+        let url = start(SqliteStorage::new(":memory:").unwrap(), false);
+        let board_id = Uuid::new_v4();
+        let ops = [
+            Operation::new(board_id, Change::AddBoard { name: "Work".into() }),
+            Operation::new(board_id, Change::AddList {
+                list: List { id: Uuid::new_v4(), name: "todo".into() },
+            }),
+            Operation::new(board_id, Change::AddList {
+                list: List { id: Uuid::new_v4(), name: "done".into() },
+            }),
+        ];
+
+        // Pushed operations are acknowledged, then sent back in order
+        let (mut a, answer) = hello(&url, board_id, 0, None);
+        assert_eq!(answer, welcome(0));
+        send(&mut a, &ClientMessage::Push { operations: ops[..2].to_vec() });
+        assert_eq!(receive(&mut a),
+                   ServerMessage::Ack { ids: vec![ops[0].id, ops[1].id] });
+        assert_eq!(receive(&mut a), ServerMessage::Operations {
+            operations: ops[..2].to_vec(),
+            cursor: 2,
+        });
+
+        // A new client gets everything, a returning one what it missed
+        let (mut b, answer) = hello(&url, board_id, 0, None);
+        assert_eq!(answer, welcome(2));
+        assert_eq!(receive(&mut b), ServerMessage::Operations {
+            operations: ops[..2].to_vec(),
+            cursor: 2,
+        });
+        let (mut c, answer) = hello(&url, board_id, 1, None);
+        assert_eq!(answer, welcome(2));
+        assert_eq!(receive(&mut c), ServerMessage::Operations {
+            operations: ops[1..2].to_vec(),
+            cursor: 2,
+        });
+
+        // Connected clients get new operations as they come in
+        send(&mut a, &ClientMessage::Push { operations: ops[2..].to_vec() });
+        for ws in [&mut b, &mut c] {
+            assert_eq!(receive(ws), ServerMessage::Operations {
+                operations: ops[2..].to_vec(),
+                cursor: 3,
+            });
+        }
+
+        // Operations for other boards are refused
+        let other = Operation::new(Uuid::new_v4(), Change::AddBoard {
+            name: "Other".into(),
+        });
+        send(&mut b, &ClientMessage::Push { operations: vec![other.clone()] });
+        assert_eq!(receive(&mut b), ServerMessage::Error {
+            error: SyncError::WrongBoard { board_id: other.board_id },
+        });
+        send(&mut b, &ClientMessage::Hello {
+            version: PROTOCOL_VERSION,
+            board_id,
+            cursor: 0,
+            credentials: None,
+            replica_id: None,
+        });
+        assert_eq!(receive(&mut b), ServerMessage::Error {
+            error: SyncError::UnexpectedMessage,
+        });
+
+        // So are other versions of the protocol
+        let (mut ws, _) = tungstenite::connect(&url).unwrap();
+        send(&mut ws, &ClientMessage::Hello {
+            version: PROTOCOL_VERSION + 1,
+            board_id,
+            cursor: 0,
+            credentials: None,
+            replica_id: None,
+        });
+        assert_eq!(receive(&mut ws), ServerMessage::Error {
+            error: SyncError::UnsupportedVersion {
+                supported: PROTOCOL_VERSION,
+            },
+        });
+    }
+
+    #[test]
+    fn test_auth() {
+        check_auth(SqliteStorage::new(":memory:").unwrap());
+    }
+
+    /// Run the server on PostgreSQL, in a new schema of the database given
+    /// by `TRIPLEDECK_TEST_POSTGRES`, if set.
+    #[cfg(feature = "postgres")]
+    #[test]
+    fn test_auth_postgres() {
+        use postgres::{Config, NoTls};
+        use tripledeck_postgres::PostgresStorage;
+
+        let url = match std::env::var("TRIPLEDECK_TEST_POSTGRES") {
+            Ok(url) => url,
+            Err(_) => {
+                eprintln!("TRIPLEDECK_TEST_POSTGRES is not set, skipping");
+                return;
+            }
+        };
+        let mut config: Config = url.parse().unwrap();
+        let schema = format!("test_{}", Uuid::new_v4().to_simple_ref());
+        let mut client = config.connect(NoTls).unwrap();
+        client.batch_execute(&format!("CREATE SCHEMA {};", schema)).unwrap();
+        config.options(&format!("-c search_path={}", schema));
+        let storage = PostgresStorage::with_config(&config).unwrap();
+        let res = std::panic::catch_unwind(|| check_auth(storage));
+        client.batch_execute(&format!("DROP SCHEMA {} CASCADE;", schema))
+            .unwrap();
+        if let Err(e) = res {
+            std::panic::resume_unwind(e);
+        }
+    }
+
+    fn check_auth<S: ServerStorage + 'static>(storage: S) {
+        storage.set_password("alice", "a").unwrap();
+        storage.set_password("bob", "b").unwrap();
+        storage.set_password("carol", "c").unwrap();
+        let token = |user: &str| Some(Credentials::Token {
+            token: storage.create_token(user).unwrap().unwrap(),
+        });
+        let (alice, bob, carol) = (token("alice"), token("bob"),
+                                   token("carol"));
+        let shared = Uuid::new_v4();
+        storage.set_role(&shared, "alice", Some(Role::Owner)).unwrap();
+        storage.set_role(&shared, "bob", Some(Role::Viewer)).unwrap();
+        storage.set_role(&shared, "carol", Some(Role::Editor)).unwrap();
+        let legacy = Uuid::new_v4();
+        let op = Operation::new(legacy, Change::AddBoard {
+            name: "Legacy".into(),
+        });
+        storage.receive_operations(vec![(op, false)]).unwrap();
+        let url = start(storage, true);
+        let denied = |board_id, role| ServerMessage::Error {
+            error: SyncError::PermissionDenied { board_id, role },
+        };
+
+        // Clients have to authenticate
+        let failed = ServerMessage::Error {
+            error: SyncError::AuthenticationFailed,
+        };
+        assert_eq!(hello(&url, shared, 0, None).1, failed);
+        let wrong = Credentials::Token { token: "00".into() };
+        assert_eq!(hello(&url, shared, 0, Some(wrong)).1, failed);
+
+        // Viewers can read but not write
+        let (mut ws, answer) = hello(&url, shared, 0, bob.clone());
+        assert_eq!(answer, welcome(0));
+        let op = Operation::new(shared, Change::AddBoard {
+            name: "Shared".into(),
+        });
+        send(&mut ws, &ClientMessage::Push { operations: vec![op.clone()] });
+        assert_eq!(receive(&mut ws), denied(shared, Some(Role::Viewer)));
+        let (mut ws, _) = hello(&url, shared, 0, alice.clone());
+        send(&mut ws, &ClientMessage::Push { operations: vec![op] });
+        assert!(matches!(receive(&mut ws), ServerMessage::Ack { .. }));
+
+        // Only owners can send snapshots, which prune the history
+        let (mut ws, _) = hello(&url, shared, 0, carol);
+        let snapshot = ClientMessage::Snapshot {
+            cursor: 1,
+            snapshot: SnapshotData::Plain {
+                snapshot: Snapshot {
+                    board: Board { id: shared, name: "Shared".into() },
+                    lists: vec![],
+                    cards: vec![],
+                },
+            },
+        };
+        send(&mut ws, &snapshot);
+        match receive(&mut ws) {
+            ServerMessage::Operations { .. } => {}
+            m => panic!("Unexpected message {:?}", m),
+        }
+        assert_eq!(receive(&mut ws), denied(shared, Some(Role::Editor)));
+
+        // A new board belongs to whoever creates it
+        let board_id = Uuid::new_v4();
+        let (mut ws, answer) = hello(&url, board_id, 0, alice.clone());
+        assert_eq!(answer, welcome(0));
+        let op = Operation::new(board_id, Change::AddBoard {
+            name: "Mine".into(),
+        });
+        send(&mut ws, &ClientMessage::Push { operations: vec![op] });
+        assert!(matches!(receive(&mut ws), ServerMessage::Ack { .. }));
+        assert_eq!(hello(&url, board_id, 0, bob.clone()).1,
+                   denied(board_id, None));
+
+        // Encrypted operations keep their actor, which can't be another user
+        let keyring = Keyring::new(BoardKey::generate());
+        let (mut ws, _) = hello(&url, board_id, 0, alice.clone());
+        assert!(matches!(receive(&mut ws), ServerMessage::Operations { .. }));
+        let mut op = Operation::new(board_id, Change::AddList {
+            list: List { id: Uuid::new_v4(), name: "secret".into() },
+        });
+        op.actor = Some(Actor::User { name: "bob".into() });
+        let forged = keyring.encrypt(&op);
+        send(&mut ws, &ClientMessage::Push { operations: vec![forged] });
+        assert!(matches!(receive(&mut ws), ServerMessage::Error {
+            error: SyncError::InvalidMessage { .. },
+        }));
+        op.actor = Some(Actor::User { name: "alice".into() });
+        let encrypted = keyring.encrypt(&op);
+        send(&mut ws, &ClientMessage::Push {
+            operations: vec![encrypted.clone()],
+        });
+        assert!(matches!(receive(&mut ws), ServerMessage::Ack { .. }));
+        match receive(&mut ws) {
+            ServerMessage::Operations { operations, .. } => {
+                assert_eq!(operations, vec![encrypted]);
+            }
+            m => panic!("Unexpected message {:?}", m),
+        }
+
+        // A board that exists with no members is not open to anyone
+        assert_eq!(hello(&url, legacy, 0, alice).1, denied(legacy, None));
+        assert_eq!(hello(&url, legacy, 0, bob).1, denied(legacy, None));
+    }
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(
+            format!("tripledeck-test-{}.sqlite3", Uuid::new_v4()),
+        )
+    }
+
+    /// Sync a board of a database with the server.
+    fn sync(url: &str, path: &PathBuf, board_id: Uuid) {
+        let storage = Rc::new(SqliteStorage::new(path).unwrap());
+        block_on(async {
+            let transport = WebSocketTransport::connect(url).unwrap();
+            let client = SyncClient::connect(storage, transport,
+                                             board_id, None).await?;
+            client.sync().await
+        }).unwrap();
+    }
+
+    fn lists(path: &PathBuf, board_id: &Uuid) -> Vec<String> {
+        let storage = SqliteStorage::new(path).unwrap();
+        let mut names: Vec<String> = block_on(storage.get_lists(board_id))
+            .unwrap().into_iter().map(|l| l.name).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_sync_client() {
+        let url = start(SqliteStorage::new(":memory:").unwrap(), false);
+        let first = temp_path();
+        let second = temp_path();
+
+        let app = App::new(SqliteStorage::new(&first).unwrap());
+        let board = block_on(app.new_board("Work")).unwrap();
+        let board_id = board.board().id;
+        block_on(board.add_list("todo")).unwrap();
+        sync(&url, &first, board_id);
+        let storage = SqliteStorage::new(&first).unwrap();
+        assert!(block_on(storage.get_pending_operations(&board_id))
+                .unwrap().is_empty());
+
+        sync(&url, &second, board_id);
+        let storage = SqliteStorage::new(&second).unwrap();
+        let board = block_on(storage.get_board(&board_id)).unwrap().unwrap();
+        assert_eq!(board.name, "Work");
+        assert_eq!(lists(&second, &board_id), vec!["todo"]);
+
+        // Changes on either side make it to the other
+        let app = App::new(storage);
+        let board = block_on(app.get_board(&board_id)).unwrap().unwrap();
+        block_on(board.add_list("done")).unwrap();
+        sync(&url, &second, board_id);
+        sync(&url, &first, board_id);
+        assert_eq!(lists(&first, &board_id), vec!["done", "todo"]);
+
+        for path in &[first, second] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    /// Store operations through a server, and get its database file.
+    fn store(operations: &[Operation]) -> Vec<u8> {
+        let path = temp_path();
+        let storage = SqliteStorage::new(&path).unwrap();
+        let server = Server::new(storage, false, Duration::from_secs(0));
+        server.store(&operations[0].board_id, None, operations).unwrap();
+        drop(server);
+        let contents = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        contents
+    }
+
+    fn contains(haystack: &[u8], needle: &str) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle.as_bytes())
+    }
+
+    #[test]
+    fn test_encrypted_storage() {
+        let board_id = Uuid::new_v4();
+        let operations = vec![
+            Operation::new(board_id, Change::AddBoard {
+                name: "Top secret board".into(),
+            }),
+            Operation::new(board_id, Change::AddList {
+                list: List { id: Uuid::new_v4(), name: "Evil plans".into() },
+            }),
+        ];
+
+        // Make sure the test can see titles at all
+        let contents = store(&operations);
+        assert!(contains(&contents, "Top secret board"));
+        assert!(contains(&contents, "Evil plans"));
+
+        let mut keyring = Keyring::new(BoardKey::generate());
+        let mut encrypted = vec![keyring.encrypt(&operations[0])];
+        keyring.rotate(BoardKey::generate());
+        encrypted.push(keyring.encrypt(&operations[1]));
+        let contents = store(&encrypted);
+        assert!(contains(&contents, &uuid2str(&board_id)));
+        assert!(!contains(&contents, "Top secret"));
+        assert!(!contains(&contents, "Evil plans"));
+    }
+}
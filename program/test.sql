@@ -1,6 +1,8 @@
 CREATE TABLE boards(id TEXT PRIMARY KEY, name TEXT);
 CREATE TABLE lists(id TEXT PRIMARY KEY, board_id TEXT, name TEXT);
 CREATE TABLE cards(id TEXT PRIMARY KEY, board_id TEXT, list_id TEXT, title TEXT);
+CREATE TABLE operations(seq INTEGER PRIMARY KEY AUTOINCREMENT, id TEXT UNIQUE, board_id TEXT, change TEXT, pending INTEGER);
+CREATE TABLE sync_cursors(board_id TEXT PRIMARY KEY, cursor INTEGER);
 
 INSERT INTO boards(id, name) VALUES('936DA01F9ABD4D9D80C70000BBBB0000', 'board');
 
@@ -0,0 +1,60 @@
+//! Storage of the keys of encrypted boards.
+//!
+//! Keys are kept in the local database, in the order they were added; the
+//! last one is current and the others are used to decrypt older operations.
+
+use rusqlite::types::{ToSql, Type};
+use uuid::Uuid;
+
+use tripledeck_core::crypto::{BoardKey, Keyring};
+
+use super::{SqliteStorage, uuid2str};
+
+impl SqliteStorage {
+    /// Make a key the current one for a board, keeping the previous ones.
+    pub fn add_board_key(&self, board_id: &Uuid, key: &BoardKey)
+        -> rusqlite::Result<()>
+    {
+        let board_id = uuid2str(board_id);
+        let key = key.to_hex();
+        self.sql().execute(
+            "DELETE FROM board_keys WHERE board_id=? AND key=?;",
+            &[&board_id as &dyn ToSql, &key as &dyn ToSql],
+        )?;
+        self.sql().execute(
+            "INSERT INTO board_keys(board_id, key) VALUES(?, ?);",
+            &[&board_id as &dyn ToSql, &key as &dyn ToSql],
+        )?;
+        Ok(())
+    }
+
+    /// Get the keys of a board, or None if it's not encrypted.
+    pub fn get_keyring(&self, board_id: &Uuid)
+        -> rusqlite::Result<Option<Keyring>>
+    {
+        let connection = self.sql();
+        let mut stmt = connection.prepare(
+            "SELECT key FROM board_keys WHERE board_id=? ORDER BY seq DESC;",
+        )?;
+        let keys = stmt.query_and_then(
+            &[&uuid2str(board_id)],
+            |row| {
+                let key: String = row.get(0);
+                BoardKey::from_hex(&key).map_err(|e| {
+                    rusqlite::Error::FromSqlConversionFailure(
+                        0, Type::Text, Box::new(e),
+                    )
+                })
+            },
+        )?;
+        let mut keyring: Option<Keyring> = None;
+        for key in keys {
+            let key = key?;
+            match keyring {
+                Some(ref mut keyring) => keyring.add_previous(key),
+                None => keyring = Some(Keyring::new(key)),
+            }
+        }
+        Ok(keyring)
+    }
+}
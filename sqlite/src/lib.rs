@@ -0,0 +1,803 @@
+//! SQLite storage for tripledeck.
+//!
+//! `SqliteStorage` implements `Storage` on top of a SQLite database, whose
+//! schema is created and upgraded when it is opened. It also keeps the keys
+//! of encrypted boards and, with the `server` feature, implements
+//! `ServerStorage` so it can hold the data of the sync server.
+//!
+//! The storage is `Send` and `Sync`, so it can be used with a `SharedApp`:
+//! its connection is locked for each call, and transactions keep other calls
+//! out until they are over.
+
+extern crate futures;
+extern crate rusqlite;
+extern crate serde;
+extern crate serde_json;
+extern crate tripledeck_core;
+extern crate uuid;
+
+mod keys;
+mod migrations;
+#[cfg(feature = "server")]
+mod server;
+
+use futures::executor::block_on;
+use futures::lock::{Mutex, MutexGuard};
+use rusqlite::Connection;
+use rusqlite::types::{ToSql, Type};
+use serde::de::DeserializeOwned;
+use std::collections::{HashMap, HashSet};
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::{self, PoisonError};
+use uuid::Uuid;
+
+use tripledeck_core::{Card, Change, List, Board, Operation, Storage, Store,
+                      Transaction};
+use tripledeck_core::activity::Activity;
+
+pub use migrations::{OpenError, latest_version};
+#[cfg(feature = "server")]
+pub use tripledeck_core::server::StoredSnapshot;
+
+/// Format a UUID the way it is stored in the database.
+pub fn uuid2str(id: &Uuid) -> String {
+    format!("{:X}", id.to_simple_ref())
+}
+
+fn parse_uuid(column: usize, value: &str) -> rusqlite::Result<Uuid> {
+    Uuid::parse_str(value).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(
+            column, Type::Text, Box::new(e),
+        )
+    })
+}
+
+/// Settings of the SQLite connection.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Use write-ahead logging, letting readers work while a change is being
+    /// written. This is recorded in the database file, and has no effect on
+    /// in-memory databases
+    pub wal: bool,
+    /// Enforce foreign key constraints
+    pub foreign_keys: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            wal: false,
+            foreign_keys: true,
+        }
+    }
+}
+
+pub struct SqliteStorage {
+    /// Locked for each call, without waiting on anything while it is held
+    sql_connection: sync::Mutex<Connection>,
+    /// Held by the transaction in progress
+    lock: Mutex<()>,
+}
+
+/// The connection, for a call made while no other transaction is in
+/// progress.
+struct Locked<'a> {
+    connection: sync::MutexGuard<'a, Connection>,
+    _lock: Option<MutexGuard<'a, ()>>,
+}
+
+impl Deref for Locked<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.connection
+    }
+}
+
+impl SqliteStorage {
+    /// Open a database, creating it or upgrading its schema if needed.
+    ///
+    /// The path `:memory:` opens a new database in memory.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<SqliteStorage, OpenError> {
+        SqliteStorage::with_options(path, &Options::default())
+    }
+
+    /// Open a new database in memory.
+    pub fn in_memory() -> Result<SqliteStorage, OpenError> {
+        SqliteStorage::new(":memory:")
+    }
+
+    /// Open a database with the given settings.
+    pub fn with_options<P: AsRef<Path>>(path: P, options: &Options)
+        -> Result<SqliteStorage, OpenError>
+    {
+        let mut sql_connection = Connection::open(path.as_ref())?;
+        if options.wal {
+            // This returns the new mode, which is "memory" for in-memory
+            // databases
+            sql_connection.query_row(
+                "PRAGMA journal_mode=WAL;",
+                rusqlite::NO_PARAMS,
+                |row| row.get::<_, String>(0),
+            )?;
+        }
+        sql_connection.execute_batch(if options.foreign_keys {
+            "PRAGMA foreign_keys=ON;"
+        } else {
+            "PRAGMA foreign_keys=OFF;"
+        })?;
+        migrations::migrate(&mut sql_connection)?;
+        Ok(SqliteStorage {
+            sql_connection: sync::Mutex::new(sql_connection),
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// Lock the connection, even if a thread panicked while holding it: an
+    /// unfinished transaction is rolled back by `SqliteTransaction`.
+    fn lock_connection(&self) -> sync::MutexGuard<'_, Connection> {
+        self.sql_connection.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Get the connection once no transaction is in progress.
+    async fn connection(&self) -> Locked<'_> {
+        let lock = self.lock.lock().await;
+        Locked {
+            connection: self.lock_connection(),
+            _lock: Some(lock),
+        }
+    }
+
+    /// Get the connection for a call that isn't async, blocking until no
+    /// transaction is in progress.
+    ///
+    /// This can't be used from a transaction, it would wait for itself.
+    fn sql(&self) -> Locked<'_> {
+        let lock = block_on(self.lock.lock());
+        Locked {
+            connection: self.lock_connection(),
+            _lock: Some(lock),
+        }
+    }
+
+    /// Get all the operations in the log, in order, with their pending flag.
+    pub fn get_all_operations(&self)
+        -> rusqlite::Result<Vec<(Operation, bool)>>
+    {
+        all_operations(&self.sql())
+    }
+
+    /// Record operations received from another replica with their pending
+    /// flag, and apply those that weren't known already, all in one
+    /// transaction.
+    ///
+    /// Replicas can receive the same operations in different orders, so to
+    /// converge they apply them sorted by `Operation::order_key()`. If a new
+    /// operation goes before ones already applied, the board is rebuilt from
+    /// the log, or if the log was pruned, the new operations are applied on
+    /// top of the current state.
+    ///
+    /// Returns the number of operations that weren't known already.
+    pub fn receive_operations<I>(&self, operations: I)
+        -> rusqlite::Result<usize>
+        where I: IntoIterator<Item=(Operation, bool)>
+    {
+        // Our futures are always ready, waiting on them doesn't block
+        let tx = block_on(self.begin())?;
+        let received = self.receive_in(&tx, operations)?;
+        block_on(tx.commit())?;
+        Ok(received)
+    }
+
+    fn receive_in<I>(&self, tx: &SqliteTransaction, operations: I)
+        -> rusqlite::Result<usize>
+        where I: IntoIterator<Item=(Operation, bool)>
+    {
+        let mut new: HashMap<Uuid, Vec<Operation>> = HashMap::new();
+        for (op, pending) in operations {
+            if block_on(tx.add_operation(&op, pending))? {
+                new.entry(op.board_id).or_default().push(op);
+            }
+        }
+        let mut received = 0;
+        for (board_id, mut ops) in new {
+            received += ops.len();
+            ops.sort_by(|a, b| a.order_key().cmp(&b.order_key()));
+            let ids: HashSet<Uuid> = ops.iter().map(|op| op.id).collect();
+            let all = all_operations(&block_on(tx.connection()))?;
+            let mut log: Vec<Operation> = all.into_iter()
+                .map(|(op, _)| op)
+                .filter(|op| op.board_id == board_id)
+                .collect();
+            let in_order = log.iter()
+                .filter(|op| !ids.contains(&op.id))
+                .all(|op| op.order_key() < ops[0].order_key());
+            let complete = log.iter()
+                .any(|op| matches!(op.change, Change::AddBoard { .. }));
+            if in_order || !complete {
+                for op in &ops {
+                    block_on(op.apply(tx))?;
+                }
+            } else {
+                log.sort_by(|a, b| a.order_key().cmp(&b.order_key()));
+                self.rebuild_board(tx, &board_id, &log)?;
+            }
+        }
+        Ok(received)
+    }
+
+    /// Clear the state of a board and its activity, and apply its operations
+    /// again.
+    fn rebuild_board(&self, tx: &SqliteTransaction, board_id: &Uuid,
+                     log: &[Operation])
+        -> rusqlite::Result<()>
+    {
+        block_on(tx.delete_board(board_id))?;
+        block_on(tx.connection()).execute(
+            "DELETE FROM activity WHERE board_id=?;",
+            &[&uuid2str(board_id)],
+        )?;
+        for op in log {
+            block_on(op.apply(tx))?;
+        }
+        Ok(())
+    }
+}
+
+/// Get all the operations in the log, see `get_all_operations()`.
+fn all_operations(connection: &Connection)
+    -> rusqlite::Result<Vec<(Operation, bool)>>
+{
+    let mut stmt = connection.prepare(
+        "SELECT id, board_id, change, pending, actor, time, undo_group
+         FROM operations
+         ORDER BY seq;",
+    )?;
+    let rows = stmt.query_and_then(
+        rusqlite::NO_PARAMS,
+        |row| -> rusqlite::Result<(Operation, bool)> {
+            let id: String = row.get(0);
+            let board_id: String = row.get(1);
+            let time: Option<i64> = row.get(5);
+            Ok((Operation {
+                id: parse_uuid(0, &id)?,
+                board_id: parse_uuid(1, &board_id)?,
+                change: parse_json(2, row.get(2))?,
+                actor: parse_optional_json(4, row.get(4))?,
+                time: time.map(|t| t as u64),
+                group: parse_optional_uuid(6, row.get(6))?,
+            }, row.get(3)))
+        },
+    )?;
+    rows.collect()
+}
+
+fn parse_json<T: DeserializeOwned>(column: usize, value: String)
+    -> rusqlite::Result<T>
+{
+    serde_json::from_str(&value).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(
+            column, Type::Text, Box::new(e),
+        )
+    })
+}
+
+fn parse_optional_json<T: DeserializeOwned>(column: usize,
+                                            value: Option<String>)
+    -> rusqlite::Result<Option<T>>
+{
+    value.map(|v| parse_json(column, v)).transpose()
+}
+
+fn parse_optional_uuid(column: usize, value: Option<String>)
+    -> rusqlite::Result<Option<Uuid>>
+{
+    value.map(|v| parse_uuid(column, &v)).transpose()
+}
+
+fn to_json<T: serde::Serialize + ?Sized>(value: &T) -> String {
+    serde_json::to_string(value).unwrap()
+}
+
+fn to_optional_json<T: serde::Serialize>(value: &Option<T>) -> Option<String> {
+    value.as_ref().map(to_json)
+}
+
+/// The methods of `Store`, which are the same on the storage and its
+/// transactions: they get the connection from `connection()`.
+macro_rules! store_methods {
+    () => {
+        async fn add_board(&self, board: &Board) -> rusqlite::Result<()> {
+            let connection = self.connection().await;
+            let res = connection.execute(
+                "INSERT INTO boards(id, name) VALUES(?, ?);",
+                &[&uuid2str(&board.id) as &dyn ToSql,
+                  &board.name as &dyn ToSql],
+            );
+            res.map(|_| ())
+        }
+
+        async fn get_board(&self, id: &Uuid)
+            -> rusqlite::Result<Option<Board>>
+        {
+            let connection = self.connection().await;
+            let res = connection.query_row_and_then(
+                "SELECT id, name FROM boards WHERE id=?;",
+                &[&uuid2str(id) as &dyn ToSql],
+                |row| -> rusqlite::Result<Board> {
+                    let id: String = row.get(0);
+                    Ok(Board {
+                        id: parse_uuid(0, &id)?,
+                        name: row.get(1),
+                    })
+                },
+            );
+            match res {
+                Ok(b) => Ok(Some(b)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(e) => Err(e),
+            }
+        }
+
+        async fn get_lists(&self, board_id: &Uuid)
+            -> rusqlite::Result<Vec<List>>
+        {
+            let connection = self.connection().await;
+            let res = connection.prepare(
+                "SELECT id, name FROM lists WHERE board_id=?
+                 ORDER BY position, rowid;",
+            );
+            res.and_then(|mut stmt| {
+                let rows = stmt.query_and_then(
+                    &[&uuid2str(board_id)],
+                    |row| -> rusqlite::Result<List> {
+                        let id: String = row.get(0);
+                        Ok(List {
+                            id: parse_uuid(0, &id)?,
+                            name: row.get(1),
+                        })
+                    },
+                )?;
+                rows.collect()
+            })
+        }
+
+        async fn add_list(&self, board_id: &Uuid, list: &List)
+            -> rusqlite::Result<()>
+        {
+            let connection = self.connection().await;
+            let res = connection.execute(
+                "INSERT INTO lists(board_id, id, name, position)
+                 SELECT ?1, ?2, ?3, COALESCE(MAX(position), 0) + 1
+                 FROM lists WHERE board_id=?1;",
+                &[&uuid2str(board_id) as &dyn ToSql,
+                  &uuid2str(&list.id) as &dyn ToSql,
+                  &list.name as &dyn ToSql],
+            );
+            res.map(|_| ())
+        }
+
+        async fn get_cards(&self, board_id: &Uuid)
+            -> rusqlite::Result<Vec<Card>>
+        {
+            let connection = self.connection().await;
+            let res = connection.prepare(
+                "SELECT id, list_id, title, description, labels, due,
+                        assignees, comments, external
+                 FROM cards WHERE board_id=?;",
+            );
+            res.and_then(|mut stmt| {
+                let rows = stmt.query_and_then(
+                    &[&uuid2str(board_id)],
+                    |row| -> rusqlite::Result<Card> {
+                        let id: String = row.get(0);
+                        let list: String = row.get(1);
+                        let due: Option<i64> = row.get(5);
+                        Ok(Card {
+                            id: parse_uuid(0, &id)?,
+                            list: parse_uuid(1, &list)?,
+                            title: row.get(2),
+                            description: row.get(3),
+                            labels: parse_json(4, row.get(4))?,
+                            due: due.map(|t| t as u64),
+                            assignees: parse_json(6, row.get(6))?,
+                            comments: parse_json(7, row.get(7))?,
+                            external: row.get(8),
+                        })
+                    },
+                )?;
+                rows.collect()
+            })
+        }
+
+        async fn add_card(&self, board_id: &Uuid, card: &Card)
+            -> rusqlite::Result<()>
+        {
+            let connection = self.connection().await;
+            let res = connection.execute(
+                "INSERT OR REPLACE INTO cards(board_id, id, list_id, title,
+                                              description, labels, due,
+                                              assignees, comments, external)
+                 VALUES(?, ?, ?, ?, ?, ?, ?, ?, ?, ?);",
+                &[&uuid2str(board_id) as &dyn ToSql,
+                  &uuid2str(&card.id) as &dyn ToSql,
+                  &uuid2str(&card.list) as &dyn ToSql,
+                  &card.title as &dyn ToSql,
+                  &card.description as &dyn ToSql,
+                  &to_json(&card.labels) as &dyn ToSql,
+                  &card.due.map(|t| t as i64) as &dyn ToSql,
+                  &to_json(&card.assignees) as &dyn ToSql,
+                  &to_json(&card.comments) as &dyn ToSql,
+                  &card.external as &dyn ToSql],
+            );
+            res.map(|_| ())
+        }
+
+        async fn delete_card(&self, board_id: &Uuid, card_id: &Uuid)
+            -> rusqlite::Result<()>
+        {
+            let connection = self.connection().await;
+            let res = connection.execute(
+                "DELETE FROM cards WHERE board_id=? AND id=?;",
+                &[&uuid2str(board_id), &uuid2str(card_id)],
+            );
+            res.map(|_| ())
+        }
+
+        async fn delete_board(&self, id: &Uuid) -> rusqlite::Result<()> {
+            let connection = self.connection().await;
+            let id = uuid2str(id);
+            let res = connection.execute(
+                "DELETE FROM cards WHERE board_id=?;",
+                &[&id],
+            ).and_then(|_| connection.execute(
+                "DELETE FROM lists WHERE board_id=?;",
+                &[&id],
+            )).and_then(|_| connection.execute(
+                "DELETE FROM boards WHERE id=?;",
+                &[&id],
+            ));
+            res.map(|_| ())
+        }
+
+        async fn delete_list(&self, board_id: &Uuid, list_id: &Uuid)
+            -> rusqlite::Result<()>
+        {
+            let connection = self.connection().await;
+            let res = connection.execute(
+                "DELETE FROM lists WHERE board_id=? AND id=?;",
+                &[&uuid2str(board_id), &uuid2str(list_id)],
+            );
+            res.map(|_| ())
+        }
+
+        async fn add_operation(&self, op: &Operation, pending: bool)
+            -> rusqlite::Result<bool>
+        {
+            let connection = self.connection().await;
+            let change = serde_json::to_string(&op.change)
+                .expect("Serializing operation");
+            let res = connection.execute(
+                "INSERT OR IGNORE INTO operations(id, board_id, change,
+                                                  pending, actor, time,
+                                                  undo_group)
+                 VALUES(?, ?, ?, ?, ?, ?, ?);",
+                &[&uuid2str(&op.id) as &dyn ToSql,
+                  &uuid2str(&op.board_id) as &dyn ToSql,
+                  &change as &dyn ToSql, &pending as &dyn ToSql,
+                  &to_optional_json(&op.actor) as &dyn ToSql,
+                  &op.time.map(|t| t as i64) as &dyn ToSql,
+                  &op.group.as_ref().map(uuid2str) as &dyn ToSql],
+            );
+            res.map(|inserted| inserted > 0)
+        }
+
+        async fn get_pending_operations(&self, board_id: &Uuid)
+            -> rusqlite::Result<Vec<Operation>>
+        {
+            let connection = self.connection().await;
+            let res = connection.prepare(
+                "SELECT id, change, actor, time, undo_group FROM operations
+                 WHERE board_id=? AND pending
+                 ORDER BY seq;",
+            );
+            res.and_then(|mut stmt| {
+                let rows = stmt.query_and_then(
+                    &[&uuid2str(board_id)],
+                    |row| -> rusqlite::Result<Operation> {
+                        let id: String = row.get(0);
+                        let time: Option<i64> = row.get(3);
+                        Ok(Operation {
+                            id: parse_uuid(0, &id)?,
+                            board_id: *board_id,
+                            change: parse_json(1, row.get(1))?,
+                            actor: parse_optional_json(2, row.get(2))?,
+                            time: time.map(|t| t as u64),
+                            group: parse_optional_uuid(4, row.get(4))?,
+                        })
+                    },
+                )?;
+                rows.collect()
+            })
+        }
+
+        async fn acknowledge_operations(&self, ids: &[Uuid])
+            -> rusqlite::Result<()>
+        {
+            let connection = self.connection().await;
+            ids.iter().try_for_each(|id| {
+                connection.execute(
+                    "UPDATE operations SET pending=0 WHERE id=?;",
+                    &[&uuid2str(id)],
+                ).map(|_| ())
+            })
+        }
+
+        async fn cancel_operation(&self, id: &Uuid) -> rusqlite::Result<bool> {
+            let connection = self.connection().await;
+            let res = connection.execute(
+                "DELETE FROM operations WHERE id=? AND pending=1;",
+                &[&uuid2str(id)],
+            );
+            res.map(|deleted| deleted > 0)
+        }
+
+        async fn get_sync_cursor(&self, board_id: &Uuid)
+            -> rusqlite::Result<u64>
+        {
+            let connection = self.connection().await;
+            let res = connection.query_row(
+                "SELECT cursor FROM sync_cursors WHERE board_id=?;",
+                &[&uuid2str(board_id)],
+                |row| row.get::<_, i64>(0) as u64,
+            );
+            match res {
+                Ok(c) => Ok(c),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(0),
+                Err(e) => Err(e),
+            }
+        }
+
+        async fn set_sync_cursor(&self, board_id: &Uuid, cursor: u64)
+            -> rusqlite::Result<()>
+        {
+            let connection = self.connection().await;
+            let res = connection.execute(
+                "INSERT OR REPLACE INTO sync_cursors(board_id, cursor)
+                 VALUES(?, ?);",
+                &[&uuid2str(board_id) as &dyn ToSql,
+                  &(cursor as i64) as &dyn ToSql],
+            );
+            res.map(|_| ())
+        }
+
+        async fn prune_operations(&self, board_id: &Uuid)
+            -> rusqlite::Result<()>
+        {
+            let connection = self.connection().await;
+            let res = connection.execute(
+                "DELETE FROM operations WHERE board_id=? AND pending=0;",
+                &[&uuid2str(board_id)],
+            );
+            res.map(|_| ())
+        }
+
+        async fn get_replica_id(&self) -> rusqlite::Result<Uuid> {
+            let connection = self.connection().await;
+            let res = connection.query_row(
+                "SELECT id FROM replica;",
+                rusqlite::NO_PARAMS,
+                |row| row.get::<_, String>(0),
+            );
+            match res {
+                Ok(id) => parse_uuid(0, &id),
+                Err(rusqlite::Error::QueryReturnedNoRows) => {
+                    let id = Uuid::new_v4();
+                    connection.execute(
+                        "INSERT INTO replica(id) VALUES(?);",
+                        &[&uuid2str(&id)],
+                    ).map(|_| id)
+                }
+                Err(e) => Err(e),
+            }
+        }
+
+        async fn add_activity(&self, activity: &Activity)
+            -> rusqlite::Result<()>
+        {
+            let connection = self.connection().await;
+            let kind = serde_json::to_string(&activity.kind).unwrap();
+            let event = serde_json::to_string(&activity.event).unwrap();
+            let res = connection.execute(
+                "INSERT INTO activity(operation, board_id, subject, kind,
+                                      actor, time, event)
+                 VALUES(?, ?, ?, ?, ?, ?, ?);",
+                &[&uuid2str(&activity.operation) as &dyn ToSql,
+                  &uuid2str(&activity.board_id) as &dyn ToSql,
+                  &uuid2str(&activity.subject) as &dyn ToSql,
+                  &kind as &dyn ToSql,
+                  &to_optional_json(&activity.actor) as &dyn ToSql,
+                  &activity.time.map(|t| t as i64) as &dyn ToSql,
+                  &event as &dyn ToSql],
+            );
+            res.map(|_| ())
+        }
+
+        async fn get_activity(&self, id: &Uuid)
+            -> rusqlite::Result<Vec<Activity>>
+        {
+            let connection = self.connection().await;
+            let res = connection.prepare(
+                "SELECT operation, board_id, subject, kind, actor, time, event
+                 FROM activity
+                 WHERE board_id=?1 OR subject=?1
+                 ORDER BY seq;",
+            );
+            res.and_then(|mut stmt| {
+                let rows = stmt.query_and_then(
+                    &[&uuid2str(id)],
+                    |row| -> rusqlite::Result<Activity> {
+                        let operation: String = row.get(0);
+                        let board_id: String = row.get(1);
+                        let subject: String = row.get(2);
+                        let time: Option<i64> = row.get(5);
+                        Ok(Activity {
+                            operation: parse_uuid(0, &operation)?,
+                            board_id: parse_uuid(1, &board_id)?,
+                            subject: parse_uuid(2, &subject)?,
+                            kind: parse_json(3, row.get(3))?,
+                            actor: parse_optional_json(4, row.get(4))?,
+                            time: time.map(|t| t as u64),
+                            event: parse_json(6, row.get(6))?,
+                        })
+                    },
+                )?;
+                rows.collect()
+            })
+        }
+    };
+}
+
+impl Store for SqliteStorage {
+    type Error = rusqlite::Error;
+
+    store_methods!();
+}
+
+impl Storage for SqliteStorage {
+    type Transaction<'a> = SqliteTransaction<'a>;
+
+    async fn begin(&self) -> rusqlite::Result<SqliteTransaction<'_>> {
+        let lock = self.lock.lock().await;
+        self.lock_connection().execute_batch("BEGIN IMMEDIATE;")?;
+        Ok(SqliteTransaction {
+            storage: self,
+            done: false,
+            _lock: lock,
+        })
+    }
+}
+
+/// A transaction on a `SqliteStorage`.
+///
+/// The storage has a single connection, so other calls wait until the
+/// transaction is over rather than becoming part of it.
+pub struct SqliteTransaction<'a> {
+    storage: &'a SqliteStorage,
+    /// Whether it was committed or rolled back already
+    done: bool,
+    _lock: MutexGuard<'a, ()>,
+}
+
+impl SqliteTransaction<'_> {
+    async fn connection(&self) -> Locked<'_> {
+        Locked { connection: self.storage.lock_connection(), _lock: None }
+    }
+}
+
+impl Store for SqliteTransaction<'_> {
+    type Error = rusqlite::Error;
+
+    store_methods!();
+}
+
+impl Transaction for SqliteTransaction<'_> {
+    async fn commit(mut self) -> rusqlite::Result<()> {
+        self.storage.lock_connection().execute_batch("COMMIT;")?;
+        self.done = true;
+        Ok(())
+    }
+
+    async fn rollback(mut self) -> rusqlite::Result<()> {
+        self.done = true;
+        self.storage.lock_connection().execute_batch("ROLLBACK;")
+    }
+}
+
+impl Drop for SqliteTransaction<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.storage.lock_connection().execute_batch("ROLLBACK;").ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use uuid::Uuid;
+
+    use tripledeck_core::{App, List, Store};
+    use tripledeck_core::conformance;
+
+    use super::{SqliteStorage, uuid2str};
+
+    #[test]
+    fn test_conformance() {
+        conformance::run(|| SqliteStorage::in_memory().unwrap());
+    }
+
+    #[test]
+    fn test_shared() {
+        let app = App::shared(SqliteStorage::in_memory().unwrap());
+        let board = block_on(app.new_board("Work")).unwrap();
+        let id = board.board().id;
+
+        // Sessions on other threads use the same connection
+        std::thread::scope(|scope| {
+            for i in 0..4 {
+                let session = app.session();
+                scope.spawn(move || {
+                    let board = block_on(session.get_board(&id)).unwrap()
+                        .unwrap();
+                    block_on(board.add_list(&format!("thread {}", i)))
+                        .unwrap();
+                });
+            }
+        });
+
+        // A future made on this thread can be run on another
+        let future = board.add_list("moved");
+        std::thread::scope(|scope| {
+            scope.spawn(move || block_on(future).unwrap());
+        });
+
+        let export = block_on(app.export_board(&id)).unwrap().unwrap();
+        let mut names: Vec<String> = export.lists.into_iter()
+            .map(|l| l.name)
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["moved", "thread 0", "thread 1", "thread 2",
+                               "thread 3"]);
+    }
+
+    #[test]
+    fn test_invalid_uuid() {
+        let storage = SqliteStorage::in_memory().unwrap();
+        let board_id = Uuid::new_v4();
+        storage.sql().execute_batch(&format!(
+            "INSERT INTO lists(board_id, id, name)
+             VALUES('{}', 'not-a-uuid', 'todo');",
+            uuid2str(&board_id),
+        )).unwrap();
+        match block_on(storage.get_lists(&board_id)) {
+            Err(rusqlite::Error::FromSqlConversionFailure(0, _, _)) => {}
+            res => panic!("Unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_list_order() {
+        let storage = SqliteStorage::in_memory().unwrap();
+        let board_id = Uuid::new_v4();
+        let names = ["todo", "doing", "done", "archive"];
+        for name in &names {
+            let list = List { id: Uuid::new_v4(), name: (*name).into() };
+            block_on(storage.add_list(&board_id, &list)).unwrap();
+        }
+        let lists = block_on(storage.get_lists(&board_id)).unwrap();
+        assert_eq!(lists.iter().map(|l| &l.name[..]).collect::<Vec<_>>(),
+                   names);
+    }
+}
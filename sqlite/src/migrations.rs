@@ -0,0 +1,226 @@
+//! Schema of the database, and its upgrades.
+//!
+//! The schema version is the number of migrations applied, recorded in the
+//! `schema_version` table. When a database is opened, the missing migrations
+//! are applied in order, in a single transaction. Databases created by a
+//! newer version of the program are refused rather than risking corrupting
+//! them.
+//!
+//! Databases from before versioning (version 0) might already have some of
+//! the tables, so the first migration only creates the missing ones, and
+//! columns added since are added to existing tables.
+
+use rusqlite::{Connection, TransactionBehavior};
+use std::fmt;
+
+/// Migrations, in order.
+const MIGRATIONS: &[&str] = &[
+    include_str!("../../migrations/001_initial.sql"),
+    include_str!("../../migrations/002_card_fields.sql"),
+    include_str!("../../migrations/003_external_ref.sql"),
+    include_str!("../../migrations/004_undo_group.sql"),
+    include_str!("../../migrations/005_list_position.sql"),
+];
+
+/// Columns added to tables before versioning, that unversioned databases
+/// might lack.
+const LEGACY_COLUMNS: &[(&str, &str, &str)] = &[
+    ("operations", "pending", "INTEGER"),
+    ("operations", "actor", "TEXT"),
+    ("operations", "time", "INTEGER"),
+];
+
+#[derive(Debug)]
+pub enum OpenError {
+    Sqlite(rusqlite::Error),
+    /// The database was upgraded by a newer version of the program
+    TooNew { version: u32, supported: u32 },
+}
+
+impl fmt::Display for OpenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OpenError::Sqlite(ref e) => write!(f, "{}", e),
+            OpenError::TooNew { version, supported } => write!(
+                f,
+                "Database has schema version {}, but this version of \
+                 tripledeck only supports up to {}; please upgrade",
+                version, supported,
+            ),
+        }
+    }
+}
+
+impl std::error::Error for OpenError {}
+
+impl From<rusqlite::Error> for OpenError {
+    fn from(e: rusqlite::Error) -> OpenError {
+        OpenError::Sqlite(e)
+    }
+}
+
+/// The schema version this program creates.
+pub fn latest_version() -> u32 {
+    MIGRATIONS.len() as u32
+}
+
+/// Bring a database up to the latest schema version.
+pub fn migrate(connection: &mut Connection) -> Result<(), OpenError> {
+    // Taking the write lock right away, so two programs opening the same
+    // database don't both upgrade it
+    let tx = connection.transaction_with_behavior(
+        TransactionBehavior::Immediate,
+    )?;
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS schema_version(version INTEGER);",
+    )?;
+    let version: Option<i64> = tx.query_row(
+        "SELECT MAX(version) FROM schema_version;",
+        rusqlite::NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    let version = version.unwrap_or(0) as u32;
+    if version > latest_version() {
+        return Err(OpenError::TooNew {
+            version,
+            supported: latest_version(),
+        });
+    }
+    if version == latest_version() {
+        return Ok(());
+    }
+
+    for migration in &MIGRATIONS[version as usize..] {
+        tx.execute_batch(migration)?;
+    }
+    if version == 0 {
+        add_legacy_columns(&tx)?;
+    }
+    tx.execute("DELETE FROM schema_version;", rusqlite::NO_PARAMS)?;
+    tx.execute(
+        "INSERT INTO schema_version(version) VALUES(?);",
+        &[&(latest_version() as i64)],
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
+fn add_legacy_columns(connection: &Connection) -> rusqlite::Result<()> {
+    for &(table, column, type_) in LEGACY_COLUMNS {
+        let mut stmt = connection.prepare(
+            &format!("PRAGMA table_info({});", table),
+        )?;
+        let columns = stmt.query_map(
+            rusqlite::NO_PARAMS,
+            |row| row.get::<_, String>(1),
+        )?.collect::<rusqlite::Result<Vec<_>>>()?;
+        if !columns.iter().any(|c| c == column) {
+            connection.execute_batch(&format!(
+                "ALTER TABLE {} ADD COLUMN {} {};",
+                table, column, type_,
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::{OpenError, latest_version, migrate};
+
+    fn version(connection: &Connection) -> i64 {
+        connection.query_row(
+            "SELECT version FROM schema_version;",
+            rusqlite::NO_PARAMS,
+            |row| row.get(0),
+        ).unwrap()
+    }
+
+    #[test]
+    fn test_new_database() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        migrate(&mut connection).unwrap();
+        assert_eq!(version(&connection), latest_version() as i64);
+        connection.execute_batch(
+            "INSERT INTO operations(id, board_id, change, pending, actor,
+                                    time)
+             VALUES('a', 'b', '{}', 1, NULL, 0);",
+        ).unwrap();
+        // Opening again doesn't change anything
+        migrate(&mut connection).unwrap();
+        assert_eq!(version(&connection), latest_version() as i64);
+    }
+
+    #[test]
+    fn test_legacy_database() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch(
+            "
+            CREATE TABLE boards(id TEXT PRIMARY KEY, name TEXT);
+            INSERT INTO boards(id, name) VALUES('b', 'Work');
+            CREATE TABLE operations(
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                id TEXT UNIQUE,
+                board_id TEXT,
+                change TEXT
+            );
+            ",
+        ).unwrap();
+        migrate(&mut connection).unwrap();
+        assert_eq!(version(&connection), latest_version() as i64);
+        let name: String = connection.query_row(
+            "SELECT name FROM boards WHERE id='b';",
+            rusqlite::NO_PARAMS,
+            |row| row.get(0),
+        ).unwrap();
+        assert_eq!(name, "Work");
+        connection.execute_batch(
+            "INSERT INTO operations(id, board_id, change, pending, actor,
+                                    time)
+             VALUES('a', 'b', '{}', 1, NULL, 0);",
+        ).unwrap();
+    }
+
+    #[test]
+    fn test_list_positions() {
+        // Lists from before they had a position keep the order they were
+        // added in
+        let mut connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch(
+            "
+            CREATE TABLE lists(id TEXT PRIMARY KEY, board_id TEXT, name TEXT);
+            INSERT INTO lists(id, board_id, name)
+            VALUES('b', 'work', 'todo'), ('a', 'work', 'done'),
+                  ('c', 'home', 'chores');
+            ",
+        ).unwrap();
+        migrate(&mut connection).unwrap();
+        let mut stmt = connection.prepare(
+            "SELECT name, position FROM lists ORDER BY board_id, position;",
+        ).unwrap();
+        let lists = stmt.query_map(
+            rusqlite::NO_PARAMS,
+            |row| (row.get::<_, String>(0), row.get::<_, i64>(1)),
+        ).unwrap().collect::<rusqlite::Result<Vec<_>>>().unwrap();
+        assert_eq!(lists, vec![("chores".into(), 1), ("todo".into(), 1),
+                               ("done".into(), 2)]);
+    }
+
+    #[test]
+    fn test_newer_database() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        migrate(&mut connection).unwrap();
+        connection.execute_batch(
+            "UPDATE schema_version SET version=version + 1;",
+        ).unwrap();
+        match migrate(&mut connection) {
+            Err(OpenError::TooNew { version, supported }) => {
+                assert_eq!(version, latest_version() + 1);
+                assert_eq!(supported, latest_version());
+            }
+            _ => panic!("Newer database was not refused"),
+        }
+    }
+}
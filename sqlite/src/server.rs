@@ -0,0 +1,481 @@
+//! Storage for the sync server: accounts and access control, snapshots and
+//! replicas, and pruning of the log.
+//!
+//! Operations are pruned once they are covered by the snapshot and were
+//! received by every replica seen recently; replicas that haven't been seen
+//! for a while are forgotten, and will get the snapshot if they come back.
+
+use rusqlite::types::{ToSql, Type};
+use std::time::Duration;
+use uuid::Uuid;
+
+use tripledeck_core::Operation;
+use tripledeck_core::activity::now;
+use tripledeck_core::server::{ServerStorage, StoredSnapshot, hash_password,
+                              hash_token, new_token, verify_password};
+use tripledeck_core::snapshot::SnapshotData;
+use tripledeck_core::sync::{Credentials, Role};
+
+use super::{SqliteStorage, parse_json, parse_optional_json, parse_uuid,
+            uuid2str};
+
+impl SqliteStorage {
+    fn user_exists(&self, name: &str) -> rusqlite::Result<bool> {
+        let count: i64 = self.sql().query_row(
+            "SELECT COUNT(*) FROM users WHERE name=?;",
+            &[&name],
+            |row| row.get(0),
+        )?;
+        Ok(count > 0)
+    }
+}
+
+impl ServerStorage for SqliteStorage {
+    type Error = rusqlite::Error;
+
+    fn set_password(&self, name: &str, password: &str)
+        -> rusqlite::Result<()>
+    {
+        let hash = hash_password(password);
+        self.sql().execute(
+            "INSERT OR REPLACE INTO users(name, password_hash) VALUES(?, ?);",
+            &[&name, &hash.as_str()],
+        )?;
+        Ok(())
+    }
+
+    fn create_token(&self, user: &str) -> rusqlite::Result<Option<String>> {
+        if !self.user_exists(user)? {
+            return Ok(None);
+        }
+        let token = new_token();
+        self.sql().execute(
+            "INSERT INTO tokens(token_hash, user) VALUES(?, ?);",
+            &[&hash_token(&token).as_str(), &user],
+        )?;
+        Ok(Some(token))
+    }
+
+    fn authenticate(&self, credentials: &Credentials)
+        -> rusqlite::Result<Option<String>>
+    {
+        match *credentials {
+            Credentials::Password { ref user, ref password } => {
+                let res = self.sql().query_row(
+                    "SELECT password_hash FROM users WHERE name=?;",
+                    &[user],
+                    |row| row.get::<_, String>(0),
+                );
+                let hash = match res {
+                    Ok(h) => h,
+                    Err(rusqlite::Error::QueryReturnedNoRows) => {
+                        return Ok(None);
+                    }
+                    Err(e) => return Err(e),
+                };
+                if verify_password(password, &hash) {
+                    Ok(Some(user.clone()))
+                } else {
+                    Ok(None)
+                }
+            }
+            Credentials::Token { ref token } => {
+                let res = self.sql().query_row(
+                    "SELECT user FROM tokens WHERE token_hash=?;",
+                    &[&hash_token(token)],
+                    |row| row.get(0),
+                );
+                match res {
+                    Ok(user) => Ok(Some(user)),
+                    Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                    Err(e) => Err(e),
+                }
+            }
+        }
+    }
+
+    fn get_role(&self, board_id: &Uuid, user: &str)
+        -> rusqlite::Result<Option<Role>>
+    {
+        let res = self.sql().query_row(
+            "SELECT role FROM board_roles WHERE board_id=? AND user=?;",
+            &[&uuid2str(board_id) as &dyn ToSql, &user as &dyn ToSql],
+            |row| row.get::<_, String>(0),
+        );
+        match res {
+            Ok(role) => Ok(Role::from_name(&role)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn set_role(&self, board_id: &Uuid, user: &str, role: Option<Role>)
+        -> rusqlite::Result<bool>
+    {
+        if !self.user_exists(user)? {
+            return Ok(false);
+        }
+        if let Some(role) = role {
+            self.sql().execute(
+                "INSERT OR REPLACE INTO board_roles(board_id, user, role)
+                 VALUES(?, ?, ?);",
+                &[&uuid2str(board_id) as &dyn ToSql, &user as &dyn ToSql,
+                  &role.name() as &dyn ToSql],
+            )?;
+        } else {
+            self.sql().execute(
+                "DELETE FROM board_roles WHERE board_id=? AND user=?;",
+                &[&uuid2str(board_id) as &dyn ToSql, &user as &dyn ToSql],
+            )?;
+        }
+        Ok(true)
+    }
+
+    fn claim_board(&self, board_id: &Uuid, user: &str)
+        -> rusqlite::Result<bool>
+    {
+        let board_id = uuid2str(board_id);
+        let inserted = self.sql().execute(
+            "INSERT INTO board_roles(board_id, user, role)
+             SELECT ?1, ?2, 'owner'
+             WHERE NOT EXISTS (SELECT 1 FROM board_roles WHERE board_id=?1)
+             AND NOT EXISTS (SELECT 1 FROM operations WHERE board_id=?1)
+             AND NOT EXISTS (SELECT 1 FROM snapshots WHERE board_id=?1);",
+            &[&board_id as &dyn ToSql, &user as &dyn ToSql],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    fn get_operations(&self, board_id: &Uuid, since: u64)
+        -> rusqlite::Result<Vec<(u64, Operation)>>
+    {
+        let connection = self.sql();
+        let mut stmt = connection.prepare(
+            "SELECT seq, id, change, actor, time FROM operations
+             WHERE board_id=? AND seq>?
+             ORDER BY seq;",
+        )?;
+        let rows = stmt.query_and_then(
+            &[&uuid2str(board_id) as &dyn ToSql,
+              &(since as i64) as &dyn ToSql],
+            |row| -> rusqlite::Result<(u64, Operation)> {
+                let seq: i64 = row.get(0);
+                let id: String = row.get(1);
+                let time: Option<i64> = row.get(4);
+                Ok((seq as u64, Operation {
+                    id: parse_uuid(1, &id)?,
+                    board_id: *board_id,
+                    change: parse_json(2, row.get(2))?,
+                    actor: parse_optional_json(3, row.get(3))?,
+                    time: time.map(|t| t as u64),
+                    group: None,
+                }))
+            },
+        )?;
+        rows.collect()
+    }
+
+    fn get_last_seq(&self, board_id: &Uuid) -> rusqlite::Result<u64> {
+        let seq: Option<i64> = self.sql().query_row(
+            "SELECT MAX(seq) FROM (
+                 SELECT MAX(seq) AS seq FROM operations WHERE board_id=?1
+                 UNION ALL
+                 SELECT cursor FROM snapshots WHERE board_id=?1
+             );",
+            &[&uuid2str(board_id) as &dyn ToSql],
+            |row| row.get(0),
+        )?;
+        Ok(seq.unwrap_or(0) as u64)
+    }
+
+    fn receive_operations<I>(&self, operations: I) -> rusqlite::Result<usize>
+        where I: IntoIterator<Item=(Operation, bool)>
+    {
+        SqliteStorage::receive_operations(self, operations)
+    }
+
+    fn record_replica(&self, board_id: &Uuid, replica_id: &Uuid, cursor: u64)
+        -> rusqlite::Result<()>
+    {
+        self.sql().execute(
+            "INSERT OR REPLACE INTO replicas(board_id, replica_id, cursor,
+                                             last_seen)
+             VALUES(?, ?, ?, ?);",
+            &[&uuid2str(board_id) as &dyn ToSql,
+              &uuid2str(replica_id) as &dyn ToSql,
+              &(cursor as i64) as &dyn ToSql, &(now() as i64) as &dyn ToSql],
+        )?;
+        Ok(())
+    }
+
+    fn get_snapshot(&self, board_id: &Uuid)
+        -> rusqlite::Result<Option<StoredSnapshot>>
+    {
+        let res = self.sql().query_row_and_then(
+            "SELECT cursor, pruned, data FROM snapshots WHERE board_id=?;",
+            &[&uuid2str(board_id)],
+            |row| -> rusqlite::Result<StoredSnapshot> {
+                let cursor: i64 = row.get(0);
+                let pruned: i64 = row.get(1);
+                let data: String = row.get(2);
+                let data = serde_json::from_str(&data).map_err(|e| {
+                    rusqlite::Error::FromSqlConversionFailure(
+                        2, Type::Text, Box::new(e),
+                    )
+                })?;
+                Ok(StoredSnapshot {
+                    cursor: cursor as u64,
+                    pruned: pruned as u64,
+                    data,
+                })
+            },
+        );
+        match res {
+            Ok(s) => Ok(Some(s)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn store_snapshot(&self, board_id: &Uuid, cursor: u64,
+                      data: &SnapshotData)
+        -> rusqlite::Result<()>
+    {
+        let board_id = uuid2str(board_id);
+        let data = serde_json::to_string(data).unwrap();
+        self.sql().execute(
+            "INSERT OR REPLACE INTO snapshots(board_id, cursor, pruned, data)
+             SELECT ?1, ?2,
+                    COALESCE((SELECT pruned FROM snapshots
+                              WHERE board_id=?1), 0),
+                    ?3
+             WHERE NOT EXISTS (SELECT 1 FROM snapshots
+                               WHERE board_id=?1 AND cursor>=?2);",
+            &[&board_id as &dyn ToSql, &(cursor as i64) as &dyn ToSql,
+              &data as &dyn ToSql],
+        )?;
+        Ok(())
+    }
+
+    fn count_operations_since(&self, board_id: &Uuid, since: u64)
+        -> rusqlite::Result<u64>
+    {
+        let count: i64 = self.sql().query_row(
+            "SELECT COUNT(*) FROM operations WHERE board_id=? AND seq>?;",
+            &[&uuid2str(board_id) as &dyn ToSql,
+              &(since as i64) as &dyn ToSql],
+            |row| row.get(0),
+        )?;
+        Ok(count as u64)
+    }
+
+    fn prune(&self, board_id: &Uuid, forget_replicas_after: Duration)
+        -> rusqlite::Result<usize>
+    {
+        let snapshot_cursor = match self.get_snapshot(board_id)? {
+            Some(s) => s.cursor as i64,
+            None => return Ok(0),
+        };
+        let board_id = uuid2str(board_id);
+        let seen_since = now() as i64 - forget_replicas_after.as_secs() as i64;
+        let replicas_cursor: Option<i64> = self.sql().query_row(
+            "SELECT MIN(cursor) FROM replicas
+             WHERE board_id=? AND last_seen>=?;",
+            &[&board_id as &dyn ToSql, &seen_since as &dyn ToSql],
+            |row| row.get(0),
+        )?;
+        let limit = match replicas_cursor {
+            Some(c) if c < snapshot_cursor => c,
+            _ => snapshot_cursor,
+        };
+        let deleted = self.sql().execute(
+            "DELETE FROM operations WHERE board_id=? AND seq<=?;",
+            &[&board_id as &dyn ToSql, &limit as &dyn ToSql],
+        )?;
+        self.sql().execute(
+            "UPDATE snapshots SET pruned=MAX(pruned, ?) WHERE board_id=?;",
+            &[&limit as &dyn ToSql, &board_id as &dyn ToSql],
+        )?;
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    use tripledeck_core::{Board, Card, Change, List, Operation, Store};
+    use tripledeck_core::server::ServerStorage;
+    use tripledeck_core::snapshot::{Snapshot, SnapshotData};
+    use tripledeck_core::sync::{Credentials, Role};
+
+    use crate::{SqliteStorage, uuid2str};
+
+    const DAY: Duration = Duration::from_secs(24 * 3600);
+
+    fn token(token: &str) -> Credentials {
+        Credentials::Token { token: token.into() }
+    }
+
+    #[test]
+    fn test_tokens() {
+        let storage = SqliteStorage::in_memory().unwrap();
+        assert_eq!(storage.create_token("alice").unwrap(), None);
+        storage.set_password("alice", "hunter2").unwrap();
+        let first = storage.create_token("alice").unwrap().unwrap();
+        let second = storage.create_token("alice").unwrap().unwrap();
+        assert_ne!(first, second);
+        for t in &[&first, &second] {
+            assert_eq!(storage.authenticate(&token(t)).unwrap(),
+                       Some("alice".into()));
+        }
+        assert_eq!(storage.authenticate(&token("0123")).unwrap(), None);
+
+        let password = |password: &str| Credentials::Password {
+            user: "alice".into(),
+            password: password.into(),
+        };
+        assert_eq!(storage.authenticate(&password("hunter2")).unwrap(),
+                   Some("alice".into()));
+        assert_eq!(storage.authenticate(&password("hunter3")).unwrap(), None);
+    }
+
+    #[test]
+    fn test_claim() {
+        let storage = SqliteStorage::in_memory().unwrap();
+        storage.set_password("alice", "a").unwrap();
+        storage.set_password("bob", "b").unwrap();
+
+        // A new board goes to whoever creates it, only once
+        let board_id = Uuid::new_v4();
+        assert!(storage.claim_board(&board_id, "alice").unwrap());
+        assert!(!storage.claim_board(&board_id, "bob").unwrap());
+        assert_eq!(storage.get_role(&board_id, "alice").unwrap(),
+                   Some(Role::Owner));
+        assert_eq!(storage.get_role(&board_id, "bob").unwrap(), None);
+
+        // A board that exists without members can't be claimed, for
+        // example one created before authentication was turned on
+        let board_id = Uuid::new_v4();
+        let op = Operation::new(board_id, Change::AddBoard {
+            name: "Work".into(),
+        });
+        storage.receive_operations(vec![(op, false)]).unwrap();
+        assert!(!storage.claim_board(&board_id, "bob").unwrap());
+        assert_eq!(storage.get_role(&board_id, "bob").unwrap(), None);
+
+        // It has to be given explicitly
+        assert!(storage.set_role(&board_id, "bob", Some(Role::Viewer))
+                .unwrap());
+        assert_eq!(storage.get_role(&board_id, "bob").unwrap(),
+                   Some(Role::Viewer));
+        assert!(!storage.set_role(&board_id, "carol", Some(Role::Owner))
+                .unwrap());
+    }
+
+    fn seqs(storage: &SqliteStorage, board_id: &Uuid) -> Vec<u64> {
+        storage.get_operations(board_id, 0).unwrap().into_iter()
+            .map(|(seq, _)| seq)
+            .collect()
+    }
+
+    fn snapshot(board_id: Uuid, name: &str) -> SnapshotData {
+        SnapshotData::Plain {
+            snapshot: Snapshot {
+                board: Board { id: board_id, name: name.into() },
+                lists: vec![],
+                cards: vec![],
+            },
+        }
+    }
+
+    #[test]
+    fn test_prune() {
+        let storage = SqliteStorage::in_memory().unwrap();
+        let board_id = Uuid::new_v4();
+        let operations = (0..5).map(|i| {
+            let op = Operation::new(board_id, Change::AddList {
+                list: List { id: Uuid::new_v4(), name: format!("{}", i) },
+            });
+            (op, false)
+        });
+        storage.receive_operations(operations).unwrap();
+
+        // Nothing is pruned without a snapshot
+        assert_eq!(storage.prune(&board_id, DAY).unwrap(), 0);
+        assert_eq!(seqs(&storage, &board_id), vec![1, 2, 3, 4, 5]);
+
+        // Pruning waits for the replicas
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        storage.record_replica(&board_id, &first, 2).unwrap();
+        storage.record_replica(&board_id, &second, 5).unwrap();
+        storage.store_snapshot(&board_id, 3, &snapshot(board_id, "3"))
+            .unwrap();
+        assert_eq!(storage.prune(&board_id, DAY).unwrap(), 2);
+        assert_eq!(seqs(&storage, &board_id), vec![3, 4, 5]);
+        let stored = storage.get_snapshot(&board_id).unwrap().unwrap();
+        assert_eq!((stored.cursor, stored.pruned), (3, 2));
+
+        // And doesn't go past the snapshot
+        storage.record_replica(&board_id, &first, 5).unwrap();
+        assert_eq!(storage.prune(&board_id, DAY).unwrap(), 1);
+        assert_eq!(seqs(&storage, &board_id), vec![4, 5]);
+
+        // An older snapshot doesn't replace a newer one
+        storage.store_snapshot(&board_id, 2, &snapshot(board_id, "2"))
+            .unwrap();
+        let stored = storage.get_snapshot(&board_id).unwrap().unwrap();
+        assert_eq!((stored.cursor, stored.pruned), (3, 3));
+        assert_eq!(stored.data, snapshot(board_id, "3"));
+
+        // Replicas that haven't been seen for a while are not waited for
+        storage.store_snapshot(&board_id, 5, &snapshot(board_id, "5"))
+            .unwrap();
+        storage.record_replica(&board_id, &second, 4).unwrap();
+        storage.sql().execute(
+            "UPDATE replicas SET last_seen=0 WHERE replica_id=?;",
+            &[&uuid2str(&second)],
+        ).unwrap();
+        assert_eq!(storage.prune(&board_id, DAY).unwrap(), 2);
+        assert!(seqs(&storage, &board_id).is_empty());
+        assert_eq!(storage.get_last_seq(&board_id).unwrap(), 5);
+    }
+
+    #[test]
+    fn test_restore_stale() {
+        let storage = SqliteStorage::in_memory().unwrap();
+        let board = Board { id: Uuid::new_v4(), name: "Old".into() };
+        let todo = List { id: Uuid::new_v4(), name: "todo".into() };
+        let done = List { id: Uuid::new_v4(), name: "done".into() };
+        let card = Card::new(todo.id, "old title");
+        let removed = Card::new(done.id, "removed");
+        block_on(async {
+            storage.add_board(&board).await?;
+            storage.add_list(&board.id, &todo).await?;
+            storage.add_list(&board.id, &done).await?;
+            storage.add_card(&board.id, &card).await?;
+            storage.add_card(&board.id, &removed).await
+        }).unwrap();
+
+        // The snapshot renamed the board and the card, removed a list and a
+        // card, and added a card
+        let added = Card::new(todo.id, "added");
+        let snapshot = Snapshot {
+            board: Board { id: board.id, name: "New".into() },
+            lists: vec![todo.clone()],
+            cards: vec![
+                Card { title: "new title".into(), ..card.clone() },
+                added.clone(),
+            ],
+        };
+        block_on(snapshot.clone().restore(&storage)).unwrap();
+        let mut restored = block_on(Snapshot::take(&storage, &board.id))
+            .unwrap().unwrap();
+        restored.cards.sort_by_key(|c| c.title.clone());
+        let mut expected = snapshot;
+        expected.cards.sort_by_key(|c| c.title.clone());
+        assert_eq!(restored, expected);
+    }
+}
@@ -5,9 +5,12 @@ const client = import("./dist/tripledeck_wasm");
 const BOARD_ID = "936DA01F9ABD4D9D80C70000BBBB0000";
 
 client.then(client => {
-    client.get_board(BOARD_ID)
+    client.open_database()
+    .then(() => client.get_board(BOARD_ID))
     .then((b) => {
         console.log("board = ", b);
+    }, (e) => {
+        alert("Couldn't access indexed storage");
     });
 
     [].forEach.call(document.querySelectorAll(".d3ck-card"), (card) => {
@@ -1,80 +1,92 @@
-const DB_NAME = "tripledeck";
-const DB_VERSION = 1;
+// The database is opened and upgraded by the Rust code, see schema.rs
 var db = null;
 
-var request = window.indexedDB.open(DB_NAME, DB_VERSION);
+window.storage_use_database = function(database) {
+    console.log("Database opened");
+    db = database;
+    window.tripledeck_db = db;
+};
 
-request.onerror = function(event) {
-    alert("Couldn't access indexed storage");
+var ALL_STORES = ["boards", "lists", "cards", "operations", "sync_cursors",
+                  "settings", "activity"];
+
+// Get the transaction a call should use: the one it was given, started by
+// storage_begin(), or a new one
+function transaction(stores, mode, reject, tran) {
+    if(tran) {
+        tran.waiting.push(reject);
+        return tran;
+    }
+    tran = db.transaction(stores, mode);
+    tran.onerror = function(event) { reject(tran.error); };
+    return tran;
 }
 
-request.onblocked = function(event) {
-    alert("Please close all other tabs of this site to allow the database " +
-          "to upgrade");
-};
+// Resolve a call once its changes are written. In a transaction from
+// storage_begin(), this is as soon as the requests are made: they run in
+// order, and are committed or rolled back together.
+function done(tran, resolve, value) {
+    if(tran.waiting) {
+        resolve(value);
+    } else {
+        tran.oncomplete = function() { resolve(value); };
+    }
+}
 
-request.onupgradeneeded = function(event) {
-    console.log("Database upgrade...");
-    var db = event.target.result;
+function abortWaiting(tran) {
+    var rejects = tran.waiting;
+    tran.waiting = [];
+    rejects.forEach(function(reject) { reject(tran.error); });
+}
 
-    db.onversionchange = function(event) {
-        db.close();
-        alert("Database upgraded, please reload or close this tab");
-    }
+// Start a transaction, which the other calls use when they are given it.
+// Calls that aren't wait until it is over: IndexedDB doesn't run a
+// transaction while a read-write one using the same stores is active.
+//
+// IndexedDB commits a transaction by itself once no requests are pending
+// at the end of a task, so the calls have to follow each other right away,
+// which they do when chained with promises
+window.storage_begin = function() {
+    console.log("Storage: begin()");
+    return new Promise(function(resolve, reject) {
+        var tran = db.transaction(ALL_STORES, "readwrite");
+        // Rejection functions of the calls made in the transaction, in case
+        // it is aborted
+        tran.waiting = [];
+        tran.onabort = function() { abortWaiting(tran); };
+        resolve(tran);
+    });
+};
 
-    var boards = db.createObjectStore("boards", {keyPath: "id"});
-    boards.createIndex("name", "name", {unique: false});
-
-    var lists = db.createObjectStore("lists", {keyPath: "id"});
-    lists.createIndex("board", "board", {unique: false});
-
-    var cards = db.createObjectStore("cards", {keyPath: "id"});
-    cards.createIndex("board", "board", {unique: false});
-    cards.createIndex("list", "list", {unique: false});
-
-    cards.transaction.oncomplete = function() {
-        var tran = db.transaction(["boards", "lists", "cards"],
-                                  "readwrite");
-
-        var boards = tran.objectStore("boards");
-        boards.add({id: "936DA01F9ABD4D9D80C70000BBBB0000", name: "board"});
-
-        var lists = tran.objectStore("lists");
-        lists.add({id: "936DA01F9ABD4D9D80C7000011110001", name: "todo",
-                  board: "936DA01F9ABD4D9D80C70000BBBB0000"});
-        lists.add({id: "936DA01F9ABD4D9D80C7000011110002", name: "doing",
-                  board: "936DA01F9ABD4D9D80C70000BBBB0000"});
-        lists.add({id: "936DA01F9ABD4D9D80C7000011110003", name: "done",
-                  board: "936DA01F9ABD4D9D80C70000BBBB0000"});
-
-        var cards = tran.objectStore("cards");
-        cards.add({id: "936DA01F9ABD4D9D80C70000CCCC0001", title: "design",
-                   board: "936DA01F9ABD4D9D80C70000BBBB0000",
-                   list: "936DA01F9ABD4D9D80C7000011110003"});
-        cards.add({id: "936DA01F9ABD4D9D80C70000CCCC0002", title: "implement",
-                   board: "936DA01F9ABD4D9D80C70000BBBB0000",
-                  list: "936DA01F9ABD4D9D80C7000011110002"});
-        cards.add({id: "936DA01F9ABD4D9D80C70000CCCC0003", title: "test",
-                   board: "936DA01F9ABD4D9D80C70000BBBB0000",
-                   list: "936DA01F9ABD4D9D80C7000011110001"});
-        cards.add({id: "936DA01F9ABD4D9D80C70000CCCC0004", title: "document",
-                   board: "936DA01F9ABD4D9D80C70000BBBB0000",
-                   list: "936DA01F9ABD4D9D80C7000011110001"});
-
-        console.log("Database upgrade complete");
-    };
+window.storage_commit = function(tran) {
+    console.log("Storage: commit()");
+    return new Promise(function(resolve, reject) {
+        tran.waiting = [];
+        tran.oncomplete = function() { resolve(); };
+        tran.onabort = function() { reject(tran.error); };
+        if(tran.commit) {
+            tran.commit();
+        }
+    });
 };
 
-request.onsuccess = function(event) {
-    console.log("Database opened");
-    db = event.target.result;
-    window.tripledeck_db = db;
+window.storage_rollback = function(tran) {
+    console.log("Storage: rollback()");
+    return new Promise(function(resolve, reject) {
+        tran.waiting = [];
+        try {
+            tran.abort();
+        } catch(e) {
+            // Already aborted because of an error, or committed
+        }
+        resolve();
+    });
 };
 
-window.storage_get_board = function(id) {
+window.storage_get_board = function(id, tran) {
     console.log("Storage: get_board(", id, ")");
     return new Promise(function(resolve, reject) {
-        var tran = db.transaction(["boards", "lists"]);
+        tran = transaction(["boards", "lists"], "readonly", reject, tran);
 
         var req = tran.objectStore("boards").get(id);
         req.onerror = function(event) { reject(event.target.errorCode); };
@@ -93,38 +105,24 @@ window.storage_get_board = function(id) {
     });
 };
 
-window.storage_add_board = function(board) {
+window.storage_add_board = function(board, tran) {
     console.log("Storage: add_board(", board.id, ")");
     return new Promise(function(resolve, reject) {
-        var tran = db.transaction(["boards", "lists"], "readwrite");
-        tran.onerror = function(event) { reject(tran.error); };
+        tran = transaction(["boards"], "readwrite", reject, tran);
 
-        // Add the board
-        var req_b = tran.objectStore("boards").put({
+        tran.objectStore("boards").put({
             id: board.id,
             name: board.name
         });
-        req_b.onerror = function(event) { reject(event.target.errorCode); };
-        req_b.onsuccess = function() {
-            for(var i = 0; i < board.lists.length; ++i) {
-                var list = board.lists[i];
-                // Add the lists
-                tran.objectStore("lists", "readwrite").put({
-                    id: list.id,
-                    name: list.name,
-                    board: board.id
-                });
-            }
-            tran.oncomplete = function() { resolve(); };
-        };
+        done(tran, resolve);
     });
 };
 
-window.storage_get_lists = function(board_id) {
+window.storage_get_lists = function(board_id, tran) {
     console.log("Storage: get_lists(", board_id, ")");
     return new Promise(function(resolve, reject) {
         var lists = [];
-        var tran = db.transaction(["lists"]);
+        tran = transaction(["lists"], "readonly", reject, tran);
         var req = tran.objectStore("lists").index("board").openCursor(IDBKeyRange.only(board_id));
         req.onerror = function(event) { console.log("no");reject(event.target.errorCode); };
         req.onsuccess = function(event) {
@@ -133,6 +131,11 @@ window.storage_get_lists = function(board_id) {
                 lists.push(cursor.value);
                 cursor.continue();
             } else {
+                // The index gives them by ID. Lists from before they had a
+                // position keep that order, before the others
+                lists.sort(function(a, b) {
+                    return (a.position || 0) - (b.position || 0);
+                });
                 console.log("Storage: got lists:", lists);
                 resolve(lists);
             }
@@ -140,17 +143,337 @@ window.storage_get_lists = function(board_id) {
     });
 }
 
-window.storage_add_list = function(board_id, list) {
+window.storage_add_list = function(board_id, list, tran) {
     console.log("Storage: add_list(", board_id, ", ", list.id, ")");
     return new Promise(function(resolve, reject) {
-        var tran = db.transaction(["lists"], "readwrite");
-        tran.onerror = function(event) { reject(tran.error); };
+        tran = transaction(["lists"], "readwrite", reject, tran);
+
+        // Put the list after the others on the board
+        var store = tran.objectStore("lists");
+        var position = 0;
+        var req = store.index("board").openCursor(IDBKeyRange.only(board_id));
+        req.onsuccess = function(event) {
+            var cursor = event.target.result;
+            if(cursor) {
+                if(cursor.value.id !== list.id) {
+                    position = Math.max(position, cursor.value.position || 0);
+                }
+                cursor.continue();
+            } else {
+                store.put({
+                    id: list.id,
+                    name: list.name,
+                    board: board_id,
+                    position: position + 1
+                });
+                done(tran, resolve);
+            }
+        };
+    });
+};
 
-        var req = tran.objectStore("lists").put({
-            id: list.id,
-            name: list.name,
+window.storage_get_cards = function(board_id, tran) {
+    console.log("Storage: get_cards(", board_id, ")");
+    return new Promise(function(resolve, reject) {
+        var cards = [];
+        tran = transaction(["cards"], "readonly", reject, tran);
+        var req = tran.objectStore("cards").index("board").openCursor(IDBKeyRange.only(board_id));
+        req.onerror = function(event) { reject(event.target.errorCode); };
+        req.onsuccess = function(event) {
+            var cursor = event.target.result;
+            if(cursor) {
+                cards.push(cursor.value);
+                cursor.continue();
+            } else {
+                console.log("Storage: got cards:", cards);
+                resolve(cards);
+            }
+        };
+    });
+}
+
+window.storage_add_card = function(board_id, card, tran) {
+    console.log("Storage: add_card(", board_id, ", ", card.id, ")");
+    return new Promise(function(resolve, reject) {
+        tran = transaction(["cards"], "readwrite", reject, tran);
+
+        var req = tran.objectStore("cards").put({
+            id: card.id,
+            title: card.title,
+            list: card.list,
+            description: card.description,
+            labels: card.labels,
+            due: card.due,
+            assignees: card.assignees,
+            comments: card.comments,
+            external: card.external,
             board: board_id
         });
-        tran.oncomplete = function() { resolve(); };
+        done(tran, resolve);
+    });
+};
+
+// Delete the records of a board from the stores indexed by board
+function delete_from_board(tran, stores, id, callback) {
+    if(stores.length === 0) {
+        callback();
+        return;
+    }
+    var req = tran.objectStore(stores[0]).index("board").openCursor(IDBKeyRange.only(id));
+    req.onsuccess = function(event) {
+        var cursor = event.target.result;
+        if(cursor) {
+            cursor.delete();
+            cursor.continue();
+        } else {
+            delete_from_board(tran, stores.slice(1), id, callback);
+        }
+    };
+}
+
+window.storage_delete_board = function(id, tran) {
+    console.log("Storage: delete_board(", id, ")");
+    return new Promise(function(resolve, reject) {
+        tran = transaction(["boards", "lists", "cards"], "readwrite", reject, tran);
+
+        tran.objectStore("boards").delete(id);
+        delete_from_board(tran, ["lists", "cards"], id, function() {
+            done(tran, resolve);
+        });
+    });
+};
+
+window.storage_delete_list = function(board_id, list_id, tran) {
+    console.log("Storage: delete_list(", board_id, ", ", list_id, ")");
+    return new Promise(function(resolve, reject) {
+        tran = transaction(["lists"], "readwrite", reject, tran);
+
+        tran.objectStore("lists").delete(list_id);
+        done(tran, resolve);
+    });
+};
+
+window.storage_delete_card = function(board_id, card_id, tran) {
+    console.log("Storage: delete_card(", board_id, ", ", card_id, ")");
+    return new Promise(function(resolve, reject) {
+        tran = transaction(["cards"], "readwrite", reject, tran);
+
+        tran.objectStore("cards").delete(card_id);
+        done(tran, resolve);
+    });
+};
+
+window.storage_add_operation = function(board_id, op, pending, tran) {
+    console.log("Storage: add_operation(", board_id, ", ", op.id, ")");
+    return new Promise(function(resolve, reject) {
+        tran = transaction(["operations"], "readwrite", reject, tran);
+
+        var store = tran.objectStore("operations");
+        var req = store.index("id").getKey(op.id);
+        req.onsuccess = function() {
+            if(req.result !== undefined) {
+                done(tran, resolve, false);
+            } else {
+                store.add({
+                    id: op.id,
+                    board: board_id,
+                    change: op.change,
+                    actor: op.actor,
+                    time: op.time,
+                    group: op.group,
+                    pending: pending ? 1 : 0
+                });
+                done(tran, resolve, true);
+            }
+        };
+    });
+};
+
+window.storage_get_pending_operations = function(board_id, tran) {
+    console.log("Storage: get_pending_operations(", board_id, ")");
+    return new Promise(function(resolve, reject) {
+        var operations = [];
+        tran = transaction(["operations"], "readonly", reject, tran);
+        // Records with the same index key are sorted by primary key (seq)
+        var req = tran.objectStore("operations").index("board").openCursor(IDBKeyRange.only(board_id));
+        req.onerror = function(event) { reject(event.target.errorCode); };
+        req.onsuccess = function(event) {
+            var cursor = event.target.result;
+            if(cursor) {
+                var op = cursor.value;
+                if(op.pending) {
+                    operations.push({
+                        id: op.id,
+                        board_id: board_id,
+                        change: op.change,
+                        actor: op.actor,
+                        time: op.time,
+                        group: op.group
+                    });
+                }
+                cursor.continue();
+            } else {
+                resolve(operations);
+            }
+        };
+    });
+};
+
+window.storage_acknowledge_operations = function(ids, tran) {
+    console.log("Storage: acknowledge_operations(", ids, ")");
+    return new Promise(function(resolve, reject) {
+        tran = transaction(["operations"], "readwrite", reject, tran);
+
+        var store = tran.objectStore("operations");
+        var remaining = ids.length;
+        if(remaining == 0) {
+            done(tran, resolve);
+        }
+        ids.forEach(function(id) {
+            var req = store.index("id").get(id);
+            req.onsuccess = function() {
+                var op = req.result;
+                if(op !== undefined) {
+                    op.pending = 0;
+                    store.put(op);
+                }
+                if(--remaining == 0) {
+                    done(tran, resolve);
+                }
+            };
+        });
+    });
+};
+
+window.storage_cancel_operation = function(id, tran) {
+    console.log("Storage: cancel_operation(", id, ")");
+    return new Promise(function(resolve, reject) {
+        tran = transaction(["operations"], "readwrite", reject, tran);
+
+        var store = tran.objectStore("operations");
+        var req = store.index("id").get(id);
+        req.onsuccess = function() {
+            var op = req.result;
+            if(op !== undefined && op.pending) {
+                store.delete(op.seq);
+                done(tran, resolve, true);
+            } else {
+                done(tran, resolve, false);
+            }
+        };
+    });
+};
+
+window.storage_get_sync_cursor = function(board_id, tran) {
+    console.log("Storage: get_sync_cursor(", board_id, ")");
+    return new Promise(function(resolve, reject) {
+        tran = transaction(["sync_cursors"], "readonly", reject, tran);
+        var req = tran.objectStore("sync_cursors").get(board_id);
+        req.onerror = function(event) { reject(event.target.errorCode); };
+        req.onsuccess = function() {
+            resolve(req.result === undefined ? 0 : req.result.cursor);
+        };
+    });
+};
+
+window.storage_set_sync_cursor = function(board_id, cursor, tran) {
+    console.log("Storage: set_sync_cursor(", board_id, ", ", cursor, ")");
+    return new Promise(function(resolve, reject) {
+        tran = transaction(["sync_cursors"], "readwrite", reject, tran);
+
+        tran.objectStore("sync_cursors").put({
+            board: board_id,
+            cursor: cursor
+        });
+        done(tran, resolve);
+    });
+};
+
+window.storage_prune_operations = function(board_id, tran) {
+    console.log("Storage: prune_operations(", board_id, ")");
+    return new Promise(function(resolve, reject) {
+        tran = transaction(["operations"], "readwrite", reject, tran);
+
+        var req = tran.objectStore("operations").index("board").openCursor(IDBKeyRange.only(board_id));
+        req.onsuccess = function(event) {
+            var cursor = event.target.result;
+            if(cursor) {
+                if(!cursor.value.pending) {
+                    cursor.delete();
+                }
+                cursor.continue();
+            } else {
+                done(tran, resolve);
+            }
+        };
+    });
+};
+
+window.storage_get_replica_id = function(tran) {
+    console.log("Storage: get_replica_id()");
+    return new Promise(function(resolve, reject) {
+        tran = transaction(["settings"], "readonly", reject, tran);
+        var req = tran.objectStore("settings").get("replica_id");
+        req.onerror = function(event) { reject(event.target.errorCode); };
+        req.onsuccess = function() {
+            resolve(req.result === undefined ? null : req.result.value);
+        };
+    });
+};
+
+window.storage_set_replica_id = function(id, tran) {
+    console.log("Storage: set_replica_id(", id, ")");
+    return new Promise(function(resolve, reject) {
+        tran = transaction(["settings"], "readwrite", reject, tran);
+
+        tran.objectStore("settings").put({
+            name: "replica_id",
+            value: id
+        });
+        done(tran, resolve);
+    });
+};
+
+window.storage_add_activity = function(activity, tran) {
+    console.log("Storage: add_activity(", activity.operation, ")");
+    return new Promise(function(resolve, reject) {
+        tran = transaction(["activity"], "readwrite", reject, tran);
+
+        tran.objectStore("activity").add(activity);
+        done(tran, resolve);
+    });
+};
+
+window.storage_get_activity = function(id, tran) {
+    console.log("Storage: get_activity(", id, ")");
+    return new Promise(function(resolve, reject) {
+        // Activity on the board, or about the object itself
+        var entries = {};
+        tran = transaction(["activity"], "readonly", reject, tran);
+
+        var store = tran.objectStore("activity");
+        var indexes = ["board", "subject"];
+        var remaining = indexes.length;
+        indexes.forEach(function(index) {
+            var req = store.index(index).openCursor(IDBKeyRange.only(id));
+            req.onsuccess = function(event) {
+                var cursor = event.target.result;
+                if(cursor) {
+                    entries[cursor.primaryKey] = cursor.value;
+                    cursor.continue();
+                } else if(--remaining == 0) {
+                    finish();
+                }
+            };
+        });
+        var finish = function() {
+            var seqs = Object.keys(entries).map(Number);
+            seqs.sort(function(a, b) { return a - b; });
+            resolve(seqs.map(function(seq) {
+                var entry = entries[seq];
+                delete entry.seq;
+                return entry;
+            }));
+        };
     });
 };
@@ -1,22 +1,72 @@
 extern crate futures;
 extern crate js_sys;
+extern crate serde;
+extern crate serde_wasm_bindgen;
 extern crate uuid;
 extern crate wasm_bindgen;
 extern crate wasm_bindgen_futures;
+extern crate web_sys;
 
 extern crate tripledeck_core;
 
-use futures::Future;
+mod schema;
+
+use futures::StreamExt;
+use futures::channel::mpsc::{UnboundedReceiver, unbounded};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_wasm_bindgen::Serializer;
+use std::cell::RefCell;
+use std::fmt;
 use std::rc::Rc;
 use uuid::Uuid;
+use wasm_bindgen::JsCast;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::{JsFuture, future_to_promise};
+use web_sys::{Blob, BlobPropertyBag, CloseEvent, IdbDatabase, MessageEvent,
+              Url, WebSocket};
 
-use tripledeck_core::{List, Board, BoardHandle, Storage};
+use tripledeck_core::{Card, List, Board, Operation, Storage, Store,
+                      Transaction};
+use tripledeck_core::activity::{Activity, Actor};
+use tripledeck_core::crypto::{BoardKey, Keyring};
+use tripledeck_core::events::{Event, SubscriptionId};
+use tripledeck_core::interchange::BoardExport;
+use tripledeck_core::sync::{ClientError, Credentials, SyncClient, Transport};
 
 #[wasm_bindgen]
 pub struct BoardWrap(Rc<tripledeck_core::BoardHandle<JsStorage>>);
 
+#[wasm_bindgen]
+impl BoardWrap {
+    /// Add a list to the board. It is there right away, and removed again
+    /// if it can't be stored.
+    pub fn add_list(&self, name: &str) -> js_sys::Promise {
+        let fut = self.0.add_list(name);
+        future_to_promise(async move {
+            fut.await?;
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+
+    /// Call a JavaScript function with each change to the board.
+    pub fn subscribe(&self, callback: js_sys::Function) -> SubscriptionId {
+        self.0.subscribe(event_callback(callback))
+    }
+
+    pub fn unsubscribe(&self, id: SubscriptionId) -> bool {
+        self.0.unsubscribe(id)
+    }
+}
+
+/// Wrap a JavaScript function as a callback receiving change events.
+fn event_callback(callback: js_sys::Function) -> impl Fn(&Event) {
+    move |event| {
+        let event = to_js(event).unwrap();
+        callback.call1(&JsValue::NULL, &event).ok();
+    }
+}
+
 fn uuid2str(id: &Uuid) -> String {
     format!("{:X}", id.to_simple_ref())
 }
@@ -26,77 +76,650 @@ thread_local! {
         tripledeck_core::App::new(JsStorage);
 }
 
-// Storage functions provided by JavaScript
+// Storage functions provided by JavaScript; they make their changes in the
+// transaction they are given, or in one of their own if it is null
 #[wasm_bindgen]
 extern {
-    pub fn storage_add_board(board: &JsValue) -> js_sys::Promise;
-    pub fn storage_get_board(id: &str) -> js_sys::Promise;
-    pub fn storage_get_lists(board_id: &str) -> js_sys::Promise;
-    pub fn storage_add_list(board_id: &str, list: &JsValue) -> js_sys::Promise;
+    pub fn storage_use_database(db: &IdbDatabase);
+    pub fn storage_add_board(board: &JsValue, tran: &JsValue)
+        -> js_sys::Promise;
+    pub fn storage_get_board(id: &str, tran: &JsValue) -> js_sys::Promise;
+    pub fn storage_get_lists(board_id: &str, tran: &JsValue)
+        -> js_sys::Promise;
+    pub fn storage_add_list(board_id: &str, list: &JsValue, tran: &JsValue)
+        -> js_sys::Promise;
+    pub fn storage_delete_board(id: &str, tran: &JsValue) -> js_sys::Promise;
+    pub fn storage_delete_list(board_id: &str, list_id: &str, tran: &JsValue)
+        -> js_sys::Promise;
+    pub fn storage_get_cards(board_id: &str, tran: &JsValue)
+        -> js_sys::Promise;
+    pub fn storage_add_card(board_id: &str, card: &JsValue, tran: &JsValue)
+        -> js_sys::Promise;
+    pub fn storage_delete_card(board_id: &str, card_id: &str, tran: &JsValue)
+        -> js_sys::Promise;
+    pub fn storage_add_operation(board_id: &str, op: &JsValue, pending: bool,
+                                 tran: &JsValue)
+        -> js_sys::Promise;
+    pub fn storage_get_pending_operations(board_id: &str, tran: &JsValue)
+        -> js_sys::Promise;
+    pub fn storage_acknowledge_operations(ids: &JsValue, tran: &JsValue)
+        -> js_sys::Promise;
+    pub fn storage_cancel_operation(id: &str, tran: &JsValue)
+        -> js_sys::Promise;
+    pub fn storage_get_sync_cursor(board_id: &str, tran: &JsValue)
+        -> js_sys::Promise;
+    pub fn storage_set_sync_cursor(board_id: &str, cursor: f64,
+                                   tran: &JsValue)
+        -> js_sys::Promise;
+    pub fn storage_prune_operations(board_id: &str, tran: &JsValue)
+        -> js_sys::Promise;
+    pub fn storage_get_replica_id(tran: &JsValue) -> js_sys::Promise;
+    pub fn storage_set_replica_id(id: &str, tran: &JsValue)
+        -> js_sys::Promise;
+    pub fn storage_add_activity(activity: &JsValue, tran: &JsValue)
+        -> js_sys::Promise;
+    pub fn storage_get_activity(id: &str, tran: &JsValue) -> js_sys::Promise;
+    pub fn storage_begin() -> js_sys::Promise;
+    pub fn storage_commit(tran: &JsValue) -> js_sys::Promise;
+    pub fn storage_rollback(tran: &JsValue) -> js_sys::Promise;
+}
+
+/// Error from JavaScript, or a value it gave us that we couldn't read.
+#[derive(Debug)]
+pub enum JsError {
+    Js(JsValue),
+    Conversion(serde_wasm_bindgen::Error),
+}
+
+impl fmt::Display for JsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JsError::Js(ref value) => {
+                if let Some(error) = value.dyn_ref::<js_sys::Error>() {
+                    write!(f, "{}", String::from(error.message()))
+                } else if let Some(message) = value.as_string() {
+                    write!(f, "{}", message)
+                } else {
+                    write!(f, "{:?}", value)
+                }
+            }
+            JsError::Conversion(ref e) => write!(f, "Invalid value: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for JsError {}
+
+impl From<JsValue> for JsError {
+    fn from(value: JsValue) -> JsError {
+        JsError::Js(value)
+    }
+}
+
+impl From<serde_wasm_bindgen::Error> for JsError {
+    fn from(error: serde_wasm_bindgen::Error) -> JsError {
+        JsError::Conversion(error)
+    }
+}
+
+impl From<JsError> for JsValue {
+    fn from(error: JsError) -> JsValue {
+        match error {
+            JsError::Js(value) => value,
+            JsError::Conversion(e) => e.into(),
+        }
+    }
+}
+
+/// Convert a value to JavaScript, the way it would be encoded as JSON.
+fn to_js<T: Serialize + ?Sized>(value: &T) -> Result<JsValue, JsError> {
+    Ok(value.serialize(&Serializer::json_compatible())?)
+}
+
+fn from_js<T: DeserializeOwned>(value: JsValue) -> Result<T, JsError> {
+    Ok(serde_wasm_bindgen::from_value(value)?)
+}
+
+/// Wait for a promise returned by JavaScript.
+async fn call(promise: js_sys::Promise) -> Result<JsValue, JsError> {
+    Ok(JsFuture::from(promise).await?)
 }
 
 /// Adapter for Storage trait using JavaScript code.
 struct JsStorage;
 
+impl JsStorage {
+    /// Calls on the storage itself are not in a transaction.
+    fn tran(&self) -> JsValue {
+        JsValue::NULL
+    }
+}
+
+/// The methods of `Store`, which are the same on the storage and its
+/// transactions: they pass the calls to JavaScript with `tran()`.
+macro_rules! store_methods {
+    () => {
+        async fn add_board(&self, board: &Board) -> Result<(), JsError> {
+            call(storage_add_board(&to_js(board)?, &self.tran())).await?;
+            Ok(())
+        }
+
+        async fn get_board(&self, id: &Uuid)
+            -> Result<Option<Board>, JsError>
+        {
+            let board = storage_get_board(&uuid2str(id), &self.tran());
+            from_js(call(board).await?)
+        }
+
+        async fn get_lists(&self, board_id: &Uuid)
+            -> Result<Vec<List>, JsError>
+        {
+            let lists = storage_get_lists(&uuid2str(board_id), &self.tran());
+            from_js(call(lists).await?)
+        }
+
+        async fn add_list(&self, board_id: &Uuid, list: &List)
+            -> Result<(), JsError>
+        {
+            call(storage_add_list(
+                &uuid2str(board_id),
+                &to_js(list)?,
+                &self.tran(),
+            )).await?;
+            Ok(())
+        }
+
+        async fn delete_board(&self, id: &Uuid) -> Result<(), JsError> {
+            call(storage_delete_board(&uuid2str(id), &self.tran())).await?;
+            Ok(())
+        }
+
+        async fn delete_list(&self, board_id: &Uuid, list_id: &Uuid)
+            -> Result<(), JsError>
+        {
+            // Lists are stored with their IDs as serialized
+            call(storage_delete_list(
+                &uuid2str(board_id),
+                &list_id.to_string(),
+                &self.tran(),
+            )).await?;
+            Ok(())
+        }
+
+        async fn get_cards(&self, board_id: &Uuid)
+            -> Result<Vec<Card>, JsError>
+        {
+            let cards = storage_get_cards(&uuid2str(board_id), &self.tran());
+            from_js(call(cards).await?)
+        }
+
+        async fn add_card(&self, board_id: &Uuid, card: &Card)
+            -> Result<(), JsError>
+        {
+            call(storage_add_card(
+                &uuid2str(board_id),
+                &to_js(card)?,
+                &self.tran(),
+            )).await?;
+            Ok(())
+        }
+
+        async fn delete_card(&self, board_id: &Uuid, card_id: &Uuid)
+            -> Result<(), JsError>
+        {
+            // Cards are stored with their IDs as serialized, like lists
+            call(storage_delete_card(
+                &uuid2str(board_id),
+                &card_id.to_string(),
+                &self.tran(),
+            )).await?;
+            Ok(())
+        }
+
+        async fn add_operation(&self, op: &Operation, pending: bool)
+            -> Result<bool, JsError>
+        {
+            from_js(call(storage_add_operation(
+                &uuid2str(&op.board_id),
+                &to_js(op)?,
+                pending,
+                &self.tran(),
+            )).await?)
+        }
+
+        async fn get_pending_operations(&self, board_id: &Uuid)
+            -> Result<Vec<Operation>, JsError>
+        {
+            from_js(call(storage_get_pending_operations(
+                &uuid2str(board_id),
+                &self.tran(),
+            )).await?)
+        }
+
+        async fn acknowledge_operations(&self, ids: &[Uuid])
+            -> Result<(), JsError>
+        {
+            call(storage_acknowledge_operations(
+                &to_js(ids)?,
+                &self.tran(),
+            )).await?;
+            Ok(())
+        }
+
+        async fn cancel_operation(&self, id: &Uuid) -> Result<bool, JsError> {
+            let cancelled = storage_cancel_operation(
+                &id.to_string(),
+                &self.tran(),
+            );
+            from_js(call(cancelled).await?)
+        }
+
+        async fn get_sync_cursor(&self, board_id: &Uuid)
+            -> Result<u64, JsError>
+        {
+            let cursor = storage_get_sync_cursor(
+                &uuid2str(board_id),
+                &self.tran(),
+            );
+            from_js(call(cursor).await?)
+        }
+
+        async fn set_sync_cursor(&self, board_id: &Uuid, cursor: u64)
+            -> Result<(), JsError>
+        {
+            call(storage_set_sync_cursor(
+                &uuid2str(board_id),
+                cursor as f64,
+                &self.tran(),
+            )).await?;
+            Ok(())
+        }
+
+        async fn prune_operations(&self, board_id: &Uuid)
+            -> Result<(), JsError>
+        {
+            call(storage_prune_operations(
+                &uuid2str(board_id),
+                &self.tran(),
+            )).await?;
+            Ok(())
+        }
+
+        async fn get_replica_id(&self) -> Result<Uuid, JsError> {
+            let id = call(storage_get_replica_id(&self.tran())).await?;
+            match from_js(id)? {
+                Some(id) => Ok(id),
+                None => {
+                    // First sync, make one up
+                    let id = Uuid::new_v4();
+                    call(storage_set_replica_id(
+                        &uuid2str(&id),
+                        &self.tran(),
+                    )).await?;
+                    Ok(id)
+                }
+            }
+        }
+
+        async fn add_activity(&self, activity: &Activity)
+            -> Result<(), JsError>
+        {
+            call(storage_add_activity(&to_js(activity)?, &self.tran()))
+                .await?;
+            Ok(())
+        }
+
+        async fn get_activity(&self, id: &Uuid)
+            -> Result<Vec<Activity>, JsError>
+        {
+            // Activity is stored with its IDs as serialized
+            let activity = storage_get_activity(
+                &id.to_string(),
+                &self.tran(),
+            );
+            from_js(call(activity).await?)
+        }
+    };
+}
+
+impl Store for JsStorage {
+    type Error = JsError;
+
+    store_methods!();
+}
+
+/// IndexedDB keeps other transactions that use the same stores waiting until
+/// a read-write transaction is over, so calls made on the storage during one
+/// don't see its changes.
 impl Storage for JsStorage {
-    type Error = JsValue;
-
-    fn add_board(&self, board: &Board)
-        -> Box<Future<Item=(), Error=Self::Error>>
-    {
-        Box::new(JsFuture::from(storage_add_board(
-            &JsValue::from_serde(board).unwrap(),
-        )).map(|_| ()))
-    }
-
-    fn get_board(&self, id: &Uuid)
-        -> Box<Future<Item=Option<Board>, Error=Self::Error>>
-    {
-        Box::new(JsFuture::from(storage_get_board(
-            &uuid2str(id),
-        )).map(|value| {
-            if value == JsValue::NULL {
-                None
-            } else {
-                value.into_serde().unwrap()
+    type Transaction<'a> = JsTransaction;
+
+    async fn begin(&self) -> Result<JsTransaction, JsError> {
+        let tran = call(storage_begin()).await?;
+        Ok(JsTransaction { tran, done: false })
+    }
+}
+
+/// A transaction, holding the IndexedDB transaction from `storage_begin()`.
+struct JsTransaction {
+    tran: JsValue,
+    /// Whether it was committed or rolled back
+    done: bool,
+}
+
+impl JsTransaction {
+    fn tran(&self) -> JsValue {
+        self.tran.clone()
+    }
+}
+
+impl Store for JsTransaction {
+    type Error = JsError;
+
+    store_methods!();
+}
+
+impl Transaction for JsTransaction {
+    async fn commit(mut self) -> Result<(), JsError> {
+        self.done = true;
+        call(storage_commit(&self.tran)).await?;
+        Ok(())
+    }
+
+    async fn rollback(mut self) -> Result<(), JsError> {
+        self.done = true;
+        call(storage_rollback(&self.tran)).await?;
+        Ok(())
+    }
+}
+
+impl Drop for JsTransaction {
+    fn drop(&mut self) {
+        if !self.done {
+            // Aborting happens right away, the promise has nothing to wait
+            // for
+            let _ = storage_rollback(&self.tran);
+        }
+    }
+}
+
+/// Adapter for Transport trait using the browser's WebSocket.
+struct BrowserTransport {
+    socket: WebSocket,
+    incoming: RefCell<Option<UnboundedReceiver<String>>>,
+    _onmessage: Closure<dyn FnMut(MessageEvent)>,
+    _onclose: Closure<dyn FnMut(CloseEvent)>,
+}
+
+impl BrowserTransport {
+    async fn connect(url: &str) -> Result<BrowserTransport, JsError> {
+        let socket = WebSocket::new(url)?;
+
+        // Queue the messages until they are received
+        let (sender, receiver) = unbounded();
+        let sender = Rc::new(RefCell::new(Some(sender)));
+        let sender_ = sender.clone();
+        let onmessage = Closure::wrap(Box::new(move |event: MessageEvent| {
+            if let Some(text) = event.data().as_string() {
+                if let Some(ref sender) = *sender_.borrow() {
+                    sender.unbounded_send(text).ok();
+                }
             }
-        }))
+        }) as Box<dyn FnMut(MessageEvent)>);
+        socket.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+        // Dropping the sender ends the stream
+        let onclose = Closure::wrap(Box::new(move |_: CloseEvent| {
+            sender.borrow_mut().take();
+        }) as Box<dyn FnMut(CloseEvent)>);
+        socket.set_onclose(Some(onclose.as_ref().unchecked_ref()));
+
+        // Wait for the connection to be open
+        let opened = js_sys::Promise::new(&mut |resolve, reject| {
+            socket.set_onopen(Some(&resolve));
+            socket.set_onerror(Some(&reject));
+        });
+        let res = call(opened).await;
+        socket.set_onopen(None);
+        socket.set_onerror(None);
+        res?;
+        Ok(BrowserTransport {
+            socket,
+            incoming: RefCell::new(Some(receiver)),
+            _onmessage: onmessage,
+            _onclose: onclose,
+        })
     }
+}
 
-    fn get_lists(&self, board_id: &Uuid)
-        -> Box<Future<Item=Vec<List>, Error=Self::Error>>
-    {
-        Box::new(JsFuture::from(storage_get_lists(
-            &uuid2str(board_id)
-        )).map(|array| {
-            array.into_serde().unwrap()
-        }))
+impl Drop for BrowserTransport {
+    fn drop(&mut self) {
+        self.socket.close().ok();
     }
+}
 
-    fn add_list(&self, board_id: &Uuid, list: &List)
-        -> Box<Future<Item=(), Error=Self::Error>>
-    {
-        Box::new(JsFuture::from(storage_add_list(
-            &uuid2str(board_id),
-            &JsValue::from_serde(list).unwrap(),
-        )).map(|_| ()))
+impl Transport for BrowserTransport {
+    type Error = JsError;
+
+    async fn send(&self, message: String) -> Result<(), JsError> {
+        Ok(self.socket.send_with_str(&message)?)
+    }
+
+    async fn receive(&self) -> Result<Option<String>, JsError> {
+        let mut receiver = match self.incoming.borrow_mut().take() {
+            Some(r) => r,
+            None => {
+                return Err(JsValue::from_str("Already receiving").into());
+            }
+        };
+        let message = receiver.next().await;
+        *self.incoming.borrow_mut() = Some(receiver);
+        Ok(message)
     }
 }
 
+fn client_error(error: ClientError<JsError, JsError>) -> JsValue {
+    match error {
+        ClientError::Storage(e) | ClientError::Transport(e) => e.into(),
+        e => JsValue::from_str(&e.to_string()),
+    }
+}
+
+/// Open the database, creating or upgrading it if needed. This has to be
+/// done before anything else.
+#[wasm_bindgen]
+pub fn open_database() -> js_sys::Promise {
+    future_to_promise(async {
+        let db = schema::open().await?;
+        storage_use_database(&db);
+        Ok(JsValue::UNDEFINED)
+    })
+}
+
 #[wasm_bindgen]
 pub fn get_board(id: &str) -> js_sys::Promise {
     // Convert str to Uuid
     let id = Uuid::parse_str(id).expect("Invalid board ID");
     // Get board from storage
-    let fut = APP.with(|app_| app_.get_board(&id))
-    // Convert Board to BoardWrap
-        .map(
-        |option: Option<Rc<BoardHandle<JsStorage>>>| option.map(
-            |b| BoardWrap(b)
-        )
-        )
-    // Convert to JsValue
-        .map(JsValue::from);
-    // Convert Future<JsValue> to Promise
-    future_to_promise(fut)
+    let fut = APP.with(|app| app.get_board(&id));
+    future_to_promise(async move {
+        let board = fut.await?;
+        // Convert Board to BoardWrap
+        Ok(JsValue::from(board.map(BoardWrap)))
+    })
+}
+
+/// Export a board as a JSON file.
+///
+/// Resolves to the URL of the file, to download it from a link; revoke it
+/// with `URL.revokeObjectURL()` once done. Resolves to null if there is no
+/// such board.
+#[wasm_bindgen]
+pub fn export_board(id: &str) -> js_sys::Promise {
+    let id = Uuid::parse_str(id).expect("Invalid board ID");
+    let fut = APP.with(|app| app.export_board(&id));
+    future_to_promise(async move {
+        let export = match fut.await? {
+            Some(e) => e,
+            None => return Ok(JsValue::NULL),
+        };
+        let parts = js_sys::Array::of1(&JsValue::from(export.to_json()));
+        let options = BlobPropertyBag::new();
+        options.set_type("application/json");
+        let blob = Blob::new_with_str_sequence_and_options(&parts, &options)?;
+        Ok(JsValue::from(Url::create_object_url_with_blob(&blob)?))
+    })
+}
+
+/// Import a board from a JSON file (a `File` from an input, or any `Blob`).
+///
+/// If the board is already there, a copy is added. Resolves to the new
+/// board.
+#[wasm_bindgen]
+pub fn import_board(file: &Blob) -> js_sys::Promise {
+    let text = file.text();
+    future_to_promise(async move {
+        let text = call(text).await?.as_string().unwrap_or_default();
+        let export = BoardExport::from_json(&text)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let fut = APP.with(|app| app.import_board(export));
+        Ok(JsValue::from(BoardWrap(fut.await?)))
+    })
+}
+
+/// Call a JavaScript function with each change to any board.
+#[wasm_bindgen]
+pub fn subscribe(callback: js_sys::Function) -> SubscriptionId {
+    APP.with(|app| app.subscribe(event_callback(callback)))
+}
+
+#[wasm_bindgen]
+pub fn unsubscribe(id: SubscriptionId) -> bool {
+    APP.with(|app| app.unsubscribe(id))
+}
+
+/// Undo the last action. Resolves to false if there was nothing to undo.
+#[wasm_bindgen]
+pub fn undo() -> js_sys::Promise {
+    let fut = APP.with(|app| app.undo());
+    future_to_promise(async move { Ok(JsValue::from(fut.await?)) })
+}
+
+/// Redo the last action that was undone. Resolves to false if there was
+/// nothing to redo.
+#[wasm_bindgen]
+pub fn redo() -> js_sys::Promise {
+    let fut = APP.with(|app| app.redo());
+    future_to_promise(async move { Ok(JsValue::from(fut.await?)) })
+}
+
+#[wasm_bindgen]
+pub fn can_undo() -> bool {
+    APP.with(|app| app.can_undo())
+}
+
+#[wasm_bindgen]
+pub fn can_redo() -> bool {
+    APP.with(|app| app.can_redo())
+}
+
+/// Set the name of the user the changes made from now on are attributed to.
+#[wasm_bindgen]
+pub fn set_user(name: Option<String>) {
+    APP.with(|app| app.set_actor(name.map(|name| Actor::User { name })));
+}
+
+/// Get the activity about a board, list or card, oldest first.
+#[wasm_bindgen]
+pub fn get_activity(id: &str) -> js_sys::Promise {
+    let id = Uuid::parse_str(id).expect("Invalid ID");
+    let fut = APP.with(|app| app.get_activity(&id));
+    future_to_promise(async move { Ok(to_js(&fut.await?)?) })
+}
+
+/// Generate a random board key, to share with the other devices.
+#[wasm_bindgen]
+pub fn generate_board_key() -> String {
+    BoardKey::generate().to_hex()
+}
+
+/// Derive a board key from a passphrase.
+#[wasm_bindgen]
+pub fn derive_board_key(passphrase: &str, board_id: &str) -> String {
+    let board_id = Uuid::parse_str(board_id).expect("Invalid board ID");
+    BoardKey::from_passphrase(passphrase, &board_id).to_hex()
+}
+
+/// Sync a board with a server, authenticating with a token if one is given.
+///
+/// Operations the server has are then forgotten, to save space.
+///
+/// If keys are given, the board is end-to-end encrypted with the first one,
+/// and the others are previous keys used to read older operations.
+#[wasm_bindgen]
+pub fn sync(url: &str, board_id: &str, token: Option<String>,
+            keys: Vec<String>)
+    -> js_sys::Promise
+{
+    let board_id = Uuid::parse_str(board_id).expect("Invalid board ID");
+    let credentials = token.map(|token| Credentials::Token { token });
+    let mut keyring: Option<Keyring> = None;
+    for key in keys {
+        let key = match BoardKey::from_hex(&key) {
+            Ok(k) => k,
+            Err(e) => {
+                return js_sys::Promise::reject(
+                    &JsValue::from_str(&e.to_string()),
+                );
+            }
+        };
+        match keyring {
+            Some(ref mut keyring) => keyring.add_previous(key),
+            None => keyring = Some(Keyring::new(key)),
+        }
+    }
+    let url = url.to_owned();
+    let notifier = APP.with(|app| app.notifier());
+    future_to_promise(async move {
+        let transport = BrowserTransport::connect(&url).await?;
+        let client = SyncClient::connect(Rc::new(JsStorage), transport,
+                                         board_id, credentials)
+            .await
+            .map_err(client_error)?;
+        let client = match keyring {
+            Some(keyring) => client.encrypted(keyring),
+            None => client,
+        };
+        let client = client.notifying(notifier);
+        client.sync().await.map_err(client_error)?;
+        client.prune().await.map_err(client_error)?;
+        Ok(JsValue::UNDEFINED)
+    })
+}
+
+/// Run in a browser with `make test-browser`.
+#[cfg(all(test, target_arch = "wasm32"))]
+mod tests {
+    use uuid::Uuid;
+    use wasm_bindgen_test::{wasm_bindgen_test, wasm_bindgen_test_configure};
+
+    use tripledeck_core::conformance;
+
+    use super::{JsStorage, schema, storage_use_database};
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    /// Switch the storage functions to a new database.
+    async fn new_storage() -> JsStorage {
+        let name = format!("test-{}", Uuid::new_v4());
+        let db = schema::open_named(&name).await.unwrap();
+        storage_use_database(&db);
+        JsStorage
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_conformance() {
+        // Define the storage functions, as the page does
+        js_sys::eval(include_str!("../database.js")).unwrap();
+        conformance::run_async(new_storage).await;
+    }
 }
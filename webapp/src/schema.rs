@@ -0,0 +1,405 @@
+//! Schema of the IndexedDB database, and its upgrades.
+//!
+//! Like the SQLite database of the native program, the schema version is the
+//! number of migrations applied. When the database is opened, the browser
+//! runs the missing migrations in its upgrade transaction; if one fails, the
+//! transaction is aborted and the database is left as it was. The browser
+//! refuses to open a database with a newer version than ours.
+
+use js_sys::Promise;
+use wasm_bindgen::JsCast;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{IdbDatabase, IdbIndexParameters, IdbObjectStoreParameters,
+              IdbOpenDbRequest, IdbTransaction, IdbVersionChangeEvent};
+
+const DB_NAME: &str = "tripledeck";
+
+/// A change to the schema.
+enum Step {
+    /// Create an object store, with the field records are keyed by, and
+    /// whether keys are generated
+    Store { name: &'static str, key: &'static str, auto_increment: bool },
+    /// Create an index on a field of a store
+    Index {
+        store: &'static str,
+        name: &'static str,
+        field: &'static str,
+        unique: bool,
+    },
+}
+
+const fn store(name: &'static str, key: &'static str, auto_increment: bool)
+    -> Step
+{
+    Step::Store { name, key, auto_increment }
+}
+
+const fn index(store: &'static str, name: &'static str, field: &'static str,
+               unique: bool)
+    -> Step
+{
+    Step::Index { store, name, field, unique }
+}
+
+/// Migrations, in order.
+///
+/// They are data rather than code, so tests can check that the stores agree
+/// with the tables of the SQL migrations.
+const MIGRATIONS: &[&[Step]] = &[
+    // Boards
+    &[
+        store("boards", "id", false),
+        index("boards", "name", "name", false),
+        store("lists", "id", false),
+        index("lists", "board", "board", false),
+        store("cards", "id", false),
+        index("cards", "board", "board", false),
+        index("cards", "list", "list", false),
+    ],
+    // Sync
+    &[
+        store("operations", "seq", true),
+        index("operations", "id", "id", true),
+        index("operations", "board", "board", false),
+        store("sync_cursors", "board", false),
+    ],
+    // Settings
+    &[store("settings", "name", false)],
+    // Activity feed
+    &[
+        store("activity", "seq", true),
+        index("activity", "board", "board_id", false),
+        index("activity", "subject", "subject", false),
+    ],
+];
+
+fn apply(step: &Step, db: &IdbDatabase, transaction: &IdbTransaction)
+    -> Result<(), JsValue>
+{
+    match *step {
+        Step::Store { name, key, auto_increment } => {
+            let params = IdbObjectStoreParameters::new();
+            params.set_key_path(&JsValue::from_str(key));
+            params.set_auto_increment(auto_increment);
+            db.create_object_store_with_optional_parameters(name, &params)?;
+        }
+        Step::Index { store, name, field, unique } => {
+            let params = IdbIndexParameters::new();
+            params.set_unique(unique);
+            transaction.object_store(store)?
+                .create_index_with_str_and_optional_parameters(
+                    name, field, &params,
+                )?;
+        }
+    }
+    Ok(())
+}
+
+/// The schema version this code creates.
+pub fn latest_version() -> u32 {
+    MIGRATIONS.len() as u32
+}
+
+/// Run the missing migrations, from the upgrade transaction.
+fn upgrade(request: &IdbOpenDbRequest, event: &IdbVersionChangeEvent)
+    -> Result<(), JsValue>
+{
+    let db: IdbDatabase = request.result()?.dyn_into()?;
+    let transaction = request.transaction()
+        .ok_or_else(|| JsValue::from_str("No upgrade transaction"))?;
+    let version = event.old_version() as usize;
+    web_sys::console::log_1(&JsValue::from_str(&format!(
+        "Database upgrade from version {} to {}...",
+        version, latest_version(),
+    )));
+    for step in MIGRATIONS[version..].iter().copied().flatten() {
+        apply(step, &db, &transaction)?;
+    }
+    Ok(())
+}
+
+fn alert(message: &str) {
+    if let Some(window) = web_sys::window() {
+        window.alert_with_message(message).ok();
+    }
+}
+
+/// Open the database, creating or upgrading it if needed.
+pub async fn open() -> Result<IdbDatabase, JsValue> {
+    open_named(DB_NAME).await
+}
+
+/// Open a database other than the app's, such as one for tests.
+pub async fn open_named(name: &str) -> Result<IdbDatabase, JsValue> {
+    let factory = web_sys::window()
+        .ok_or_else(|| JsValue::from_str("No window"))
+        .and_then(|window| window.indexed_db())
+        .and_then(|factory| {
+            factory.ok_or_else(|| JsValue::from_str("No IndexedDB"))
+        })?;
+    let request = factory.open_with_u32(name, latest_version())?;
+
+    let request_ = request.clone();
+    let onupgradeneeded = Closure::once_into_js(
+        move |event: IdbVersionChangeEvent| {
+            if let Err(e) = upgrade(&request_, &event) {
+                web_sys::console::error_2(
+                    &JsValue::from_str("Database upgrade failed:"), &e,
+                );
+                if let Some(transaction) = request_.transaction() {
+                    transaction.abort().ok();
+                }
+            }
+        },
+    );
+    request.set_onupgradeneeded(Some(onupgradeneeded.unchecked_ref()));
+    let onblocked = Closure::once_into_js(|| {
+        alert("Please close all other tabs of this site to allow the \
+               database to upgrade");
+    });
+    request.set_onblocked(Some(onblocked.unchecked_ref()));
+
+    // Wait for it to be open
+    let opened = Promise::new(&mut |resolve, reject| {
+        request.set_onsuccess(Some(&resolve));
+        request.set_onerror(Some(&reject));
+    });
+    let res = JsFuture::from(opened).await;
+    request.set_onsuccess(None);
+    request.set_onerror(None);
+    request.set_onupgradeneeded(None);
+    request.set_onblocked(None);
+    let db = match res {
+        Ok(_) => request.result()?.dyn_into::<IdbDatabase>()?,
+        Err(_) => {
+            return Err(match request.error() {
+                Ok(Some(e)) => e.into(),
+                _ => JsValue::from_str("Couldn't open database"),
+            });
+        }
+    };
+
+    // Another tab wants to upgrade the database
+    let db_ = db.clone();
+    let onversionchange = Closure::once_into_js(move || {
+        db_.close();
+        alert("Database upgraded, please reload or close this tab");
+    });
+    db.set_onversionchange(Some(onversionchange.unchecked_ref()));
+    Ok(db)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{MIGRATIONS, Step};
+
+    /// Fields of records, and the columns holding them.
+    type Fields = &'static [(&'static str, &'static str)];
+
+    /// Object stores and the SQL tables holding the same records, with the
+    /// column of each field that is a key or is indexed.
+    const TABLES: &[(&str, &str, Fields)] = &[
+        ("boards", "boards", &[("id", "id"), ("name", "name")]),
+        ("lists", "lists", &[("id", "id"), ("board", "board_id")]),
+        ("cards", "cards",
+         &[("id", "id"), ("board", "board_id"), ("list", "list_id")]),
+        ("operations", "operations",
+         &[("seq", "seq"), ("id", "id"), ("board", "board_id")]),
+        ("sync_cursors", "sync_cursors", &[("board", "board_id")]),
+        ("activity", "activity",
+         &[("seq", "seq"), ("board_id", "board_id"), ("subject", "subject")]),
+    ];
+
+    /// The browser keeps settings as name/value records, including the
+    /// replica ID that has its own table
+    const SETTINGS: (&str, &str) = ("settings", "replica");
+
+    /// Tables the browser doesn't have: it doesn't store board keys, and
+    /// the others are for the server
+    const NOT_IN_BROWSER: &[&str] = &[
+        "board_keys", "users", "tokens", "board_roles", "replicas",
+        "snapshots",
+    ];
+
+    #[derive(Default)]
+    struct Table {
+        columns: Vec<String>,
+        primary_key: Vec<String>,
+        auto_increment: bool,
+        unique: Vec<String>,
+        indexed: Vec<String>,
+    }
+
+    #[derive(Default)]
+    struct Store {
+        key: String,
+        auto_increment: bool,
+        /// Indexed fields, and whether they are unique
+        indexes: Vec<(String, bool)>,
+    }
+
+    /// Split on commas that are not between parentheses.
+    fn split_top_level(text: &str) -> Vec<&str> {
+        let mut items = Vec::new();
+        let (mut depth, mut start) = (0, 0);
+        for (i, c) in text.char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                ',' if depth == 0 => {
+                    items.push(text[start..i].trim());
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+        items.push(text[start..].trim());
+        items
+    }
+
+    /// Get the name before the parenthesis and what's between them.
+    fn parenthesized(text: &str) -> (&str, &str) {
+        let open = text.find('(').unwrap();
+        let close = text.rfind(')').unwrap();
+        (text[..open].split_whitespace().last().unwrap(),
+         &text[open + 1..close])
+    }
+
+    /// Read the tables created by the SQL migrations, shared by the SQLite
+    /// and PostgreSQL backends.
+    fn sql_tables() -> BTreeMap<String, Table> {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/../migrations");
+        let mut files: Vec<_> = std::fs::read_dir(dir).unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        files.sort();
+        let mut tables = BTreeMap::<String, Table>::new();
+        for file in files {
+            let sql: String = std::fs::read_to_string(file).unwrap().lines()
+                .filter(|l| !l.trim_start().starts_with("--"))
+                .collect::<Vec<_>>()
+                .join("\n");
+            for statement in sql.split(';').map(str::trim) {
+                let words: Vec<&str> = statement.split_whitespace().collect();
+                if statement.starts_with("CREATE TABLE") {
+                    let (name, body) = parenthesized(statement);
+                    let table = tables.entry(name.into()).or_default();
+                    for item in split_top_level(body) {
+                        if item.starts_with("PRIMARY KEY") {
+                            table.primary_key = split_top_level(
+                                parenthesized(item).1,
+                            ).into_iter().map(String::from).collect();
+                            continue;
+                        }
+                        let column = item.split_whitespace().next().unwrap();
+                        table.columns.push(column.into());
+                        if item.contains("PRIMARY KEY") {
+                            table.primary_key = vec![column.into()];
+                            table.auto_increment =
+                                item.contains("AUTOINCREMENT");
+                        }
+                        if item.contains("UNIQUE") {
+                            table.unique.push(column.into());
+                        }
+                    }
+                } else if statement.starts_with("ALTER TABLE") {
+                    // ALTER TABLE <table> ADD COLUMN <column> ...
+                    tables.get_mut(words[2]).unwrap()
+                        .columns.push(words[5].into());
+                } else if statement.starts_with("UPDATE") {
+                    // Changes records, not the schema
+                } else if statement.starts_with("CREATE INDEX") {
+                    let (name, column) = parenthesized(
+                        &statement[statement.find(" ON ").unwrap()..],
+                    );
+                    tables.get_mut(name).unwrap()
+                        .indexed.push(column.trim().into());
+                } else {
+                    assert!(statement.is_empty(),
+                            "Unknown statement: {}", statement);
+                }
+            }
+        }
+        tables
+    }
+
+    /// Get the stores created by the IndexedDB migrations.
+    fn stores() -> BTreeMap<&'static str, Store> {
+        let mut stores = BTreeMap::<&str, Store>::new();
+        for step in MIGRATIONS.iter().copied().flatten() {
+            match *step {
+                Step::Store { name, key, auto_increment } => {
+                    assert!(!stores.contains_key(name));
+                    stores.insert(name, Store {
+                        key: key.into(),
+                        auto_increment,
+                        indexes: Vec::new(),
+                    });
+                }
+                Step::Index { store, field, unique, .. } => {
+                    stores.get_mut(store).unwrap()
+                        .indexes.push((field.into(), unique));
+                }
+            }
+        }
+        stores
+    }
+
+    #[test]
+    fn test_agrees_with_sql() {
+        let tables = sql_tables();
+        let mut stores = stores();
+        assert!(stores.remove(SETTINGS.0).is_some());
+        assert!(tables.contains_key(SETTINGS.1));
+
+        // Every table is in the browser, or known not to be
+        for name in tables.keys() {
+            assert!(
+                NOT_IN_BROWSER.contains(&name.as_str()) ||
+                    name == SETTINGS.1 ||
+                    TABLES.iter().any(|&(_, t, _)| t == name),
+                "Table {} has no object store", name,
+            );
+        }
+
+        assert_eq!(stores.len(), TABLES.len());
+        for &(name, table, fields) in TABLES {
+            let store = stores.get(name)
+                .unwrap_or_else(|| panic!("No object store {}", name));
+            let table = &tables[table];
+            let column = |field: &str| {
+                let &(_, column) = fields.iter()
+                    .find(|&&(f, _)| f == field)
+                    .unwrap_or_else(|| panic!("No column for {}", field));
+                assert!(table.columns.iter().any(|c| c == column),
+                        "No column {} for {}", column, name);
+                column
+            };
+
+            // Keyed the same way
+            assert_eq!(table.primary_key, vec![column(&store.key)],
+                       "Key of {}", name);
+            assert_eq!(store.auto_increment, table.auto_increment,
+                       "Generated keys of {}", name);
+
+            // Indexes exist on the same columns, and are unique if the
+            // columns are
+            for &(ref field, unique) in &store.indexes {
+                let column = column(field);
+                assert_eq!(unique, table.unique.iter().any(|c| c == column),
+                           "Unique index on {}.{}", name, field);
+            }
+            for column in &table.indexed {
+                assert!(
+                    store.indexes.iter().any(|(field, _)| {
+                        fields.contains(&(field.as_str(), column.as_str()))
+                    }),
+                    "Object store {} has no index on {}", name, column,
+                );
+            }
+        }
+    }
+}